@@ -0,0 +1,131 @@
+//! Runner of the `BlockchainTests` fixtures of ethereum/tests. The
+//! encoded blocks of a fixture are inserted one by one on top of its
+//! genesis block, going through fork choice as with
+//! `debug_insertBlock`, and the resulting head is compared with the
+//! expected one.
+
+use block::{Block, HeaderHash};
+use rlp::UntrustedRlp;
+use serde_json::Value;
+use sputnikvm::Patch;
+use std::collections::HashMap;
+
+use error::Error;
+use miner;
+use statetest::{FixtureAccount, parse_alloc, parse_bytes, parse_hash};
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct FixtureBlock {
+    pub rlp: String,
+    /// Decoded header, only given for blocks expected to be valid.
+    #[serde(default)]
+    pub block_header: Option<Value>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct BlockchainTest {
+    pub blocks: Vec<FixtureBlock>,
+    #[serde(rename = "genesisRLP")]
+    pub genesis_rlp: String,
+    pub lastblockhash: String,
+    /// Hard fork the chain runs under, such as `Byzantium`.
+    pub network: String,
+    pub pre: HashMap<String, FixtureAccount>,
+}
+
+fn decode_block(field: &str, value: &str) -> Result<Block, Error> {
+    let bytes = parse_bytes(field, value)?;
+    Ok(UntrustedRlp::new(&bytes).as_val()?)
+}
+
+/// Import the blocks of `test` under the rules of `P`, returning what
+/// differs from the expected chain, if anything.
+pub fn check_chain<P: Patch>(test: &BlockchainTest) -> Result<Option<String>, Error> {
+    let genesis = decode_block("genesisRLP", &test.genesis_rlp)?;
+    let mut state = miner::make_state_from_genesis(genesis, &parse_alloc(&test.pre)?)?;
+
+    for (index, fixture) in test.blocks.iter().enumerate() {
+        let imported = decode_block("rlp", &fixture.rlp)
+            .and_then(|block| miner::insert_block::<P>(&mut state, block));
+        match (imported, fixture.block_header.is_some()) {
+            (Ok(hash), false) =>
+                return Ok(Some(format!("block #{} 0x{:x} is invalid but was imported", index, hash))),
+            (Err(err), true) =>
+                return Ok(Some(format!("block #{} was rejected: {}", index, err))),
+            _ => (),
+        }
+    }
+
+    let head = state.current_block();
+    let expected_head = parse_hash("lastblockhash", &test.lastblockhash)?;
+    if head.header.header_hash() != expected_head {
+        return Ok(Some(format!("head 0x{:x}, expected 0x{:x}", head.header.header_hash(), expected_head)));
+    }
+    if state.stateful().root() != head.header.state_root {
+        return Ok(Some(format!("state root 0x{:x}, expected 0x{:x}", state.stateful().root(), head.header.state_root)));
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bigint::{Address, Gas};
+    use hexutil::to_hex;
+    use rlp;
+    use serde_json;
+    use sputnikvm_network_classic::MainnetEIP160Patch;
+    use std::sync::{Arc, RwLock};
+
+    /// Fixture of the chain of a fresh node with one mined block, the
+    /// block made invalid if `tampered`.
+    fn fixture(tampered: bool, expected_valid: bool) -> BlockchainTest {
+        let state = Arc::new(RwLock::new(miner::make_state_at::<MainnetEIP160Patch>(Vec::new(), 0)));
+        miner::mine_one_at::<MainnetEIP160Patch>(state.clone(), Address::default(), 1);
+        let state = state.read().unwrap();
+        let genesis = state.get_block_by_number(0);
+
+        let mut block = state.get_block_by_number(1);
+        if tampered {
+            block.header.gas_used = block.header.gas_limit + Gas::from(1u64);
+        }
+        let last = if tampered { &genesis } else { &block };
+        BlockchainTest {
+            blocks: vec![FixtureBlock {
+                rlp: to_hex(&rlp::encode(&block)),
+                block_header: if expected_valid { Some(Value::Object(Default::default())) } else { None },
+            }],
+            genesis_rlp: to_hex(&rlp::encode(&genesis)),
+            lastblockhash: format!("0x{:x}", last.header.header_hash()),
+            network: "EIP158".to_string(),
+            pre: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn valid_chain_passes() {
+        assert_eq!(check_chain::<MainnetEIP160Patch>(&fixture(false, true)).unwrap(), None);
+    }
+
+    #[test]
+    fn invalid_block_is_rejected() {
+        assert_eq!(check_chain::<MainnetEIP160Patch>(&fixture(true, false)).unwrap(), None);
+
+        let failure = check_chain::<MainnetEIP160Patch>(&fixture(true, true)).unwrap().unwrap();
+        assert!(failure.starts_with("block #0 was rejected"), "{}", failure);
+
+        let failure = check_chain::<MainnetEIP160Patch>(&fixture(false, false)).unwrap().unwrap();
+        assert!(failure.contains("is invalid but was imported"), "{}", failure);
+    }
+
+    #[test]
+    fn blocks_without_header_are_expected_invalid() {
+        let blocks: Vec<FixtureBlock> = serde_json::from_str(
+            r#"[{"rlp": "0x00", "blockHeader": {"number": "0x01"}}, {"rlp": "0x00", "expectException": "InvalidNumber"}]"#
+        ).unwrap();
+        assert!(blocks[0].block_header.is_some());
+        assert!(blocks[1].block_header.is_none());
+    }
+}
@@ -0,0 +1,121 @@
+use bigint::Address;
+use hexutil::to_hex;
+use sha3::{Digest, Keccak256};
+use std::cell::Cell;
+
+/// How a node reads and writes addresses in RPC calls.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct AddressFormat {
+    /// Print addresses in RPC responses and logs with their EIP-55
+    /// checksum instead of in lowercase.
+    pub checksum_output: bool,
+    /// Reject mixed-case addresses with a wrong EIP-55 checksum.
+    pub strict_checksum: bool,
+}
+
+thread_local! {
+    static FORMAT: Cell<AddressFormat> = Cell::new(AddressFormat::default());
+}
+
+/// Puts the previous format back, even if the scoped call panics.
+struct FormatGuard(AddressFormat);
+
+impl Drop for FormatGuard {
+    fn drop(&mut self) {
+        let previous = self.0;
+        FORMAT.with(|format| format.set(previous));
+    }
+}
+
+impl AddressFormat {
+    /// Run `f` with addresses read and written in this format on the
+    /// current thread. Serde gives `Hex<Address>` no way to reach the
+    /// node it serves, so each node sets its format around the calls it
+    /// executes.
+    pub fn scope<T, F: FnOnce() -> T>(self, f: F) -> T {
+        let _guard = FormatGuard(FORMAT.with(|format| format.replace(self)));
+        f()
+    }
+
+    /// Format set on the current thread, the default outside of calls.
+    pub fn current() -> AddressFormat {
+        FORMAT.with(|format| format.get())
+    }
+}
+
+pub fn is_strict_checksum() -> bool {
+    AddressFormat::current().strict_checksum
+}
+
+pub fn is_checksum_output() -> bool {
+    AddressFormat::current().checksum_output
+}
+
+/// EIP-55 mixed-case representation of an address.
+pub fn to_checksum(address: &Address) -> String {
+    let lower = to_hex(&address[..])[2..].to_string();
+    let hash = Keccak256::digest(lower.as_bytes());
+
+    let mut ret = "0x".to_string();
+    for (i, c) in lower.chars().enumerate() {
+        let nibble = if i % 2 == 0 { hash[i / 2] >> 4 } else { hash[i / 2] & 0x0f };
+        if nibble >= 8 {
+            ret.extend(c.to_uppercase());
+        } else {
+            ret.push(c);
+        }
+    }
+    ret
+}
+
+/// Whether an address string is acceptable. All-lowercase and
+/// all-uppercase addresses carry no checksum and are always accepted.
+pub fn is_valid_checksum(value: &str, address: &Address) -> bool {
+    let digits = value.trim_start_matches("0x");
+    if digits == digits.to_lowercase() || digits == digits.to_uppercase() {
+        return true;
+    }
+    to_checksum(address)[2..] == *digits
+}
+
+/// Address in the format of the current thread, see `AddressFormat`.
+pub fn format_address(address: &Address) -> String {
+    if is_checksum_output() {
+        to_checksum(address)
+    } else {
+        to_hex(&address[..])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn eip55_vectors() {
+        for expected in &["0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed",
+                          "0xfB6916095ca1df60bB79Ce92cE3Ea74c37c5d359",
+                          "0xdbF03B407c01E7cD3CBea99509d93f8DDDC8C6FB",
+                          "0xD1220A0cf47c7B9Be7A2E6BA89F429762e7b9aDb"] {
+            let address = Address::from_str(&expected.to_lowercase()).unwrap();
+            assert_eq!(&to_checksum(&address), expected);
+            assert!(is_valid_checksum(expected, &address));
+        }
+    }
+
+    #[test]
+    fn format_is_scoped_to_the_call() {
+        let address = Address::from_str("0x5aaeb6053f3e94c9b9a09f33669435e7ef1beaed").unwrap();
+        let format = AddressFormat { checksum_output: true, strict_checksum: false };
+        assert_eq!(format.scope(|| format_address(&address)), "0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed");
+        assert_eq!(format_address(&address), "0x5aaeb6053f3e94c9b9a09f33669435e7ef1beaed");
+    }
+
+    #[test]
+    fn rejects_wrong_checksum() {
+        let address = Address::from_str("0x5aaeb6053f3e94c9b9a09f33669435e7ef1beaed").unwrap();
+        assert!(is_valid_checksum("0x5aaeb6053f3e94c9b9a09f33669435e7ef1beaed", &address));
+        assert!(!is_valid_checksum("0x5AAeb6053F3E94C9b9A09f33669435E7Ef1BeAed", &address));
+    }
+}
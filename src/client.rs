@@ -0,0 +1,169 @@
+use serde_json::{self, Value};
+use std::fmt;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use error::Error;
+
+/// Seconds connecting to a node, and then each read or write of a
+/// call, may take before the call fails.
+pub const REQUEST_TIMEOUT: u64 = 30;
+
+#[derive(Serialize, Debug)]
+struct RPCRequest<'a> {
+    jsonrpc: &'a str,
+    id: usize,
+    method: &'a str,
+    params: Value,
+}
+
+#[derive(Deserialize, Debug)]
+struct RPCResponse {
+    result: Option<Value>,
+    error: Option<Value>,
+}
+
+/// Plain HTTP endpoint of a JSON-RPC node, such as
+/// `http://localhost:8545/rpc`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HttpUrl {
+    /// Host and port as given, sent in the `Host` header.
+    pub host: String,
+    pub addr: SocketAddr,
+    /// Path calls are posted to, `/` if the url has none.
+    pub path: String,
+}
+
+impl HttpUrl {
+    /// Parse `[http://]host[:port][/path]`, the port defaulting to 80.
+    /// `https://` urls are rejected, as the client does not speak TLS.
+    pub fn parse(url: &str) -> Result<HttpUrl, Error> {
+        if url.starts_with("https://") {
+            return Err(Error::InvalidUrl(format!("{}: https is not supported, use a plain http endpoint", url)));
+        }
+        let rest = url.trim_start_matches("http://");
+        if rest.contains("://") {
+            return Err(Error::InvalidUrl(format!("{}: only http is supported", url)));
+        }
+
+        let (host, path) = match rest.find('/') {
+            Some(index) => (&rest[..index], &rest[index..]),
+            None => (rest, "/"),
+        };
+        let resolved = if host.ends_with(']') || !host.contains(':') {
+            (host.trim_start_matches('[').trim_end_matches(']'), 80).to_socket_addrs()
+        } else {
+            host.to_socket_addrs()
+        };
+        let addr = match resolved.ok().and_then(|mut addrs| addrs.next()) {
+            Some(addr) => addr,
+            None => return Err(Error::InvalidUrl(format!("{}: cannot resolve {}", url, host))),
+        };
+
+        Ok(HttpUrl { host: host.to_string(), addr, path: path.to_string() })
+    }
+}
+
+impl From<SocketAddr> for HttpUrl {
+    fn from(addr: SocketAddr) -> HttpUrl {
+        HttpUrl { host: addr.to_string(), addr, path: "/".to_string() }
+    }
+}
+
+impl fmt::Display for HttpUrl {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "http://{}{}", self.host, self.path)
+    }
+}
+
+/// Issue a single JSON-RPC call against a running node over plain HTTP,
+/// passing `api_key` in the `X-Api-Key` header if given.
+pub fn request(addr: &SocketAddr, api_key: Option<&str>, method: &str, params: Value) -> Result<Value, Error> {
+    request_url(&HttpUrl::from(*addr), api_key, method, params)
+}
+
+/// Issue a single JSON-RPC call against the node at `url`, as `request`.
+pub fn request_url(url: &HttpUrl, api_key: Option<&str>, method: &str, params: Value) -> Result<Value, Error> {
+    let body = encode_request(method, params)?;
+
+    let timeout = Duration::from_secs(REQUEST_TIMEOUT);
+    let mut stream = TcpStream::connect_timeout(&url.addr, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    let api_key = match api_key {
+        Some(api_key) => format!("X-Api-Key: {}\r\n", api_key),
+        None => String::new(),
+    };
+    write!(stream,
+           "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+           url.path, url.host, api_key, body.len(), body)?;
+
+    let mut raw = String::new();
+    stream.read_to_string(&mut raw)?;
+
+    let (status, body) = match raw.find("\r\n\r\n") {
+        Some(index) => (raw.lines().next().unwrap_or(""), &raw[(index + 4)..]),
+        None => return Err(Error::RemoteError("malformed HTTP response".to_string())),
+    };
+    match serde_json::from_str(body) {
+        Ok(response) => response_result(response),
+        // Not a JSON-RPC response, such as an HTTP error page.
+        Err(_) => Err(Error::RemoteError(status.to_string())),
+    }
+}
+
+/// Body of a single JSON-RPC call.
+pub fn encode_request(method: &str, params: Value) -> Result<String, Error> {
+    serde_json::to_string(&RPCRequest {
+        jsonrpc: "2.0",
+        id: 1,
+        method,
+        params,
+    }).map_err(|err| Error::RemoteError(err.to_string()))
+}
+
+/// Extract the result of a JSON-RPC response body.
+pub fn parse_response(body: &str) -> Result<Value, Error> {
+    let response: RPCResponse = serde_json::from_str(body)
+        .map_err(|err| Error::RemoteError(format!("invalid response: {}", err)))?;
+    response_result(response)
+}
+
+/// Result of a response, or its error with the message of the node.
+fn response_result(response: RPCResponse) -> Result<Value, Error> {
+    match response.error {
+        Some(error) => Err(Error::RemoteError(match error["message"].as_str() {
+            Some(message) => message.to_string(),
+            None => error.to_string(),
+        })),
+        None => Ok(response.result.unwrap_or(Value::Null)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn url_keeps_path() {
+        let url = HttpUrl::parse("http://127.0.0.1:8545/rpc/v1").unwrap();
+        assert_eq!(url.addr, "127.0.0.1:8545".parse().unwrap());
+        assert_eq!(url.host, "127.0.0.1:8545");
+        assert_eq!(url.path, "/rpc/v1");
+        assert_eq!(url.to_string(), "http://127.0.0.1:8545/rpc/v1");
+
+        let url = HttpUrl::parse("127.0.0.1").unwrap();
+        assert_eq!(url.addr, "127.0.0.1:80".parse().unwrap());
+        assert_eq!(url.path, "/");
+    }
+
+    #[test]
+    fn https_is_rejected() {
+        match HttpUrl::parse("https://127.0.0.1:8545") {
+            Err(Error::InvalidUrl(message)) => assert!(message.contains("https")),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(HttpUrl::parse("ws://127.0.0.1:8546").is_err());
+    }
+}
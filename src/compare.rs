@@ -0,0 +1,154 @@
+use serde_json::Value;
+use std::net::SocketAddr;
+
+use client;
+use error::Error;
+use rpc::LocalNode;
+
+const HEADER_FIELDS: &[&str] = &[
+    "hash", "parentHash", "stateRoot", "transactionsRoot", "receiptsRoot",
+    "logsBloom", "gasUsed", "gasLimit", "timestamp", "miner",
+];
+
+const RECEIPT_FIELDS: &[&str] = &[
+    "status", "gasUsed", "cumulativeGasUsed", "contractAddress", "logs", "root",
+];
+
+/// A chain to compare or export, either a running node or a snapshot
+/// loaded in-process.
+pub enum ChainSource {
+    /// Address of the node, and the API key sent with each call.
+    Node(SocketAddr, Option<String>),
+    Local(LocalNode),
+}
+
+impl ChainSource {
+    pub fn request(&self, method: &str, params: Vec<Value>) -> Result<Value, Error> {
+        match self {
+            &ChainSource::Node(ref addr, ref api_key) =>
+                client::request(addr, api_key.as_ref().map(|key| key.as_str()), method, Value::Array(params)),
+            &ChainSource::Local(ref node) => node.request(method, Value::Array(params)),
+        }
+    }
+
+    pub fn block_number(&self) -> Result<usize, Error> {
+        let number = self.request("eth_blockNumber", Vec::new())?;
+        let number = number.as_str()
+            .ok_or(Error::RemoteError(format!("invalid block number {}", number)))?;
+        Ok(usize::from_str_radix(number.trim_start_matches("0x"), 16)?)
+    }
+
+    /// Block `number` with the hashes of its transactions.
+    pub fn block(&self, number: usize) -> Result<Value, Error> {
+        self.request("eth_getBlockByNumber",
+                     vec![Value::String(format!("0x{:x}", number)), Value::Bool(false)])
+    }
+
+    pub fn receipt(&self, transaction_hash: &Value) -> Result<Value, Error> {
+        self.request("eth_getTransactionReceipt", vec![transaction_hash.clone()])
+    }
+}
+
+/// First point at which two chains differ.
+#[derive(Debug)]
+pub struct Divergence {
+    pub number: usize,
+    pub transaction: Option<Value>,
+    pub field: String,
+    pub left: Value,
+    pub right: Value,
+}
+
+fn diff_fields(fields: &[&str], left: &Value, right: &Value) -> Option<(String, Value, Value)> {
+    for field in fields {
+        let l = left.get(*field).cloned().unwrap_or(Value::Null);
+        let r = right.get(*field).cloned().unwrap_or(Value::Null);
+        if l != r {
+            return Some((field.to_string(), l, r));
+        }
+    }
+    None
+}
+
+/// Compare one block and its receipts. Receipts are checked first, as
+/// a differing post-transaction state root pinpoints the transaction
+/// that made the block state roots differ.
+pub fn compare_block(
+    number: usize, left: &ChainSource, right: &ChainSource
+) -> Result<Option<Divergence>, Error> {
+    let left_block = left.block(number)?;
+    let right_block = right.block(number)?;
+
+    let empty = Vec::new();
+    let left_transactions = left_block.get("transactions").and_then(|t| t.as_array()).unwrap_or(&empty);
+    let right_transactions = right_block.get("transactions").and_then(|t| t.as_array()).unwrap_or(&empty);
+
+    if left_transactions != right_transactions {
+        return Ok(Some(Divergence {
+            number, transaction: None, field: "transactions".to_string(),
+            left: Value::Array(left_transactions.clone()),
+            right: Value::Array(right_transactions.clone()),
+        }));
+    }
+
+    for transaction_hash in left_transactions {
+        let left_receipt = left.receipt(transaction_hash)?;
+        let right_receipt = right.receipt(transaction_hash)?;
+
+        if let Some((field, l, r)) = diff_fields(RECEIPT_FIELDS, &left_receipt, &right_receipt) {
+            return Ok(Some(Divergence {
+                number, transaction: Some(transaction_hash.clone()), field, left: l, right: r,
+            }));
+        }
+    }
+
+    Ok(diff_fields(HEADER_FIELDS, &left_block, &right_block).map(|(field, l, r)| Divergence {
+        number, transaction: None, field, left: l, right: r,
+    }))
+}
+
+/// Compare blocks `from..=to`, where `to` defaults to the lowest of
+/// both heads, returning the first divergence if any.
+pub fn compare(
+    left: &ChainSource, right: &ChainSource, from: usize, to: Option<usize>
+) -> Result<Option<Divergence>, Error> {
+    let to = match to {
+        Some(to) => to,
+        None => {
+            let left_height = left.block_number()?;
+            let right_height = right.block_number()?;
+            if left_height != right_height {
+                println!("heights differ: {} and {}, comparing up to the lowest",
+                         left_height, right_height);
+            }
+            if left_height < right_height { left_height } else { right_height }
+        },
+    };
+
+    for number in from..(to + 1) {
+        if let Some(divergence) = compare_block(number, left, right)? {
+            return Ok(Some(divergence));
+        }
+    }
+
+    println!("blocks {} to {} are identical", from, to);
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json;
+
+    #[test]
+    fn reports_first_differing_field() {
+        let left: Value = serde_json::from_str(r#"{"status": 1, "gasUsed": "0x5208", "root": "0x01"}"#).unwrap();
+        let right: Value = serde_json::from_str(r#"{"status": 1, "gasUsed": "0x5209", "root": "0x02"}"#).unwrap();
+
+        let (field, l, r) = diff_fields(RECEIPT_FIELDS, &left, &right).unwrap();
+        assert_eq!(field, "gasUsed");
+        assert_eq!(l, Value::String("0x5208".to_string()));
+        assert_eq!(r, Value::String("0x5209".to_string()));
+        assert!(diff_fields(RECEIPT_FIELDS, &left, &left).is_none());
+    }
+}
@@ -1,26 +1,152 @@
-use jsonrpc_core;
+use jsonrpc_core::{self, ErrorCode};
+use serde_json::Value;
 use secp256k1;
 use sputnikvm::errors::PreExecutionError;
 use rlp::DecoderError;
-use hexutil::ParseHexError;
+use hexutil::{ParseHexError, to_hex};
+use bigint::{Address, Gas, H256, U256};
 use std::num::ParseIntError;
+use std::io;
+use thiserror::Error as ThisError;
 
-#[derive(Debug)]
+/// Broad class of an error, for embedders to decide how to react.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ErrorKind {
+    /// The request or its input is invalid.
+    User,
+    /// The requested block, receipt or state is not available.
+    StateMissing,
+    /// An I/O failure, a remote node failure or a broken invariant of
+    /// the node itself.
+    Internal,
+}
+
+#[derive(Debug, ThisError)]
 pub enum Error {
+    #[error("invalid params")]
     InvalidParams,
+    #[error("invalid hex string")]
     HexError,
+    #[error("invalid integer")]
     IntError,
+    #[error("unsupported trie query")]
     UnsupportedTrieQuery,
+    #[error("invalid signature or key")]
     ECDSAError,
+    #[error("not found")]
     NotFound,
+    #[error("invalid rlp encoding")]
     RlpError,
-    CallError,
+    #[error("transaction rejected: {0}")]
+    CallError(String),
+    #[error("execution error")]
+    ExecutionError(Vec<u8>),
+    #[error("memory limit exceeded: {0} bytes")]
+    MemoryLimitExceeded(usize),
+    #[error("unknown source map jump type")]
     UnknownSourceMapJump,
+    #[error("invalid block header field {0}: {1}")]
+    InvalidHeader(&'static str, String),
+    #[error("invalid archive")]
+    InvalidArchive,
+    #[error("chain mismatch: {0}")]
+    ChainMismatch(String),
+    #[error("invalid journal")]
+    InvalidJournal,
+    #[error("receipts and logs before block {0} are pruned")]
+    Pruned(usize),
+    #[error("blocks before {0} are frozen")]
+    Frozen(usize),
+    #[error("io error")]
+    IoError,
+    #[error("{0} is locked by another node, remove it if none is running")]
+    Locked(String),
+    #[error("remote node error: {0}")]
+    RemoteError(String),
+    #[error("invalid url {0}")]
+    InvalidUrl(String),
+    #[error("unauthorized")]
+    Unauthorized,
+    #[error("rate limit exceeded")]
+    RateLimited,
+    #[error("could not decrypt key with given password")]
+    InvalidPassword,
+    #[error("invalid key file")]
+    InvalidKeyFile,
+    #[error("invalid mnemonic: {0}")]
+    InvalidMnemonic(String),
+    #[error("invalid test fixture: {0}")]
+    InvalidFixture(String),
+    #[error("{0}")]
+    Miner(MinerError),
+    #[error("internal error: {0}")]
+    Internal(&'static str),
+}
+
+/// Why a transaction cannot be executed on a state, whether it is
+/// submitted, mined or part of an imported block.
+#[derive(Debug, ThisError)]
+pub enum MinerError {
+    #[error("invalid transaction signature")]
+    InvalidSignature,
+    #[error("invalid nonce 0x{0:x}, expected 0x{1:x}")]
+    InvalidNonce(U256, U256),
+    #[error("intrinsic gas too low: gas limit 0x{0:x}, at least 0x{1:x} needed")]
+    IntrinsicGasTooLow(Gas, Gas),
+    #[error("insufficient funds for gas * price + value: balance 0x{0:x}, cost 0x{1:x}")]
+    InsufficientFunds(U256, U256),
+    #[error("transaction rejected: {0}")]
+    PreExecution(String),
+    #[error("account 0x{0:x} is missing from the state")]
+    MissingAccount(Address),
+    #[error("code 0x{0:x} is missing from the state")]
+    MissingCode(H256),
+}
+
+impl Error {
+    pub fn kind(&self) -> ErrorKind {
+        match *self {
+            Error::NotFound | Error::Pruned(_) => ErrorKind::StateMissing,
+            Error::IoError | Error::RemoteError(_) | Error::Internal(_) |
+            Error::Miner(MinerError::MissingAccount(_)) | Error::Miner(MinerError::MissingCode(_)) =>
+                ErrorKind::Internal,
+            _ => ErrorKind::User,
+        }
+    }
+}
+
+/// Message of a revert with the `Error(string)` ABI payload used by
+/// Solidity's `require` and `revert`.
+pub fn revert_reason(out: &[u8]) -> Option<String> {
+    if out.len() < 4 + 64 || out[0..4] != [0x08, 0xc3, 0x79, 0xa0] {
+        return None;
+    }
+    let data = &out[4..];
+
+    let word = |at: usize| -> Option<usize> {
+        let word = data.get(at..at.checked_add(32)?)?;
+        if word[..24].iter().any(|byte| *byte != 0) {
+            return None;
+        }
+        Some(word[24..].iter().fold(0usize, |acc, byte| (acc << 8) | *byte as usize))
+    };
+    let offset = word(0)?;
+    let len = word(offset)?;
+    let start = offset.checked_add(32)?;
+    let bytes = data.get(start..start.checked_add(len)?)?;
+
+    String::from_utf8(bytes.to_vec()).ok()
 }
 
 impl From<PreExecutionError> for Error {
-    fn from(_val: PreExecutionError) -> Error {
-        Error::CallError
+    fn from(val: PreExecutionError) -> Error {
+        Error::CallError(format!("{:?}", val))
+    }
+}
+
+impl From<MinerError> for Error {
+    fn from(val: MinerError) -> Error {
+        Error::Miner(val)
     }
 }
 
@@ -42,6 +168,12 @@ impl From<ParseIntError> for Error {
     }
 }
 
+impl From<io::Error> for Error {
+    fn from(_val: io::Error) -> Error {
+        Error::IoError
+    }
+}
+
 impl From<secp256k1::Error> for Error {
     fn from(_val: secp256k1::Error) -> Error {
         Error::ECDSAError
@@ -50,6 +182,91 @@ impl From<secp256k1::Error> for Error {
 
 impl Into<jsonrpc_core::Error> for Error {
     fn into(self) -> jsonrpc_core::Error {
-        jsonrpc_core::Error::invalid_request()
+        let mut message = self.to_string();
+        let (code, data) = match self {
+            Error::InvalidParams | Error::HexError | Error::IntError | Error::RlpError |
+            Error::ECDSAError | Error::UnknownSourceMapJump | Error::InvalidUrl(_) | Error::InvalidMnemonic(_) =>
+                (ErrorCode::InvalidParams, None),
+            Error::InvalidHeader(field, _) =>
+                (ErrorCode::ServerError(-32000), Some(Value::String(field.to_string()))),
+            Error::Pruned(number) | Error::Frozen(number) =>
+                (ErrorCode::ServerError(-32000), Some(Value::String(format!("0x{:x}", number)))),
+            Error::CallError(_) =>
+                (ErrorCode::ServerError(-32010), None),
+            Error::Miner(MinerError::MissingAccount(_)) | Error::Miner(MinerError::MissingCode(_)) =>
+                (ErrorCode::InternalError, None),
+            Error::Miner(_) =>
+                (ErrorCode::ServerError(-32010), None),
+            Error::ExecutionError(out) => {
+                if let Some(reason) = revert_reason(&out) {
+                    message = format!("execution reverted: {}", reason);
+                }
+                (ErrorCode::ServerError(-32015), Some(Value::String(to_hex(&out))))
+            },
+            Error::MemoryLimitExceeded(_) =>
+                (ErrorCode::ServerError(-32015), None),
+            Error::Unauthorized =>
+                (ErrorCode::ServerError(-32001), None),
+            Error::RateLimited =>
+                (ErrorCode::ServerError(-32005), None),
+            Error::IoError | Error::RemoteError(_) | Error::Internal(_) =>
+                (ErrorCode::InternalError, None),
+            Error::UnsupportedTrieQuery | Error::NotFound | Error::InvalidArchive | Error::ChainMismatch(_) |
+            Error::Locked(_) | Error::InvalidJournal | Error::InvalidPassword | Error::InvalidKeyFile | Error::InvalidFixture(_) =>
+                (ErrorCode::ServerError(-32000), None),
+        };
+
+        jsonrpc_core::Error { code, message, data }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn not_found_is_server_error() {
+        let err: jsonrpc_core::Error = Error::NotFound.into();
+        assert_eq!(err.code, ErrorCode::ServerError(-32000));
+        assert!(err.data.is_none());
+    }
+
+    #[test]
+    fn execution_error_carries_output() {
+        let err: jsonrpc_core::Error = Error::ExecutionError(vec![1, 2]).into();
+        assert_eq!(err.code, ErrorCode::ServerError(-32015));
+        assert_eq!(err.data, Some(Value::String("0x0102".to_string())));
+    }
+
+    #[test]
+    fn execution_error_decodes_revert_reason() {
+        // Error("insufficient allowance")
+        let mut out = vec![0x08, 0xc3, 0x79, 0xa0];
+        let mut word = [0u8; 32];
+        word[31] = 0x20;
+        out.extend_from_slice(&word);
+        word[31] = 22;
+        out.extend_from_slice(&word);
+        let mut reason = b"insufficient allowance".to_vec();
+        reason.resize(32, 0);
+        out.extend_from_slice(&reason);
+
+        assert_eq!(revert_reason(&out), Some("insufficient allowance".to_string()));
+        let err: jsonrpc_core::Error = Error::ExecutionError(out).into();
+        assert_eq!(err.message, "execution reverted: insufficient allowance");
+
+        assert_eq!(revert_reason(&[0x08, 0xc3, 0x79, 0xa0]), None);
+    }
+
+    #[test]
+    fn error_kinds() {
+        assert_eq!(Error::InvalidParams.kind(), ErrorKind::User);
+        assert_eq!(Error::Pruned(3).kind(), ErrorKind::StateMissing);
+        assert_eq!(Error::Internal("missing parent").kind(), ErrorKind::Internal);
+        assert_eq!(Error::Pruned(3).to_string(), "receipts and logs before block 3 are pruned");
+        assert_eq!(Error::Miner(MinerError::InvalidSignature).kind(), ErrorKind::User);
+        assert_eq!(Error::Miner(MinerError::MissingAccount(Address::default())).kind(), ErrorKind::Internal);
+        assert_eq!(Error::Miner(MinerError::InvalidNonce(U256::from(2u64), U256::one())).to_string(),
+                   "invalid nonce 0x2, expected 0x1");
     }
 }
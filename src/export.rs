@@ -0,0 +1,179 @@
+use bigint::{H256, U256};
+use hexutil::read_hex;
+use rlp::{RlpStream, UntrustedRlp};
+use serde_json::{self, Value};
+use std::io::Write;
+use std::str::FromStr;
+
+use compare::ChainSource;
+use error::Error;
+use miner::ConsensusReceipt;
+
+/// Encoding of exported receipts.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExportFormat {
+    /// One `eth_getTransactionReceipt` object per line, logs included.
+    Jsonl,
+    /// One RLP list `[blockNumber, transactionIndex, transactionHash,
+    /// receipt]` per receipt, the receipt in its consensus encoding.
+    Rlp,
+}
+
+impl FromStr for ExportFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s {
+            "jsonl" => Ok(ExportFormat::Jsonl),
+            "rlp" => Ok(ExportFormat::Rlp),
+            _ => Err(Error::InvalidParams),
+        }
+    }
+}
+
+fn transaction_hashes(source: &ChainSource, number: usize) -> Result<Vec<Value>, Error> {
+    let block = source.block(number)?;
+    if block.is_null() {
+        return Err(Error::NotFound);
+    }
+    Ok(block.get("transactions").and_then(|t| t.as_array()).cloned().unwrap_or(Vec::new()))
+}
+
+fn write_jsonl<W: Write>(source: &ChainSource, number: usize, out: &mut W) -> Result<usize, Error> {
+    let hashes = transaction_hashes(source, number)?;
+    for hash in &hashes {
+        let receipt = source.receipt(hash)?;
+        serde_json::to_writer(&mut *out, &receipt).map_err(|_| Error::IoError)?;
+        out.write_all(b"\n")?;
+    }
+    Ok(hashes.len())
+}
+
+fn write_rlp<W: Write>(source: &ChainSource, number: usize, out: &mut W) -> Result<usize, Error> {
+    let hashes = transaction_hashes(source, number)?;
+    let receipts = source.request("debug_getRawReceipts", vec![Value::from(number as u64)])?;
+    let receipts = receipts.as_array()
+        .ok_or(Error::RemoteError(format!("invalid receipts {}", receipts)))?;
+    if receipts.len() != hashes.len() {
+        return Err(Error::RemoteError(format!("{} receipts for {} transactions in block {}",
+                                              receipts.len(), hashes.len(), number)));
+    }
+
+    for (index, (hash, receipt)) in hashes.iter().zip(receipts).enumerate() {
+        let hash = hash.as_str().ok_or(Error::HexError)?;
+        let hash = H256::from_str(hash.trim_start_matches("0x")).map_err(|_| Error::HexError)?;
+        let raw = read_hex(receipt.as_str().ok_or(Error::HexError)?)?;
+        let receipt: ConsensusReceipt = UntrustedRlp::new(&raw).as_val()?;
+
+        let mut stream = RlpStream::new_list(4);
+        stream.append(&U256::from(number));
+        stream.append(&U256::from(index));
+        stream.append(&hash);
+        stream.append(&receipt);
+        out.write_all(&stream.out())?;
+    }
+    Ok(hashes.len())
+}
+
+/// Write the receipts and logs of blocks `from..=to` of `source`, where
+/// `to` defaults to its head, returning the number of receipts written.
+/// Blocks are fetched and written one at a time, so neither the source
+/// nor the export hold the whole range in memory.
+pub fn export_receipts<W: Write>(
+    source: &ChainSource, from: usize, to: Option<usize>, format: ExportFormat, out: &mut W
+) -> Result<usize, Error> {
+    let to = match to {
+        Some(to) => to,
+        None => source.block_number()?,
+    };
+
+    let mut count = 0;
+    for number in from..(to + 1) {
+        count += match format {
+            ExportFormat::Jsonl => write_jsonl(source, number, out)?,
+            ExportFormat::Rlp => write_rlp(source, number, out)?,
+        };
+    }
+    out.flush()?;
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bigint::{Address, Gas};
+    use block::{TransactionAction, UnsignedTransaction};
+    use keystore::KeyStore;
+    use miner::{make_state_at, mine_one_at, sign_transaction};
+    use rpc::LocalNode;
+    use secp256k1::SECP256K1;
+    use secp256k1::key::SecretKey;
+    use sputnikvm_network_classic::MainnetEIP160Patch;
+    use std::env;
+    use std::sync::{Arc, RwLock};
+
+    fn source_with_transfers() -> ChainSource {
+        let key = SecretKey::from_slice(&SECP256K1, &[1u8; 32]).unwrap();
+        let mut state = make_state_at::<MainnetEIP160Patch>(vec![(key.clone(), U256::from(1_000_000u64))], 0);
+        for nonce in 0..2u64 {
+            state.append_pending_transaction(sign_transaction(UnsignedTransaction {
+                nonce: U256::from(nonce),
+                gas_price: Gas::zero(),
+                gas_limit: Gas::from(21000u64),
+                action: TransactionAction::Call(Address::from(0x10u64)),
+                value: U256::from(1000u64),
+                input: Vec::new(),
+            }, &key, None)).unwrap();
+        }
+
+        let state = Arc::new(RwLock::new(state));
+        mine_one_at::<MainnetEIP160Patch>(state.clone(), Address::default(), 1);
+        let state = Arc::try_unwrap(state).ok().unwrap().into_inner().unwrap();
+        ChainSource::Local(LocalNode::new::<MainnetEIP160Patch>(
+            state, KeyStore::new(env::temp_dir().join("svmdev-export-test"))))
+    }
+
+    #[test]
+    fn exports_one_json_receipt_per_line() {
+        let source = source_with_transfers();
+        let mut out = Vec::new();
+        assert_eq!(export_receipts(&source, 0, None, ExportFormat::Jsonl, &mut out).unwrap(), 2);
+
+        let lines: Vec<Value> = String::from_utf8(out).unwrap().lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[1]["transactionIndex"], Value::String("0x1".to_string()));
+        assert_eq!(lines[1]["cumulativeGasUsed"], Value::String("0xa410".to_string()));
+        assert_eq!(lines[1]["gasUsed"], Value::String("0x5208".to_string()));
+    }
+
+    #[test]
+    fn exports_consensus_receipts_as_rlp() {
+        let source = source_with_transfers();
+        let mut out = Vec::new();
+        assert_eq!(export_receipts(&source, 1, Some(1), ExportFormat::Rlp, &mut out).unwrap(), 2);
+
+        let mut at = 0;
+        let mut records = Vec::new();
+        while at < out.len() {
+            let rlp = UntrustedRlp::new(&out[at..]);
+            at += rlp.as_raw().len();
+            let receipt: ConsensusReceipt = rlp.val_at(3).unwrap();
+            records.push((rlp.val_at::<U256>(0).unwrap(), rlp.val_at::<U256>(1).unwrap(), receipt.used_gas));
+        }
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[1].0, U256::one());
+        assert_eq!(records[1].1, U256::one());
+        assert_eq!(records[1].2, Gas::from(42000u64));
+    }
+
+    #[test]
+    fn missing_blocks_are_not_found() {
+        let source = source_with_transfers();
+        match export_receipts(&source, 0, Some(5), ExportFormat::Jsonl, &mut Vec::new()) {
+            Err(Error::NotFound) => (),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+}
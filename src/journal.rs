@@ -0,0 +1,159 @@
+use bigint::{H256, U256};
+use hexutil::{read_hex, to_hex};
+use secp256k1::SECP256K1;
+use secp256k1::key::SecretKey;
+use serde_json::{self, Value};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Mutex, MutexGuard};
+
+use error::Error;
+use miner::ChainIdentity;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct JournalAccount {
+    pub secret_key: String,
+    pub balance: String,
+}
+
+/// One external input of the node. The first entry of a journal is
+/// always `Genesis`, every following entry is applied in order.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum JournalEntry {
+    Genesis {
+        timestamp: u64,
+        accounts: Vec<JournalAccount>,
+        /// Chain, chain id and genesis hash the journal was recorded
+        /// on, missing in journals of older versions.
+        #[serde(default)]
+        chain: Option<String>,
+        #[serde(default, rename = "chainId")]
+        chain_id: Option<u64>,
+        #[serde(default, rename = "genesisHash")]
+        genesis_hash: Option<String>,
+    },
+    Rpc { request: Value },
+    Mine { timestamp: u64 },
+}
+
+impl JournalEntry {
+    pub fn genesis(timestamp: u64, accounts: &[(SecretKey, U256)], identity: &ChainIdentity) -> Self {
+        JournalEntry::Genesis {
+            timestamp,
+            accounts: accounts.iter().map(|&(ref secret_key, balance)| JournalAccount {
+                secret_key: to_hex(&secret_key[..]),
+                balance: format!("0x{:x}", balance),
+            }).collect(),
+            chain: Some(identity.chain.clone()),
+            chain_id: identity.chain_id,
+            genesis_hash: Some(format!("0x{:x}", identity.genesis_hash)),
+        }
+    }
+
+    /// Chain a journal was recorded on, if its genesis entry records it.
+    pub fn identity(&self) -> Result<Option<ChainIdentity>, Error> {
+        match *self {
+            JournalEntry::Genesis { chain: Some(ref chain), chain_id, genesis_hash: Some(ref genesis_hash), .. } =>
+                Ok(Some(ChainIdentity {
+                    chain: chain.clone(),
+                    chain_id,
+                    genesis_hash: H256::from_str(genesis_hash.trim_start_matches("0x"))
+                        .map_err(|_| Error::InvalidJournal)?,
+                })),
+            _ => Ok(None),
+        }
+    }
+
+    pub fn genesis_accounts(accounts: &[JournalAccount]) -> Result<Vec<(SecretKey, U256)>, Error> {
+        let mut ret = Vec::new();
+        for account in accounts {
+            ret.push((SecretKey::from_slice(&SECP256K1, &read_hex(&account.secret_key)?)?,
+                      U256::from_str(&account.balance)?));
+        }
+        Ok(ret)
+    }
+}
+
+/// Append-only journal of every input that changes the chain, one JSON
+/// entry per line.
+pub struct Journal {
+    file: Mutex<File>,
+}
+
+/// Holding the guard keeps other inputs from being applied, so that the
+/// order of the journal is the order in which inputs took effect.
+pub struct JournalGuard<'a>(MutexGuard<'a, File>);
+
+impl Journal {
+    pub fn create(path: &Path, genesis: &JournalEntry) -> Result<Journal, Error> {
+        let file = OpenOptions::new().create(true).write(true).truncate(true).open(path)?;
+        let journal = Journal { file: Mutex::new(file) };
+        journal.lock().append(genesis)?;
+        Ok(journal)
+    }
+
+    pub fn lock(&self) -> JournalGuard {
+        JournalGuard(self.file.lock().unwrap())
+    }
+}
+
+impl<'a> JournalGuard<'a> {
+    pub fn append(&mut self, entry: &JournalEntry) -> Result<(), Error> {
+        let mut line = serde_json::to_string(entry).unwrap();
+        line.push('\n');
+        self.0.write_all(line.as_bytes())?;
+        self.0.flush()?;
+        Ok(())
+    }
+}
+
+pub fn read_journal(path: &Path) -> Result<Vec<JournalEntry>, Error> {
+    let file = File::open(path)?;
+
+    let mut entries = Vec::new();
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        entries.push(serde_json::from_str(&line).map_err(|_| Error::InvalidJournal)?);
+    }
+
+    match entries.first() {
+        Some(&JournalEntry::Genesis { .. }) => Ok(entries),
+        _ => Err(Error::InvalidJournal),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entry_roundtrip() {
+        let secret_key = SecretKey::from_slice(&SECP256K1, &[1u8; 32]).unwrap();
+        let identity = ChainIdentity {
+            chain: "classic".to_string(),
+            chain_id: Some(61),
+            genesis_hash: H256::from(7u64),
+        };
+        let genesis = JournalEntry::genesis(1500000000, &[(secret_key, U256::from(1000u64))], &identity);
+
+        let line = serde_json::to_string(&genesis).unwrap();
+        let entry: JournalEntry = serde_json::from_str(&line).unwrap();
+        assert_eq!(entry.identity().unwrap(), Some(identity));
+        match entry {
+            JournalEntry::Genesis { timestamp, accounts, .. } => {
+                assert_eq!(timestamp, 1500000000);
+                let accounts = JournalEntry::genesis_accounts(&accounts).unwrap();
+                assert_eq!(accounts[0].0, secret_key);
+                assert_eq!(accounts[0].1, U256::from(1000u64));
+            },
+            _ => panic!(),
+        }
+    }
+}
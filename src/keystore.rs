@@ -0,0 +1,401 @@
+use bigint::{H256, Address};
+use block::FromKey;
+use crypto::aes::{self, KeySize};
+use crypto::hmac::Hmac;
+use crypto::pbkdf2::pbkdf2;
+use crypto::scrypt::{scrypt, ScryptParams};
+use crypto::sha2::Sha256;
+use rand::Rng;
+use rand::os::OsRng;
+use secp256k1::SECP256K1;
+use secp256k1::key::SecretKey;
+use serde_json;
+use sha3::{Digest, Keccak256};
+use std::fs::{self, File, OpenOptions};
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use error::Error;
+
+// Light scrypt parameters, the same as geth's `--lightkdf`. Keys of a
+// development node are not worth the standard parameters' cost.
+const SCRYPT_LOG_N: u8 = 12;
+const SCRYPT_R: u32 = 8;
+const SCRYPT_P: u32 = 6;
+const DKLEN: usize = 32;
+const IV_LEN: usize = 16;
+
+// Bounds on the parameters of imported key files, well above the
+// standard ones, so that a crafted file cannot abort the node or keep
+// it busy for hours.
+const MAX_SCRYPT_LOG_N: u8 = 20;
+const MAX_SCRYPT_R: u32 = 32;
+const MAX_SCRYPT_P: u32 = 16;
+const MAX_SCRYPT_MEMORY: u64 = 1 << 28;
+const MAX_PBKDF2_C: u32 = 10_000_000;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct KeyFile {
+    pub address: String,
+    #[serde(alias = "Crypto")]
+    pub crypto: KeyFileCrypto,
+    pub id: String,
+    pub version: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct KeyFileCrypto {
+    pub cipher: String,
+    pub ciphertext: String,
+    pub cipherparams: KeyFileCipherParams,
+    pub kdf: String,
+    pub kdfparams: KeyFileKdfParams,
+    pub mac: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct KeyFileCipherParams {
+    pub iv: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct KeyFileKdfParams {
+    pub dklen: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub n: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub r: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub p: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub c: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prf: Option<String>,
+    pub salt: String,
+}
+
+fn to_plain_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_plain_hex(s: &str) -> Result<Vec<u8>, Error> {
+    let s = s.trim_start_matches("0x");
+    if s.len() % 2 != 0 {
+        return Err(Error::InvalidKeyFile);
+    }
+    let mut ret = Vec::new();
+    for i in 0..(s.len() / 2) {
+        ret.push(u8::from_str_radix(&s[(i * 2)..(i * 2 + 2)], 16)
+                 .map_err(|_| Error::InvalidKeyFile)?);
+    }
+    Ok(ret)
+}
+
+/// Parse a raw hex private key, with or without the `0x` prefix.
+pub fn parse_raw_key(s: &str) -> Result<SecretKey, Error> {
+    Ok(SecretKey::from_slice(&SECP256K1, &from_plain_hex(s)?)?)
+}
+
+fn key_file_address(key_file: &KeyFile) -> Option<Address> {
+    match from_plain_hex(&key_file.address) {
+        Ok(ref address) if address.len() == 20 => Some(Address::from(address.as_slice())),
+        _ => None,
+    }
+}
+
+fn random_bytes(len: usize) -> Vec<u8> {
+    let mut rng = OsRng::new().unwrap();
+    let mut ret = vec![0u8; len];
+    rng.fill_bytes(&mut ret);
+    ret
+}
+
+fn mac(derived: &[u8], ciphertext: &[u8]) -> H256 {
+    let mut data = Vec::new();
+    data.extend_from_slice(&derived[16..32]);
+    data.extend_from_slice(ciphertext);
+    H256::from(Keccak256::digest(&data).as_slice())
+}
+
+fn aes_ctr(key: &[u8], iv: &[u8], input: &[u8]) -> Vec<u8> {
+    let mut output = vec![0u8; input.len()];
+    aes::ctr(KeySize::KeySize128, key, iv).process(input, &mut output);
+    output
+}
+
+fn uuid() -> String {
+    let mut bytes = random_bytes(16);
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex = to_plain_hex(&bytes);
+    format!("{}-{}-{}-{}-{}", &hex[0..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..32])
+}
+
+/// Encrypt a secret key following the web3 secret storage definition.
+pub fn encrypt(secret_key: &SecretKey, password: &str) -> Result<KeyFile, Error> {
+    let salt = random_bytes(32);
+    let iv = random_bytes(IV_LEN);
+
+    let mut derived = [0u8; DKLEN];
+    scrypt(password.as_bytes(), &salt, &ScryptParams::new(SCRYPT_LOG_N, SCRYPT_R, SCRYPT_P),
+           &mut derived);
+
+    let ciphertext = aes_ctr(&derived[0..16], &iv, &secret_key[..]);
+    let address = Address::from_secret_key(secret_key)?;
+
+    Ok(KeyFile {
+        address: to_plain_hex(&address),
+        crypto: KeyFileCrypto {
+            cipher: "aes-128-ctr".to_string(),
+            ciphertext: to_plain_hex(&ciphertext),
+            cipherparams: KeyFileCipherParams {
+                iv: to_plain_hex(&iv),
+            },
+            kdf: "scrypt".to_string(),
+            kdfparams: KeyFileKdfParams {
+                dklen: DKLEN,
+                n: Some(1 << SCRYPT_LOG_N),
+                r: Some(SCRYPT_R),
+                p: Some(SCRYPT_P),
+                c: None,
+                prf: None,
+                salt: to_plain_hex(&salt),
+            },
+            mac: to_plain_hex(&mac(&derived, &ciphertext)),
+        },
+        id: uuid(),
+        version: 3,
+    })
+}
+
+fn derive_key(params: &KeyFileKdfParams, kdf: &str, password: &str) -> Result<[u8; DKLEN], Error> {
+    if params.dklen != DKLEN {
+        return Err(Error::InvalidKeyFile);
+    }
+
+    let salt = from_plain_hex(&params.salt)?;
+    let mut derived = [0u8; DKLEN];
+
+    match (kdf, params.n, params.r, params.p, params.c) {
+        ("scrypt", Some(n), Some(r), Some(p), _) if n.is_power_of_two() => {
+            // `ScryptParams::new` asserts on out of range parameters.
+            let log_n = n.trailing_zeros() as u8;
+            if log_n == 0 || log_n > MAX_SCRYPT_LOG_N || r == 0 || r > MAX_SCRYPT_R || p == 0 || p > MAX_SCRYPT_P ||
+                (128 * r as u64) << log_n > MAX_SCRYPT_MEMORY {
+                return Err(Error::InvalidKeyFile);
+            }
+            scrypt(password.as_bytes(), &salt, &ScryptParams::new(log_n, r, p), &mut derived);
+        },
+        ("pbkdf2", _, _, _, Some(c)) => {
+            if c == 0 || c > MAX_PBKDF2_C {
+                return Err(Error::InvalidKeyFile);
+            }
+            if params.prf.as_ref().map(|prf| prf.as_str()) != Some("hmac-sha256") {
+                return Err(Error::InvalidKeyFile);
+            }
+            let mut mac = Hmac::new(Sha256::new(), password.as_bytes());
+            pbkdf2(&mut mac, &salt, c, &mut derived);
+        },
+        _ => return Err(Error::InvalidKeyFile),
+    }
+
+    Ok(derived)
+}
+
+/// Decrypt a key file, failing with `InvalidPassword` on MAC mismatch.
+/// Both scrypt (geth) and pbkdf2 (parity) key derivations are accepted.
+pub fn decrypt(key_file: &KeyFile, password: &str) -> Result<SecretKey, Error> {
+    let crypto = &key_file.crypto;
+    if crypto.cipher != "aes-128-ctr" {
+        return Err(Error::InvalidKeyFile);
+    }
+
+    let iv = from_plain_hex(&crypto.cipherparams.iv)?;
+    if iv.len() != IV_LEN {
+        return Err(Error::InvalidKeyFile);
+    }
+    let ciphertext = from_plain_hex(&crypto.ciphertext)?;
+    let expected_mac = from_plain_hex(&crypto.mac)?;
+
+    let derived = derive_key(&crypto.kdfparams, &crypto.kdf, password)?;
+
+    if &mac(&derived, &ciphertext)[..] != &expected_mac[..] {
+        return Err(Error::InvalidPassword);
+    }
+
+    let secret = aes_ctr(&derived[0..16], &iv, &ciphertext);
+    Ok(SecretKey::from_slice(&SECP256K1, &secret)?)
+}
+
+/// A directory of encrypted key files, one per account.
+pub struct KeyStore {
+    dir: PathBuf,
+}
+
+impl KeyStore {
+    pub fn new(dir: PathBuf) -> Self {
+        KeyStore { dir }
+    }
+
+    pub fn new_account(&self, password: &str) -> Result<Address, Error> {
+        let mut rng = OsRng::new().unwrap();
+        let secret_key = SecretKey::new(&SECP256K1, &mut rng);
+        self.store(&secret_key, password)
+    }
+
+    pub fn store(&self, secret_key: &SecretKey, password: &str) -> Result<Address, Error> {
+        let key_file = encrypt(secret_key, password)?;
+        self.write(&key_file)?;
+
+        Ok(Address::from_secret_key(secret_key)?)
+    }
+
+    /// Add a key file produced by another client, after checking that
+    /// it decrypts with the given password. The file is kept as-is.
+    pub fn import(&self, key_file: &KeyFile, password: &str) -> Result<Address, Error> {
+        let secret_key = decrypt(key_file, password)?;
+        let address = Address::from_secret_key(&secret_key)?;
+
+        let mut key_file = key_file.clone();
+        key_file.address = to_plain_hex(&address);
+        self.write(&key_file)?;
+
+        Ok(address)
+    }
+
+    /// Key files are only readable by their owner.
+    fn write(&self, key_file: &KeyFile) -> Result<(), Error> {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+
+        fs::create_dir_all(&self.dir)?;
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        options.mode(0o600);
+        let file = options.open(self.dir.join(format!("UTC--{}--{}", timestamp, key_file.address)))?;
+        serde_json::to_writer(file, key_file).map_err(|_| Error::InvalidKeyFile)?;
+
+        Ok(())
+    }
+
+    pub fn key_file(&self, address: Address) -> Result<KeyFile, Error> {
+        for key_file in self.key_files()? {
+            if key_file_address(&key_file) == Some(address) {
+                return Ok(key_file);
+            }
+        }
+        Err(Error::NotFound)
+    }
+
+    pub fn unlock(&self, address: Address, password: &str) -> Result<SecretKey, Error> {
+        decrypt(&self.key_file(address)?, password)
+    }
+
+    pub fn addresses(&self) -> Result<Vec<Address>, Error> {
+        Ok(self.key_files()?.iter().filter_map(key_file_address).collect())
+    }
+
+    fn key_files(&self) -> Result<Vec<KeyFile>, Error> {
+        if !self.dir.exists() {
+            return Ok(Vec::new());
+        }
+
+        let mut ret = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let file = File::open(entry?.path())?;
+            if let Ok(key_file) = serde_json::from_reader(file) {
+                ret.push(key_file);
+            }
+        }
+        Ok(ret)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encrypt_roundtrip() {
+        let mut rng = OsRng::new().unwrap();
+        let secret_key = SecretKey::new(&SECP256K1, &mut rng);
+        let key_file = encrypt(&secret_key, "testpassword").unwrap();
+
+        assert_eq!(decrypt(&key_file, "testpassword").unwrap(), secret_key);
+        match decrypt(&key_file, "wrongpassword") {
+            Err(Error::InvalidPassword) => (),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn decrypt_pbkdf2_test_vector() {
+        // Test vector from the web3 secret storage definition.
+        let key_file: KeyFile = serde_json::from_str(r#"{
+            "address": "008aeeda4d805471df9b2a5b0f38a0c3bcba786b",
+            "crypto": {
+                "cipher": "aes-128-ctr",
+                "cipherparams": { "iv": "6087dab2f9fdbbfaddc31a909735c1e6" },
+                "ciphertext": "5318b4d5bcd28de64ee5559e671353e16f075ecae9f99c7a79a38af5f869aa46",
+                "kdf": "pbkdf2",
+                "kdfparams": {
+                    "c": 262144,
+                    "dklen": 32,
+                    "prf": "hmac-sha256",
+                    "salt": "ae3cd4e7013836a3df6bd7241b12db061dbe2c6785853cce422d148a624ce0bd"
+                },
+                "mac": "517ead924a9d0dc3124507e3393d175ce3ff7c1e96529c6c555ce9e51205e9b2"
+            },
+            "id": "3198bc9c-6672-5ab3-d995-4942343ae5b6",
+            "version": 3
+        }"#).unwrap();
+
+        let secret_key = decrypt(&key_file, "testpassword").unwrap();
+        assert_eq!(to_plain_hex(&secret_key[..]),
+                   "7a28b5ba57c53603b0b07b56bba752f7784bf506fa95edc395f5cf6c7514fe9d");
+    }
+
+    #[test]
+    fn invalid_params_are_rejected() {
+        let mut rng = OsRng::new().unwrap();
+        let secret_key = SecretKey::new(&SECP256K1, &mut rng);
+        let key_file = encrypt(&secret_key, "testpassword").unwrap();
+
+        for &(n, r, p) in &[(1, 8, 6), (1 << 12, 0, 6), (1 << 12, 8, 0), (1 << 40, 8, 6), (1 << 12, 1 << 20, 6)] {
+            let mut invalid = key_file.clone();
+            invalid.crypto.kdfparams.n = Some(n);
+            invalid.crypto.kdfparams.r = Some(r);
+            invalid.crypto.kdfparams.p = Some(p);
+            match decrypt(&invalid, "testpassword") {
+                Err(Error::InvalidKeyFile) => (),
+                other => panic!("unexpected {:?} for n={} r={} p={}", other, n, r, p),
+            }
+        }
+
+        let mut invalid = key_file.clone();
+        invalid.crypto.cipherparams.iv = "00".to_string();
+        match decrypt(&invalid, "testpassword") {
+            Err(Error::InvalidKeyFile) => (),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn key_files_are_private() {
+        use std::env;
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = env::temp_dir().join(format!("svmdev-keystore-{}", uuid()));
+        let keystore = KeyStore::new(dir.clone());
+        keystore.new_account("testpassword").unwrap();
+
+        for entry in fs::read_dir(&dir).unwrap() {
+            let mode = entry.unwrap().metadata().unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,61 @@
+//! SputnikVM Development Environment as a library, to run in-process
+//! development chains, e.g. from the tests of a Rust project:
+//!
+//! ```ignore
+//! let node = DevNodeBuilder::new().chain_id(1337).block_time(0).build()?;
+//! node.request("eth_blockNumber", Value::Array(Vec::new()))?;
+//! ```
+//!
+//! The `svmdev` binary is a command line front end to it.
+
+extern crate sputnikvm;
+extern crate sputnikvm_stateful;
+extern crate secp256k1;
+extern crate rand;
+extern crate sha3;
+extern crate blockchain;
+extern crate bigint;
+extern crate rlp;
+extern crate bloom;
+extern crate block;
+extern crate trie;
+extern crate hexutil;
+#[macro_use]
+extern crate lazy_static;
+extern crate jsonrpc_core;
+extern crate jsonrpc_http_server;
+#[macro_use]
+extern crate jsonrpc_macros;
+extern crate serde;
+extern crate serde_json;
+#[macro_use]
+extern crate serde_derive;
+#[macro_use]
+extern crate log;
+extern crate crypto;
+extern crate flate2;
+extern crate thiserror;
+extern crate memmap;
+extern crate futures_cpupool;
+
+extern crate sputnikvm_network_classic;
+extern crate sputnikvm_network_foundation;
+
+pub mod error;
+pub mod miner;
+pub mod rpc;
+pub mod client;
+pub mod keystore;
+pub mod mnemonic;
+pub mod journal;
+pub mod compare;
+pub mod export;
+pub mod checksum;
+pub mod statetest;
+pub mod blocktest;
+pub mod node;
+#[cfg(test)]
+mod vectors;
+
+pub use error::Error;
+pub use node::{DevNode, DevNodeBuilder};
@@ -1,29 +1,12 @@
+extern crate sputnikvm_dev;
 extern crate sputnikvm;
-extern crate sputnikvm_stateful;
 extern crate secp256k1;
 extern crate rand;
-extern crate sha3;
-extern crate blockchain;
 extern crate bigint;
-extern crate rlp;
-extern crate bloom;
-extern crate block;
-extern crate trie;
 extern crate hexutil;
-#[macro_use]
-extern crate lazy_static;
-extern crate jsonrpc_core;
-extern crate jsonrpc_http_server;
-#[macro_use]
-extern crate jsonrpc_macros;
-extern crate serde;
 extern crate serde_json;
 #[macro_use]
-extern crate serde_derive;
-#[macro_use]
 extern crate clap;
-#[macro_use]
-extern crate log;
 extern crate env_logger;
 
 extern crate sputnikvm_network_classic;
@@ -37,22 +20,26 @@ extern crate sputnikvm_network_gallactic;
 #[cfg(feature = "frontend")]
 extern crate hyper;
 
-mod error;
-mod miner;
-mod rpc;
-
 #[cfg(feature = "frontend")]
 mod assets;
 
+use sputnikvm_dev::{
+    miner, rpc, client, keystore, mnemonic, journal, compare, export, checksum, statetest, blocktest, node,
+};
+
 use rand::os::OsRng;
 use secp256k1::key::{SecretKey};
 use secp256k1::SECP256K1;
 use bigint::U256;
 use hexutil::*;
 use std::thread;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
 use std::str::FromStr;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, RwLock};
 use std::sync::mpsc::{channel};
+use std::path::{Path, PathBuf};
+use std::process;
 use sputnikvm::Patch;
 
 use sputnikvm_network_classic::{
@@ -104,10 +91,134 @@ fn main() {
             (@arg PRIVATE_KEY: -k --private +takes_value "Private key for the account to be generated, if not provided, a random private key will be generated.")
             (@arg BALANCE: -b --balance +takes_value "Balance in Wei for the account to be generated, default is 0x10000000000000000000000000000.")
             (@arg LISTEN: -l --listen +takes_value "Listen address and port for the RPC, e.g. 127.0.0.1:8545.")
+            (@arg RPC_WORKERS: --("rpc-workers") +takes_value "Number of threads executing RPC calls, default to one per CPU.")
             (@arg ACCOUNTS: -a --accounts +takes_value "Additional accounts to be generated, default to 9.")
+            (@arg MNEMONIC: -m --mnemonic +takes_value conflicts_with[PRIVATE_KEY DETERMINISTIC] "Derive the accounts from this BIP-39 mnemonic, at m/44'/60'/0'/0/i.")
+            (@arg DETERMINISTIC: --deterministic conflicts_with[PRIVATE_KEY] "Derive the accounts from a fixed well-known mnemonic.")
+            (@arg CHECKSUM_ADDRESSES: --("checksum-addresses") "Output EIP-55 checksummed addresses in RPC responses and logs.")
+            (@arg STRICT_CHECKSUM: --("strict-checksum") "Reject mixed-case addresses with an invalid EIP-55 checksum in RPC inputs.")
+            (@arg FEE_PAYER: --("fee-payer") +takes_value "Charge all transaction fees to this account instead of the senders, as long as it can afford them.")
+            (@arg WATCH: --watch +takes_value "Deploy the compiled contracts found in this directory, and redeploy them or swap their code whenever they change.")
             (@arg CHAIN: -c --chain +takes_value "Specify the chain to use. Refer to the documentation for a full list of valid values.")
+            (@arg WORK_DIFFICULTY: --("work-difficulty") +takes_value "Difficulty that solutions submitted through eth_submitWork are checked against, default to 1.")
+            (@arg FORK: --fork +takes_value "Fork the state of a remote node, given as <url>[@<block>]. Accounts and storage not known locally are fetched from it on first use.")
+            (@arg NO_FORK_CACHE: --("no-fork-cache") requires[FORK] "Do not cache state fetched from the forked node on disk.")
+            (@arg CLEAR_FORK_CACHE: --("clear-fork-cache") requires[FORK] conflicts_with[NO_FORK_CACHE] "Drop the state cached on disk for the forked node and block before starting.")
+            (@arg SELFDESTRUCT: --selfdestruct +takes_value possible_value[legacy eip6780] "What SELFDESTRUCT does to contracts created before the transaction: legacy deletes them, eip6780 only sends their balance away. Default to legacy.")
+            (@arg CHAIN_ID: --("chain-id") +takes_value "EIP-155 chain id transactions signed by the node commit to. Submitted transactions must then be signed for it.")
+            (@arg EXPOSE_PRIVATE_KEYS: --("expose-private-keys") "Return the private keys of the generated accounts from dev_accounts.")
+            (@arg ALLOW_UNPROTECTED_TXS: --("allow-unprotected-txs") requires[CHAIN_ID] "Still accept transactions signed without a chain id when --chain-id is set.")
+            (@arg NETWORK_ID: --("network-id") +takes_value "Network id returned by net_version, default to the chain id, or 1 without one.")
+            (@arg MEMORY_LIMIT: --("memory-limit") +takes_value "Maximum size in bytes of the memory of a VM call frame, default to the limit of the chain. Calls going past it fail with a memory limit error.")
+            (@arg LOG_RETENTION: --("log-retention") +takes_value "Only keep receipts and logs of this many most recent blocks. Headers, transactions and state are kept.")
+            (@arg LOG_WORKERS: --("log-workers") +takes_value "Number of threads eth_getLogs splits large block ranges across, default to 1.")
+            (@arg EXECUTION_WORKERS: --("execution-workers") +takes_value "Number of transactions of a mined block executed in parallel, default to 1. Not used with --fork or a tracer.")
+            (@arg ACCOUNT_CACHE: --("account-cache") +takes_value "Number of accounts read by calls and transactions kept in memory by state root, default to 4096. 0 disables the cache.")
+            (@arg RPC_CACHE: --("rpc-cache") +takes_value "Number of blocks, and of receipts, kept as last returned by the RPC, default to 1024. 0 disables the caches.")
+            (@arg LOG_INDEX: --("log-index") "Keep an index of the blocks holding logs of each address and topic in the data directory, which eth_getLogs only scans.")
+            (@arg LOG_ARCHIVE: --("log-archive") +takes_value requires[LOG_RETENTION] "Append receipts pruned by --log-retention to this gzip-compressed file instead of dropping them.")
+            (@arg PRICE_BUMP: --("price-bump") +takes_value "Percentage by which a transaction must raise the gas price of a pooled transaction with the same sender and nonce to replace it, default to 10.")
+            (@arg MIN_GAS_PRICE: --("min-gas-price") +takes_value "Reject transactions with a lower gas price in Wei, default to 0. Transactions signed by the node default to this gas price.")
+            (@arg ALLOW_ZERO_GAS_PRICE: --("allow-zero-gas-price") requires[MIN_GAS_PRICE] "Still accept transactions with a zero gas price when --min-gas-price is set.")
+            (@arg GAS_PRICE_BLOCKS: --("gas-price-blocks") +takes_value "Number of recent blocks whose transactions eth_gasPrice samples, default to 20.")
+            (@arg GAS_PRICE_PERCENTILE: --("gas-price-percentile") +takes_value "Percentile of the sampled gas prices eth_gasPrice suggests, default to 60.")
+            (@arg GAS_MARGIN: --("gas-margin") +takes_value "Percentage added to the estimated gas of transactions sent without a gas limit, default to 10.")
+            (@arg POOL_SIZE: --("pool-size") +takes_value "Most transactions the pool holds before evicting the lowest gas price ones, default to 4096.")
+            (@arg POOL_SENDER_SLOTS: --("pool-sender-slots") +takes_value "Most transactions of one sender the pool holds, default to 64.")
+            (@arg FREEZE_DEPTH: --("freeze-depth") +takes_value "Move blocks and receipts this many blocks below the head into an immutable segment. Frozen blocks can no longer be rewound with debug_setHead.")
+            (@arg FREEZE_DIR: --("freeze-dir") +takes_value requires[FREEZE_DEPTH] "Write frozen blocks to files in this directory and map them into memory, instead of keeping them in memory.")
+            (@arg ALLOW_CHAIN_MISMATCH: --("allow-chain-mismatch") "Restore an archive or replay a journal recorded on another chain or genesis, instead of refusing to start.")
+            (@arg BLOCK_TIME: --("block-time") +takes_value "Mine a block at least every this many seconds, default to 10. 0 only mines on transactions and dev_mine.")
+            (@arg NO_INSTAMINE: --("no-instamine") "Do not mine a block as soon as a transaction is received.")
+            (@arg MAX_CLOCK_DRIFT: --("max-clock-drift") +takes_value "Maximum number of seconds an imported block timestamp may be ahead of local time, default to 15.")
+            (@arg DATADIR: -d --datadir +takes_value "Data directory for the keystore, default to .svmdev.")
+            (@arg JOURNAL: -j --journal +takes_value "Record every input of the node to this file, so that the chain can be reproduced with the replay command.")
+            (@arg API_KEYS: --("api-keys") +takes_value "JSON file listing API keys with their allowed namespaces and rate limits. If not provided, the RPC is open to everyone.")
+            (@subcommand backup =>
+                (about: "Save a consistent snapshot of a running node to an archive.")
+                (@arg FILE: +required "Path of the archive to write.")
+                (@arg RPC: -r --rpc +takes_value "RPC address of the running node, default to 127.0.0.1:8545.")
+                (@arg INCLUDE_KEYS: --("include-keys") "Also write the private keys of the generated accounts, so that the restored node can sign for them.")
+                (@arg API_KEY: --("api-key") +takes_value "API key sent in the X-Api-Key header, for a node started with --api-keys."))
+            (@subcommand db =>
+                (about: "Maintain the state database of a running node.")
+                (@subcommand verify =>
+                    (about: "Check that every trie node referenced by a stored state root is present and intact.")
+                    (@arg REPAIR: --repair "Rebuild the database by re-executing the chain if problems are found.")
+                    (@arg RPC: -r --rpc +takes_value "RPC address of the running node, default to 127.0.0.1:8545.")
+                    (@arg API_KEY: --("api-key") +takes_value "API key sent in the X-Api-Key header, for a node started with --api-keys."))))
+            (@subcommand restore =>
+                (about: "Start the node from a previously saved archive.")
+                (@arg FILE: +required "Path of the archive to read."))
+            (@subcommand replay =>
+                (about: "Start the node by replaying a journal recorded with --journal.")
+                (@arg FILE: +required "Path of the journal to replay."))
+            (@subcommand compare =>
+                (about: "Find the first block, receipt or state root where two chains diverge.")
+                (@arg LEFT: +required "RPC address of a running node, or path of a backup archive.")
+                (@arg RIGHT: +required "RPC address of a running node, or path of a backup archive.")
+                (@arg FROM: --from +takes_value "First block to compare, default to 0.")
+                (@arg TO: --to +takes_value "Last block to compare, default to the lowest of both heads.")
+                (@arg API_KEY: --("api-key") +takes_value "API key sent in the X-Api-Key header, for nodes started with --api-keys."))
+            (@subcommand export =>
+                (about: "Stream the receipts and logs of a block range, one block at a time.")
+                (@arg SOURCE: +required "RPC address of a running node, or path of a backup archive.")
+                (@arg FROM: --from +takes_value "First block to export, default to 0.")
+                (@arg TO: --to +takes_value "Last block to export, default to the head.")
+                (@arg FORMAT: --format +takes_value possible_value[jsonl rlp] "jsonl writes one eth_getTransactionReceipt object per line, rlp one [blockNumber, transactionIndex, transactionHash, receipt] list per receipt. Default to jsonl.")
+                (@arg OUTPUT: -o --output +takes_value "File to write, default to the standard output.")
+                (@arg API_KEY: --("api-key") +takes_value "API key sent in the X-Api-Key header, for a node started with --api-keys."))
+            (@subcommand statetest =>
+                (about: "Run GeneralStateTests fixtures of ethereum/tests and check their post states.")
+                (@arg PATH: +required "Fixture file, or directory searched for fixture files.")
+                (@arg FORK: --fork +takes_value "Only check the post states of this hard fork, e.g. Byzantium."))
+            (@subcommand blocktest =>
+                (about: "Run BlockchainTests fixtures of ethereum/tests and check their resulting head.")
+                (@arg PATH: +required "Fixture file, or directory searched for fixture files."))
+            (@subcommand account =>
+                (about: "Manage accounts of the keystore.")
+                (@subcommand import =>
+                    (about: "Import a keystore V3 JSON file, e.g. from geth or parity.")
+                    (@arg FILE: +required "Path of the key file to import.")
+                    (@arg PASSWORD: -p --password +takes_value +required "Password of the key file."))
+                (@subcommand export =>
+                    (about: "Export an account as a keystore V3 JSON file.")
+                    (@arg ADDRESS: +required "Address of the account to export.")
+                    (@arg FILE: +required "Path of the key file to write.")))
     ).get_matches();
 
+    address_format(&matches).scope(|| dispatch(matches));
+}
+
+fn address_format<'a>(matches: &clap::ArgMatches<'a>) -> checksum::AddressFormat {
+    checksum::AddressFormat {
+        checksum_output: matches.is_present("CHECKSUM_ADDRESSES"),
+        strict_checksum: matches.is_present("STRICT_CHECKSUM"),
+    }
+}
+
+fn dispatch<'a>(matches: clap::ArgMatches<'a>) {
+    if let Some(matches) = matches.subcommand_matches("backup") {
+        backup(matches);
+        return;
+    }
+    if let Some(db_matches) = matches.subcommand_matches("db") {
+        db(db_matches);
+        return;
+    }
+    if let Some(account_matches) = matches.subcommand_matches("account") {
+        account(&matches, account_matches);
+        return;
+    }
+    if let Some(statetest_matches) = matches.subcommand_matches("statetest") {
+        statetest(statetest_matches);
+        return;
+    }
+    if let Some(blocktest_matches) = matches.subcommand_matches("blocktest") {
+        blocktest(blocktest_matches);
+        return;
+    }
+
     match matches.value_of("CHAIN") {
         None => with_patch::<PClassicEIP160>(matches),
 
@@ -152,7 +263,255 @@ fn main() {
     }
 }
 
+fn backup<'a>(matches: &clap::ArgMatches<'a>) {
+    let addr = matches.value_of("RPC").unwrap_or("127.0.0.1:8545").parse().unwrap();
+    let params = serde_json::Value::Array(vec![serde_json::Value::Bool(matches.is_present("INCLUDE_KEYS"))]);
+    let archive = client::request(&addr, matches.value_of("API_KEY"), "dev_backup", params)
+        .expect("Expect the node to return a backup archive");
+    let archive = read_hex(archive.as_str().expect("Expect the archive to be a hex string")).unwrap();
+
+    let mut file = File::create(matches.value_of("FILE").unwrap()).unwrap();
+    file.write_all(&archive).unwrap();
+    println!("backup written: {} bytes", archive.len());
+}
+
+fn db<'a>(db_matches: &clap::ArgMatches<'a>) {
+    match db_matches.subcommand() {
+        ("verify", Some(matches)) => {
+            let addr = matches.value_of("RPC").unwrap_or("127.0.0.1:8545").parse().unwrap();
+            let params = serde_json::Value::Array(vec![serde_json::Value::Bool(matches.is_present("REPAIR"))]);
+            let report = client::request(&addr, matches.value_of("API_KEY"), "dev_verifyDatabase", params)
+                .expect("Expect the node to return a verification report");
+            println!("{}", serde_json::to_string_pretty(&report).unwrap());
+
+            let is_ok = ["missing", "corrupt", "missingCode"].iter().all(|field| {
+                report[*field].as_array().map(|items| items.is_empty()).unwrap_or(false)
+            });
+            if !is_ok {
+                process::exit(1);
+            }
+        },
+        _ => panic!("Unsupported db command."),
+    }
+}
+
+fn account<'a>(matches: &clap::ArgMatches<'a>, account_matches: &clap::ArgMatches<'a>) {
+    let datadir = PathBuf::from(matches.value_of("DATADIR").unwrap_or(".svmdev"));
+    let keystore = keystore::KeyStore::new(datadir.join("keystore"));
+
+    match account_matches.subcommand() {
+        ("import", Some(import)) => {
+            let file = File::open(import.value_of("FILE").unwrap()).unwrap();
+            let key_file: keystore::KeyFile = serde_json::from_reader(file)
+                .expect("Expect a valid keystore V3 JSON file");
+            let address = keystore.import(&key_file, import.value_of("PASSWORD").unwrap())
+                .expect("Expect the key file to decrypt with the given password");
+            println!("imported: {}", checksum::format_address(&address));
+        },
+        ("export", Some(export)) => {
+            let address = bigint::Address::from_str(export.value_of("ADDRESS").unwrap())
+                .expect("Expect a valid address");
+            let key_file = keystore.key_file(address).expect("Expect the account to be in the keystore");
+            let file = File::create(export.value_of("FILE").unwrap()).unwrap();
+            serde_json::to_writer_pretty(file, &key_file).unwrap();
+            println!("exported: {}", checksum::format_address(&address));
+        },
+        _ => panic!("Unsupported account command."),
+    }
+}
+
+fn statetest<'a>(matches: &clap::ArgMatches<'a>) {
+    let tests = statetest::load_fixtures::<statetest::StateTest>(Path::new(matches.value_of("PATH").unwrap()))
+        .expect("Expect valid test fixtures");
+    let only = matches.value_of("FORK");
+
+    let (mut passed, mut failed, mut skipped) = (0, 0, 0);
+    for (name, test) in &tests {
+        let mut forks: Vec<&String> = test.post.keys().collect();
+        forks.sort();
+        for fork in forks {
+            if only.map(|only| only != fork.as_str()).unwrap_or(false) {
+                continue;
+            }
+            for (index, post) in test.post[fork].iter().enumerate() {
+                let result = match fork.as_str() {
+                    "Frontier" => statetest::check_post::<PFoundationFrontier>(test, post),
+                    "Homestead" => statetest::check_post::<PFoundationHomestead>(test, post),
+                    "EIP150" => statetest::check_post::<PFoundationEIP150>(test, post),
+                    "EIP158" => statetest::check_post::<PFoundationSpuriousDragon>(test, post),
+                    "Byzantium" => statetest::check_post::<PFoundationByzantium>(test, post),
+                    _ => {
+                        skipped += 1;
+                        continue;
+                    },
+                };
+                match result {
+                    Ok(None) => passed += 1,
+                    Ok(Some(mismatch)) => {
+                        failed += 1;
+                        println!("FAIL {} {} #{}: {}", name, fork, index, mismatch);
+                    },
+                    Err(err) => {
+                        failed += 1;
+                        println!("FAIL {} {} #{}: {}", name, fork, index, err);
+                    },
+                }
+            }
+        }
+    }
+
+    println!("passed: {}, failed: {}, skipped: {}", passed, failed, skipped);
+    if failed > 0 {
+        process::exit(1);
+    }
+}
+
+fn blocktest<'a>(matches: &clap::ArgMatches<'a>) {
+    let tests = statetest::load_fixtures::<blocktest::BlockchainTest>(Path::new(matches.value_of("PATH").unwrap()))
+        .expect("Expect valid test fixtures");
+
+    let (mut passed, mut failed, mut skipped) = (0, 0, 0);
+    for (name, test) in &tests {
+        let result = match test.network.as_str() {
+            "Frontier" => blocktest::check_chain::<PFoundationFrontier>(test),
+            "Homestead" => blocktest::check_chain::<PFoundationHomestead>(test),
+            "EIP150" => blocktest::check_chain::<PFoundationEIP150>(test),
+            "EIP158" => blocktest::check_chain::<PFoundationSpuriousDragon>(test),
+            "Byzantium" => blocktest::check_chain::<PFoundationByzantium>(test),
+            _ => {
+                skipped += 1;
+                continue;
+            },
+        };
+        match result {
+            Ok(None) => passed += 1,
+            Ok(Some(mismatch)) => {
+                failed += 1;
+                println!("FAIL {} {}: {}", name, test.network, mismatch);
+            },
+            Err(err) => {
+                failed += 1;
+                println!("FAIL {} {}: {}", name, test.network, err);
+            },
+        }
+    }
+
+    println!("passed: {}, failed: {}, skipped: {}", passed, failed, skipped);
+    if failed > 0 {
+        process::exit(1);
+    }
+}
+
+fn chain_source<'a, P: 'static + Patch + Send>(
+    matches: &clap::ArgMatches<'a>, value: &str, max_clock_drift: u64, datadir: &Path, api_key: Option<&str>
+) -> compare::ChainSource {
+    if Path::new(value).is_file() {
+        let mut file = File::open(value).unwrap();
+        let mut archive = Vec::new();
+        file.read_to_end(&mut archive).unwrap();
+        let mut state = miner::make_state_at::<P>(Vec::new(), 0);
+        state.set_max_clock_drift(max_clock_drift);
+        execution_settings(matches, &mut state);
+        miner::read_archive::<P>(&archive, &mut state).expect("Expect a valid backup archive");
+        compare::ChainSource::Local(rpc::LocalNode::new::<P>(
+            state, keystore::KeyStore::new(datadir.join("keystore"))))
+    } else {
+        compare::ChainSource::Node(value.parse().expect("Expect an archive path or an RPC address"),
+                                   api_key.map(|key| key.to_string()))
+    }
+}
+
+fn compare<'a, P: 'static + Patch + Send>(matches: &clap::ArgMatches<'a>, compare_matches: &clap::ArgMatches<'a>) {
+    let max_clock_drift: u64 = match matches.value_of("MAX_CLOCK_DRIFT") {
+        Some(val) => val.parse().unwrap(),
+        None => 15,
+    };
+    let datadir = PathBuf::from(matches.value_of("DATADIR").unwrap_or(".svmdev"));
+
+    let api_key = compare_matches.value_of("API_KEY");
+    let left = chain_source::<P>(matches, compare_matches.value_of("LEFT").unwrap(), max_clock_drift,
+                                 &datadir, api_key);
+    let right = chain_source::<P>(matches, compare_matches.value_of("RIGHT").unwrap(), max_clock_drift,
+                                  &datadir, api_key);
+    let from: usize = compare_matches.value_of("FROM").map(|val| val.parse().unwrap()).unwrap_or(0);
+    let to: Option<usize> = compare_matches.value_of("TO").map(|val| val.parse().unwrap());
+
+    match compare::compare(&left, &right, from, to).expect("Expect both chains to be readable") {
+        Some(divergence) => {
+            println!("first divergence at block {}", divergence.number);
+            if let Some(transaction) = divergence.transaction {
+                println!("transaction: {}", transaction);
+            }
+            println!("field: {}", divergence.field);
+            println!("left:  {}", divergence.left);
+            println!("right: {}", divergence.right);
+            process::exit(1);
+        },
+        None => (),
+    }
+}
+
+fn export<'a, P: 'static + Patch + Send>(matches: &clap::ArgMatches<'a>, export_matches: &clap::ArgMatches<'a>) {
+    let max_clock_drift: u64 = match matches.value_of("MAX_CLOCK_DRIFT") {
+        Some(val) => val.parse().unwrap(),
+        None => 15,
+    };
+    let datadir = PathBuf::from(matches.value_of("DATADIR").unwrap_or(".svmdev"));
+
+    let source = chain_source::<P>(matches, export_matches.value_of("SOURCE").unwrap(), max_clock_drift, &datadir,
+                                   export_matches.value_of("API_KEY"));
+    let from: usize = export_matches.value_of("FROM").map(|val| val.parse().unwrap()).unwrap_or(0);
+    let to: Option<usize> = export_matches.value_of("TO").map(|val| val.parse().unwrap());
+    let format: export::ExportFormat = export_matches.value_of("FORMAT").unwrap_or("jsonl").parse().unwrap();
+
+    let count = match export_matches.value_of("OUTPUT") {
+        Some(path) => {
+            let mut file = BufWriter::new(File::create(path).unwrap());
+            export::export_receipts(&source, from, to, format, &mut file)
+        },
+        None => {
+            let stdout = io::stdout();
+            let mut stdout = stdout.lock();
+            export::export_receipts(&source, from, to, format, &mut stdout)
+        },
+    }.expect("Expect the chain to be readable");
+    // The standard output may be the export itself.
+    eprintln!("exported {} receipts", count);
+}
+
+fn memory_limit<'a>(matches: &clap::ArgMatches<'a>) -> Option<usize> {
+    matches.value_of("MEMORY_LIMIT").map(|limit| limit.parse().expect("Expect a valid memory limit"))
+}
+
+/// Settings changing how transactions execute, which archives must be
+/// re-executed with as well.
+fn execution_settings<'a>(matches: &clap::ArgMatches<'a>, state: &mut miner::MinerState) {
+    state.set_chain_id(matches.value_of("CHAIN_ID").map(|val| val.parse().expect("Expect a valid chain id")));
+    state.set_memory_limit(memory_limit(matches));
+    if let Some(sponsor) = matches.value_of("FEE_PAYER") {
+        let sponsor = bigint::Address::from_str(sponsor).expect("Expect a valid fee payer address");
+        state.set_fee_policy(Box::new(miner::SponsorFeePolicy { sponsor }));
+    }
+    if let Some(mode) = matches.value_of("SELFDESTRUCT") {
+        state.set_selfdestruct_mode(mode.parse().unwrap());
+    }
+}
+
 fn with_patch<'a, P: 'static + Patch + Send>(matches: clap::ArgMatches<'a>) {
+    // Archives read by the subcommands are executed on this thread.
+    miner::with_memory_limit(memory_limit(&matches), || run::<miner::DevPatch<P>>(matches))
+}
+
+fn run<'a, P: 'static + Patch + Send>(matches: clap::ArgMatches<'a>) {
+    if let Some(compare_matches) = matches.subcommand_matches("compare") {
+        compare::<P>(&matches, compare_matches);
+        return;
+    }
+    if let Some(export_matches) = matches.subcommand_matches("export") {
+        export::<P>(&matches, export_matches);
+        return;
+    }
+
     let mut rng = OsRng::new().unwrap();
 
     let secret_key = match matches.value_of("PRIVATE_KEY") {
@@ -161,7 +520,8 @@ fn with_patch<'a, P: 'static + Patch + Send>(matches: clap::ArgMatches<'a>) {
     };
 
     let balance = {
-        let s = matches.value_of("BALANCE").unwrap_or("0x10000000000000000000000000000");
+        let s = matches.value_of("BALANCE")
+            .unwrap_or("0x10000000000000000000000000000");
         if s.starts_with("0x") {
             U256::from_str(s).unwrap()
         } else {
@@ -173,23 +533,231 @@ fn with_patch<'a, P: 'static + Patch + Send>(matches: clap::ArgMatches<'a>) {
         None => 9,
     };
 
+    let mnemonic = match matches.value_of("MNEMONIC") {
+        Some(val) => Some(val),
+        None if matches.is_present("DETERMINISTIC") => Some(mnemonic::DEFAULT_MNEMONIC),
+        None => None,
+    };
+
     let mut genesis = Vec::new();
-    genesis.push((secret_key, balance));
+    match mnemonic {
+        Some(mnemonic) => {
+            if let Err(err) = mnemonic::validate(mnemonic) {
+                println!("{}", err);
+                process::exit(1);
+            }
+            println!("mnemonic: {}", mnemonic);
+            println!("derivation path: m/44'/60'/0'/0/{{account_index}}");
+            for index in 0..(accounts_len + 1) {
+                let secret_key = mnemonic::account(mnemonic, index as u32)
+                    .expect("Expect a valid account derivation");
+                genesis.push((secret_key, balance));
+            }
+        },
+        None => {
+            genesis.push((secret_key, balance));
 
-    for _ in 0..accounts_len {
-        genesis.push((SecretKey::new(&SECP256K1, &mut rng), balance));
+            for _ in 0..accounts_len {
+                genesis.push((SecretKey::new(&SECP256K1, &mut rng), balance));
+            }
+        },
     }
 
-    let (sender, receiver) = channel::<bool>();
+    let max_clock_drift: u64 = match matches.value_of("MAX_CLOCK_DRIFT") {
+        Some(val) => val.parse().unwrap(),
+        None => 15,
+    };
 
-    let state = miner::make_state::<P>(genesis);
+    let replay = matches.subcommand_matches("replay").map(|replay| {
+        journal::read_journal(Path::new(replay.value_of("FILE").unwrap()))
+            .expect("Expect a valid journal")
+    });
 
-    let miner_arc = Arc::new(Mutex::new(state));
-    let rpc_arc = miner_arc.clone();
+    // An archive is only read once the settings its blocks were mined
+    // with are applied.
+    let mut archive = None;
+    let (mut state, recorded) = match (matches.subcommand_matches("restore"), &replay) {
+        (Some(restore), _) => {
+            let mut file = File::open(restore.value_of("FILE").unwrap()).unwrap();
+            let mut data = Vec::new();
+            file.read_to_end(&mut data).unwrap();
+            let recorded = miner::archive_identity(&data).expect("Expect a valid backup archive");
+            archive = Some(data);
+            (miner::make_state_at::<P>(Vec::new(), 0), recorded)
+        },
+        (None, &Some(ref entries)) => match entries[0] {
+            journal::JournalEntry::Genesis { timestamp, ref accounts, .. } => {
+                let accounts = journal::JournalEntry::genesis_accounts(accounts)
+                    .expect("Expect valid journal genesis accounts");
+                let recorded = entries[0].identity().expect("Expect a valid journal genesis");
+                (miner::make_state_at::<P>(accounts, timestamp), recorded)
+            },
+            _ => unreachable!(),
+        },
+        (None, &None) => (miner::make_state::<P>(genesis), None),
+    };
 
-    thread::spawn(move || {
-        miner::mine_loop::<P>(miner_arc, receiver);
+    state.set_max_clock_drift(max_clock_drift);
+    state.set_chain(matches.value_of("CHAIN").unwrap_or("classic").to_string());
+    execution_settings(&matches, &mut state);
+    state.set_network_id(match matches.value_of("NETWORK_ID") {
+        Some(val) => val.parse().expect("Expect a valid network id"),
+        None => state.chain_id().unwrap_or(1),
     });
+    state.set_address_format(address_format(&matches));
+    if let Some(fork) = matches.value_of("FORK") {
+        let (url, block) = miner::parse_fork(fork).unwrap_or_else(|err| {
+            println!("{}", err);
+            process::exit(1);
+        });
+        let cache_dir = if matches.is_present("NO_FORK_CACHE") {
+            None
+        } else {
+            Some(PathBuf::from(matches.value_of("DATADIR").unwrap_or(".svmdev")).join("fork-cache"))
+        };
+        let fork = miner::Fork::new(url, block, cache_dir.as_ref().map(|dir| dir.as_path()))
+            .expect("Expect the forked node to be reachable");
+        if matches.is_present("CLEAR_FORK_CACHE") {
+            fork.clear_cache().expect("Expect the fork cache to be writable");
+        }
+        println!("forking {} at block {}", fork.url(), fork.block());
+        state.set_fork(Some(fork));
+    }
+    // The chain is checked on the genesis alone, before any archived
+    // block is re-executed or journal entry replayed.
+    if let Some(ref archive) = archive {
+        miner::read_archive_genesis::<P>(archive, &mut state).expect("Expect a valid backup archive");
+    }
+    if let Some(recorded) = recorded {
+        if let Err(err) = recorded.check(&miner::ChainIdentity::of(&state)) {
+            if matches.is_present("ALLOW_CHAIN_MISMATCH") {
+                println!("warning: {}", err);
+            } else {
+                println!("{}, use --allow-chain-mismatch to open it anyway", err);
+                process::exit(1);
+            }
+        }
+    }
+    if let Some(ref archive) = archive {
+        miner::read_archive_blocks::<P>(archive, &mut state).expect("Expect a valid backup archive");
+    }
+    if let Some(blocks) = matches.value_of("LOG_RETENTION") {
+        state.set_log_retention(Some(miner::LogRetention {
+            blocks: blocks.parse().expect("Expect a valid number of blocks to retain"),
+            archive: matches.value_of("LOG_ARCHIVE").map(PathBuf::from),
+        }));
+    }
+    if let Some(bump) = matches.value_of("PRICE_BUMP") {
+        state.set_price_bump(bump.parse().expect("Expect a valid price bump percentage"));
+    }
+    state.set_mining_schedule(miner::MiningSchedule {
+        instamine: !matches.is_present("NO_INSTAMINE"),
+        interval: match matches.value_of("BLOCK_TIME") {
+            Some(val) => match val.parse().expect("Expect a valid block time") {
+                0 => None,
+                seconds => Some(seconds),
+            },
+            None => Some(miner::MINING_INTERVAL),
+        },
+    });
+    state.set_allow_unprotected_transactions(matches.is_present("ALLOW_UNPROTECTED_TXS"));
+    state.set_expose_private_keys(matches.is_present("EXPOSE_PRIVATE_KEYS"));
+    if let Some(price) = matches.value_of("MIN_GAS_PRICE") {
+        state.set_min_gas_price(bigint::Gas::from(U256::from_dec_str(price).expect("Expect a valid minimum gas price")));
+        state.set_allow_zero_gas_price(matches.is_present("ALLOW_ZERO_GAS_PRICE"));
+    }
+    {
+        let mut oracle = miner::GasPriceOracle::default();
+        if let Some(blocks) = matches.value_of("GAS_PRICE_BLOCKS") {
+            oracle.blocks = blocks.parse().expect("Expect a valid number of blocks");
+        }
+        if let Some(percentile) = matches.value_of("GAS_PRICE_PERCENTILE") {
+            oracle.percentile = percentile.parse().expect("Expect a valid percentile");
+        }
+        state.set_gas_price_oracle(oracle);
+    }
+    if let Some(margin) = matches.value_of("GAS_MARGIN") {
+        state.set_gas_margin(margin.parse().expect("Expect a valid gas margin percentage"));
+    }
+    if let Some(size) = matches.value_of("POOL_SIZE") {
+        state.set_pool_size(size.parse().expect("Expect a valid pool size"));
+    }
+    if let Some(slots) = matches.value_of("POOL_SENDER_SLOTS") {
+        state.set_pool_sender_slots(slots.parse().expect("Expect a valid number of sender slots"));
+    }
+    if let Some(depth) = matches.value_of("FREEZE_DEPTH") {
+        let dir = matches.value_of("FREEZE_DIR").map(PathBuf::from);
+        if let Some(ref dir) = dir {
+            std::fs::create_dir_all(dir).expect("Expect the freeze directory to be writable");
+        }
+        state.set_freeze_config(Some(miner::FreezeConfig {
+            depth: depth.parse().expect("Expect a valid freeze depth"),
+            dir,
+        }));
+    }
+    if let Some(workers) = matches.value_of("LOG_WORKERS") {
+        state.set_log_workers(workers.parse().expect("Expect a valid number of log workers"));
+    }
+    if let Some(workers) = matches.value_of("EXECUTION_WORKERS") {
+        state.set_execution_workers(workers.parse().expect("Expect a valid number of execution workers"));
+    }
+    if let Some(capacity) = matches.value_of("ACCOUNT_CACHE") {
+        state.set_account_cache(capacity.parse().expect("Expect a valid account cache size"));
+    }
+    if let Some(capacity) = matches.value_of("RPC_CACHE") {
+        state.set_rpc_cache(capacity.parse().expect("Expect a valid RPC cache size"));
+    }
+    if let Some(difficulty) = matches.value_of("WORK_DIFFICULTY") {
+        state.set_work_difficulty(U256::from_dec_str(difficulty).unwrap());
+    }
+
+    let journal = matches.value_of("JOURNAL").map(|path| {
+        if matches.subcommand_matches("restore").is_some() {
+            panic!("A journal can only be recorded from genesis, not from a restored archive.");
+        }
+        let genesis = journal::JournalEntry::genesis(
+            state.get_block_by_number(0).header.timestamp, state.genesis_accounts(),
+            &miner::ChainIdentity::of(&state));
+        Arc::new(journal::Journal::create(Path::new(path), &genesis)
+                 .expect("Expect the journal file to be writable"))
+    });
+
+    let datadir = PathBuf::from(matches.value_of("DATADIR").unwrap_or(".svmdev"));
+    state.set_data_dir(datadir.clone());
+    if matches.is_present("LOG_INDEX") {
+        state.enable_log_index(&datadir.join("logindex")).expect("Expect the log index to be readable and writable");
+    }
+
+    let state = Arc::new(RwLock::new(state));
+
+    if let Some(entries) = replay {
+        let (sender, _) = channel::<miner::MinerEvent>();
+        rpc::replay::<P>(state.clone(), sender,
+                         keystore::KeyStore::new(datadir.join("keystore")),
+                         &entries, journal.clone());
+        println!("replayed: {} journal entries", entries.len());
+    }
+
+    let api_keys: Vec<rpc::ApiKeyConfig> = match matches.value_of("API_KEYS") {
+        Some(path) => serde_json::from_reader(File::open(path).unwrap())
+            .expect("Expect a valid API keys file"),
+        None => Vec::new(),
+    };
+
+    let rpc_workers = matches.value_of("RPC_WORKERS")
+        .map(|val| val.parse().expect("Expect a valid number of RPC workers"));
+
+    let node = node::DevNode::start::<P>(
+        state, keystore::KeyStore::new(datadir.join("keystore")), api_keys, journal, rpc_workers);
+
+    if let Some(dir) = matches.value_of("WATCH") {
+        let watch_arc = node.state();
+        let watch_sender = node.channel();
+        let dir = PathBuf::from(dir);
+        thread::spawn(move || {
+            miner::watch_loop(watch_arc, watch_sender, dir);
+        });
+    }
 
     #[cfg(feature = "frontend")]
     {
@@ -226,8 +794,5 @@ fn with_patch<'a, P: 'static + Patch + Send>(matches: clap::ArgMatches<'a>) {
         });
     }
 
-    rpc::rpc_loop::<P>(
-        rpc_arc,
-        &matches.value_of("LISTEN").unwrap_or("127.0.0.1:8545").parse().unwrap(),
-        sender);
+    node.serve(&matches.value_of("LISTEN").unwrap_or("127.0.0.1:8545").parse().unwrap());
 }
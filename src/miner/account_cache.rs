@@ -0,0 +1,124 @@
+use bigint::{Address, H256, U256};
+use block::Account;
+use sputnikvm::{AccountCommitment, Patch, SeqTransactionVM, VM};
+use sputnikvm::errors::RequireError;
+use sputnikvm_stateful::MemoryStateful;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+
+use super::lru::LruCache;
+
+/// Default number of accounts kept by the cache of a state.
+pub const DEFAULT_ACCOUNT_CACHE: usize = 4096;
+
+#[derive(Clone)]
+struct CachedAccount {
+    nonce: U256,
+    balance: U256,
+    code: Arc<Vec<u8>>,
+}
+
+/// Accounts and their code as the VM required them, by the state root
+/// they were read at. A root always holds the same accounts, so entries
+/// never go stale; they are only evicted once the cache is full. It is
+/// shared by every execution on the state, so that repeated calls
+/// against the same block no longer read the trie and the code of each
+/// account they touch.
+#[derive(Clone)]
+pub struct AccountCache {
+    entries: Arc<Mutex<LruCache<(H256, Address), Option<CachedAccount>>>>,
+}
+
+impl AccountCache {
+    /// Cache of `capacity` accounts. Zero disables it.
+    pub fn new(capacity: usize) -> Self {
+        AccountCache {
+            entries: Arc::new(Mutex::new(LruCache::new(capacity))),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.entries.lock().unwrap().capacity()
+    }
+
+    fn account(&self, stateful: &MemoryStateful, address: Address) -> Option<Option<CachedAccount>> {
+        let key = (stateful.root(), address);
+        if let Some(account) = self.entries.lock().unwrap().get(&key) {
+            return Some(account);
+        }
+
+        let account: Option<Account> = stateful.state_of(stateful.root()).get(&address);
+        let cached = match account {
+            Some(account) => Some(CachedAccount {
+                nonce: account.nonce,
+                balance: account.balance,
+                code: Arc::new(stateful.code(account.code_hash)?),
+            }),
+            None => None,
+        };
+        self.entries.lock().unwrap().insert(key, cached.clone());
+        Some(cached)
+    }
+
+    /// Commit the account or code `require` asks for to `vm`. Returns
+    /// false if it is left to `stateful`: for storage and block hashes,
+    /// when the cache is disabled, or when the code of the account is
+    /// missing.
+    pub fn fulfill<P: Patch>(
+        &self, stateful: &MemoryStateful, vm: &mut SeqTransactionVM<P>, require: &RequireError
+    ) -> bool {
+        let (address, code_only) = match *require {
+            RequireError::Account(address) => (address, false),
+            RequireError::AccountCode(address) => (address, true),
+            _ => return false,
+        };
+        if self.capacity() == 0 {
+            return false;
+        }
+
+        let commitment = match self.account(stateful, address) {
+            None => return false,
+            Some(None) => AccountCommitment::Nonexist(address),
+            Some(Some(account)) => {
+                let code = Rc::new(account.code.as_ref().clone());
+                if code_only {
+                    AccountCommitment::Code { address, code }
+                } else {
+                    AccountCommitment::Full {
+                        nonce: account.nonce,
+                        address,
+                        balance: account.balance,
+                        code,
+                    }
+                }
+            },
+        };
+        vm.commit_account(commitment).is_ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use secp256k1::SECP256K1;
+    use secp256k1::key::SecretKey;
+    use sputnikvm_network_classic::MainnetEIP160Patch;
+    use block::FromKey;
+    use super::super::make_state_at;
+
+    #[test]
+    fn accounts_are_cached_by_root() {
+        let secret_key = SecretKey::from_slice(&SECP256K1, &[1u8; 32]).unwrap();
+        let state = make_state_at::<MainnetEIP160Patch>(vec![(secret_key, U256::from(1000u64))], 0);
+        let address = Address::from_secret_key(&secret_key).unwrap();
+        let stateful = state.stateful_at(state.stateful().root());
+
+        let cache = AccountCache::new(1);
+        let account = cache.account(&stateful, address).unwrap().unwrap();
+        assert_eq!(account.balance, U256::from(1000u64));
+        assert!(cache.entries.lock().unwrap().get(&(stateful.root(), address)).is_some());
+
+        assert!(cache.account(&stateful, Address::default()).unwrap().is_none());
+        assert!(cache.entries.lock().unwrap().get(&(stateful.root(), address)).is_none());
+    }
+}
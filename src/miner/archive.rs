@@ -0,0 +1,455 @@
+use rlp::{RlpStream, UntrustedRlp};
+use block::{Block, Transaction, HeaderHash};
+use bigint::{Address, H256, M256, U256};
+use secp256k1::SECP256K1;
+use secp256k1::key::SecretKey;
+use sputnikvm::Patch;
+
+use error::Error;
+use super::{MinerState, StateWrite, GenesisAccount, make_state_with, import_block};
+
+const ARCHIVE_VERSION: u8 = 7;
+
+/// Chain an archive or a journal was recorded on, checked when it is
+/// opened again so that blocks of one chain are not re-executed on
+/// another.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChainIdentity {
+    pub chain: String,
+    /// Chain id returned by `eth_chainId`, `None` in archives and
+    /// journals of versions that do not record it.
+    pub chain_id: Option<u64>,
+    pub genesis_hash: H256,
+}
+
+impl ChainIdentity {
+    pub fn of(state: &MinerState) -> Self {
+        ChainIdentity {
+            chain: state.chain().to_string(),
+            chain_id: Some(state.chain_id().unwrap_or(state.network_id())),
+            genesis_hash: state.get_block_by_number(0).header.header_hash(),
+        }
+    }
+
+    /// Refuse data recorded as `self` when the node runs as `current`.
+    pub fn check(&self, current: &ChainIdentity) -> Result<(), Error> {
+        if self.chain != current.chain {
+            return Err(Error::ChainMismatch(format!(
+                "recorded on chain {}, opened with chain {}", self.chain, current.chain)));
+        }
+        if let (Some(recorded), Some(opened)) = (self.chain_id, current.chain_id) {
+            if recorded != opened {
+                return Err(Error::ChainMismatch(format!(
+                    "recorded with chain id {}, opened with chain id {}", recorded, opened)));
+            }
+        }
+        if self.genesis_hash != current.genesis_hash {
+            return Err(Error::ChainMismatch(format!(
+                "recorded with genesis 0x{:x}, opened with genesis 0x{:x}",
+                self.genesis_hash, current.genesis_hash)));
+        }
+        Ok(())
+    }
+}
+
+/// Serialize the whole chain into an archive. The caller is expected
+/// to hold the state lock, which keeps the miner from sealing a new
+/// block while the archive is written. Unless `include_keys` is set,
+/// genesis accounts are written by address only, and the restored node
+/// cannot sign for them.
+pub fn write_archive(state: &MinerState, include_keys: bool) -> Vec<u8> {
+    let genesis = state.get_block_by_number(0);
+    let genesis_accounts = state.genesis_accounts();
+    let pending = state.pool_transactions();
+    let impersonated_transactions = state.impersonated_transactions();
+    let impersonated_accounts = state.impersonated_accounts();
+    let state_writes = state.state_writes();
+    let alloc = state.genesis_alloc();
+
+    let identity = ChainIdentity::of(state);
+
+    let mut stream = RlpStream::new_list(12);
+    stream.append(&ARCHIVE_VERSION);
+    stream.append(&genesis.header.timestamp);
+
+    stream.begin_list(genesis_accounts.len());
+    for &(ref secret_key, ref balance) in genesis_accounts {
+        stream.begin_list(2);
+        if include_keys {
+            stream.append(&secret_key[..].to_vec());
+        } else {
+            stream.append(&Address::from_secret_key(secret_key).unwrap());
+        }
+        stream.append(balance);
+    }
+
+    stream.begin_list(state.block_height());
+    for number in 1..(state.block_height() + 1) {
+        stream.append(&state.get_block_by_number(number));
+    }
+
+    stream.begin_list(pending.len());
+    for transaction in &pending {
+        stream.append(transaction);
+    }
+
+    stream.append(&identity.chain);
+    stream.append(&identity.genesis_hash);
+
+    // Transactions sent from impersonated accounts are signed with a
+    // throwaway key, and are only executed as their sender again once
+    // it is known.
+    stream.begin_list(impersonated_transactions.len());
+    for &(ref hash, ref sender) in &impersonated_transactions {
+        stream.begin_list(2);
+        stream.append(hash);
+        stream.append(sender);
+    }
+
+    stream.begin_list(impersonated_accounts.len());
+    for address in &impersonated_accounts {
+        stream.append(address);
+    }
+
+    // Writes of the `set_*` methods are applied again before the block
+    // they were made before, as `[before, kind, address, values...]`.
+    stream.begin_list(state_writes.len());
+    for &(before, ref write) in state_writes {
+        match *write {
+            StateWrite::Balance(address, balance) => {
+                stream.begin_list(4).append(&before).append(&0u8).append(&address).append(&balance);
+            },
+            StateWrite::Nonce(address, nonce) => {
+                stream.begin_list(4).append(&before).append(&1u8).append(&address).append(&nonce);
+            },
+            StateWrite::Code(address, ref code) => {
+                stream.begin_list(4).append(&before).append(&2u8).append(&address).append(code);
+            },
+            StateWrite::Storage(address, index, value) => {
+                stream.begin_list(5).append(&before).append(&3u8).append(&address).append(&index)
+                    .append(&U256::from(value));
+            },
+        }
+    }
+
+    // Accounts pre-allocated in the genesis, as by `dev_reset`, as
+    // `[address, balance, nonce, code, [[index, value]...]]`.
+    stream.begin_list(alloc.len());
+    for account in alloc {
+        stream.begin_list(5);
+        stream.append(&account.address);
+        stream.append(&account.balance);
+        stream.append(&account.nonce);
+        stream.append(&account.code);
+        stream.begin_list(account.storage.len());
+        for &(index, value) in &account.storage {
+            stream.begin_list(2).append(&index).append(&U256::from(value));
+        }
+    }
+
+    stream.append(&identity.chain_id.expect("identity of a node records its chain id"));
+
+    stream.out().to_vec()
+}
+
+/// Replace the chain of `state` by the one of an archive, re-creating
+/// the genesis and re-executing every archived block on top of it. The
+/// node settings of `state`, such as its fee policy, SELFDESTRUCT mode,
+/// memory limit or fork, are kept and apply to the re-execution, so
+/// they must be set as when the blocks were mined. If a block fails to
+/// re-execute, `state` is left with the blocks before it.
+pub fn read_archive<P: Patch>(data: &[u8], state: &mut MinerState) -> Result<(), Error> {
+    read_archive_genesis::<P>(data, state)?;
+    read_archive_blocks::<P>(data, state)
+}
+
+fn archive_version(rlp: &UntrustedRlp) -> Result<u8, Error> {
+    let version: u8 = rlp.val_at(0)?;
+    if version < 1 || version > ARCHIVE_VERSION {
+        return Err(Error::InvalidArchive);
+    }
+    Ok(version)
+}
+
+/// Replace the chain of `state` by the genesis of an archive, so that
+/// it can be checked before any block is re-executed.
+pub fn read_archive_genesis<P: Patch>(data: &[u8], state: &mut MinerState) -> Result<(), Error> {
+    let rlp = UntrustedRlp::new(data);
+
+    let version = archive_version(&rlp)?;
+    let timestamp: u64 = rlp.val_at(1)?;
+
+    // Accounts written without their key are funded as pre-allocated
+    // accounts, which gives the same genesis state.
+    let mut genesis_accounts = Vec::new();
+    let mut alloc = Vec::new();
+    for account in rlp.at(2)?.iter() {
+        let key: Vec<u8> = account.val_at(0)?;
+        let balance: U256 = account.val_at(1)?;
+        if version >= 5 && key.len() == 20 {
+            alloc.push(GenesisAccount {
+                address: Address::from(&key[..]),
+                balance,
+                nonce: U256::zero(),
+                code: Vec::new(),
+                storage: Vec::new(),
+            });
+        } else {
+            genesis_accounts.push((SecretKey::from_slice(&SECP256K1, &key)?, balance));
+        }
+    }
+
+    if version >= 6 {
+        for account in rlp.at(10)?.iter() {
+            let mut storage = Vec::new();
+            for slot in account.at(4)?.iter() {
+                storage.push((slot.val_at(0)?, M256::from(slot.val_at::<U256>(1)?)));
+            }
+            alloc.push(GenesisAccount {
+                address: account.val_at(0)?,
+                balance: account.val_at(1)?,
+                nonce: account.val_at(2)?,
+                code: account.val_at(3)?,
+                storage,
+            });
+        }
+    }
+
+    state.reset(make_state_with::<P>(genesis_accounts, timestamp, &alloc));
+    Ok(())
+}
+
+/// Re-execute the blocks of an archive on top of its genesis, read
+/// into `state` by `read_archive_genesis`.
+pub fn read_archive_blocks<P: Patch>(data: &[u8], state: &mut MinerState) -> Result<(), Error> {
+    let rlp = UntrustedRlp::new(data);
+
+    let version = archive_version(&rlp)?;
+    if version >= 3 {
+        for transaction in rlp.at(7)?.iter() {
+            let hash: H256 = transaction.val_at(0)?;
+            let sender: Address = transaction.val_at(1)?;
+            state.insert_impersonated_transaction(hash, sender);
+        }
+        for address in rlp.at(8)?.iter() {
+            let address: Address = address.as_val()?;
+            state.impersonate_account(address);
+        }
+    }
+
+    let mut state_writes = Vec::new();
+    if version >= 4 {
+        for write in rlp.at(9)?.iter() {
+            let before: usize = write.val_at(0)?;
+            let kind: u8 = write.val_at(1)?;
+            let address: Address = write.val_at(2)?;
+            state_writes.push((before, match kind {
+                0 => StateWrite::Balance(address, write.val_at(3)?),
+                1 => StateWrite::Nonce(address, write.val_at(3)?),
+                2 => StateWrite::Code(address, write.val_at(3)?),
+                3 => StateWrite::Storage(address, write.val_at(3)?, M256::from(write.val_at::<U256>(4)?)),
+                _ => return Err(Error::InvalidArchive),
+            }));
+        }
+    }
+    let mut state_writes = state_writes.into_iter().peekable();
+
+    for block in rlp.at(3)?.iter() {
+        let block: Block = block.as_val()?;
+        let number = block.header.number.as_usize();
+        while state_writes.peek().map(|&(before, _)| before <= number).unwrap_or(false) {
+            let (_, write) = state_writes.next().unwrap();
+            state.apply_state_write(write);
+        }
+        import_block::<P>(state, block)?;
+    }
+    for (_, write) in state_writes {
+        state.apply_state_write(write);
+    }
+
+    for transaction in rlp.at(4)?.iter() {
+        let transaction: Transaction = transaction.as_val()?;
+        state.append_pending_transaction(transaction)?;
+    }
+
+    Ok(())
+}
+
+/// Chain an archive was written on. Archives of the first version do
+/// not record it.
+pub fn archive_identity(data: &[u8]) -> Result<Option<ChainIdentity>, Error> {
+    let rlp = UntrustedRlp::new(data);
+
+    let version: u8 = rlp.val_at(0)?;
+    if version < 2 {
+        return Ok(None);
+    }
+    Ok(Some(ChainIdentity {
+        chain: rlp.val_at(5)?,
+        chain_id: if version >= 7 { Some(rlp.val_at(11)?) } else { None },
+        genesis_hash: rlp.val_at(6)?,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bigint::Gas;
+    use block::{FromKey, RlpHash, TransactionAction, UnsignedTransaction};
+    use sputnikvm_network_classic::MainnetEIP160Patch;
+    use std::sync::{Arc, RwLock};
+    use super::super::{impersonation_key, make_state_at, mine_one_at, sign_transaction, SponsorFeePolicy};
+
+    fn restore(archive: &[u8]) -> MinerState {
+        let mut state = make_state_at::<MainnetEIP160Patch>(Vec::new(), 0);
+        read_archive::<MainnetEIP160Patch>(archive, &mut state).unwrap();
+        state
+    }
+
+    #[test]
+    fn impersonated_transactions_are_restored() {
+        let secret_key = SecretKey::from_slice(&SECP256K1, &[1u8; 32]).unwrap();
+        let mut state = make_state_at::<MainnetEIP160Patch>(vec![(secret_key, U256::from(1_000_000u64))], 0);
+        let sender = Address::from_secret_key(&secret_key).unwrap();
+        state.impersonate_account(sender);
+
+        let transaction = sign_transaction(UnsignedTransaction {
+            nonce: U256::zero(),
+            gas_price: Gas::zero(),
+            gas_limit: Gas::from(21000u64),
+            action: TransactionAction::Call(Address::from(0x10u64)),
+            value: U256::from(1000u64),
+            input: Vec::new(),
+        }, &impersonation_key(sender), None);
+        state.insert_impersonated_transaction(transaction.rlp_hash(), sender);
+        state.append_pending_transaction(transaction).unwrap();
+
+        let state = Arc::new(RwLock::new(state));
+        mine_one_at::<MainnetEIP160Patch>(state.clone(), Address::default(), 1);
+        let state = state.read().unwrap();
+        assert_eq!(state.current_block().transactions.len(), 1);
+
+        let restored = restore(&write_archive(&state, true));
+        assert_eq!(restored.current_block().header.header_hash(), state.current_block().header.header_hash());
+        assert!(restored.is_impersonated(sender));
+    }
+
+    #[test]
+    fn state_writes_are_restored() {
+        let secret_key = SecretKey::from_slice(&SECP256K1, &[1u8; 32]).unwrap();
+        let state = make_state_at::<MainnetEIP160Patch>(vec![(secret_key, U256::from(1_000_000u64))], 0);
+        let address = Address::from(0x10u64);
+
+        let state = Arc::new(RwLock::new(state));
+        state.write().unwrap().set_balance(address, U256::from(7u64));
+        mine_one_at::<MainnetEIP160Patch>(state.clone(), Address::default(), 1);
+        state.write().unwrap().set_code(address, vec![0x60, 0x00]);
+        mine_one_at::<MainnetEIP160Patch>(state.clone(), Address::default(), 2);
+        state.write().unwrap().set_storage(address, U256::one(), M256::from(3u64));
+
+        let mut state = state.write().unwrap();
+        state.set_head(1).unwrap();
+        state.set_nonce(address, U256::from(5u64));
+        assert_eq!(state.state_writes().len(), 2);
+
+        let restored = restore(&write_archive(&state, true));
+        assert_eq!(restored.block_height(), 1);
+        assert_eq!(restored.state_root_at(1), state.state_root_at(1));
+        assert_eq!(restored.state_writes(), state.state_writes());
+    }
+
+    #[test]
+    fn keys_are_opt_in() {
+        let secret_key = SecretKey::from_slice(&SECP256K1, &[1u8; 32]).unwrap();
+        let state = make_state_at::<MainnetEIP160Patch>(vec![(secret_key, U256::from(1_000_000u64))], 0);
+        let address = Address::from_secret_key(&secret_key).unwrap();
+
+        let archive = write_archive(&state, false);
+        assert!(!archive.windows(32).any(|window| window == &secret_key[..]));
+        let restored = restore(&archive);
+        assert!(restored.genesis_accounts().is_empty());
+        assert_eq!(restored.get_block_by_number(0).header.header_hash(),
+                   state.get_block_by_number(0).header.header_hash());
+        assert!(restored.secret_key(address).is_none());
+
+        let restored = restore(&write_archive(&state, true));
+        assert_eq!(restored.genesis_accounts(), state.genesis_accounts());
+    }
+
+    #[test]
+    fn genesis_alloc_is_restored() {
+        let secret_key = SecretKey::from_slice(&SECP256K1, &[1u8; 32]).unwrap();
+        let alloc = vec![GenesisAccount {
+            address: Address::from(0x10u64),
+            balance: U256::from(7u64),
+            nonce: U256::one(),
+            code: vec![0x60, 0x00],
+            storage: vec![(U256::one(), M256::from(3u64))],
+        }];
+        let state = make_state_with::<MainnetEIP160Patch>(
+            vec![(secret_key, U256::from(1_000_000u64))], 0, &alloc);
+
+        let restored = restore(&write_archive(&state, false));
+        assert_eq!(restored.get_block_by_number(0).header.header_hash(),
+                   state.get_block_by_number(0).header.header_hash());
+        assert_eq!(&restored.genesis_alloc()[1..], &alloc[..]);
+
+        let restored = restore(&write_archive(&restored, false));
+        assert_eq!(restored.get_block_by_number(0).header.header_hash(),
+                   state.get_block_by_number(0).header.header_hash());
+    }
+
+    #[test]
+    fn blocks_are_re_executed_with_the_node_settings() {
+        let key = SecretKey::from_slice(&SECP256K1, &[1u8; 32]).unwrap();
+        let sponsor_key = SecretKey::from_slice(&SECP256K1, &[2u8; 32]).unwrap();
+        let sponsor = Address::from_secret_key(&sponsor_key).unwrap();
+        let mut state = make_state_at::<MainnetEIP160Patch>(
+            vec![(key.clone(), U256::from(1_000_000u64)), (sponsor_key, U256::from(1_000_000u64))], 0);
+        state.set_fee_policy(Box::new(SponsorFeePolicy { sponsor }));
+        state.append_pending_transaction(sign_transaction(UnsignedTransaction {
+            nonce: U256::zero(),
+            gas_price: Gas::from(1u64),
+            gas_limit: Gas::from(21000u64),
+            action: TransactionAction::Call(Address::from(0x10u64)),
+            value: U256::from(1000u64),
+            input: Vec::new(),
+        }, &key, None)).unwrap();
+
+        let state = Arc::new(RwLock::new(state));
+        mine_one_at::<MainnetEIP160Patch>(state.clone(), Address::default(), 1);
+        let state = state.read().unwrap();
+        let archive = write_archive(&state, true);
+
+        let mut restored = make_state_at::<MainnetEIP160Patch>(Vec::new(), 0);
+        restored.set_fee_policy(Box::new(SponsorFeePolicy { sponsor }));
+        read_archive::<MainnetEIP160Patch>(&archive, &mut restored).unwrap();
+        assert_eq!(restored.current_block().header.header_hash(), state.current_block().header.header_hash());
+
+        // Without the policy, the sender pays the fee and the state root differs.
+        let mut restored = make_state_at::<MainnetEIP160Patch>(Vec::new(), 0);
+        assert!(read_archive::<MainnetEIP160Patch>(&archive, &mut restored).is_err());
+    }
+
+    #[test]
+    fn identity_mismatch() {
+        let recorded = ChainIdentity {
+            chain: "classic".to_string(),
+            chain_id: Some(61),
+            genesis_hash: H256::from(1u64),
+        };
+        assert!(recorded.check(&recorded.clone()).is_ok());
+
+        let other_chain = ChainIdentity { chain: "foundation".to_string(), ..recorded.clone() };
+        assert!(recorded.check(&other_chain).is_err());
+
+        let other_chain_id = ChainIdentity { chain_id: Some(62), ..recorded.clone() };
+        assert!(recorded.check(&other_chain_id).is_err());
+
+        let unrecorded_chain_id = ChainIdentity { chain_id: None, ..recorded.clone() };
+        assert!(unrecorded_chain_id.check(&other_chain_id).is_ok());
+
+        let other_genesis = ChainIdentity { genesis_hash: H256::from(2u64), ..recorded.clone() };
+        assert!(recorded.check(&other_genesis).is_err());
+    }
+}
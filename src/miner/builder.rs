@@ -0,0 +1,224 @@
+use bigint::{Address, Gas, H64, H256, U256, B256};
+use block::{Block, Header, HeaderHash, Transaction, ommers_hash, transactions_root};
+use bloom::LogsBloom;
+use trie::{MemoryDatabase, Database};
+
+use super::block_gas_limit;
+
+/// Header of a block on top of `parent`. Fields default to what the
+/// miner would produce for an empty block one second after the parent,
+/// and any of them can be overridden, e.g. to craft invalid headers.
+pub struct HeaderBuilder {
+    header: Header,
+    transactions_root: Option<H256>,
+    ommers_hash: Option<H256>,
+}
+
+impl HeaderBuilder {
+    pub fn new(parent: &Header) -> Self {
+        let empty_root = MemoryDatabase::default().create_empty().root();
+
+        HeaderBuilder {
+            header: Header {
+                parent_hash: parent.header_hash(),
+                ommers_hash: ommers_hash(&[]),
+                beneficiary: Address::default(),
+                state_root: parent.state_root,
+                transactions_root: empty_root,
+                receipts_root: empty_root,
+                logs_bloom: LogsBloom::new(),
+                gas_limit: block_gas_limit(),
+                gas_used: Gas::zero(),
+                timestamp: parent.timestamp + 1,
+                extra_data: B256::default(),
+                number: parent.number + U256::one(),
+
+                difficulty: U256::zero(),
+                mix_hash: H256::default(),
+                nonce: H64::default(),
+            },
+            transactions_root: None,
+            ommers_hash: None,
+        }
+    }
+
+    pub fn parent_hash(mut self, parent_hash: H256) -> Self {
+        self.header.parent_hash = parent_hash;
+        self
+    }
+
+    pub fn ommers_hash(mut self, ommers_hash: H256) -> Self {
+        self.ommers_hash = Some(ommers_hash);
+        self
+    }
+
+    pub fn beneficiary(mut self, beneficiary: Address) -> Self {
+        self.header.beneficiary = beneficiary;
+        self
+    }
+
+    pub fn state_root(mut self, state_root: H256) -> Self {
+        self.header.state_root = state_root;
+        self
+    }
+
+    pub fn transactions_root(mut self, transactions_root: H256) -> Self {
+        self.transactions_root = Some(transactions_root);
+        self
+    }
+
+    pub fn receipts_root(mut self, receipts_root: H256) -> Self {
+        self.header.receipts_root = receipts_root;
+        self
+    }
+
+    pub fn logs_bloom(mut self, logs_bloom: LogsBloom) -> Self {
+        self.header.logs_bloom = logs_bloom;
+        self
+    }
+
+    pub fn gas_limit(mut self, gas_limit: Gas) -> Self {
+        self.header.gas_limit = gas_limit;
+        self
+    }
+
+    pub fn gas_used(mut self, gas_used: Gas) -> Self {
+        self.header.gas_used = gas_used;
+        self
+    }
+
+    pub fn timestamp(mut self, timestamp: u64) -> Self {
+        self.header.timestamp = timestamp;
+        self
+    }
+
+    pub fn extra_data(mut self, extra_data: B256) -> Self {
+        self.header.extra_data = extra_data;
+        self
+    }
+
+    pub fn number(mut self, number: U256) -> Self {
+        self.header.number = number;
+        self
+    }
+
+    pub fn difficulty(mut self, difficulty: U256) -> Self {
+        self.header.difficulty = difficulty;
+        self
+    }
+
+    pub fn mix_hash(mut self, mix_hash: H256) -> Self {
+        self.header.mix_hash = mix_hash;
+        self
+    }
+
+    pub fn nonce(mut self, nonce: H64) -> Self {
+        self.header.nonce = nonce;
+        self
+    }
+
+    /// Hash of the header as built so far.
+    pub fn hash(&self) -> H256 {
+        self.clone_header().header_hash()
+    }
+
+    fn clone_header(&self) -> Header {
+        let mut header = self.header.clone();
+        if let Some(transactions_root) = self.transactions_root {
+            header.transactions_root = transactions_root;
+        }
+        if let Some(ommers_hash) = self.ommers_hash {
+            header.ommers_hash = ommers_hash;
+        }
+        header
+    }
+
+    pub fn build(self) -> Header {
+        self.clone_header()
+    }
+}
+
+/// Block on top of `parent`. The transactions root and ommers hash are
+/// computed from the block content, unless set explicitly on the
+/// header.
+pub struct BlockBuilder {
+    header: HeaderBuilder,
+    transactions: Vec<Transaction>,
+    ommers: Vec<Header>,
+}
+
+impl BlockBuilder {
+    pub fn new(parent: &Block) -> Self {
+        BlockBuilder {
+            header: HeaderBuilder::new(&parent.header),
+            transactions: Vec::new(),
+            ommers: Vec::new(),
+        }
+    }
+
+    pub fn header<F: FnOnce(HeaderBuilder) -> HeaderBuilder>(mut self, f: F) -> Self {
+        self.header = f(self.header);
+        self
+    }
+
+    pub fn transaction(mut self, transaction: Transaction) -> Self {
+        self.transactions.push(transaction);
+        self
+    }
+
+    pub fn ommer(mut self, ommer: Header) -> Self {
+        self.ommers.push(ommer);
+        self
+    }
+
+    pub fn build(self) -> Block {
+        let mut header = self.header;
+        if header.transactions_root.is_none() {
+            header.transactions_root = Some(transactions_root(&self.transactions));
+        }
+        if header.ommers_hash.is_none() {
+            header.ommers_hash = Some(ommers_hash(&self.ommers));
+        }
+
+        Block {
+            header: header.build(),
+            transactions: self.transactions,
+            ommers: self.ommers,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header_defaults_from_parent() {
+        let parent = Header {
+            parent_hash: H256::default(),
+            ommers_hash: ommers_hash(&[]),
+            beneficiary: Address::default(),
+            state_root: H256::default(),
+            transactions_root: H256::default(),
+            receipts_root: H256::default(),
+            logs_bloom: LogsBloom::new(),
+            gas_limit: Gas::zero(),
+            gas_used: Gas::zero(),
+            timestamp: 10,
+            extra_data: B256::default(),
+            number: U256::one(),
+            difficulty: U256::zero(),
+            mix_hash: H256::default(),
+            nonce: H64::default(),
+        };
+
+        let builder = HeaderBuilder::new(&parent).timestamp(5);
+        let hash = builder.hash();
+        let header = builder.build();
+        assert_eq!(header.parent_hash, parent.header_hash());
+        assert_eq!(header.number, U256::from(2u64));
+        assert_eq!(header.timestamp, 5);
+        assert_eq!(header.gas_limit, block_gas_limit());
+        assert_eq!(header.header_hash(), hash);
+    }
+}
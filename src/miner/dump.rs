@@ -0,0 +1,249 @@
+use bigint::{H256, M256, U256, Address};
+use block::Account;
+use rlp::UntrustedRlp;
+use trie::{MemoryDatabase, Database, DatabaseGuard};
+use sha3::{Digest, Keccak256};
+use std::collections::{BTreeMap, HashMap};
+
+use error::Error;
+use super::MinerState;
+use super::proof::{nibbles, decode_path};
+
+/// Account read from a state trie.
+pub struct DumpedAccount {
+    pub account: Account,
+    pub code: Vec<u8>,
+    pub storage: BTreeMap<U256, M256>,
+    /// Slots whose index is not known to the node, by hashed key.
+    pub unknown_storage: BTreeMap<H256, M256>,
+}
+
+/// Every account of a state root.
+pub struct StateDump {
+    pub accounts: BTreeMap<Address, DumpedAccount>,
+    /// Accounts whose address is not known to the node, by hashed key.
+    pub unknown_accounts: BTreeMap<H256, DumpedAccount>,
+}
+
+fn keccak(data: &[u8]) -> H256 {
+    H256::from(Keccak256::digest(data).as_slice())
+}
+
+fn leaf_key(path: &[u8]) -> Result<H256, Error> {
+    if path.len() != 64 {
+        return Err(Error::Internal("key of a secure trie is not 32 bytes long"));
+    }
+    let bytes: Vec<u8> = path.chunks(2).map(|pair| pair[0] << 4 | pair[1]).collect();
+    Ok(H256::from(bytes.as_slice()))
+}
+
+struct Walker<'a, G: 'a + DatabaseGuard> {
+    guard: &'a G,
+    start: Vec<u8>,
+    limit: usize,
+    path: Vec<u8>,
+    leaves: Vec<(H256, Vec<u8>)>,
+}
+
+impl<'a, G: DatabaseGuard> Walker<'a, G> {
+    fn is_full(&self) -> bool {
+        self.leaves.len() >= self.limit
+    }
+
+    /// Whether every key under the current path comes before the start
+    /// key.
+    fn is_before_start(&self) -> bool {
+        let length = self.path.len().min(self.start.len());
+        self.path[..length] < self.start[..length]
+    }
+
+    fn walk_root(&mut self, hash: H256) -> Result<(), Error> {
+        let node = self.guard.get(hash).ok_or(Error::Internal("trie node of a dump is missing"))?;
+        self.walk_node(&UntrustedRlp::new(&node))
+    }
+
+    /// Children of less than 32 bytes are embedded in their parent
+    /// instead of being referenced by hash.
+    fn walk_reference(&mut self, rlp: &UntrustedRlp) -> Result<(), Error> {
+        if self.is_full() || self.is_before_start() {
+            Ok(())
+        } else if rlp.is_list() {
+            self.walk_node(rlp)
+        } else if rlp.is_empty() {
+            Ok(())
+        } else {
+            self.walk_root(rlp.as_val()?)
+        }
+    }
+
+    fn walk_node(&mut self, rlp: &UntrustedRlp) -> Result<(), Error> {
+        match rlp.item_count() {
+            0 => Ok(()),
+            2 => {
+                let (partial, is_leaf) = decode_path(rlp.at(0)?.data()?);
+                let length = self.path.len();
+                self.path.extend(partial);
+                if !is_leaf {
+                    self.walk_reference(&rlp.at(1)?)?;
+                } else if !self.is_full() && !self.is_before_start() {
+                    let key = leaf_key(&self.path)?;
+                    self.leaves.push((key, rlp.at(1)?.data()?.to_vec()));
+                }
+                self.path.truncate(length);
+                Ok(())
+            },
+            17 => {
+                for i in 0..16 {
+                    self.path.push(i as u8);
+                    self.walk_reference(&rlp.at(i)?)?;
+                    self.path.pop();
+                }
+                Ok(())
+            },
+            _ => Err(Error::RlpError),
+        }
+    }
+}
+
+/// Hashed keys and values of the secure trie at `root` in key order,
+/// from the first key not below `start` and at most `limit` of them.
+/// The key of the leaf following the range is returned along.
+pub fn trie_range(
+    database: &MemoryDatabase, root: H256, start: &[u8], limit: usize
+) -> Result<(Vec<(H256, Vec<u8>)>, Option<H256>), Error> {
+    if root == database.create_empty().root() {
+        return Ok((Vec::new(), None));
+    }
+
+    let guard = database.create_guard();
+    let mut walker = Walker {
+        guard: &guard,
+        start: nibbles(start),
+        limit: limit.saturating_add(1),
+        path: Vec::new(),
+        leaves: Vec::new(),
+    };
+    walker.walk_root(root)?;
+
+    let mut leaves = walker.leaves;
+    let next = if leaves.len() > limit {
+        leaves.pop().map(|(key, _)| key)
+    } else {
+        None
+    };
+    Ok((leaves, next))
+}
+
+/// Hashed keys and values of the secure trie at `root`, in key order.
+pub fn trie_leaves(database: &MemoryDatabase, root: H256) -> Result<Vec<(H256, Vec<u8>)>, Error> {
+    Ok(trie_range(database, root, &[], usize::max_value())?.0)
+}
+
+/// Read every account and storage slot of the state at `root` from its
+/// tries. Hashed keys are mapped back to the addresses and indexes the
+/// node saw written.
+pub fn dump_state(state: &MinerState, root: H256) -> Result<StateDump, Error> {
+    Ok(dump_range(state, root, &[], usize::max_value(), true, true)?.0)
+}
+
+/// Read the accounts of the state at `root` as `dump_state` does, from
+/// the first hashed key not below `start` and at most `limit` of them.
+/// The hashed key of the account following the range is returned along.
+pub fn dump_range(
+    state: &MinerState, root: H256, start: &[u8], limit: usize, with_code: bool, with_storage: bool
+) -> Result<(StateDump, Option<H256>), Error> {
+    let database = state.stateful().database();
+    let guard = database.create_guard();
+    let empty_code = keccak(&[]);
+
+    let known = state.known_storage();
+    let addresses: HashMap<H256, Address> = known.keys()
+        .map(|address| (keccak(address.as_ref()), *address))
+        .collect();
+
+    let mut dump = StateDump {
+        accounts: BTreeMap::new(),
+        unknown_accounts: BTreeMap::new(),
+    };
+    let (leaves, next) = trie_range(database, root, start, limit)?;
+    for (key, value) in leaves {
+        let account: Account = UntrustedRlp::new(&value).as_val()?;
+        let code = if !with_code || account.code_hash == empty_code {
+            Vec::new()
+        } else {
+            guard.get(account.code_hash).ok_or(Error::Internal("code of a dumped account is missing"))?
+        };
+
+        let address = addresses.get(&key).cloned();
+        let mut storage = BTreeMap::new();
+        let mut unknown_storage = BTreeMap::new();
+        if with_storage {
+            let indexes: HashMap<H256, U256> = match address.and_then(|address| known.get(&address)) {
+                Some(indexes) => indexes.iter().map(|index| (keccak(&H256::from(*index)), *index)).collect(),
+                None => HashMap::new(),
+            };
+
+            for (slot, value) in trie_leaves(database, account.storage_root)? {
+                let value: U256 = UntrustedRlp::new(&value).as_val()?;
+                match indexes.get(&slot) {
+                    Some(index) => { storage.insert(*index, M256::from(value)); },
+                    None => { unknown_storage.insert(slot, M256::from(value)); },
+                }
+            }
+        }
+
+        let dumped = DumpedAccount { account, code, storage, unknown_storage };
+        match address {
+            Some(address) => { dump.accounts.insert(address, dumped); },
+            None => { dump.unknown_accounts.insert(key, dumped); },
+        }
+    }
+    Ok((dump, next))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use trie::FixedSecureTrie;
+
+    #[test]
+    fn leaves_are_listed_in_key_order() {
+        let database = MemoryDatabase::default();
+        let mut trie: FixedSecureTrie<_, U256, Vec<u8>> = database.create_fixed_secure_trie(database.create_empty().root());
+        for i in 0..50u64 {
+            trie.insert(U256::from(i), vec![i as u8; 40]);
+        }
+
+        let leaves = trie_leaves(&database, trie.root()).unwrap();
+        assert_eq!(leaves.len(), 50);
+        assert!(leaves.windows(2).all(|pair| pair[0].0 < pair[1].0));
+        assert!(trie_leaves(&database, database.create_empty().root()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn ranges_page_through_leaves() {
+        let database = MemoryDatabase::default();
+        let mut trie: FixedSecureTrie<_, U256, Vec<u8>> = database.create_fixed_secure_trie(database.create_empty().root());
+        for i in 0..50u64 {
+            trie.insert(U256::from(i), vec![i as u8; 40]);
+        }
+        let leaves = trie_leaves(&database, trie.root()).unwrap();
+
+        let mut paged = Vec::new();
+        let mut start = Vec::new();
+        loop {
+            let (page, next) = trie_range(&database, trie.root(), &start, 7).unwrap();
+            assert!(page.len() <= 7);
+            paged.extend(page);
+            match next {
+                Some(next) => start = next.as_ref().to_vec(),
+                None => break,
+            }
+        }
+        assert_eq!(paged, leaves);
+
+        let prefix = leaves[10].0.as_ref()[0];
+        let (page, _) = trie_range(&database, trie.root(), &[prefix], 3).unwrap();
+        assert!(page[0].0 <= leaves[10].0 && page[0].0.as_ref()[0] == prefix);
+    }
+}
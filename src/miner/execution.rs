@@ -0,0 +1,133 @@
+use bigint::{Address, Gas, U256};
+use block::{Account, Transaction, TransactionAction};
+use sputnikvm::{AccountChange, AccountCommitment, Patch, UntrustedTransaction, ValidTransaction};
+use sputnikvm_stateful::MemoryStateful;
+use std::collections::HashSet;
+use std::rc::Rc;
+
+use error::{Error, MinerError};
+
+/// Gas a transaction costs before any of its code runs: the base cost
+/// of a call or a creation, plus the cost of its input data.
+pub fn intrinsic_gas<P: Patch>(transaction: &Transaction) -> Gas {
+    let base = match transaction.action {
+        TransactionAction::Create => P::gas_transaction_create(),
+        TransactionAction::Call(_) => Gas::from(21000u64),
+    };
+    let zero = transaction.input.iter().filter(|byte| **byte == 0).count();
+    let non_zero = transaction.input.len() - zero;
+    base + Gas::from(zero as u64 * 4) + Gas::from(non_zero as u64 * 68)
+}
+
+/// Check the gas limit and cost of a transaction against the balance
+/// of its sender, before the VM checks the rest.
+fn check_affordable<P: Patch>(transaction: &Transaction, balance: U256) -> Result<(), Error> {
+    let intrinsic = intrinsic_gas::<P>(transaction);
+    if transaction.gas_limit < intrinsic {
+        return Err(MinerError::IntrinsicGasTooLow(transaction.gas_limit, intrinsic).into());
+    }
+
+    let (fee, fee_overflow) = U256::from(transaction.gas_limit).overflowing_mul(U256::from(transaction.gas_price));
+    let (cost, cost_overflow) = fee.overflowing_add(transaction.value);
+    if fee_overflow || cost_overflow {
+        return Err(MinerError::InsufficientFunds(balance, U256::max_value()).into());
+    }
+    if cost > balance {
+        return Err(MinerError::InsufficientFunds(balance, cost).into());
+    }
+    Ok(())
+}
+
+/// Check a signed transaction against `stateful` as
+/// `MemoryStateful::to_valid` does, with every failure reported as an
+/// error instead of a panic. Only the account of the sender is read,
+/// so validation never requires a block hash; those the execution
+/// requires are resolved by `fork::fulfill`.
+pub fn to_valid<P: Patch>(stateful: &MemoryStateful, transaction: &Transaction) -> Result<ValidTransaction, Error> {
+    let caller = transaction.caller().map_err(|_| MinerError::InvalidSignature)?;
+
+    let account: Option<Account> = stateful.state_of(stateful.root()).get(&caller);
+    let commitment = match account {
+        Some(account) => {
+            if transaction.nonce != account.nonce {
+                return Err(MinerError::InvalidNonce(transaction.nonce, account.nonce).into());
+            }
+            check_affordable::<P>(transaction, account.balance)?;
+            let code = stateful.code(account.code_hash).ok_or(MinerError::MissingCode(account.code_hash))?;
+            AccountCommitment::Full {
+                address: caller,
+                nonce: account.nonce,
+                balance: account.balance,
+                code: Rc::new(code),
+            }
+        },
+        None => {
+            if transaction.nonce != U256::zero() {
+                return Err(MinerError::InvalidNonce(transaction.nonce, U256::zero()).into());
+            }
+            check_affordable::<P>(transaction, U256::zero())?;
+            AccountCommitment::Nonexist(caller)
+        },
+    };
+
+    let untrusted = UntrustedTransaction {
+        caller: commitment,
+        gas_price: transaction.gas_price,
+        gas_limit: transaction.gas_limit,
+        action: transaction.action.clone(),
+        value: transaction.value,
+        input: Rc::new(transaction.input.clone()),
+    };
+    untrusted.to_valid::<P>().map_err(|err| MinerError::PreExecution(format!("{:?}", err)).into())
+}
+
+/// Apply account changes to `stateful` as `MemoryStateful::transit`
+/// does, failing instead of panicking when a fully changed account is
+/// missing from the state. Nothing is applied on failure.
+pub fn transit(stateful: &mut MemoryStateful<'static>, accounts: &[AccountChange]) -> Result<(), Error> {
+    {
+        let trie = stateful.state_of(stateful.root());
+        let mut created: HashSet<Address> = HashSet::new();
+        for account in accounts {
+            match *account {
+                AccountChange::Create { address, .. } => { created.insert(address); },
+                AccountChange::Full { address, .. } if !created.contains(&address) => {
+                    let existing: Option<Account> = trie.get(&address);
+                    if existing.is_none() {
+                        return Err(MinerError::MissingAccount(address).into());
+                    }
+                },
+                _ => (),
+            }
+        }
+    }
+
+    stateful.transit(accounts);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use block::UnsignedTransaction;
+    use secp256k1::SECP256K1;
+    use secp256k1::key::SecretKey;
+    use sputnikvm_network_classic::MainnetEIP160Patch;
+    use super::super::sign_transaction;
+
+    #[test]
+    fn intrinsic_gas_counts_data_bytes() {
+        let secret_key = SecretKey::from_slice(&SECP256K1, &[1u8; 32]).unwrap();
+        let transaction = sign_transaction(UnsignedTransaction {
+            nonce: U256::zero(),
+            gas_price: Gas::zero(),
+            gas_limit: Gas::from(21000u64),
+            action: TransactionAction::Call(Address::default()),
+            value: U256::zero(),
+            input: vec![0, 0, 1],
+        }, &secret_key, None);
+
+        assert_eq!(intrinsic_gas::<MainnetEIP160Patch>(&transaction), Gas::from(21000u64 + 4 + 4 + 68));
+        assert!(check_affordable::<MainnetEIP160Patch>(&transaction, U256::zero()).is_err());
+    }
+}
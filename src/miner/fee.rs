@@ -0,0 +1,117 @@
+use bigint::{U256, Gas, Address};
+use block::Account;
+use sputnikvm::{AccountChange, Storage};
+use sputnikvm_stateful::MemoryStateful;
+use std::rc::Rc;
+
+/// Fees paid by one executed transaction.
+pub struct FeeContext {
+    pub caller: Address,
+    pub gas_price: Gas,
+    pub used_gas: Gas,
+}
+
+impl FeeContext {
+    pub fn fee(&self) -> U256 {
+        U256::from(self.used_gas * self.gas_price)
+    }
+}
+
+/// Hook run on the account changes of every transaction before they
+/// are committed, so that fee debits and credits can be redirected.
+/// The changes already include the VM's own fee accounting.
+pub trait FeePolicy {
+    fn apply(&self, stateful: &MemoryStateful, fee: &FeeContext, accounts: &mut Vec<AccountChange>);
+
+    /// Account paying fees in place of the senders, if any.
+    fn sponsor(&self) -> Option<Address> { None }
+}
+
+/// Leave fees as charged by the VM.
+pub struct StandardFeePolicy;
+
+impl FeePolicy for StandardFeePolicy {
+    fn apply(&self, _stateful: &MemoryStateful, _fee: &FeeContext, _accounts: &mut Vec<AccountChange>) { }
+}
+
+/// Refund the sender and charge the fee to a sponsor account instead,
+/// as long as the sponsor can afford it.
+pub struct SponsorFeePolicy {
+    pub sponsor: Address,
+}
+
+impl FeePolicy for SponsorFeePolicy {
+    fn apply(&self, stateful: &MemoryStateful, fee: &FeeContext, accounts: &mut Vec<AccountChange>) {
+        let amount = fee.fee();
+        if amount.is_zero() || fee.caller == self.sponsor {
+            return;
+        }
+
+        let sponsor_balance = match balance_of(accounts, self.sponsor) {
+            Some(balance) => balance,
+            None => {
+                let account: Option<Account> = stateful.state_of(stateful.root()).get(&self.sponsor);
+                account.map(|account| account.balance).unwrap_or(U256::zero())
+            },
+        };
+        if sponsor_balance < amount {
+            return;
+        }
+
+        credit(accounts, fee.caller, amount);
+        debit(stateful, accounts, self.sponsor, amount);
+    }
+
+    fn sponsor(&self) -> Option<Address> { Some(self.sponsor) }
+}
+
+fn balance_of(accounts: &[AccountChange], address: Address) -> Option<U256> {
+    for account in accounts {
+        match account {
+            &AccountChange::Full { address: a, balance, .. } |
+            &AccountChange::Create { address: a, balance, .. } if a == address => return Some(balance),
+            _ => (),
+        }
+    }
+    None
+}
+
+pub fn credit(accounts: &mut Vec<AccountChange>, address: Address, amount: U256) {
+    for account in accounts.iter_mut() {
+        match account {
+            &mut AccountChange::Full { address: a, ref mut balance, .. } |
+            &mut AccountChange::Create { address: a, ref mut balance, .. } if a == address => {
+                *balance = *balance + amount;
+                return;
+            },
+            _ => (),
+        }
+    }
+    accounts.push(AccountChange::IncreaseBalance(address, amount));
+}
+
+/// There is no change decreasing a balance alone, so an account not
+/// touched by the transaction is rewritten in full from the state.
+pub fn debit(stateful: &MemoryStateful, accounts: &mut Vec<AccountChange>, address: Address, amount: U256) {
+    for account in accounts.iter_mut() {
+        match account {
+            &mut AccountChange::Full { address: a, ref mut balance, .. } |
+            &mut AccountChange::Create { address: a, ref mut balance, .. } if a == address => {
+                *balance = *balance - amount;
+                return;
+            },
+            _ => (),
+        }
+    }
+    let account: Account = match stateful.state_of(stateful.root()).get(&address) {
+        Some(account) => account,
+        None => return,
+    };
+    accounts.push(AccountChange::Full {
+        nonce: account.nonce,
+        address,
+        balance: account.balance - amount,
+        changing_storage: Storage::new(address, false),
+        code: Rc::new(stateful.code(account.code_hash).unwrap_or(Vec::new())),
+    });
+}
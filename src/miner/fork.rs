@@ -0,0 +1,369 @@
+use bigint::{H256, M256, U256, Address};
+use block::Account;
+use hexutil::read_hex;
+use serde_json::Value;
+use sputnikvm::{AccountChange, HeaderParams, Patch, SeqTransactionVM, ValidTransaction, VM, VMStatus};
+use sputnikvm::errors::RequireError;
+use sputnikvm_stateful::MemoryStateful;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use client::{self, HttpUrl};
+use error::Error;
+use super::state::{modify_account_at, set_account_code, set_account_storage};
+use super::fork_cache::{ForkCache, ForkValues};
+use super::account_cache::AccountCache;
+
+/// Account as read from the forked node.
+#[derive(Clone, Debug)]
+pub struct ForkAccount {
+    pub balance: U256,
+    pub nonce: U256,
+    pub code: Vec<u8>,
+}
+
+/// Remote node whose state at `block` backs every account and storage
+/// slot the local chain does not know about. Remote values are fetched
+/// on first use and cached, on disk as well if a cache directory is
+/// given.
+pub struct Fork {
+    url: HttpUrl,
+    block: U256,
+    accounts: Mutex<HashMap<Address, Option<ForkAccount>>>,
+    storage: Mutex<HashMap<(Address, U256), M256>>,
+    /// Accounts created or deleted locally, which no longer derive from
+    /// the remote state.
+    detached: Mutex<HashSet<Address>>,
+    /// Storage slots written locally.
+    written: Mutex<HashSet<(Address, U256)>>,
+    cache: Mutex<Option<ForkCache>>,
+}
+
+/// Hex quantity as expected by JSON-RPC, `0x0` for zero.
+pub fn quantity(value: U256) -> String {
+    if value.is_zero() {
+        "0x0".to_string()
+    } else {
+        format!("0x{:x}", value)
+    }
+}
+
+fn parse_quantity(value: Value) -> Result<U256, Error> {
+    match value.as_str() {
+        Some(s) => Ok(U256::from_str(s).map_err(|_| Error::RemoteError(format!("invalid quantity {}", s)))?),
+        None => Err(Error::RemoteError(format!("invalid quantity {}", value))),
+    }
+}
+
+impl Fork {
+    /// Fork from `block` of the node at `url`, or from its latest block
+    /// at the time of the call.
+    pub fn new(url: HttpUrl, block: Option<U256>, cache_dir: Option<&Path>) -> Result<Self, Error> {
+        let block = match block {
+            Some(block) => block,
+            None => parse_quantity(client::request_url(&url, None, "eth_blockNumber", Value::Array(Vec::new()))?)?,
+        };
+
+        let (cache, values) = match cache_dir {
+            Some(dir) => {
+                let (cache, values) = ForkCache::open(dir, &url, block)?;
+                (Some(cache), values)
+            },
+            None => (None, ForkValues::default()),
+        };
+
+        Ok(Fork {
+            url, block,
+            accounts: Mutex::new(values.accounts),
+            storage: Mutex::new(values.storage),
+            detached: Mutex::new(HashSet::new()),
+            written: Mutex::new(HashSet::new()),
+            cache: Mutex::new(cache),
+        })
+    }
+
+    /// Forget all fetched values, in memory and on disk. Accounts and
+    /// slots already copied into the local state are kept.
+    pub fn clear_cache(&self) -> Result<(), Error> {
+        self.accounts.lock().unwrap().clear();
+        self.storage.lock().unwrap().clear();
+        if let Some(ref mut cache) = *self.cache.lock().unwrap() {
+            cache.clear()?;
+        }
+        Ok(())
+    }
+
+    pub fn url(&self) -> &HttpUrl {
+        &self.url
+    }
+
+    pub fn block(&self) -> U256 {
+        self.block
+    }
+
+    fn request(&self, method: &str, mut params: Vec<Value>) -> Result<Value, Error> {
+        params.push(Value::String(quantity(self.block)));
+        client::request_url(&self.url, None, method, Value::Array(params))
+    }
+
+    fn fetch_account(&self, address: Address) -> Result<Option<ForkAccount>, Error> {
+        let params = vec![Value::String(format!("0x{:x}", address))];
+        let balance = parse_quantity(self.request("eth_getBalance", params.clone())?)?;
+        let nonce = parse_quantity(self.request("eth_getTransactionCount", params.clone())?)?;
+        let code = match self.request("eth_getCode", params)?.as_str() {
+            Some(code) => read_hex(code)?,
+            None => return Err(Error::RemoteError("invalid code".to_string())),
+        };
+
+        if balance.is_zero() && nonce.is_zero() && code.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(ForkAccount { balance, nonce, code }))
+        }
+    }
+
+    fn fetch_storage(&self, address: Address, index: U256) -> Result<M256, Error> {
+        let params = vec![Value::String(format!("0x{:x}", address)),
+                          Value::String(quantity(index))];
+        Ok(M256::from(parse_quantity(self.request("eth_getStorageAt", params)?)?))
+    }
+
+    /// Remote account, unless it was created or deleted locally. A
+    /// remote node that cannot be reached is an error, rather than an
+    /// account it does not have.
+    pub fn account(&self, address: Address) -> Result<Option<ForkAccount>, Error> {
+        if self.detached.lock().unwrap().contains(&address) {
+            return Ok(None);
+        }
+        if let Some(account) = self.accounts.lock().unwrap().get(&address) {
+            return Ok(account.clone());
+        }
+
+        let account = self.fetch_account(address)?;
+        if let Some(ref mut cache) = *self.cache.lock().unwrap() {
+            if let Err(err) = cache.append_account(address, &account) {
+                warn!("fork: cannot cache account 0x{:x}: {:?}", address, err);
+            }
+        }
+        self.accounts.lock().unwrap().insert(address, account.clone());
+        Ok(account)
+    }
+
+    /// Remote storage value, unless the slot was written locally.
+    pub fn storage(&self, address: Address, index: U256) -> Result<Option<M256>, Error> {
+        if self.detached.lock().unwrap().contains(&address) ||
+            self.written.lock().unwrap().contains(&(address, index))
+        {
+            return Ok(None);
+        }
+        if let Some(value) = self.storage.lock().unwrap().get(&(address, index)) {
+            return Ok(Some(*value));
+        }
+
+        let value = self.fetch_storage(address, index)?;
+        if let Some(ref mut cache) = *self.cache.lock().unwrap() {
+            if let Err(err) = cache.append_storage(address, index, value) {
+                warn!("fork: cannot cache storage 0x{:x} of 0x{:x}: {:?}", index, address, err);
+            }
+        }
+        self.storage.lock().unwrap().insert((address, index), value);
+        Ok(Some(value))
+    }
+
+    /// Keep track of local changes committed to the head state, so that
+    /// they are not shadowed by remote values afterwards.
+    pub fn record_changes(&self, accounts: &[AccountChange]) {
+        for account in accounts {
+            match account {
+                &AccountChange::Full { address, ref changing_storage, .. } => {
+                    let changing_storage: HashMap<U256, M256> = changing_storage.clone().into();
+                    let mut written = self.written.lock().unwrap();
+                    for index in changing_storage.keys() {
+                        written.insert((address, *index));
+                    }
+                },
+                &AccountChange::Create { address, .. } |
+                &AccountChange::Nonexist(address) => {
+                    self.detached.lock().unwrap().insert(address);
+                },
+                &AccountChange::IncreaseBalance(_, _) => (),
+            }
+        }
+    }
+
+    /// Forget local changes, when the local chain is reset.
+    pub fn clear_changes(&self) {
+        self.detached.lock().unwrap().clear();
+        self.written.lock().unwrap().clear();
+    }
+
+    /// Copy a remote account into the local state if it is not there.
+    pub fn materialize_account(&self, stateful: &mut MemoryStateful<'static>, address: Address) -> Result<(), Error> {
+        let local: Option<Account> = stateful.state_of(stateful.root()).get(&address);
+        if local.is_some() {
+            return Ok(());
+        }
+
+        if let Some(remote) = self.account(address)? {
+            let database = stateful.database();
+            let root = modify_account_at(database, stateful.root(), address, |account| {
+                account.balance = remote.balance;
+                account.nonce = remote.nonce;
+                set_account_code(database, account, remote.code);
+            });
+            *stateful = MemoryStateful::new(database, root);
+        }
+        Ok(())
+    }
+
+    /// Copy a remote storage slot into the local state if it is not
+    /// there.
+    pub fn materialize_storage(
+        &self, stateful: &mut MemoryStateful<'static>, address: Address, index: U256
+    ) -> Result<(), Error> {
+        let local: Option<Account> = stateful.state_of(stateful.root()).get(&address);
+        let local = match local {
+            Some(local) => local,
+            None => return Ok(()),
+        };
+        let value: Option<M256> = stateful.storage_state_of(local.storage_root).get(&H256::from(index));
+        if value.is_some() {
+            return Ok(());
+        }
+
+        match self.storage(address, index)? {
+            Some(value) if value != M256::zero() => {
+                let database = stateful.database();
+                let root = modify_account_at(database, stateful.root(), address, |account| {
+                    set_account_storage(database, account, index, value);
+                });
+                *stateful = MemoryStateful::new(database, root);
+            },
+            _ => (),
+        }
+        Ok(())
+    }
+}
+
+/// Execute a transaction on `stateful`. With a fork, accounts and
+/// storage the VM requires but the local state lacks are first copied
+/// from the remote node, then committed to the VM as usual. Fails if
+/// the remote node cannot be reached.
+pub fn call<P: Patch>(
+    fork: Option<&Fork>, cache: &AccountCache, stateful: &mut MemoryStateful<'static>, valid: ValidTransaction,
+    header: &HeaderParams, last_hashes: &[H256]
+) -> Result<SeqTransactionVM<P>, Error> {
+    let mut vm = SeqTransactionVM::<P>::new(valid, header.clone());
+    loop {
+        match vm.status() {
+            VMStatus::Running => (),
+            _ => break,
+        }
+
+        match vm.step() {
+            Ok(()) => (),
+            Err(require) => fulfill(fork, cache, stateful, &mut vm, require, header, last_hashes)?,
+        }
+    }
+    Ok(vm)
+}
+
+/// Hash BLOCKHASH returns for block `number` in the block `current`,
+/// from the hashes of the blocks before it, most recent first. Blocks
+/// the hashes do not reach, such as those before the block a fork
+/// starts from, hash to zero as blocks more than 256 back do.
+pub fn blockhash(current: U256, number: U256, last_hashes: &[H256]) -> H256 {
+    if number >= current {
+        return H256::default();
+    }
+    let back = current - number - U256::one();
+    if back >= U256::from(last_hashes.len()) {
+        H256::default()
+    } else {
+        last_hashes[back.as_usize()]
+    }
+}
+
+/// Commit what the VM requires from `stateful`, copying it from the
+/// forked node first if needed. Accounts and code go through `cache`.
+/// Block hashes are resolved from `last_hashes` rather than by
+/// `stateful`, which panics on hashes it does not have. Only fails
+/// with a fork, if the remote node cannot be reached.
+pub fn fulfill<P: Patch>(
+    fork: Option<&Fork>, cache: &AccountCache, stateful: &mut MemoryStateful<'static>,
+    vm: &mut SeqTransactionVM<P>, require: RequireError, header: &HeaderParams, last_hashes: &[H256]
+) -> Result<(), Error> {
+    if let RequireError::Blockhash(number) = require {
+        if vm.commit_blockhash(number, blockhash(header.number, number, last_hashes)).is_err() {
+            warn!("cannot commit the hash of block 0x{:x}", number);
+        }
+        return Ok(());
+    }
+    if let Some(fork) = fork {
+        match require {
+            RequireError::Account(address) |
+            RequireError::AccountCode(address) => fork.materialize_account(stateful, address)?,
+            RequireError::AccountStorage(address, index) => fork.materialize_storage(stateful, address, index)?,
+            RequireError::Blockhash(_) => (),
+        }
+    }
+    if cache.fulfill(stateful, vm, &require) {
+        return Ok(());
+    }
+    stateful.step(vm, header.number, last_hashes);
+    Ok(())
+}
+
+/// Parse `<url>[@<block>]`, where the url is a plain HTTP endpoint of a
+/// JSON-RPC node, see `HttpUrl::parse`, and the block a decimal or hex
+/// number.
+pub fn parse_fork(value: &str) -> Result<(HttpUrl, Option<U256>), Error> {
+    let (url, block) = match value.rfind('@') {
+        Some(index) => (&value[..index], Some(&value[(index + 1)..])),
+        None => (value, None),
+    };
+
+    let url = HttpUrl::parse(url)?;
+
+    let block = match block {
+        Some(block) if block.starts_with("0x") => Some(U256::from_str(block).map_err(|_| Error::IntError)?),
+        Some(block) => Some(U256::from_dec_str(block).map_err(|_| Error::IntError)?),
+        None => None,
+    };
+
+    Ok((url, block))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blockhash_from_last_hashes() {
+        let last_hashes = vec![H256::from(U256::from(9u64)), H256::from(U256::from(8u64))];
+        assert_eq!(blockhash(U256::from(10u64), U256::from(9u64), &last_hashes), last_hashes[0]);
+        assert_eq!(blockhash(U256::from(10u64), U256::from(8u64), &last_hashes), last_hashes[1]);
+        assert_eq!(blockhash(U256::from(10u64), U256::from(7u64), &last_hashes), H256::default());
+        assert_eq!(blockhash(U256::from(10u64), U256::from(10u64), &last_hashes), H256::default());
+    }
+
+    #[test]
+    fn fork_url_with_block() {
+        let (url, block) = parse_fork("http://127.0.0.1:8545/@0x10").unwrap();
+        assert_eq!(url.addr, "127.0.0.1:8545".parse().unwrap());
+        assert_eq!(url.path, "/");
+        assert_eq!(block, Some(U256::from(16u64)));
+
+        let (url, block) = parse_fork("http://127.0.0.1:8545/rpc@0x10").unwrap();
+        assert_eq!(url.path, "/rpc");
+        assert_eq!(block, Some(U256::from(16u64)));
+
+        let (_, block) = parse_fork("127.0.0.1:8545@100").unwrap();
+        assert_eq!(block, Some(U256::from(100u64)));
+
+        let (_, block) = parse_fork("127.0.0.1:8545").unwrap();
+        assert_eq!(block, None);
+    }
+}
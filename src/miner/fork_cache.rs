@@ -0,0 +1,196 @@
+use bigint::{M256, U256, Address};
+use hexutil::{read_hex, to_hex};
+use serde_json;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use client::HttpUrl;
+use error::Error;
+use super::fork::{ForkAccount, quantity};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+struct CachedAccount {
+    balance: String,
+    nonce: String,
+    code: String,
+}
+
+/// One value fetched from the forked node. Accounts the node does not
+/// have are cached as `None` too.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "camelCase")]
+enum CacheEntry {
+    Account { address: String, account: Option<CachedAccount> },
+    Storage { address: String, index: String, value: String },
+}
+
+/// Add the value of a cache entry to `values`, unless one of its
+/// fields does not parse.
+fn read_entry(values: &mut ForkValues, entry: CacheEntry) -> Result<(), Error> {
+    match entry {
+        CacheEntry::Account { address, account } => {
+            let account = match account {
+                Some(account) => Some(ForkAccount {
+                    balance: U256::from_str(&account.balance)?,
+                    nonce: U256::from_str(&account.nonce)?,
+                    code: read_hex(&account.code)?,
+                }),
+                None => None,
+            };
+            values.accounts.insert(Address::from_str(&address).map_err(|_| Error::HexError)?, account);
+        },
+        CacheEntry::Storage { address, index, value } => {
+            let key = (Address::from_str(&address).map_err(|_| Error::HexError)?, U256::from_str(&index)?);
+            values.storage.insert(key, M256::from(U256::from_str(&value)?));
+        },
+    }
+    Ok(())
+}
+
+/// Values fetched from the forked node, in memory and on disk.
+#[derive(Default)]
+pub struct ForkValues {
+    pub accounts: HashMap<Address, Option<ForkAccount>>,
+    pub storage: HashMap<(Address, U256), M256>,
+}
+
+/// Append-only file of the values fetched from one node at one block,
+/// one JSON entry per line, so that later runs forking the same block
+/// do not fetch them again.
+pub struct ForkCache {
+    path: PathBuf,
+    file: File,
+}
+
+impl ForkCache {
+    /// Endpoints on the same host and port, told apart by their path,
+    /// get different files.
+    pub fn path(dir: &Path, url: &HttpUrl, block: U256) -> PathBuf {
+        let path: String = url.path.trim_matches('/').chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect();
+        if path.is_empty() {
+            dir.join(format!("{}-{}-{}.jsonl", url.addr.ip(), url.addr.port(), quantity(block)))
+        } else {
+            dir.join(format!("{}-{}-{}-{}.jsonl", url.addr.ip(), url.addr.port(), path, quantity(block)))
+        }
+    }
+
+    /// Open the cache in `dir`, returning the values it already holds.
+    pub fn open(dir: &Path, url: &HttpUrl, block: U256) -> Result<(ForkCache, ForkValues), Error> {
+        fs::create_dir_all(dir)?;
+        let path = Self::path(dir, url, block);
+
+        let mut values = ForkValues::default();
+        if path.exists() {
+            for line in BufReader::new(File::open(&path)?).lines() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                // A line cut short by an interrupted run, or otherwise
+                // unreadable, is only refetched.
+                let entry: CacheEntry = match serde_json::from_str(&line) {
+                    Ok(entry) => entry,
+                    Err(_) => continue,
+                };
+                if read_entry(&mut values, entry).is_err() {
+                    continue;
+                }
+            }
+        }
+
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        Ok((ForkCache { path, file }, values))
+    }
+
+    fn append(&mut self, entry: &CacheEntry) -> Result<(), Error> {
+        let mut line = serde_json::to_string(entry).unwrap();
+        line.push('\n');
+        self.file.write_all(line.as_bytes())?;
+        Ok(())
+    }
+
+    pub fn append_account(&mut self, address: Address, account: &Option<ForkAccount>) -> Result<(), Error> {
+        self.append(&CacheEntry::Account {
+            address: format!("0x{:x}", address),
+            account: account.as_ref().map(|account| CachedAccount {
+                balance: quantity(account.balance),
+                nonce: quantity(account.nonce),
+                code: to_hex(&account.code),
+            }),
+        })
+    }
+
+    pub fn append_storage(&mut self, address: Address, index: U256, value: M256) -> Result<(), Error> {
+        self.append(&CacheEntry::Storage {
+            address: format!("0x{:x}", address),
+            index: quantity(index),
+            value: quantity(U256::from(value)),
+        })
+    }
+
+    /// Drop everything cached so far.
+    pub fn clear(&mut self) -> Result<(), Error> {
+        self.file = OpenOptions::new().create(true).write(true).truncate(true).open(&self.path)?;
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn values_are_read_back() {
+        let dir = env::temp_dir().join("svmdev-fork-cache-test");
+        let url = HttpUrl::parse("http://127.0.0.1:8545/").unwrap();
+        let block = U256::from(16u64);
+        let _ = fs::remove_file(ForkCache::path(&dir, &url, block));
+
+        {
+            let (mut cache, values) = ForkCache::open(&dir, &url, block).unwrap();
+            assert!(values.accounts.is_empty() && values.storage.is_empty());
+            cache.append_account(Address::from(1u64), &Some(ForkAccount {
+                balance: U256::from(7u64),
+                nonce: U256::one(),
+                code: vec![0x60, 0x00],
+            })).unwrap();
+            cache.append_account(Address::from(2u64), &None).unwrap();
+            cache.append_storage(Address::from(1u64), U256::one(), M256::from(3u64)).unwrap();
+        }
+
+        let (_, values) = ForkCache::open(&dir, &url, block).unwrap();
+        let account = values.accounts[&Address::from(1u64)].clone().unwrap();
+        assert_eq!(account.balance, U256::from(7u64));
+        assert_eq!(account.nonce, U256::one());
+        assert_eq!(account.code, vec![0x60, 0x00]);
+        assert!(values.accounts[&Address::from(2u64)].is_none());
+        assert_eq!(values.storage[&(Address::from(1u64), U256::one())], M256::from(3u64));
+    }
+
+    #[test]
+    fn unreadable_lines_are_skipped() {
+        let dir = env::temp_dir().join("svmdev-fork-cache-bad-test");
+        let url = HttpUrl::parse("http://127.0.0.1:8545/").unwrap();
+        let block = U256::from(16u64);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(ForkCache::path(&dir, &url, block), concat!(
+            r#"{"type":"account","address":"0xzz","account":null}"#, "\n",
+            r#"{"type":"storage","address":"0x0000000000000000000000000000000000000001","index":"0x1","value":"0xnothex"}"#, "\n",
+            r#"{"type":"storage","address":"0x0000000000000000000000000000000000000001","index":"0x2","value":"0x5"}"#, "\n",
+            r#"{"type":"account","address":"0x00000000000"#,
+        )).unwrap();
+
+        let (_, values) = ForkCache::open(&dir, &url, block).unwrap();
+        assert!(values.accounts.is_empty());
+        assert_eq!(values.storage.len(), 1);
+        assert_eq!(values.storage[&(Address::from(1u64), U256::from(2u64))], M256::from(5u64));
+    }
+}
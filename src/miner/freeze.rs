@@ -0,0 +1,149 @@
+use bigint::H256;
+use block::{Block, Receipt};
+use memmap::Mmap;
+use rlp::{self, UntrustedRlp};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use error::Error;
+use super::MinerState;
+
+static SEGMENTS: AtomicUsize = AtomicUsize::new(0);
+
+/// Move blocks at least `depth` blocks below the head, with their
+/// receipts, into the frozen segment. Frozen blocks can no longer be
+/// discarded by `set_head`.
+#[derive(Clone, Debug)]
+pub struct FreezeConfig {
+    pub depth: usize,
+    /// Write frozen chunks to files in this directory and map them,
+    /// instead of keeping them in memory.
+    pub dir: Option<PathBuf>,
+}
+
+enum Chunk {
+    Memory(Vec<u8>),
+    Mapped(Mmap),
+}
+
+impl Chunk {
+    fn bytes(&self) -> &[u8] {
+        match *self {
+            Chunk::Memory(ref data) => data,
+            Chunk::Mapped(ref map) => &map[..],
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Location {
+    chunk: usize,
+    offset: usize,
+    len: usize,
+}
+
+/// Immutable store of old blocks and receipts. Each freeze appends one
+/// chunk holding the RLP of its blocks and receipts back to back, which
+/// is never modified afterwards.
+pub struct FrozenSegment {
+    /// Part of the file names of the chunks telling apart the segments
+    /// of nodes sharing a directory, even on the same genesis.
+    owner: String,
+    chunks: Vec<Chunk>,
+    blocks: HashMap<H256, Location>,
+    /// Receipts by transaction hash, with the number of their block.
+    receipts: HashMap<H256, (Location, usize)>,
+    frozen_before: usize,
+}
+
+impl FrozenSegment {
+    pub fn new() -> Self {
+        FrozenSegment {
+            owner: format!("{}-{}", process::id(), SEGMENTS.fetch_add(1, Ordering::SeqCst)),
+            chunks: Vec::new(),
+            blocks: HashMap::new(),
+            receipts: HashMap::new(),
+            frozen_before: 0,
+        }
+    }
+
+    /// First block that is not frozen.
+    pub fn frozen_before(&self) -> usize {
+        self.frozen_before
+    }
+
+    fn data(&self, location: Location) -> &[u8] {
+        &self.chunks[location.chunk].bytes()[location.offset..(location.offset + location.len)]
+    }
+
+    pub fn block(&self, hash: H256) -> Option<Block> {
+        self.blocks.get(&hash).and_then(|&location| {
+            UntrustedRlp::new(self.data(location)).as_val().ok()
+        })
+    }
+
+    pub fn receipt(&self, transaction_hash: H256) -> Option<(usize, Receipt)> {
+        self.receipts.get(&transaction_hash).and_then(|&(location, number)| {
+            UntrustedRlp::new(self.data(location)).as_val().ok().map(|receipt| (number, receipt))
+        })
+    }
+
+    /// Append the blocks from `frozen_before` up to `until`, and the
+    /// receipts of their transactions, as one chunk.
+    pub fn append(
+        &mut self, until: usize, blocks: &[(H256, Block)], receipts: &[(H256, usize, Receipt)],
+        dir: Option<&Path>, name: &str,
+    ) -> Result<(), Error> {
+        let chunk = self.chunks.len();
+        let mut data = Vec::new();
+
+        let mut block_locations = Vec::new();
+        for &(hash, ref block) in blocks {
+            let encoded = rlp::encode(block);
+            block_locations.push((hash, Location { chunk, offset: data.len(), len: encoded.len() }));
+            data.extend_from_slice(&encoded);
+        }
+        let mut receipt_locations = Vec::new();
+        for &(hash, number, ref receipt) in receipts {
+            let encoded = rlp::encode(receipt);
+            receipt_locations.push((hash, (Location { chunk, offset: data.len(), len: encoded.len() }, number)));
+            data.extend_from_slice(&encoded);
+        }
+
+        let chunk = match dir {
+            Some(dir) => {
+                let path = dir.join(format!("{}-{}-{}-{}.frozen", name, self.owner, self.frozen_before, until));
+                File::create(&path)?.write_all(&data)?;
+                Chunk::Mapped(unsafe { Mmap::map(&File::open(&path)?)? })
+            },
+            None => Chunk::Memory(data),
+        };
+
+        self.chunks.push(chunk);
+        self.blocks.extend(block_locations);
+        self.receipts.extend(receipt_locations);
+        self.frozen_before = until;
+
+        Ok(())
+    }
+}
+
+/// Freeze blocks that fell below the configured depth after a block
+/// was sealed.
+pub fn freeze_blocks(state: &mut MinerState) -> Result<(), Error> {
+    let config = match state.freeze_config() {
+        Some(config) => config.clone(),
+        None => return Ok(()),
+    };
+
+    let until = (state.block_height() + 1).saturating_sub(config.depth);
+    if state.frozen_before() >= until {
+        return Ok(());
+    }
+
+    state.freeze_until(until, config.dir.as_ref().map(|dir| dir.as_path()))
+}
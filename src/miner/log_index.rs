@@ -0,0 +1,238 @@
+use bigint::{Address, H256};
+use block::Log;
+use serde_json;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::str::FromStr;
+
+use error::Error;
+
+/// One change to the index. Every appended block is recorded, with or
+/// without logs, so that a chain appended again on top of the same
+/// genesis can be told apart from the one indexed before.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "camelCase")]
+enum IndexEntry {
+    Block { number: usize, hash: String, addresses: Vec<String>, topics: Vec<String> },
+    Rewind { number: usize },
+}
+
+/// File next to an index while a node has it open, so that nodes
+/// sharing a data directory do not append to the index of the same
+/// genesis at once. It is removed once the index is dropped.
+struct IndexLock(PathBuf);
+
+impl IndexLock {
+    fn acquire(index: &Path) -> Result<IndexLock, Error> {
+        let path = index.with_extension("lock");
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(mut file) => {
+                let lock = IndexLock(path);
+                writeln!(file, "{}", process::id())?;
+                Ok(lock)
+            },
+            Err(ref err) if err.kind() == io::ErrorKind::AlreadyExists =>
+                Err(Error::Locked(path.display().to_string())),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+impl Drop for IndexLock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+/// Block numbers of the canonical blocks holding logs of each address
+/// and with each topic, at any position. Kept in memory and in an
+/// append-only file of the data directory, one JSON entry per line, so
+/// that the index of a chain restored from an archive is not built
+/// again. Numbers of blocks that may have been replaced since are
+/// dropped as soon as another block is appended at their height.
+pub struct LogIndex {
+    dir: PathBuf,
+    _lock: IndexLock,
+    file: File,
+    blocks: BTreeMap<usize, H256>,
+    addresses: HashMap<Address, BTreeSet<usize>>,
+    topics: HashMap<H256, BTreeSet<usize>>,
+}
+
+impl LogIndex {
+    pub fn path(dir: &Path, genesis: H256) -> PathBuf {
+        dir.join(format!("{:x}.jsonl", genesis))
+    }
+
+    /// Open the index in `dir` of the chain starting at `genesis`,
+    /// loading what it already holds. Fails if another node has it
+    /// open.
+    pub fn open(dir: &Path, genesis: H256) -> Result<LogIndex, Error> {
+        fs::create_dir_all(dir)?;
+        let path = Self::path(dir, genesis);
+        let lock = IndexLock::acquire(&path)?;
+
+        let mut entries = Vec::new();
+        if path.exists() {
+            for line in BufReader::new(File::open(&path)?).lines() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                // A line cut short by an interrupted run is dropped with
+                // the block it records, which is indexed again.
+                match serde_json::from_str(&line) {
+                    Ok(entry) => entries.push(entry),
+                    Err(_) => continue,
+                }
+            }
+        }
+
+        let mut index = LogIndex {
+            dir: dir.to_path_buf(),
+            _lock: lock,
+            file: OpenOptions::new().create(true).append(true).open(&path)?,
+            blocks: BTreeMap::new(),
+            addresses: HashMap::new(),
+            topics: HashMap::new(),
+        };
+        for entry in entries {
+            index.apply(entry)?;
+        }
+        Ok(index)
+    }
+
+    /// Directory the index is kept in.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn apply(&mut self, entry: IndexEntry) -> Result<(), Error> {
+        match entry {
+            IndexEntry::Block { number, hash, addresses, topics } => {
+                self.blocks.insert(number, H256::from_str(&hash).map_err(|_| Error::HexError)?);
+                for address in addresses {
+                    let address = Address::from_str(&address).map_err(|_| Error::HexError)?;
+                    self.addresses.entry(address).or_insert(BTreeSet::new()).insert(number);
+                }
+                for topic in topics {
+                    let topic = H256::from_str(&topic).map_err(|_| Error::HexError)?;
+                    self.topics.entry(topic).or_insert(BTreeSet::new()).insert(number);
+                }
+            },
+            IndexEntry::Rewind { number } => {
+                self.blocks.split_off(&(number + 1));
+                for numbers in self.addresses.values_mut().chain(self.topics.values_mut()) {
+                    numbers.split_off(&(number + 1));
+                }
+                self.addresses.retain(|_, numbers| !numbers.is_empty());
+                self.topics.retain(|_, numbers| !numbers.is_empty());
+            },
+        }
+        Ok(())
+    }
+
+    fn append(&mut self, entry: IndexEntry) -> Result<(), Error> {
+        let mut line = serde_json::to_string(&entry).unwrap();
+        line.push('\n');
+        self.apply(entry)?;
+        self.file.write_all(line.as_bytes())?;
+        Ok(())
+    }
+
+    /// Index the block `hash` appended at `number` with its logs. A
+    /// block already indexed at that height is kept if it is the same,
+    /// and dropped with the later ones otherwise.
+    pub fn append_block(&mut self, number: usize, hash: H256, logs: &[Log]) -> Result<(), Error> {
+        if self.blocks.get(&number) == Some(&hash) {
+            return Ok(());
+        }
+        if self.blocks.range(number..).next().is_some() {
+            self.rewind(number.saturating_sub(1))?;
+        }
+
+        let mut addresses = BTreeSet::new();
+        let mut topics = BTreeSet::new();
+        for log in logs {
+            addresses.insert(log.address);
+            topics.extend(log.topics.iter().cloned());
+        }
+        self.append(IndexEntry::Block {
+            number,
+            hash: format!("0x{:x}", hash),
+            addresses: addresses.into_iter().map(|address| format!("0x{:x}", address)).collect(),
+            topics: topics.into_iter().map(|topic| format!("0x{:x}", topic)).collect(),
+        })
+    }
+
+    /// Drop the blocks after `number`, which left the chain.
+    pub fn rewind(&mut self, number: usize) -> Result<(), Error> {
+        if self.blocks.range((number + 1)..).next().is_none() {
+            return Ok(());
+        }
+        self.append(IndexEntry::Rewind { number })
+    }
+
+    /// Blocks with logs of `address`.
+    pub fn address_blocks(&self, address: &Address) -> BTreeSet<usize> {
+        self.addresses.get(address).cloned().unwrap_or(BTreeSet::new())
+    }
+
+    /// Blocks with logs having `topic` at any position.
+    pub fn topic_blocks(&self, topic: &H256) -> BTreeSet<usize> {
+        self.topics.get(topic).cloned().unwrap_or(BTreeSet::new())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn log(address: u64, topic: u64) -> Log {
+        Log {
+            address: Address::from(address),
+            topics: vec![H256::from(topic)],
+            data: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn rewound_blocks_leave_the_index_on_disk() {
+        let dir = env::temp_dir().join("svmdev-log-index-test");
+        let genesis = H256::from(1u64);
+        let _ = fs::remove_file(LogIndex::path(&dir, genesis));
+
+        {
+            let mut index = LogIndex::open(&dir, genesis).unwrap();
+            index.append_block(1, H256::from(11u64), &[log(1, 2)]).unwrap();
+            index.append_block(2, H256::from(12u64), &[log(1, 3)]).unwrap();
+            index.append_block(2, H256::from(22u64), &[log(4, 3)]).unwrap();
+        }
+
+        let index = LogIndex::open(&dir, genesis).unwrap();
+        assert_eq!(index.address_blocks(&Address::from(1u64)), vec![1].into_iter().collect());
+        assert_eq!(index.address_blocks(&Address::from(4u64)), vec![2].into_iter().collect());
+        assert_eq!(index.topic_blocks(&H256::from(3u64)), vec![2].into_iter().collect());
+        assert!(index.topic_blocks(&H256::from(5u64)).is_empty());
+    }
+
+    #[test]
+    fn index_is_open_in_one_node_at_a_time() {
+        let dir = env::temp_dir().join("svmdev-log-index-lock-test");
+        let genesis = H256::from(2u64);
+
+        let index = LogIndex::open(&dir, genesis).unwrap();
+        match LogIndex::open(&dir, genesis) {
+            Err(Error::Locked(_)) => (),
+            _ => panic!("the index was opened twice"),
+        }
+        assert!(LogIndex::open(&dir, H256::from(3u64)).is_ok());
+
+        drop(index);
+        assert!(LogIndex::open(&dir, genesis).is_ok());
+    }
+}
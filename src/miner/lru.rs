@@ -0,0 +1,125 @@
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+
+/// Map holding at most `capacity` entries, evicting the least recently
+/// used one when full. A zero capacity holds nothing.
+pub struct LruCache<K, V> {
+    capacity: usize,
+    tick: u64,
+    entries: HashMap<K, (V, u64)>,
+    /// Keys by the tick they were last used at, oldest first.
+    order: BTreeMap<u64, K>,
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> LruCache<K, V> {
+    pub fn new(capacity: usize) -> Self {
+        LruCache {
+            capacity,
+            tick: 0,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Value of `key`, marking it as the most recently used.
+    pub fn get(&mut self, key: &K) -> Option<V> {
+        self.tick += 1;
+        let tick = self.tick;
+        match self.entries.get_mut(key) {
+            Some(entry) => {
+                self.order.remove(&entry.1);
+                self.order.insert(tick, key.clone());
+                entry.1 = tick;
+                Some(entry.0.clone())
+            },
+            None => None,
+        }
+    }
+
+    pub fn insert(&mut self, key: K, value: V) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.tick += 1;
+        if let Some((_, tick)) = self.entries.insert(key.clone(), (value, self.tick)) {
+            self.order.remove(&tick);
+        }
+        self.order.insert(self.tick, key);
+
+        while self.entries.len() > self.capacity {
+            let oldest = match self.order.keys().next() {
+                Some(oldest) => *oldest,
+                None => break,
+            };
+            if let Some(key) = self.order.remove(&oldest) {
+                self.entries.remove(&key);
+            }
+        }
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        match self.entries.remove(key) {
+            Some((value, tick)) => {
+                self.order.remove(&tick);
+                Some(value)
+            },
+            None => None,
+        }
+    }
+
+    /// Remove the entries whose key `f` rejects.
+    pub fn retain<F: FnMut(&K) -> bool>(&mut self, mut f: F) {
+        let order = &mut self.order;
+        self.entries.retain(|key, entry| {
+            let keep = f(key);
+            if !keep {
+                order.remove(&entry.1);
+            }
+            keep
+        });
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evicts_the_least_recently_used() {
+        let mut cache = LruCache::new(2);
+        cache.insert(1, "one");
+        cache.insert(2, "two");
+        assert_eq!(cache.get(&1), Some("one"));
+
+        cache.insert(3, "three");
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get(&2), None);
+        assert_eq!(cache.get(&1), Some("one"));
+        assert_eq!(cache.get(&3), Some("three"));
+    }
+
+    #[test]
+    fn zero_capacity_holds_nothing() {
+        let mut cache = LruCache::new(0);
+        cache.insert(1, "one");
+        assert!(cache.is_empty());
+        assert_eq!(cache.get(&1), None);
+    }
+}
@@ -1,77 +1,199 @@
-use block::{Receipt, Block, Transaction, TransactionAction, Log, FromKey, Header, HeaderHash, ommers_hash, transactions_root, receipts_root, RlpHash};
+use block::{Receipt, Block, Transaction, TransactionAction, Log, FromKey, Header, HeaderHash, ommers_hash, transactions_root, RlpHash};
 use trie::{MemoryDatabase, Database};
-use bigint::{H256, U256, H64, B256, Gas, Address};
+use rlp::RlpStream;
+use bigint::{H256, U256, M256, H64, B256, Gas, Address};
 use bloom::LogsBloom;
+use secp256k1::SECP256K1;
 use secp256k1::key::{SecretKey};
-use std::time::Duration;
+use std::collections::{HashMap, HashSet};
 use std::str::FromStr;
-use std::time::{SystemTime, UNIX_EPOCH};
-use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use std::sync::{Arc, RwLock};
 use std::sync::mpsc::{Receiver};
 use std::rc::Rc;
-use sputnikvm::{ValidTransaction, Patch, HeaderParams, SeqTransactionVM, VM, VMStatus};
+use sputnikvm::{ValidTransaction, Patch, HeaderParams, SeqTransactionVM, VM, VMStatus, AccountChange};
 use sputnikvm_stateful::MemoryStateful;
 use hexutil::*;
+use error::{Error, revert_reason};
+use checksum;
+use journal::{Journal, JournalEntry};
+use self::scheduler::{Scheduler, Wake};
+use self::reward::{MAX_OMMERS, MAX_OMMER_DEPTH};
 
 mod state;
-
-pub use self::state::MinerState;
-
-fn next<'a>(
+mod archive;
+mod work;
+mod fee;
+mod retention;
+mod verify;
+mod fork;
+mod fork_cache;
+mod patch;
+mod selfdestruct;
+mod overrides;
+mod builder;
+mod proof;
+mod receipt;
+mod ordered_trie;
+mod watch;
+mod reward;
+mod pool;
+mod freeze;
+mod scheduler;
+mod oracle;
+mod tracer;
+mod dump;
+mod reorg;
+mod execution;
+mod parallel;
+mod lru;
+mod account_cache;
+mod log_index;
+
+pub use self::state::{MinerState, ReceiptContext, StateWrite};
+pub use self::archive::{
+    ChainIdentity, write_archive, read_archive, read_archive_genesis, read_archive_blocks, archive_identity,
+};
+pub use self::work::{WorkPackage, work_package, verify_work};
+pub use self::fee::{FeePolicy, FeeContext, StandardFeePolicy, SponsorFeePolicy};
+pub use self::retention::LogRetention;
+pub use self::verify::{VerifyReport, verify_database, repair_database};
+pub use self::fork::{Fork, ForkAccount, call, fulfill, parse_fork};
+pub use self::patch::{DevPatch, with_memory_limit, sign_transaction, is_byzantium};
+pub use self::selfdestruct::SelfdestructMode;
+pub use self::overrides::{AccountOverride, StateOverrides, call_with_overrides};
+pub use self::builder::{HeaderBuilder, BlockBuilder};
+pub use self::proof::{InclusionProof, transaction_proof, receipt_proof};
+pub use self::receipt::{ConsensusReceipt, ReceiptOutcome, consensus_receipts, receipts_root};
+pub use self::watch::{WatchEvent, Watcher, Artifact, read_artifact, watch_loop};
+pub use self::reward::{block_reward, ommer_reward};
+pub use self::pool::{TransactionPool, validate_pooled};
+pub use self::freeze::{FreezeConfig, FrozenSegment, freeze_blocks};
+pub use self::scheduler::{MinerEvent, MiningSchedule};
+pub use self::oracle::GasPriceOracle;
+pub use self::tracer::{Tracer, StepContext, FrameContext, ReturnContext, call_with_tracer};
+pub use self::dump::{DumpedAccount, StateDump, trie_leaves, trie_range, dump_state, dump_range};
+pub use self::reorg::{SideChain, SideChainHead, create_side_chain, extend_side_chain};
+pub use self::execution::{to_valid, transit};
+pub use self::lru::LruCache;
+pub use self::account_cache::{AccountCache, DEFAULT_ACCOUNT_CACHE};
+pub use self::log_index::LogIndex;
+
+fn next<'a, P: Patch>(
     state: &mut MinerState,
-    current_block: &Block, transactions: &[Transaction], receipts: &[Receipt],
-    beneficiary: Address, gas_limit: Gas, state_root: H256,
+    current_block: &Block, transactions: &[Transaction], receipts: &[Receipt], ommers: Vec<Header>,
+    beneficiary: Address, gas_limit: Gas, state_root: H256, timestamp: u64,
 ) -> Block {
-    // TODO: Handle block rewards.
-
     debug_assert!(transactions.len() == receipts.len());
 
     let mut logs_bloom = LogsBloom::new();
-    let mut gas_used = Gas::zero();
 
     for i in 0..transactions.len() {
         state.insert_receipt(transactions[i].rlp_hash(), receipts[i].clone());
 
         logs_bloom = logs_bloom | receipts[i].logs_bloom.clone();
-        gas_used = gas_used + receipts[i].used_gas.clone();
-    }
-
-    let header = Header {
-        parent_hash: current_block.header.header_hash(),
-        ommers_hash: ommers_hash(&[]),
-        beneficiary,
-        state_root: state_root,
-        transactions_root: transactions_root(transactions),
-        receipts_root: receipts_root(receipts),
-        logs_bloom,
-        gas_limit,
-        gas_used,
-        timestamp: current_timestamp(),
-        extra_data: B256::default(),
-        number: current_block.header.number + U256::one(),
-
-        difficulty: U256::zero(),
-        mix_hash: H256::default(),
-        nonce: H64::default(),
-    };
+    }
+    let gas_used = block_gas_used(receipts);
+    let receipts_root = receipts_root(&consensus_receipts::<P>(state, transactions, receipts));
+
+    let header = HeaderBuilder::new(&current_block.header)
+        .beneficiary(beneficiary)
+        .state_root(state_root)
+        .transactions_root(transactions_root(transactions))
+        .ommers_hash(ommers_hash(&ommers))
+        .receipts_root(receipts_root)
+        .logs_bloom(logs_bloom)
+        .gas_limit(gas_limit)
+        .gas_used(gas_used)
+        .timestamp(timestamp)
+        .build();
 
     Block {
         header,
         transactions: transactions.into(),
-        ommers: Vec::new()
+        ommers,
     }
 }
 
+/// Gas limit of every mined block.
+pub fn block_gas_limit() -> Gas {
+    Gas::from_str("0x10000000000000000000000").unwrap()
+}
+
 fn current_timestamp() -> u64 {
     SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
 }
 
+/// Blocks sealed within the same second still get strictly increasing
+/// timestamps.
+fn next_timestamp(parent_timestamp: u64) -> u64 {
+    let now = current_timestamp();
+    if now > parent_timestamp { now } else { parent_timestamp + 1 }
+}
+
+/// Check the timestamp of a block against its parent and the local
+/// clock, allowing at most `max_clock_drift` seconds in the future.
+pub fn validate_timestamp(parent: &Header, header: &Header, max_clock_drift: u64) -> Result<(), Error> {
+    if header.timestamp <= parent.timestamp {
+        return Err(Error::InvalidHeader(
+            "timestamp", format!("{} is not after parent timestamp {}",
+                                 header.timestamp, parent.timestamp)));
+    }
+
+    let now = current_timestamp();
+    if header.timestamp > now.saturating_add(max_clock_drift) {
+        return Err(Error::InvalidHeader(
+            "timestamp", format!("{} is more than {} seconds ahead of local time {}",
+                                 header.timestamp, max_clock_drift, now)));
+    }
+
+    Ok(())
+}
+
 lazy_static! {
     static ref DATABASE: MemoryDatabase = MemoryDatabase::default();
 }
 
+/// Account pre-allocated in the genesis state, in addition to the
+/// generated accounts.
+#[derive(Clone, Debug, PartialEq)]
+pub struct GenesisAccount {
+    pub address: Address,
+    pub balance: U256,
+    pub nonce: U256,
+    pub code: Vec<u8>,
+    pub storage: Vec<(U256, M256)>,
+}
+
+/// Write `alloc` over the state at `root`, returning the new root.
+pub fn apply_alloc(database: &MemoryDatabase, root: H256, alloc: &[GenesisAccount]) -> H256 {
+    let mut root = root;
+    for account in alloc {
+        root = state::modify_account_at(database, root, account.address, |record| {
+            record.balance = account.balance;
+            record.nonce = account.nonce;
+            state::set_account_code(database, record, account.code.clone());
+            for &(index, value) in &account.storage {
+                state::set_account_storage(database, record, index, value);
+            }
+        });
+    }
+    root
+}
+
 pub fn make_state<P: Patch>(genesis_accounts: Vec<(SecretKey, U256)>) -> MinerState {
-    let mut stateful = MemoryStateful::empty(&DATABASE);
+    make_state_at::<P>(genesis_accounts, current_timestamp())
+}
+
+pub fn make_state_at<P: Patch>(genesis_accounts: Vec<(SecretKey, U256)>, timestamp: u64) -> MinerState {
+    make_state_with::<P>(genesis_accounts, timestamp, &[])
+}
+
+pub fn make_state_with<P: Patch>(
+    genesis_accounts: Vec<(SecretKey, U256)>, timestamp: u64, alloc: &[GenesisAccount]
+) -> MinerState {
+    let database: &'static MemoryDatabase = &DATABASE;
+    let mut stateful = MemoryStateful::empty(database);
     let mut genesis = Block {
         header: Header {
             parent_hash: H256::default(),
@@ -85,7 +207,7 @@ pub fn make_state<P: Patch>(genesis_accounts: Vec<(SecretKey, U256)>) -> MinerSt
             number: U256::zero(),
             gas_limit: Gas::zero(),
             gas_used: Gas::zero(),
-            timestamp: current_timestamp(),
+            timestamp,
             extra_data: B256::default(),
 
             difficulty: U256::zero(),
@@ -120,13 +242,18 @@ pub fn make_state<P: Patch>(genesis_accounts: Vec<(SecretKey, U256)>) -> MinerSt
         };
     }
 
+    let root = apply_alloc(database, stateful.root(), alloc);
+    let stateful = MemoryStateful::new(database, root);
+
     genesis.header.state_root = stateful.root();
 
     let mut state = MinerState::new(genesis, stateful);
+    state.set_genesis_accounts(genesis_accounts.clone());
+    state.set_genesis_alloc(alloc.to_vec());
 
     for (secret_key, _balance) in genesis_accounts {
         let address = Address::from_secret_key(&secret_key).unwrap();
-        println!("address: {:?}", address);
+        println!("address: {}", checksum::format_address(&address));
         println!("private key: {}", to_hex(&secret_key[..]));
 
         state.append_account(secret_key);
@@ -135,78 +262,699 @@ pub fn make_state<P: Patch>(genesis_accounts: Vec<(SecretKey, U256)>) -> MinerSt
         }
     }
 
+    for account in alloc {
+        state.touch_fat_account(0, account.address);
+        for &(index, value) in &account.storage {
+            state.set_fat_storage(0, account.address, index, value);
+        }
+    }
+
     state
 }
 
-pub fn mine_loop<P: Patch>(state: Arc<Mutex<MinerState>>, channel: Receiver<bool>) {
+/// Start a chain from a given genesis block, whose state is `alloc`.
+pub fn make_state_from_genesis(genesis: Block, alloc: &[GenesisAccount]) -> Result<MinerState, Error> {
+    let database: &'static MemoryDatabase = &DATABASE;
+    let root = apply_alloc(database, MemoryStateful::empty(database).root(), alloc);
+    if root != genesis.header.state_root {
+        return Err(Error::InvalidHeader(
+            "stateRoot", format!("0x{:x} does not match the genesis accounts", genesis.header.state_root)));
+    }
+    if !genesis.transactions.is_empty() {
+        return Err(Error::InvalidHeader("transactionsRoot", "genesis block has transactions".to_string()));
+    }
+
+    let mut state = MinerState::new(genesis, MemoryStateful::new(database, root));
+    state.set_genesis_alloc(alloc.to_vec());
+    for account in alloc {
+        state.touch_fat_account(0, account.address);
+        for &(index, value) in &account.storage {
+            state.set_fat_storage(0, account.address, index, value);
+        }
+    }
+    Ok(state)
+}
+
+/// Replace the whole chain by a new genesis, reusing the generated
+/// accounts of the current one.
+pub fn reset<P: Patch>(state: &mut MinerState, alloc: &[GenesisAccount], timestamp: Option<u64>) {
+    let fresh = make_state_with::<P>(state.genesis_accounts().to_vec(),
+                                     timestamp.unwrap_or(current_timestamp()), alloc);
+    state.reset(fresh);
+}
+
+/// Default percentage added to the estimated gas of transactions the
+/// node fills the gas limit of.
+pub const DEFAULT_GAS_MARGIN: usize = 10;
+
+/// Default number of blocks, and of receipts, the RPC keeps converted.
+pub const DEFAULT_RPC_CACHE: usize = 1024;
+
+/// Default seconds after which a block is mined even without new
+/// transactions.
+pub const MINING_INTERVAL: u64 = 10;
+
+/// Mine blocks as the schedule of the state dictates, until every
+/// sender of the channel is gone.
+pub fn mine_loop<P: 'static + Patch>(
+    state: Arc<RwLock<MinerState>>, channel: Receiver<MinerEvent>, journal: Option<Arc<Journal>>
+) {
+    let schedule = state.read().unwrap().mining_schedule();
+    let mut scheduler = Scheduler::new(schedule, Instant::now());
+
     loop {
-        mine_one::<P>(state.clone(), Address::default());
+        match scheduler.wait(schedule, &channel) {
+            Wake::Stop => return,
+            Wake::Idle => continue,
+            Wake::Mine => (),
+        }
 
-        channel.recv_timeout(Duration::new(10, 0));
+        {
+            let mut journal = journal.as_ref().map(|journal| journal.lock());
+            let timestamp = mine_one::<P>(state.clone(), Address::default());
+            if let Some(ref mut journal) = journal {
+                if let Err(err) = journal.append(&JournalEntry::Mine { timestamp }) {
+                    error!("cannot record block mined at {} in the journal: {}", timestamp, err);
+                }
+            }
+        }
+        scheduler.mined(schedule, Instant::now());
     }
 }
 
-pub fn mine_one<P: Patch>(state: Arc<Mutex<MinerState>>, address: Address) {
-    let mut state = state.lock().unwrap();
+/// Seal the pending transactions into a new block, returning its
+/// timestamp. Queued transactions stay in the pool.
+pub fn mine_one<P: 'static + Patch>(state: Arc<RwLock<MinerState>>, address: Address) -> u64 {
+    let timestamp = next_timestamp(state.read().unwrap().current_block().header.timestamp);
+    mine_one_at::<P>(state, address, timestamp);
+    timestamp
+}
+
+/// Seal the pending transactions into a new block with the given
+/// timestamp, used when replaying a journal.
+pub fn mine_one_at<P: 'static + Patch>(state: Arc<RwLock<MinerState>>, address: Address, timestamp: u64) {
+    let mut state = state.write().unwrap();
 
     let current_block = state.current_block();
-    let transactions = state.clear_pending_transactions();
-    let block_hashes = state.get_last_256_block_hashes();
+    let pending = state.clear_pending_transactions();
 
     let beneficiary = address;
 
-    let mut receipts = Vec::new();
+    state.begin_sealing();
 
-    state.fat_transit(current_block.header.number.as_usize(), &[]);
+    // The fork and the tracer are not shared with other threads.
+    let pool = state.execution_pool().cloned();
+    let executed = match pool {
+        Some(ref pool) if state.fork().is_none() && !state.has_tracer() => {
+            let workers = state.execution_workers();
+            self::parallel::execute_transactions::<P>(&mut state, &current_block, pending.clone(), true, pool, workers)
+        },
+        _ => execute_transactions::<P>(&mut state, &current_block, pending.clone(), true),
+    };
+    // Transactions stay pending if the block fails, such as when the
+    // fork cannot be reached, to be mined with a later one.
+    let (transactions, receipts) = match executed {
+        Ok(executed) => executed,
+        Err(err) => {
+            error!("cannot execute pending transactions: {}", err);
+            state.end_sealing(false);
+            state.return_pending_transactions(&pending);
+            return;
+        },
+    };
 
-    for transaction in transactions.clone() {
-        let transaction_hash = transaction.rlp_hash();
-        let valid = state.stateful_mut().to_valid::<P>(&transaction).unwrap();
-        let vm: SeqTransactionVM<P> = {
-            let vm = state.stateful_mut().call(valid, &HeaderParams::from(&current_block.header),
-                               &block_hashes);
-            let mut accounts = Vec::new();
-            for account in vm.accounts() {
-                accounts.push(account.clone());
-            }
-            state.stateful_mut().transit(&accounts);
-            state.fat_transit(current_block.header.number.as_usize(), &accounts);
-            vm
+    let number = current_block.header.number + U256::one();
+    let ommers = state.ommer_candidates(number.as_usize());
+    if let Err(err) = reward::apply::<P>(&mut state, number, beneficiary, &ommers) {
+        error!("cannot credit the block rewards: {}", err);
+        state.end_sealing(false);
+        state.return_pending_transactions(&pending);
+        return;
+    }
+
+    let root = state.stateful_mut().root();
+    let next_block = next::<P>(&mut state, &current_block, transactions.as_ref(), receipts.as_ref(), ommers,
+                          beneficiary, block_gas_limit(), root, timestamp);
+    debug!("block number: 0x{:x}", next_block.header.number);
+    if let Err(err) = state.append_block(next_block) {
+        error!("cannot append mined block: {}", err);
+        state.end_sealing(false);
+        return;
+    }
+    state.end_sealing(true);
+    if let Err(err) = self::retention::prune_receipts(&mut state) {
+        error!("cannot archive pruned receipts: {}", err);
+    }
+    if let Err(err) = self::freeze::freeze_blocks(&mut state) {
+        error!("cannot freeze old blocks: {}", err);
+    }
+}
+
+/// Check the fields of `block` that follow from its parent and its
+/// body, before any of its transactions is executed.
+pub fn validate_block(parent: &Block, block: &Block, max_clock_drift: u64) -> Result<(), Error> {
+    let header = &block.header;
+    if header.parent_hash != parent.header.header_hash() {
+        return Err(Error::InvalidHeader(
+            "parentHash", format!("0x{:x} is not the current head", header.parent_hash)));
+    }
+    if header.number != parent.header.number + U256::one() {
+        return Err(Error::InvalidHeader(
+            "number", format!("0x{:x} does not follow the parent 0x{:x}", header.number, parent.header.number)));
+    }
+    validate_timestamp(&parent.header, header, max_clock_drift)?;
+    if header.gas_used > header.gas_limit {
+        return Err(Error::InvalidHeader(
+            "gasUsed", format!("0x{:x} is above the gas limit 0x{:x}", header.gas_used, header.gas_limit)));
+    }
+    if header.transactions_root != transactions_root(&block.transactions) {
+        return Err(Error::InvalidHeader(
+            "transactionsRoot", format!("0x{:x} does not match the transactions", header.transactions_root)));
+    }
+    if header.ommers_hash != ommers_hash(&block.ommers) {
+        return Err(Error::InvalidHeader(
+            "ommersHash", format!("0x{:x} does not match the ommers", header.ommers_hash)));
+    }
+
+    Ok(())
+}
+
+/// Check the ommers of `block` against its ancestors: at most
+/// `MAX_OMMERS` of them, each a child of one of the `MAX_OMMER_DEPTH`
+/// ancestors before its parent, and none of them an ancestor, an ommer
+/// an ancestor already included, or listed twice.
+pub fn validate_ommers(state: &MinerState, block: &Block) -> Result<(), Error> {
+    if block.ommers.len() > MAX_OMMERS {
+        return Err(Error::InvalidHeader(
+            "ommers", format!("{} ommers, at most {} are allowed", block.ommers.len(), MAX_OMMERS)));
+    }
+    if block.ommers.is_empty() {
+        return Ok(());
+    }
+
+    let mut ancestors = HashMap::new();
+    let mut included = HashSet::new();
+    let mut hash = block.header.parent_hash;
+    for _ in 0..(MAX_OMMER_DEPTH + 1) {
+        let ancestor = match state.get_block_by_hash(hash) {
+            Ok(ancestor) => ancestor,
+            Err(_) => break,
         };
+        for ommer in &ancestor.ommers {
+            included.insert(ommer.header_hash());
+        }
+        ancestors.insert(hash, ancestor.header.number);
+        if ancestor.header.number.is_zero() {
+            break;
+        }
+        hash = ancestor.header.parent_hash;
+    }
 
-        let logs: Vec<Log> = vm.logs().into();
-        let used_gas = vm.used_gas();
-        let mut logs_bloom = LogsBloom::new();
-        for log in logs.clone() {
-            logs_bloom.set(&log.address);
-            for topic in log.topics {
-                logs_bloom.set(&topic)
-            }
+    for ommer in &block.ommers {
+        let hash = ommer.header_hash();
+        if ancestors.contains_key(&hash) || !included.insert(hash) {
+            return Err(Error::InvalidHeader("ommers", format!("0x{:x} is already included", hash)));
+        }
+        let branches_off = ommer.parent_hash != block.header.parent_hash &&
+            ancestors.get(&ommer.parent_hash).map(|&number| ommer.number == number + U256::one()).unwrap_or(false);
+        if !branches_off {
+            return Err(Error::InvalidHeader(
+                "ommers", format!("0x{:x} does not branch off within {} blocks", hash, MAX_OMMER_DEPTH)));
         }
+    }
+
+    Ok(())
+}
+
+/// Gas used by a block, that of its last receipt, as receipts hold the
+/// gas used by the block up to and including their transaction.
+pub fn block_gas_used(receipts: &[Receipt]) -> Gas {
+    receipts.last().map(|receipt| receipt.used_gas).unwrap_or(Gas::zero())
+}
+
+/// Gas used by the transaction of `receipt` alone, given the receipt of
+/// the transaction before it in the block, if any.
+pub fn transaction_gas_used(receipt: &Receipt, previous: Option<&Receipt>) -> Gas {
+    match previous {
+        Some(previous) => receipt.used_gas - previous.used_gas,
+        None => receipt.used_gas,
+    }
+}
+
+/// Check the fields of `header` that follow from the receipts of its
+/// executed transactions.
+fn validate_receipts<P: Patch>(
+    state: &MinerState, header: &Header, transactions: &[Transaction], receipts: &[Receipt]
+) -> Result<(), Error> {
+    let mut logs_bloom = LogsBloom::new();
+    for receipt in receipts {
+        logs_bloom = logs_bloom | receipt.logs_bloom.clone();
+    }
+    let gas_used = block_gas_used(receipts);
+
+    if header.gas_used != gas_used {
+        return Err(Error::InvalidHeader(
+            "gasUsed", format!("0x{:x} does not match the executed gas 0x{:x}", header.gas_used, gas_used)));
+    }
+    if header.receipts_root != receipts_root(&consensus_receipts::<P>(state, transactions, receipts)) {
+        return Err(Error::InvalidHeader(
+            "receiptsRoot", format!("0x{:x} does not match the receipts", header.receipts_root)));
+    }
+    if header.logs_bloom != logs_bloom {
+        return Err(Error::InvalidHeader("logsBloom", "does not match the logs".to_string()));
+    }
 
-        let receipt = Receipt {
-            used_gas: used_gas.clone(),
-            logs,
-            logs_bloom: logs_bloom.clone(),
-            state_root: state.stateful_mut().root(),
+    Ok(())
+}
+
+/// Re-execute an already sealed block on top of the current head and
+/// append it as-is. Its header is checked against its parent and body
+/// first, then against the resulting state root and receipts.
+pub fn import_block<P: Patch>(state: &mut MinerState, block: Block) -> Result<H256, Error> {
+    let current_block = state.current_block();
+    validate_block(&current_block, &block, state.max_clock_drift())?;
+    validate_ommers(state, &block)?;
+
+    state.begin_sealing();
+    let receipts = match execute_transactions::<P>(state, &current_block, block.transactions.clone(), false) {
+        Ok((_, receipts)) => receipts,
+        Err(err) => {
+            state.end_sealing(false);
+            return Err(err);
+        },
+    };
+    if let Err(err) = reward::apply::<P>(state, block.header.number, block.header.beneficiary, &block.ommers) {
+        state.end_sealing(false);
+        return Err(err);
+    }
+    if state.stateful_mut().root() != block.header.state_root {
+        state.end_sealing(false);
+        return Err(Error::InvalidHeader(
+            "stateRoot", format!("0x{:x} does not match the executed state", block.header.state_root)));
+    }
+    if let Err(err) = validate_receipts::<P>(state, &block.header, &block.transactions, &receipts) {
+        state.end_sealing(false);
+        return Err(err);
+    }
+
+    for (transaction, receipt) in block.transactions.iter().zip(receipts.into_iter()) {
+        state.insert_receipt(transaction.rlp_hash(), receipt);
+    }
+
+    let result = state.append_block(block);
+    state.end_sealing(result.is_ok());
+    result
+}
+
+/// Store `block` wherever it branches off and switch the head to the
+/// branch fork choice prefers. Blocks on top of the head are imported
+/// right away, while those of other branches are only executed when
+/// their branch becomes canonical.
+pub fn insert_block<P: Patch>(state: &mut MinerState, block: Block) -> Result<H256, Error> {
+    let hash = block.header.header_hash();
+    if block.header.parent_hash == state.current_block().header.header_hash() {
+        return import_block::<P>(state, block);
+    }
+
+    let parent = state.get_block_by_hash(block.header.parent_hash).map_err(|_| Error::InvalidHeader(
+        "parentHash", format!("0x{:x} is not a known block", block.header.parent_hash)))?;
+    validate_block(&parent, &block, state.max_clock_drift())?;
+    validate_ommers(state, &block)?;
+    state.insert_side_block(block)?;
+    apply_fork_choice::<P>(state)?;
+    Ok(hash)
+}
+
+/// Reorganize the chain onto the side branch fork choice prefers, as
+/// long as there is one. The canonical blocks after the last common
+/// ancestor become side blocks, and their signed transactions that are
+/// not on the new branch return to the pool. A block failing to import
+/// is dropped with the blocks built on it and its error is returned,
+/// once the chain settled on the preferred valid branch.
+pub fn apply_fork_choice<P: Patch>(state: &mut MinerState) -> Result<(), Error> {
+    let mut replaced = Vec::new();
+    let mut failure = None;
+
+    while let Some(head) = state.preferred_side_block() {
+        // A branch whose blocks are gone can no longer be followed.
+        let (ancestor, blocks) = match state.side_branch(head) {
+            Ok(branch) => branch,
+            Err(_) => {
+                state.remove_side_block(head);
+                continue;
+            },
         };
-        receipts.push(receipt);
+        if ancestor + 1 < state.frozen_before() {
+            if let Some(first) = blocks.first() {
+                state.remove_side_block(first.header.header_hash());
+            }
+            continue;
+        }
 
-        state.set_receipt_status(
-            transaction_hash,
-            match vm.status() {
-                VMStatus::ExitedOk => true,
-                _ => false,
+        for number in (ancestor + 1)..(state.block_height() + 1) {
+            for transaction in state.get_block_by_number(number).transactions {
+                if state.impersonated_sender(transaction.rlp_hash()).is_none() {
+                    replaced.push(transaction);
+                }
             }
-        );
+        }
 
-        println!("0x{:x}", transaction_hash);
+        state.set_head(ancestor)?;
+        for block in blocks {
+            let hash = block.header.header_hash();
+            if let Err(err) = import_block::<P>(state, block) {
+                state.remove_side_block(hash);
+                failure = Some(err);
+                break;
+            }
+        }
     }
 
-    let root = state.stateful_mut().root();
-    let next_block = next(&mut state, &current_block, transactions.as_ref(), receipts.as_ref(),
-                          beneficiary, Gas::from_str("0x10000000000000000000000").unwrap(),
-                          root);
-    debug!("block number: 0x{:x}", next_block.header.number);
-    state.append_block(next_block);
+    for transaction in replaced {
+        if state.get_transaction_block_hash_by_hash(transaction.rlp_hash()).is_err() {
+            let _ = state.append_pending_transaction(transaction);
+        }
+    }
+
+    match failure {
+        Some(err) => Err(err),
+        None => Ok(()),
+    }
+}
+
+/// Impersonated transactions are executed as sent from the
+/// impersonated address, without checking their signature.
+pub fn valid_transaction<P: Patch>(
+    state: &MinerState, stateful: &MemoryStateful, transaction: &Transaction
+) -> Result<ValidTransaction, Error> {
+    match state.impersonated_sender(transaction.rlp_hash()) {
+        Some(caller) => Ok(ValidTransaction {
+            caller: Some(caller),
+            gas_price: transaction.gas_price,
+            gas_limit: transaction.gas_limit,
+            action: transaction.action.clone(),
+            value: transaction.value,
+            input: Rc::new(transaction.input.clone()),
+            nonce: transaction.nonce,
+        }),
+        None => to_valid::<P>(stateful, transaction),
+    }
+}
+
+/// Account changes of a transaction executed on `stateful` outside of
+/// mining, with the SELFDESTRUCT mode applied.
+pub fn account_changes<P: Patch>(
+    state: &MinerState, stateful: &MemoryStateful, vm: &SeqTransactionVM<P>
+) -> Vec<AccountChange> {
+    let mut accounts: Vec<AccountChange> = vm.accounts().cloned().collect();
+    selfdestruct::apply(state.selfdestruct_mode(), stateful, &mut accounts);
+    accounts
+}
+
+/// Account changes of a transaction as committed when it is mined on
+/// `stateful`, with the fee policy and the SELFDESTRUCT mode applied.
+pub fn mined_account_changes<P: Patch>(
+    state: &MinerState, stateful: &MemoryStateful, caller: Address, gas_price: Gas,
+    vm: &SeqTransactionVM<P>
+) -> Vec<AccountChange> {
+    let mut accounts: Vec<AccountChange> = vm.accounts().cloned().collect();
+    let fee = FeeContext {
+        caller, gas_price,
+        used_gas: vm.used_gas(),
+    };
+    state.fee_policy().apply(stateful, &fee, &mut accounts);
+    selfdestruct::apply(state.selfdestruct_mode(), stateful, &mut accounts);
+    accounts
+}
+
+/// Copy a sender from the fork into the head state before its nonce
+/// and balance are checked.
+fn materialize_account(state: &mut MinerState, address: Address) -> Result<(), Error> {
+    let mut stateful = state.stateful_at(state.stateful().root());
+    if let Some(fork) = state.fork() {
+        fork.materialize_account(&mut stateful, address)?;
+    }
+    *state.stateful_mut() = stateful;
+    Ok(())
+}
+
+/// Key signing transactions of an impersonated address, so that they
+/// are still well-formed and have distinct hashes per sender.
+pub fn impersonation_key(address: Address) -> SecretKey {
+    use sha3::{Digest, Keccak256};
+    SecretKey::from_slice(&SECP256K1, Keccak256::digest(&address).as_slice()).unwrap()
+}
+
+/// Address of a contract created by `caller` with the given nonce.
+pub fn contract_address(caller: Address, nonce: U256) -> Address {
+    use sha3::{Digest, Keccak256};
+
+    let mut stream = RlpStream::new_list(2);
+    stream.append(&caller);
+    stream.append(&nonce);
+    Address::from(&Keccak256::digest(&stream.out())[12..])
+}
+
+/// Leave out a pending transaction the miner cannot execute, and
+/// forget it.
+fn skip_transaction(state: &mut MinerState, transaction: &Transaction, err: Error) {
+    warn!("skipping transaction 0x{:x}: {}", transaction.rlp_hash(), err);
+    state.drop_transaction(transaction.rlp_hash());
+}
+
+/// Transactions executed so far in a block, with their receipts.
+struct ExecutedBlock {
+    transactions: Vec<Transaction>,
+    receipts: Vec<Receipt>,
+    cumulative_gas_used: Gas,
+    log_count: usize,
+}
+
+impl ExecutedBlock {
+    fn new() -> Self {
+        ExecutedBlock {
+            transactions: Vec::new(),
+            receipts: Vec::new(),
+            cumulative_gas_used: Gas::zero(),
+            log_count: 0,
+        }
+    }
+}
+
+/// Commit the account changes of `transaction`, executed by `vm` on
+/// the working state, and add it to `block` with its receipt. Nothing
+/// is committed on failure, and the transaction is given back. Returns
+/// the changes as committed.
+fn commit_transaction<P: Patch>(
+    state: &mut MinerState, current_block: &Block, block: &mut ExecutedBlock,
+    transaction: Transaction, caller: Address, gas_price: Gas, nonce: U256, vm: SeqTransactionVM<P>
+) -> Result<Vec<AccountChange>, (Error, Transaction)> {
+    let transaction_index = block.transactions.len();
+    let transaction_hash = transaction.rlp_hash();
+    let contract_address = match transaction.action {
+        TransactionAction::Create => Some(contract_address(caller, nonce)),
+        _ => None,
+    };
+
+    let accounts = mined_account_changes(state, state.stateful(), caller, gas_price, &vm);
+    if let Err(err) = transit(state.stateful_mut(), &accounts) {
+        return Err((err, transaction));
+    }
+    if let Some(fork) = state.fork() {
+        fork.record_changes(&accounts);
+    }
+    state.fat_transit(current_block.header.number.as_usize(), &accounts);
+
+    let logs: Vec<Log> = vm.logs().into();
+    let used_gas = vm.used_gas();
+    let mut logs_bloom = LogsBloom::new();
+    for log in logs.clone() {
+        logs_bloom.set(&log.address);
+        for topic in log.topics {
+            logs_bloom.set(&topic)
+        }
+    }
+
+    block.cumulative_gas_used = block.cumulative_gas_used + used_gas;
+    let receipt = Receipt {
+        used_gas: block.cumulative_gas_used,
+        logs,
+        logs_bloom: logs_bloom.clone(),
+        state_root: state.stateful_mut().root(),
+    };
+    let log_count = receipt.logs.len();
+    block.receipts.push(receipt);
+
+    state.set_receipt_status(
+        transaction_hash,
+        match vm.status() {
+            VMStatus::ExitedOk => true,
+            _ => false,
+        }
+    );
+    if let VMStatus::ExitedErr(_) = vm.status() {
+        if let Some(reason) = revert_reason(vm.out()) {
+            state.set_revert_reason(transaction_hash, reason);
+        }
+    }
+
+    state.set_receipt_context(transaction_hash, ReceiptContext {
+        transaction_index,
+        first_log_index: block.log_count,
+        cumulative_gas_used: block.cumulative_gas_used,
+        contract_address,
+    });
+    block.log_count += log_count;
+
+    println!("0x{:x}", transaction_hash);
+    block.transactions.push(transaction);
+    Ok(accounts)
+}
+
+/// Execute `transactions` on the working state, returning those that
+/// were executed with their receipts. An invalid transaction fails the
+/// whole block, unless `skip_invalid` is set, in which case it is left
+/// out and the next ones still run. A fork that cannot be reached
+/// always fails the block.
+fn execute_transactions<P: Patch>(
+    state: &mut MinerState, current_block: &Block, transactions: Vec<Transaction>, skip_invalid: bool
+) -> Result<(Vec<Transaction>, Vec<Receipt>), Error> {
+    let block_hashes = state.get_last_256_block_hashes();
+    let mut block = ExecutedBlock::new();
+
+    state.fat_transit(current_block.header.number.as_usize(), &[]);
+
+    for transaction in transactions {
+        if let Ok(sender) = state.transaction_sender(&transaction) {
+            materialize_account(state, sender)?;
+        }
+        let valid = match valid_transaction::<P>(state, state.stateful(), &transaction) {
+            Ok(valid) => valid,
+            Err(err) if skip_invalid => {
+                skip_transaction(state, &transaction, err);
+                continue;
+            },
+            Err(err) => return Err(err),
+        };
+        let caller = valid.caller.unwrap_or(Address::default());
+        let gas_price = valid.gas_price;
+        let nonce = valid.nonce;
+
+        let vm = state.call(valid, &HeaderParams::from(&current_block.header), &block_hashes)?;
+        match commit_transaction::<P>(state, current_block, &mut block, transaction, caller, gas_price, nonce, vm) {
+            Ok(_) => (),
+            Err((err, transaction)) if skip_invalid => skip_transaction(state, &transaction, err),
+            Err((err, _)) => return Err(err),
+        }
+    }
+
+    Ok((block.transactions, block.receipts))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn contract_address_from_sender_and_nonce() {
+        let sender = Address::from_str("6ac7ea33f8831ea9dcc53393aaa88b25a785dbf0").unwrap();
+        assert_eq!(contract_address(sender, U256::zero()),
+                   Address::from_str("cd234a471b72ba2f1ccf0a70fcaba648a5eecd8d").unwrap());
+        assert_eq!(contract_address(sender, U256::one()),
+                   Address::from_str("343c43a37d37dff08ae8c4a11544c718abb4fcf8").unwrap());
+    }
+
+    #[test]
+    fn unlock_duration_overflow_is_rejected() {
+        use sputnikvm_network_classic::MainnetEIP160Patch;
+        use std::time::Duration;
+
+        let key = SecretKey::from_slice(&SECP256K1, &[1u8; 32]).unwrap();
+        let mut state = make_state_at::<MainnetEIP160Patch>(Vec::new(), 0);
+        match state.unlock_account(key.clone(), Some(Duration::from_secs(u64::max_value()))) {
+            Err(Error::InvalidParams) => (),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(state.unlock_account(key, Some(Duration::from_secs(300))).is_ok());
+    }
+
+    #[test]
+    fn fork_choice_switches_to_the_longest_branch() {
+        use sputnikvm_network_classic::MainnetEIP160Patch;
+
+        let mut state = make_state_at::<MainnetEIP160Patch>(Vec::new(), 0);
+        let first = create_side_chain(&mut state, 0).unwrap();
+        let first = extend_side_chain::<MainnetEIP160Patch>(&mut state, first, 2).unwrap();
+        assert!(first.canonical);
+
+        let second = create_side_chain(&mut state, 0).unwrap();
+        let head = extend_side_chain::<MainnetEIP160Patch>(&mut state, second, 2).unwrap();
+        assert!(!head.canonical);
+        assert_eq!(state.current_block().header.header_hash(), first.hash);
+
+        let head = extend_side_chain::<MainnetEIP160Patch>(&mut state, second, 1).unwrap();
+        assert!(head.canonical);
+        assert_eq!(state.block_height(), 3);
+        assert!(!state.is_canonical(first.hash));
+        assert!(state.get_block_by_hash(first.hash).is_ok());
+    }
+
+    #[test]
+    fn ommers_must_branch_off_recent_ancestors() {
+        use sputnikvm_network_classic::MainnetEIP160Patch;
+
+        let state = Arc::new(RwLock::new(make_state_at::<MainnetEIP160Patch>(Vec::new(), 0)));
+        for timestamp in 1..10 {
+            mine_one_at::<MainnetEIP160Patch>(state.clone(), Address::default(), timestamp);
+        }
+        let state = state.read().unwrap();
+        let head = state.current_block();
+        let ommer_of = |number: usize| BlockBuilder::new(&state.get_block_by_number(number))
+            .header(|header| header.mix_hash(H256::from(1u64)))
+            .build().header;
+        let with_ommers = |ommers: Vec<Header>| {
+            let block = ommers.into_iter().fold(BlockBuilder::new(&head), |block, ommer| block.ommer(ommer));
+            validate_ommers(&state, &block.build())
+        };
+
+        assert!(with_ommers(vec![ommer_of(8), ommer_of(3)]).is_ok());
+        assert!(with_ommers(vec![ommer_of(9)]).is_err());
+        assert!(with_ommers(vec![ommer_of(2)]).is_err());
+        assert!(with_ommers(vec![ommer_of(7), ommer_of(7)]).is_err());
+        assert!(with_ommers(vec![ommer_of(5), ommer_of(6), ommer_of(7)]).is_err());
+        assert!(with_ommers(vec![state.get_block_by_number(8).header]).is_err());
+    }
+
+    #[test]
+    fn receipts_hold_cumulative_gas() {
+        use block::UnsignedTransaction;
+        use sputnikvm_network_classic::MainnetEIP160Patch;
+
+        let key = SecretKey::from_slice(&SECP256K1, &[1u8; 32]).unwrap();
+        let mut state = make_state_at::<MainnetEIP160Patch>(vec![(key.clone(), U256::from(1_000_000u64))], 0);
+        for nonce in 0..2u64 {
+            state.append_pending_transaction(sign_transaction(UnsignedTransaction {
+                nonce: U256::from(nonce),
+                gas_price: Gas::zero(),
+                gas_limit: Gas::from(21000u64),
+                action: TransactionAction::Call(Address::from(0x10u64)),
+                value: U256::from(1000u64),
+                input: Vec::new(),
+            }, &key, None)).unwrap();
+        }
+        let state = Arc::new(RwLock::new(state));
+        mine_one_at::<MainnetEIP160Patch>(state.clone(), Address::default(), 1);
+
+        let state = state.read().unwrap();
+        let block = state.current_block();
+        let receipts: Vec<Receipt> = block.transactions.iter()
+            .map(|transaction| state.get_receipt_by_transaction_hash(transaction.rlp_hash()).unwrap())
+            .collect();
+        assert_eq!(receipts[0].used_gas, Gas::from(21000u64));
+        assert_eq!(receipts[1].used_gas, Gas::from(42000u64));
+        assert_eq!(transaction_gas_used(&receipts[1], Some(&receipts[0])), Gas::from(21000u64));
+        assert_eq!(block.header.gas_used, block_gas_used(&receipts));
+        assert_eq!(block.header.receipts_root,
+                   receipts_root(&consensus_receipts::<MainnetEIP160Patch>(&state, &block.transactions, &receipts)));
+    }
 }
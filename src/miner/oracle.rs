@@ -0,0 +1,75 @@
+use bigint::Gas;
+
+use super::MinerState;
+
+/// Default number of most recent blocks sampled by the oracle.
+pub const DEFAULT_ORACLE_BLOCKS: usize = 20;
+
+/// Default percentile of the sampled gas prices suggested.
+pub const DEFAULT_ORACLE_PERCENTILE: usize = 60;
+
+/// Suggest a gas price from the transactions of recent blocks, for
+/// `eth_gasPrice`.
+#[derive(Clone, Copy, Debug)]
+pub struct GasPriceOracle {
+    pub blocks: usize,
+    pub percentile: usize,
+}
+
+impl Default for GasPriceOracle {
+    fn default() -> Self {
+        GasPriceOracle {
+            blocks: DEFAULT_ORACLE_BLOCKS,
+            percentile: DEFAULT_ORACLE_PERCENTILE,
+        }
+    }
+}
+
+impl GasPriceOracle {
+    /// The configured percentile of the gas prices of transactions in
+    /// the last `blocks` blocks, never below the minimum gas price of
+    /// the node, which is also suggested when those blocks are empty.
+    pub fn suggest(&self, state: &MinerState) -> Gas {
+        let height = state.block_height();
+        let from = (height + 1).saturating_sub(self.blocks).max(1);
+
+        let mut prices = Vec::new();
+        for number in from..(height + 1) {
+            let block = state.get_block_by_number(number);
+            prices.extend(block.transactions.iter().map(|transaction| transaction.gas_price));
+        }
+
+        match percentile(&mut prices, self.percentile) {
+            Some(price) if price > state.min_gas_price() => price,
+            _ => state.min_gas_price(),
+        }
+    }
+}
+
+/// Value at `percentile` of `prices`, rounding down to the closest
+/// sample.
+pub fn percentile(prices: &mut [Gas], percentile: usize) -> Option<Gas> {
+    if prices.is_empty() {
+        return None;
+    }
+
+    prices.sort();
+    let index = (prices.len() - 1) * percentile.min(100) / 100;
+    Some(prices[index])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentile_of_samples() {
+        let mut prices: Vec<Gas> = [5u64, 1, 4, 2, 3].iter().map(|&price| Gas::from(price)).collect();
+        assert_eq!(percentile(&mut prices, 0), Some(Gas::from(1u64)));
+        assert_eq!(percentile(&mut prices, 50), Some(Gas::from(3u64)));
+        assert_eq!(percentile(&mut prices, 60), Some(Gas::from(3u64)));
+        assert_eq!(percentile(&mut prices, 100), Some(Gas::from(5u64)));
+        assert_eq!(percentile(&mut prices, 250), Some(Gas::from(5u64)));
+        assert_eq!(percentile(&mut [], 60), None);
+    }
+}
@@ -0,0 +1,61 @@
+use bigint::{H256, U256};
+use rlp::{Encodable, Decodable};
+use trie::{MemoryDatabase, Database, FixedTrie};
+
+/// Build the trie of `items` keyed by the RLP of their index, as the
+/// transactions and receipts tries of a block, and return its root.
+/// Items come from a slice and are inserted by increasing index. The
+/// node layout of a Merkle Patricia trie only depends on its keys and
+/// values, so the root and nodes do not depend on the iteration order
+/// of the hash map backing `database` either.
+pub fn build_ordered_trie<T: Encodable + Decodable + Clone>(database: &MemoryDatabase, items: &[T]) -> H256 {
+    let mut trie: FixedTrie<_, U256, T> = database.create_fixed_trie(database.create_empty().root());
+    for (index, item) in items.iter().enumerate() {
+        trie.insert(U256::from(index), item.clone());
+    }
+    trie.root()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use block::{Transaction, transactions_root};
+    use hexutil::read_hex;
+    use super::super::proof::prove;
+
+    fn items() -> Vec<Vec<u8>> {
+        (0..40u64).map(|i| vec![i as u8; (i as usize % 7) * 10]).collect()
+    }
+
+    #[test]
+    fn root_does_not_depend_on_insertion_order() {
+        let items = items();
+
+        let ordered = MemoryDatabase::default();
+        let root = build_ordered_trie(&ordered, &items);
+
+        let shuffled = MemoryDatabase::default();
+        let mut trie: FixedTrie<_, U256, Vec<u8>> = shuffled.create_fixed_trie(shuffled.create_empty().root());
+        for index in (0..items.len()).rev().filter(|i| i % 2 == 0).chain((0..items.len()).filter(|i| i % 2 == 1)) {
+            trie.insert(U256::from(index), items[index].clone());
+        }
+        assert_eq!(trie.root(), root);
+
+        for index in 0..items.len() {
+            let key = ::rlp::encode(&U256::from(index)).to_vec();
+            assert_eq!(prove(&ordered, root, &key).unwrap(), prove(&shuffled, root, &key).unwrap());
+        }
+    }
+
+    #[test]
+    fn root_is_stable_across_runs() {
+        let empty: Vec<Transaction> = Vec::new();
+        let expected = H256::from(read_hex("0x56e81f171bcc55a6ff8345e692c0f86e5b48e01b996cadc001622fb5e363b421").unwrap().as_slice());
+        assert_eq!(build_ordered_trie(&MemoryDatabase::default(), &empty), expected);
+        assert_eq!(transactions_root(&empty), expected);
+
+        let items = items();
+        assert_eq!(build_ordered_trie(&MemoryDatabase::default(), &items),
+                   build_ordered_trie(&MemoryDatabase::default(), &items));
+    }
+}
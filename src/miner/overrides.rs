@@ -0,0 +1,88 @@
+use bigint::{H256, M256, U256, Address};
+use block::Account;
+use sputnikvm::{AccountCommitment, HeaderParams, Patch, SeqTransactionVM, ValidTransaction, VM, VMStatus};
+use sputnikvm::errors::RequireError;
+use sputnikvm_stateful::MemoryStateful;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use error::Error;
+use super::fork::{Fork, fulfill};
+use super::account_cache::AccountCache;
+
+/// Replacement of an account for the duration of a call. Fields left
+/// as `None` keep the value of the state the call runs on.
+#[derive(Clone, Debug, Default)]
+pub struct AccountOverride {
+    pub balance: Option<U256>,
+    pub nonce: Option<U256>,
+    pub code: Option<Vec<u8>>,
+    /// Whole storage of the account, every other slot reading as zero.
+    pub state: Option<HashMap<U256, M256>>,
+    /// Slots to change, keeping the rest of the storage.
+    pub state_diff: Option<HashMap<U256, M256>>,
+}
+
+pub type StateOverrides = HashMap<Address, AccountOverride>;
+
+/// Execute a transaction like `call`, with the overridden accounts
+/// committed to the VM before it runs, so that they are never read
+/// from `stateful`.
+pub fn call_with_overrides<P: Patch>(
+    fork: Option<&Fork>, cache: &AccountCache, stateful: &mut MemoryStateful<'static>, overrides: &StateOverrides,
+    valid: ValidTransaction, header: &HeaderParams, last_hashes: &[H256]
+) -> Result<SeqTransactionVM<P>, Error> {
+    let mut vm = SeqTransactionVM::<P>::new(valid, header.clone());
+
+    for (address, value) in overrides {
+        let address = *address;
+        if value.state.is_some() && value.state_diff.is_some() {
+            return Err(Error::InvalidParams);
+        }
+
+        if let Some(fork) = fork {
+            fork.materialize_account(stateful, address)?;
+        }
+        let account: Option<Account> = stateful.state_of(stateful.root()).get(&address);
+        let (nonce, balance, code) = match account {
+            Some(account) => (account.nonce, account.balance,
+                              stateful.code(account.code_hash).unwrap_or(Vec::new())),
+            None => (U256::zero(), U256::zero(), Vec::new()),
+        };
+
+        vm.commit_account(AccountCommitment::Full {
+            nonce: value.nonce.unwrap_or(nonce),
+            address,
+            balance: value.balance.unwrap_or(balance),
+            code: Rc::new(value.code.clone().unwrap_or(code)),
+        }).map_err(|_| Error::InvalidParams)?;
+
+        let storage = value.state.as_ref().or(value.state_diff.as_ref());
+        for (index, value) in storage.into_iter().flat_map(|storage| storage.iter()) {
+            vm.commit_account(AccountCommitment::Storage {
+                address, index: *index, value: *value,
+            }).map_err(|_| Error::InvalidParams)?;
+        }
+    }
+
+    loop {
+        match vm.status() {
+            VMStatus::Running => (),
+            _ => break,
+        }
+
+        match vm.step() {
+            Ok(()) => (),
+            Err(RequireError::AccountStorage(address, index))
+                if overrides.get(&address).map(|value| value.state.is_some()).unwrap_or(false) =>
+            {
+                vm.commit_account(AccountCommitment::Storage {
+                    address, index, value: M256::zero(),
+                }).unwrap();
+            },
+            Err(require) => fulfill(fork, cache, stateful, &mut vm, require, header, last_hashes)?,
+        }
+    }
+
+    Ok(vm)
+}
@@ -0,0 +1,390 @@
+//! Parallel execution of the transactions of a mined block.
+//! Transactions whose senders and recipients are disjoint are executed
+//! at once on the same state, recording the accounts and storage slots
+//! each of them requires. They are then committed in block order; the
+//! first one that read something a previous one of the batch wrote is
+//! executed again, with the following ones, on top of the committed
+//! changes. The resulting block is the same as with sequential
+//! execution.
+
+use bigint::{Address, Gas, H256, M256, U256};
+use block::{Account, Block, Receipt, Transaction, TransactionAction};
+use sputnikvm::{AccountChange, HeaderParams, Patch, SeqTransactionVM, ValidTransaction, VM, VMStatus};
+use sputnikvm::errors::RequireError;
+use sputnikvm_stateful::MemoryStateful;
+use futures_cpupool::CpuPool;
+use jsonrpc_core::futures::Future;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::panic::{self, AssertUnwindSafe};
+
+use error::Error;
+use super::{MinerState, ExecutedBlock, commit_transaction, contract_address, skip_transaction, valid_transaction};
+use super::fork;
+use super::patch::with_memory_limit;
+use super::account_cache::AccountCache;
+
+/// Accounts and storage slots read or written by a transaction.
+#[derive(Default, Debug)]
+struct Footprint {
+    accounts: HashSet<Address>,
+    storage: HashSet<(Address, U256)>,
+}
+
+impl Footprint {
+    fn require(&mut self, require: &RequireError) {
+        match *require {
+            RequireError::Account(address) |
+            RequireError::AccountCode(address) => { self.accounts.insert(address); },
+            RequireError::AccountStorage(address, index) => { self.storage.insert((address, index)); },
+            RequireError::Blockhash(_) => (),
+        }
+    }
+
+    /// Record committed changes, on top of the state `base` they were
+    /// executed on. An account whose storage alone changed is not
+    /// written as a whole.
+    fn write(&mut self, base: &MemoryStateful, accounts: &[AccountChange]) {
+        let trie = base.state_of(base.root());
+        for account in accounts {
+            match *account {
+                AccountChange::Full { address, nonce, balance, ref changing_storage, .. } => {
+                    let changing_storage: HashMap<U256, M256> = changing_storage.clone().into();
+                    self.storage.extend(changing_storage.keys().map(|index| (address, *index)));
+
+                    let existing: Option<Account> = trie.get(&address);
+                    let unchanged = existing.map(|existing| existing.nonce == nonce && existing.balance == balance)
+                        .unwrap_or(false);
+                    if !unchanged {
+                        self.accounts.insert(address);
+                    }
+                },
+                AccountChange::Create { address, .. } |
+                AccountChange::IncreaseBalance(address, _) |
+                AccountChange::Nonexist(address) => { self.accounts.insert(address); },
+            }
+        }
+    }
+
+    /// Whether what `reads` read was written here.
+    fn conflicts(&self, reads: &Footprint) -> bool {
+        !self.accounts.is_disjoint(&reads.accounts) || !self.storage.is_disjoint(&reads.storage)
+    }
+}
+
+/// Moves a transaction, and the state it is executed on, to a worker
+/// thread.
+struct Input(MemoryStateful<'static>, ValidTransaction);
+
+// SAFETY: the only `Rc` in an `Input` is the `input` of the
+// transaction. `valid_transaction` creates it for this execution, and
+// it is moved into the `Input` without being cloned, so no other
+// reference to it is left on the mining thread. The state is a handle
+// of its own on the shared database, which is `Sync`.
+unsafe impl Send for Input { }
+
+/// Moves the VM that executed a transaction back from its worker.
+struct Output<P: Patch>(SeqTransactionVM<P>, Footprint);
+
+// SAFETY: the `Rc`s of the VM are the `input` moved in with the
+// `Input`, and the code the worker committed to it from its own state.
+// `execute` drops that state, and with it every other reference to
+// them, before the VM is wrapped into an `Output`, and the worker keeps
+// nothing of the task once it is done. The mining thread only reads the
+// VM after waiting for the task, so the `Rc`s are never used on two
+// threads at once.
+unsafe impl<P: Patch> Send for Output<P> { }
+
+/// Accounts a transaction is known to touch before it is executed: its
+/// sender and its recipient, or the contract it creates.
+fn static_footprint(state: &MinerState, transaction: &Transaction) -> Vec<Address> {
+    let mut addresses = Vec::new();
+    let sender = state.transaction_sender(transaction).ok();
+    if let Some(sender) = sender {
+        addresses.push(sender);
+    }
+    match transaction.action {
+        TransactionAction::Call(to) => addresses.push(to),
+        TransactionAction::Create => if let Some(sender) = sender {
+            addresses.push(contract_address(sender, transaction.nonce));
+        },
+    }
+    addresses
+}
+
+fn execute<P: 'static + Patch>(
+    mut stateful: MemoryStateful<'static>, cache: AccountCache, valid: ValidTransaction,
+    header: HeaderParams, last_hashes: Vec<H256>
+) -> (SeqTransactionVM<P>, Footprint) {
+    let mut reads = Footprint::default();
+    if let Some(caller) = valid.caller {
+        reads.accounts.insert(caller);
+    }
+
+    let mut vm = SeqTransactionVM::<P>::new(valid, header.clone());
+    loop {
+        match vm.status() {
+            VMStatus::Running => (),
+            _ => break,
+        }
+
+        match vm.step() {
+            Ok(()) => (),
+            Err(require) => {
+                reads.require(&require);
+                // Only a fork can fail to fulfill a requirement.
+                fork::fulfill(None, &cache, &mut stateful, &mut vm, require, &header, &last_hashes)
+                    .expect("fulfilled without a fork");
+            },
+        }
+    }
+    (vm, reads)
+}
+
+/// Up to `workers` pending transactions no two of which are known to
+/// touch the same account.
+fn next_batch(state: &MinerState, pending: &mut VecDeque<Transaction>, workers: usize) -> Vec<Transaction> {
+    let mut batch = Vec::new();
+    let mut touched = HashSet::new();
+    while batch.len() < workers {
+        let footprint = match pending.front() {
+            Some(transaction) => static_footprint(state, transaction),
+            None => break,
+        };
+        if footprint.iter().any(|address| touched.contains(address)) {
+            break;
+        }
+        touched.extend(footprint);
+        batch.push(pending.pop_front().unwrap());
+    }
+    batch
+}
+
+struct Executed<P: Patch> {
+    vm: SeqTransactionVM<P>,
+    reads: Footprint,
+    caller: Address,
+    gas_price: Gas,
+    nonce: U256,
+}
+
+/// Execute `transactions` as `execute_transactions` does, up to
+/// `workers` of them at once on the threads of `pool`. Requires a state
+/// without a fork or a tracer, which are not shared between threads.
+pub fn execute_transactions<P: 'static + Patch>(
+    state: &mut MinerState, current_block: &Block, transactions: Vec<Transaction>,
+    skip_invalid: bool, pool: &CpuPool, workers: usize
+) -> Result<(Vec<Transaction>, Vec<Receipt>), Error> {
+    let header = HeaderParams::from(&current_block.header);
+    let block_hashes = state.get_last_256_block_hashes();
+    let memory_limit = state.memory_limit();
+    let mut block = ExecutedBlock::new();
+
+    state.fat_transit(current_block.header.number.as_usize(), &[]);
+
+    let mut pending: VecDeque<Transaction> = transactions.into_iter().collect();
+    while !pending.is_empty() {
+        let batch = next_batch(state, &mut pending, workers);
+        let base = state.stateful_at(state.stateful().root());
+
+        let mut tasks = Vec::new();
+        for transaction in &batch {
+            tasks.push(match valid_transaction::<P>(state, &base, transaction) {
+                Ok(valid) => {
+                    let caller = valid.caller.unwrap_or(Address::default());
+                    let gas_price = valid.gas_price;
+                    let nonce = valid.nonce;
+                    let input = Input(state.stateful_at(base.root()), valid);
+                    let cache = state.account_cache().clone();
+                    let header = header.clone();
+                    let block_hashes = block_hashes.clone();
+                    // A panic is caught here, as the pool would resume it
+                    // on the mining thread.
+                    let task = pool.spawn_fn(move || panic::catch_unwind(AssertUnwindSafe(move || {
+                        let Input(stateful, valid) = input;
+                        let (vm, reads) = with_memory_limit(memory_limit, || {
+                            execute::<P>(stateful, cache, valid, header, block_hashes)
+                        });
+                        Output(vm, reads)
+                    })).map_err(|_| ()));
+                    Ok((task, caller, gas_price, nonce))
+                },
+                Err(err) => Err(err),
+            });
+        }
+
+        let mut results = Vec::new();
+        for task in tasks {
+            results.push(match task {
+                Ok((task, caller, gas_price, nonce)) => {
+                    let Output(vm, reads) = task.wait()
+                        .map_err(|_| Error::Internal("transaction execution worker panicked"))?;
+                    Ok(Executed { vm, reads, caller, gas_price, nonce })
+                },
+                Err(err) => Err(err),
+            });
+        }
+
+        // Commit in block order, until a transaction read what one
+        // before it wrote. That one and those after it run again.
+        let mut written = Footprint::default();
+        let mut results = batch.into_iter().zip(results);
+        while let Some((transaction, result)) = results.next() {
+            let conflicts = match result {
+                Ok(ref executed) => written.conflicts(&executed.reads),
+                // Validation only read the sender.
+                Err(_) => state.transaction_sender(&transaction)
+                    .map(|sender| written.accounts.contains(&sender))
+                    .unwrap_or(false),
+            };
+            if conflicts {
+                let rerun: Vec<Transaction> = Some(transaction).into_iter()
+                    .chain(results.by_ref().map(|(transaction, _)| transaction))
+                    .collect();
+                for transaction in rerun.into_iter().rev() {
+                    pending.push_front(transaction);
+                }
+                break;
+            }
+
+            let failed = match result {
+                Ok(Executed { vm, caller, gas_price, nonce, .. }) => {
+                    match commit_transaction::<P>(state, current_block, &mut block, transaction,
+                                                  caller, gas_price, nonce, vm) {
+                        Ok(accounts) => {
+                            written.write(&base, &accounts);
+                            continue;
+                        },
+                        Err(failed) => failed,
+                    }
+                },
+                Err(err) => (err, transaction),
+            };
+            let (err, transaction) = failed;
+            if !skip_invalid {
+                return Err(err);
+            }
+            skip_transaction(state, &transaction, err);
+        }
+    }
+
+    Ok((block.transactions, block.receipts))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use block::UnsignedTransaction;
+    use secp256k1::SECP256K1;
+    use secp256k1::key::SecretKey;
+    use sputnikvm_network_classic::MainnetEIP160Patch;
+    use std::sync::{Arc, RwLock};
+    use super::super::{make_state_at, mine_one_at, sign_transaction};
+
+    #[test]
+    fn reads_conflict_with_writes_of_the_same_slot_only() {
+        let address = Address::from(1u64);
+        let mut written = Footprint::default();
+        written.storage.insert((address, U256::zero()));
+
+        let mut reads = Footprint::default();
+        reads.accounts.insert(address);
+        reads.storage.insert((address, U256::one()));
+        assert!(!written.conflicts(&reads));
+
+        reads.require(&RequireError::AccountStorage(address, U256::zero()));
+        assert!(written.conflicts(&reads));
+    }
+
+    fn transfer(secret_key: &SecretKey, nonce: u64, to: Address) -> Transaction {
+        sign_transaction(UnsignedTransaction {
+            nonce: U256::from(nonce),
+            gas_price: Gas::zero(),
+            gas_limit: Gas::from(21000u64),
+            action: TransactionAction::Call(to),
+            value: U256::from(1000u64),
+            input: Vec::new(),
+        }, secret_key, None)
+    }
+
+    fn mine_transfers(workers: usize) -> (H256, usize) {
+        let keys: Vec<SecretKey> = (1..4u8)
+            .map(|byte| SecretKey::from_slice(&SECP256K1, &[byte; 32]).unwrap())
+            .collect();
+        let genesis = keys.iter().map(|key| (key.clone(), U256::from(1_000_000u64))).collect();
+        let mut state = make_state_at::<MainnetEIP160Patch>(genesis, 0);
+        state.set_execution_workers(workers);
+
+        let first = Address::from(0x10u64);
+        let sender = state.transaction_sender(&transfer(&keys[0], 0, first)).unwrap();
+        for transaction in vec![
+            transfer(&keys[0], 0, first),
+            transfer(&keys[1], 0, Address::from(0x11u64)),
+            transfer(&keys[2], 0, sender),
+            transfer(&keys[0], 1, first),
+        ] {
+            state.append_pending_transaction(transaction).unwrap();
+        }
+
+        let state = Arc::new(RwLock::new(state));
+        mine_one_at::<MainnetEIP160Patch>(state.clone(), Address::default(), 1);
+        let state = state.read().unwrap();
+        let block = state.current_block();
+        (block.header.state_root, block.transactions.len())
+    }
+
+    #[test]
+    fn parallel_execution_mines_the_same_block() {
+        let sequential = mine_transfers(1);
+        assert_eq!(sequential.1, 4);
+        assert_eq!(mine_transfers(4), sequential);
+    }
+
+    fn call(secret_key: &SecretKey, to: Address) -> Transaction {
+        sign_transaction(UnsignedTransaction {
+            nonce: U256::zero(),
+            gas_price: Gas::zero(),
+            gas_limit: Gas::from(100000u64),
+            action: TransactionAction::Call(to),
+            value: U256::zero(),
+            input: Vec::new(),
+        }, secret_key, None)
+    }
+
+    /// Two calls of the same batch incrementing a counter, the second
+    /// one through another contract, so that it reads the slot the
+    /// first one writes without the batch knowing it up front.
+    fn mine_counter_calls(workers: usize) -> (H256, usize) {
+        let keys: Vec<SecretKey> = (1..3u8)
+            .map(|byte| SecretKey::from_slice(&SECP256K1, &[byte; 32]).unwrap())
+            .collect();
+        let genesis = keys.iter().map(|key| (key.clone(), U256::from(1_000_000u64))).collect();
+        let mut state = make_state_at::<MainnetEIP160Patch>(genesis, 0);
+        state.set_execution_workers(workers);
+
+        let counter = Address::from(0x20u64);
+        let proxy = Address::from(0x21u64);
+        // SSTORE(0, SLOAD(0) + 1)
+        state.set_code(counter, vec![0x60, 0x00, 0x54, 0x60, 0x01, 0x01, 0x60, 0x00, 0x55, 0x00]);
+        // CALL(GAS, counter, 0, 0, 0, 0, 0)
+        let mut code = vec![0x60, 0x00, 0x60, 0x00, 0x60, 0x00, 0x60, 0x00, 0x60, 0x00, 0x73];
+        code.extend_from_slice(&counter[..]);
+        code.extend_from_slice(&[0x5a, 0xf1, 0x50, 0x00]);
+        state.set_code(proxy, code);
+
+        state.append_pending_transaction(call(&keys[0], counter)).unwrap();
+        state.append_pending_transaction(call(&keys[1], proxy)).unwrap();
+
+        let state = Arc::new(RwLock::new(state));
+        mine_one_at::<MainnetEIP160Patch>(state.clone(), Address::default(), 1);
+        let state = state.read().unwrap();
+        let block = state.current_block();
+        (block.header.state_root, block.transactions.len())
+    }
+
+    #[test]
+    fn storage_read_after_write_is_executed_again() {
+        let sequential = mine_counter_calls(1);
+        assert_eq!(sequential.1, 2);
+        assert_eq!(mine_counter_calls(2), sequential);
+    }
+}
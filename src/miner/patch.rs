@@ -0,0 +1,178 @@
+use bigint::{Address, Gas, H256};
+use block::{Transaction, TransactionSignature, UnsignedTransaction};
+use rlp::RlpStream;
+use secp256k1::{SECP256K1, Message};
+use secp256k1::key::SecretKey;
+use sha3::{Digest, Keccak256};
+use sputnikvm::{Patch, Precompiled};
+use std::cell::Cell;
+use std::marker::PhantomData;
+
+thread_local! {
+    static MEMORY_LIMIT: Cell<usize> = Cell::new(0);
+}
+
+/// Puts the previous limit back, even if the scoped call panics.
+struct MemoryLimitGuard(usize);
+
+impl Drop for MemoryLimitGuard {
+    fn drop(&mut self) {
+        let previous = self.0;
+        MEMORY_LIMIT.with(|limit| limit.set(previous));
+    }
+}
+
+/// Run `f` with the VM memory limit, in bytes, of the node executing on
+/// the current thread. `None` keeps the limit of the patch. Patches are
+/// types, so `DevPatch` cannot be handed the limit of a node otherwise.
+pub fn with_memory_limit<T, F: FnOnce() -> T>(limit: Option<usize>, f: F) -> T {
+    let _guard = MemoryLimitGuard(MEMORY_LIMIT.with(|current| current.replace(limit.unwrap_or(0))));
+    f()
+}
+
+/// Sign a transaction for `chain_id` as EIP-155 describes, or without
+/// a chain id if `None`. This is `UnsignedTransaction::sign` with the
+/// chain id of a node instead of that of a `SignaturePatch`.
+pub fn sign_transaction(unsigned: UnsignedTransaction, secret_key: &SecretKey, chain_id: Option<u64>) -> Transaction {
+    let mut stream = RlpStream::new_list(if chain_id.is_some() { 9 } else { 6 });
+    stream.append(&unsigned.nonce);
+    stream.append(&unsigned.gas_price);
+    stream.append(&unsigned.gas_limit);
+    stream.append(&unsigned.action);
+    stream.append(&unsigned.value);
+    stream.append(&unsigned.input);
+    if let Some(chain_id) = chain_id {
+        stream.append(&chain_id);
+        stream.append(&0u8);
+        stream.append(&0u8);
+    }
+    let hash = H256::from(Keccak256::digest(&stream.out()).as_slice());
+
+    let signature = SECP256K1.sign_recoverable(&Message::from_slice(&hash).unwrap(), secret_key).unwrap();
+    let (recovery, signature) = signature.serialize_compact(&SECP256K1);
+    let v = match chain_id {
+        Some(chain_id) => 35 + chain_id * 2,
+        None => 27,
+    } + recovery.to_i32() as u64;
+
+    Transaction {
+        nonce: unsigned.nonce,
+        gas_price: unsigned.gas_price,
+        gas_limit: unsigned.gas_limit,
+        action: unsigned.action,
+        value: unsigned.value,
+        input: unsigned.input,
+        signature: TransactionSignature {
+            v,
+            r: H256::from(&signature[0..32]),
+            s: H256::from(&signature[32..64]),
+        },
+    }
+}
+
+/// Chain id a signature commits to, given its `v`, or `None` for a
+/// signature made before EIP-155.
+pub fn signature_chain_id(v: u64) -> Option<u64> {
+    if v >= 35 {
+        Some((v - 35) / 2)
+    } else {
+        None
+    }
+}
+
+/// Whether `P` includes the Byzantium changes. Patches do not name
+/// their hard fork, so this is told by the opcodes it introduced.
+pub fn is_byzantium<P: Patch>() -> bool {
+    P::has_revert() && P::has_return_data() && P::has_static_call()
+}
+
+/// Patch `P` with the memory limit of the node applied on top, see
+/// `with_memory_limit`.
+pub struct DevPatch<P: Patch>(PhantomData<P>);
+
+impl<P: Patch> Patch for DevPatch<P> {
+    type Account = P::Account;
+
+    fn code_deposit_limit() -> Option<usize> { P::code_deposit_limit() }
+    fn callstack_limit() -> usize { P::callstack_limit() }
+    fn gas_extcode() -> Gas { P::gas_extcode() }
+    fn gas_balance() -> Gas { P::gas_balance() }
+    fn gas_sload() -> Gas { P::gas_sload() }
+    fn gas_suicide() -> Gas { P::gas_suicide() }
+    fn gas_suicide_new_account() -> Gas { P::gas_suicide_new_account() }
+    fn gas_call() -> Gas { P::gas_call() }
+    fn gas_expbyte() -> Gas { P::gas_expbyte() }
+    fn gas_transaction_create() -> Gas { P::gas_transaction_create() }
+    fn force_code_deposit() -> bool { P::force_code_deposit() }
+    fn has_delegate_call() -> bool { P::has_delegate_call() }
+    fn has_static_call() -> bool { P::has_static_call() }
+    fn has_revert() -> bool { P::has_revert() }
+    fn has_return_data() -> bool { P::has_return_data() }
+    fn err_on_call_with_more_gas() -> bool { P::err_on_call_with_more_gas() }
+    fn call_create_l64_after_gas() -> bool { P::call_create_l64_after_gas() }
+    fn memory_limit() -> usize {
+        match MEMORY_LIMIT.with(|limit| limit.get()) {
+            0 => P::memory_limit(),
+            limit => limit,
+        }
+    }
+    fn precompileds() -> &'static [(Address, Option<&'static [u8]>, &'static Precompiled)] {
+        P::precompileds()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bigint::U256;
+    use block::{SignaturePatch, TransactionAction};
+
+    #[test]
+    fn chain_id_of_signatures() {
+        assert_eq!(signature_chain_id(27), None);
+        assert_eq!(signature_chain_id(28), None);
+        assert_eq!(signature_chain_id(37), Some(1));
+        assert_eq!(signature_chain_id(38), Some(1));
+        assert_eq!(signature_chain_id(2 * 1337 + 35), Some(1337));
+    }
+
+    struct ChainPatch;
+
+    impl SignaturePatch for ChainPatch {
+        fn chain_id() -> Option<u64> { Some(1337) }
+    }
+
+    struct NoChainPatch;
+
+    impl SignaturePatch for NoChainPatch {
+        fn chain_id() -> Option<u64> { None }
+    }
+
+    fn unsigned() -> UnsignedTransaction {
+        UnsignedTransaction {
+            nonce: U256::from(3u64),
+            gas_price: Gas::from(20u64),
+            gas_limit: Gas::from(21000u64),
+            action: TransactionAction::Call(Address::from(0x10u64)),
+            value: U256::from(1000u64),
+            input: vec![1, 2, 3],
+        }
+    }
+
+    #[test]
+    fn signs_as_signature_patches() {
+        let key = SecretKey::from_slice(&SECP256K1, &[1u8; 32]).unwrap();
+        assert_eq!(sign_transaction(unsigned(), &key, Some(1337)), unsigned().sign::<ChainPatch>(&key));
+        assert_eq!(sign_transaction(unsigned(), &key, None), unsigned().sign::<NoChainPatch>(&key));
+    }
+
+    #[test]
+    fn memory_limit_is_scoped_to_the_call() {
+        use sputnikvm_network_classic::MainnetEIP160Patch;
+
+        let default = DevPatch::<MainnetEIP160Patch>::memory_limit();
+        assert_eq!(with_memory_limit(Some(1024), || DevPatch::<MainnetEIP160Patch>::memory_limit()), 1024);
+        assert_eq!(with_memory_limit(None, || DevPatch::<MainnetEIP160Patch>::memory_limit()), default);
+        assert_eq!(DevPatch::<MainnetEIP160Patch>::memory_limit(), default);
+    }
+}
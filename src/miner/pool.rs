@@ -0,0 +1,379 @@
+use bigint::{H256, U256, Address, Gas};
+use block::Transaction;
+use sputnikvm::Patch;
+use sputnikvm_stateful::MemoryStateful;
+use std::collections::{BTreeMap, HashMap};
+
+use error::Error;
+use super::state::{MinerState, modify_account_at};
+use super::patch::signature_chain_id;
+use super::execution::to_valid;
+
+/// Default cap on the number of pooled transactions.
+pub const DEFAULT_POOL_SIZE: usize = 4096;
+
+/// Default cap on the number of pooled transactions of one sender.
+pub const DEFAULT_POOL_SENDER_SLOTS: usize = 64;
+
+#[derive(Clone, Copy, Debug)]
+struct PoolEntry {
+    sequence: usize,
+    hash: H256,
+    gas_price: Gas,
+}
+
+/// Transactions waiting to be mined, by sender and nonce. A transaction
+/// is pending once all lower nonces of its sender are mined or pending,
+/// and queued while there is a gap before it.
+pub struct TransactionPool {
+    next_sequence: usize,
+    senders: HashMap<Address, BTreeMap<U256, PoolEntry>>,
+}
+
+impl TransactionPool {
+    pub fn new() -> Self {
+        TransactionPool {
+            next_sequence: 0,
+            senders: HashMap::new(),
+        }
+    }
+
+    /// Add a transaction, replacing the one of the same sender and
+    /// nonce if any, which is returned.
+    pub fn insert(&mut self, sender: Address, nonce: U256, gas_price: Gas, hash: H256) -> Option<H256> {
+        let sequence = self.next_sequence;
+        self.next_sequence += 1;
+
+        self.senders.entry(sender).or_insert(BTreeMap::new())
+            .insert(nonce, PoolEntry { sequence, hash, gas_price }).map(|entry| entry.hash)
+    }
+
+    pub fn get(&self, sender: Address, nonce: U256) -> Option<H256> {
+        self.senders.get(&sender).and_then(|transactions| transactions.get(&nonce))
+            .map(|entry| entry.hash)
+    }
+
+    pub fn senders(&self) -> Vec<Address> {
+        self.senders.keys().cloned().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.senders.values().map(|transactions| transactions.len()).sum()
+    }
+
+    pub fn sender_len(&self, sender: Address) -> usize {
+        self.senders.get(&sender).map(|transactions| transactions.len()).unwrap_or(0)
+    }
+
+    /// Highest nonce pooled for `sender`.
+    pub fn highest_nonce(&self, sender: Address) -> Option<U256> {
+        self.senders.get(&sender).and_then(|transactions| transactions.keys().next_back().cloned())
+    }
+
+    /// Gas price of the transaction evicted first, of one sender or of
+    /// the whole pool.
+    pub fn lowest_gas_price(&self, sender: Option<Address>) -> Option<Gas> {
+        self.eviction_candidate(sender).map(|(_, entry)| entry.gas_price)
+    }
+
+    /// The lowest gas price transaction, the oldest one among equals,
+    /// of those with the highest nonce of their sender, so that a
+    /// sender never loses a nonce its later transactions wait for.
+    fn eviction_candidate(&self, sender: Option<Address>) -> Option<(Address, PoolEntry)> {
+        self.senders.iter()
+            .filter(|&(address, _)| sender.map(|sender| sender == *address).unwrap_or(true))
+            .filter_map(|(address, transactions)| transactions.values().next_back().map(|entry| (*address, *entry)))
+            .min_by_key(|&(_, entry)| (entry.gas_price, entry.sequence))
+    }
+
+    /// Evict transactions until every sender has at most `sender_slots`
+    /// of them and the pool at most `size`, returning the evicted ones.
+    pub fn evict(&mut self, size: usize, sender_slots: usize) -> Vec<H256> {
+        let mut evicted = Vec::new();
+
+        let senders: Vec<Address> = self.senders.keys().cloned().collect();
+        for sender in senders {
+            while self.sender_len(sender) > sender_slots {
+                match self.eviction_candidate(Some(sender)) {
+                    Some((_, entry)) => {
+                        self.remove(&[entry.hash]);
+                        evicted.push(entry.hash);
+                    },
+                    None => break,
+                }
+            }
+        }
+        while self.len() > size {
+            match self.eviction_candidate(None) {
+                Some((_, entry)) => {
+                    self.remove(&[entry.hash]);
+                    evicted.push(entry.hash);
+                },
+                None => break,
+            }
+        }
+
+        evicted
+    }
+
+    /// Drop transactions whose nonce is already used on chain. Senders
+    /// whose nonce is unknown are kept as they are.
+    pub fn prune<F: Fn(Address) -> Option<U256>>(&mut self, nonce_of: F) {
+        for (sender, transactions) in self.senders.iter_mut() {
+            if let Some(nonce) = nonce_of(*sender) {
+                *transactions = transactions.split_off(&nonce);
+            }
+        }
+        self.senders.retain(|_, transactions| !transactions.is_empty());
+    }
+
+    /// Transactions executable in a row given the account nonces, in
+    /// submission order except that each sender's nonces come in
+    /// order. Senders whose nonce is unknown have none.
+    pub fn pending<F: Fn(Address) -> Option<U256>>(&self, nonce_of: F) -> Vec<H256> {
+        let mut runs: Vec<Vec<PoolEntry>> = Vec::new();
+        for (sender, transactions) in &self.senders {
+            let mut nonce = match nonce_of(*sender) {
+                Some(nonce) => nonce,
+                None => continue,
+            };
+            let mut run = Vec::new();
+            while let Some(&entry) = transactions.get(&nonce) {
+                run.push(entry);
+                nonce = nonce + U256::one();
+            }
+            if !run.is_empty() {
+                run.reverse();
+                runs.push(run);
+            }
+        }
+
+        let mut ret = Vec::new();
+        loop {
+            let next = runs.iter().enumerate()
+                .filter_map(|(index, run)| run.last().map(|entry| (entry.sequence, index)))
+                .min();
+            match next {
+                Some((_, index)) => ret.push(runs[index].pop().unwrap().hash),
+                None => return ret,
+            }
+        }
+    }
+
+    /// Nonce following the pooled transactions of `sender` that run in
+    /// a row from its account nonce `nonce`.
+    pub fn next_nonce(&self, sender: Address, nonce: U256) -> U256 {
+        let mut nonce = nonce;
+        if let Some(transactions) = self.senders.get(&sender) {
+            while transactions.contains_key(&nonce) {
+                nonce = nonce + U256::one();
+            }
+        }
+        nonce
+    }
+
+    /// Transactions waiting for a lower nonce of their sender, in
+    /// submission order.
+    pub fn queued<F: Fn(Address) -> Option<U256>>(&self, nonce_of: F) -> Vec<H256> {
+        let pending = self.pending(&nonce_of);
+        self.all().into_iter().filter(|hash| !pending.contains(hash)).collect()
+    }
+
+    /// All transactions in submission order.
+    pub fn all(&self) -> Vec<H256> {
+        let mut entries: Vec<(usize, H256)> = self.senders.values()
+            .flat_map(|transactions| transactions.values().map(|entry| (entry.sequence, entry.hash)))
+            .collect();
+        entries.sort();
+        entries.into_iter().map(|(_, hash)| hash).collect()
+    }
+
+    /// Remove the transactions whose gas price is not accepted,
+    /// returning them.
+    pub fn retain_gas_price<F: Fn(Gas) -> bool>(&mut self, accepts: F) -> Vec<H256> {
+        let rejected: Vec<H256> = self.senders.values()
+            .flat_map(|transactions| transactions.values())
+            .filter(|entry| !accepts(entry.gas_price))
+            .map(|entry| entry.hash).collect();
+        self.remove(&rejected);
+        rejected
+    }
+
+    pub fn remove(&mut self, hashes: &[H256]) {
+        for transactions in self.senders.values_mut() {
+            let nonces: Vec<U256> = transactions.iter()
+                .filter(|&(_, entry)| hashes.contains(&entry.hash))
+                .map(|(nonce, _)| *nonce).collect();
+            for nonce in nonces {
+                transactions.remove(&nonce);
+            }
+        }
+        self.senders.retain(|_, transactions| !transactions.is_empty());
+    }
+}
+
+/// Whether a transaction of `gas_price` may enter the pool and be
+/// mined, given the minimum gas price. Transactions with no gas price
+/// at all are let through with `allow_zero`.
+pub fn accepts_gas_price(gas_price: Gas, min_gas_price: Gas, allow_zero: bool) -> bool {
+    gas_price >= min_gas_price || (allow_zero && gas_price == Gas::zero())
+}
+
+/// Default percentage by which a replacement transaction must raise
+/// the gas price of the pooled one.
+pub const DEFAULT_PRICE_BUMP: usize = 10;
+
+/// Lowest gas price replacing a pooled transaction of gas price
+/// `gas_price`.
+pub fn replacement_gas_price(gas_price: Gas, price_bump: usize) -> Gas {
+    gas_price + gas_price * Gas::from(price_bump) / Gas::from(100u64)
+}
+
+/// Check the chain id a signature commits to against the one of the
+/// node. Signatures without a chain id are only accepted if
+/// `allow_unprotected` is set, or if the node has no chain id.
+pub fn check_chain_id(v: u64, chain_id: Option<u64>, allow_unprotected: bool) -> Result<(), Error> {
+    match (chain_id, signature_chain_id(v)) {
+        (Some(expected), Some(signed)) if signed != expected => Err(Error::CallError(
+            format!("invalid chain id {} for signer, expected {}", signed, expected))),
+        (Some(_), None) if !allow_unprotected => Err(Error::CallError(
+            "only replay-protected (EIP-155) transactions are accepted".to_string())),
+        _ => Ok(()),
+    }
+}
+
+/// Check a signed transaction before it enters the pool. The nonce may
+/// be ahead of the sender's, in which case the transaction is queued,
+/// so the rest of the checks run against the head state with the
+/// sender's nonce moved up to the transaction's. A transaction with
+/// the nonce of a pooled one replaces it if its gas price is higher by
+/// at least the price bump. When the pool or the sender's slots are
+/// full, the transaction must pay more than the one it evicts.
+pub fn validate_pooled<P: Patch>(state: &MinerState, transaction: &Transaction) -> Result<(), Error> {
+    check_chain_id(transaction.signature.v, state.chain_id(), state.allow_unprotected_transactions())?;
+
+    let sender = state.transaction_sender(transaction)?;
+    if transaction.nonce < state.account_nonce(sender)? {
+        return Err(Error::CallError("nonce too low".to_string()));
+    }
+    match state.pooled_transaction(sender, transaction.nonce) {
+        Some(hash) => {
+            let pooled = state.get_transaction_by_hash(hash)?;
+            let minimum = replacement_gas_price(pooled.gas_price, state.price_bump());
+            if transaction.gas_price <= pooled.gas_price || transaction.gas_price < minimum {
+                return Err(Error::CallError("replacement transaction underpriced".to_string()));
+            }
+        },
+        None => {
+            // A sender out of slots only gets a transaction in before its
+            // highest nonce, which is evicted, and a full pool only takes
+            // a transaction paying more than the one it would evict.
+            let pool = state.transaction_pool();
+            let outbid = |lowest: Option<Gas>| lowest.map(|lowest| transaction.gas_price > lowest).unwrap_or(true);
+            let below_highest = pool.highest_nonce(sender).map(|highest| transaction.nonce < highest).unwrap_or(false);
+            if pool.sender_len(sender) >= state.pool_sender_slots() && !below_highest {
+                return Err(Error::CallError("sender has too many pooled transactions".to_string()));
+            }
+            if pool.len() >= state.pool_size() && !outbid(pool.lowest_gas_price(None)) {
+                return Err(Error::CallError("transaction pool is full".to_string()));
+            }
+        },
+    }
+
+    let mut stateful = state.stateful_at(state.head_root());
+    if let Some(fork) = state.fork() {
+        fork.materialize_account(&mut stateful, sender)?;
+    }
+    let database = stateful.database();
+    let root = modify_account_at(database, stateful.root(), sender, |account| account.nonce = transaction.nonce);
+    to_valid::<P>(&MemoryStateful::new(database, root), transaction)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn minimum_gas_price() {
+        assert!(accepts_gas_price(Gas::from(2u64), Gas::from(2u64), false));
+        assert!(!accepts_gas_price(Gas::from(1u64), Gas::from(2u64), false));
+        assert!(!accepts_gas_price(Gas::zero(), Gas::from(2u64), false));
+        assert!(accepts_gas_price(Gas::zero(), Gas::from(2u64), true));
+        assert!(!accepts_gas_price(Gas::from(1u64), Gas::from(2u64), true));
+    }
+
+    #[test]
+    fn chain_id_must_match() {
+        assert!(check_chain_id(37, Some(1), false).is_ok());
+        assert!(check_chain_id(2 * 61 + 36, Some(1), false).is_err());
+        assert!(check_chain_id(27, Some(1), false).is_err());
+        assert!(check_chain_id(27, Some(1), true).is_ok());
+        assert!(check_chain_id(37, None, false).is_ok());
+        assert!(check_chain_id(28, None, false).is_ok());
+    }
+
+    #[test]
+    fn replacement_needs_price_bump() {
+        assert_eq!(replacement_gas_price(Gas::from(100u64), 10), Gas::from(110u64));
+        assert_eq!(replacement_gas_price(Gas::from(5u64), 10), Gas::from(5u64));
+        assert_eq!(replacement_gas_price(Gas::zero(), 10), Gas::zero());
+    }
+
+    #[test]
+    fn gap_is_queued_until_filled() {
+        let alice = Address::from(1u64);
+        let bob = Address::from(2u64);
+        let nonce_of = |_: Address| Some(U256::zero());
+
+        let mut pool = TransactionPool::new();
+        pool.insert(alice, U256::from(1u64), Gas::zero(), H256::from(11u64));
+        pool.insert(bob, U256::zero(), Gas::zero(), H256::from(20u64));
+        assert_eq!(pool.pending(nonce_of), vec![H256::from(20u64)]);
+        assert_eq!(pool.queued(nonce_of), vec![H256::from(11u64)]);
+
+        pool.insert(alice, U256::zero(), Gas::zero(), H256::from(10u64));
+        assert_eq!(pool.pending(nonce_of),
+                   vec![H256::from(20u64), H256::from(10u64), H256::from(11u64)]);
+        assert!(pool.queued(nonce_of).is_empty());
+
+        assert_eq!(pool.insert(alice, U256::zero(), Gas::zero(), H256::from(12u64)), Some(H256::from(10u64)));
+        pool.remove(&[H256::from(12u64)]);
+        pool.prune(|sender| if sender == alice { Some(U256::one()) } else { Some(U256::zero()) });
+        assert_eq!(pool.all(), vec![H256::from(11u64), H256::from(20u64)]);
+    }
+
+    #[test]
+    fn evicts_cheapest_then_oldest() {
+        let alice = Address::from(1u64);
+        let bob = Address::from(2u64);
+
+        let mut pool = TransactionPool::new();
+        pool.insert(alice, U256::zero(), Gas::from(5u64), H256::from(10u64));
+        pool.insert(alice, U256::one(), Gas::from(1u64), H256::from(11u64));
+        pool.insert(alice, U256::from(2u64), Gas::from(1u64), H256::from(12u64));
+        pool.insert(bob, U256::zero(), Gas::from(3u64), H256::from(20u64));
+
+        assert_eq!(pool.evict(10, 2), vec![H256::from(12u64)]);
+        assert_eq!(pool.evict(2, 2), vec![H256::from(11u64)]);
+        assert_eq!(pool.all(), vec![H256::from(10u64), H256::from(20u64)]);
+        assert_eq!(pool.lowest_gas_price(None), Some(Gas::from(3u64)));
+        assert_eq!(pool.lowest_gas_price(Some(alice)), Some(Gas::from(5u64)));
+    }
+
+    #[test]
+    fn evicts_from_the_highest_nonce_down() {
+        let alice = Address::from(1u64);
+        let bob = Address::from(2u64);
+
+        let mut pool = TransactionPool::new();
+        pool.insert(alice, U256::zero(), Gas::from(1u64), H256::from(10u64));
+        pool.insert(alice, U256::one(), Gas::from(5u64), H256::from(11u64));
+        pool.insert(bob, U256::zero(), Gas::from(3u64), H256::from(20u64));
+
+        assert_eq!(pool.evict(2, 2), vec![H256::from(20u64)]);
+        assert_eq!(pool.evict(1, 2), vec![H256::from(11u64)]);
+        assert_eq!(pool.all(), vec![H256::from(10u64)]);
+    }
+}
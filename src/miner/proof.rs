@@ -0,0 +1,265 @@
+use bigint::{H256, U256};
+use block::{Block, Transaction};
+use rlp::{self, Encodable, Decodable, UntrustedRlp};
+use trie::{MemoryDatabase, Database, DatabaseGuard};
+
+use error::Error;
+use super::ordered_trie::build_ordered_trie;
+use super::receipt::ConsensusReceipt;
+
+/// Merkle proof of one item of a block trie, keyed by the RLP of its
+/// index as in the transactions and receipts tries.
+pub struct InclusionProof {
+    pub root: H256,
+    pub key: Vec<u8>,
+    pub value: Vec<u8>,
+    /// Nodes from the root down to the item, embedded nodes of less
+    /// than 32 bytes being part of their parent.
+    pub nodes: Vec<Vec<u8>>,
+}
+
+pub fn nibbles(key: &[u8]) -> Vec<u8> {
+    let mut ret = Vec::new();
+    for byte in key {
+        ret.push(byte >> 4);
+        ret.push(byte & 0x0f);
+    }
+    ret
+}
+
+/// Decode a hex-prefix encoded path, returning its nibbles and whether
+/// it belongs to a leaf.
+pub fn decode_path(data: &[u8]) -> (Vec<u8>, bool) {
+    if data.is_empty() {
+        return (Vec::new(), false);
+    }
+
+    let flag = data[0] >> 4;
+    let mut path = Vec::new();
+    if flag & 1 != 0 {
+        path.push(data[0] & 0x0f);
+    }
+    path.extend(nibbles(&data[1..]));
+    (path, flag & 2 != 0)
+}
+
+/// Follow `key` from `rlp` through embedded nodes, returning the hash
+/// of the next node to fetch, if the path goes on.
+fn descend(rlp: &UntrustedRlp, key: &[u8], at: &mut usize) -> Result<Option<H256>, Error> {
+    match rlp.item_count() {
+        0 => Ok(None),
+        2 => {
+            let (path, is_leaf) = decode_path(rlp.at(0)?.data()?);
+            if is_leaf || !key[*at..].starts_with(&path) {
+                return Ok(None);
+            }
+            *at += path.len();
+            reference(&rlp.at(1)?, key, at)
+        },
+        17 => {
+            if *at >= key.len() {
+                return Ok(None);
+            }
+            let child = rlp.at(key[*at] as usize)?;
+            *at += 1;
+            reference(&child, key, at)
+        },
+        _ => Err(Error::RlpError),
+    }
+}
+
+fn reference(rlp: &UntrustedRlp, key: &[u8], at: &mut usize) -> Result<Option<H256>, Error> {
+    if rlp.is_list() {
+        descend(rlp, key, at)
+    } else if rlp.is_empty() {
+        Ok(None)
+    } else {
+        Ok(Some(rlp.as_val()?))
+    }
+}
+
+/// Nodes on the path from `root` to `key`.
+pub fn prove(database: &MemoryDatabase, root: H256, key: &[u8]) -> Result<Vec<Vec<u8>>, Error> {
+    let guard = database.create_guard();
+    let key = nibbles(key);
+    let mut at = 0;
+
+    let mut nodes = Vec::new();
+    let mut next = Some(root);
+    while let Some(hash) = next {
+        let node = guard.get(hash).ok_or(Error::Internal("trie node of a proof is missing"))?;
+        next = descend(&UntrustedRlp::new(&node), &key, &mut at)?;
+        nodes.push(node);
+    }
+    Ok(nodes)
+}
+
+fn prove_item<T: Encodable + Decodable + Clone>(
+    items: &[T], index: usize, expected_root: H256
+) -> Result<InclusionProof, Error> {
+    let database = MemoryDatabase::default();
+    let root = build_ordered_trie(&database, items);
+    if root != expected_root {
+        return Err(Error::Internal("rebuilt trie does not match the block header"));
+    }
+
+    let key = rlp::encode(&U256::from(index)).to_vec();
+    Ok(InclusionProof {
+        root,
+        nodes: prove(&database, root, &key)?,
+        key,
+        value: rlp::encode(&items[index]).to_vec(),
+    })
+}
+
+/// Proof of the transaction at `index` against the transactions root
+/// of `block`.
+pub fn transaction_proof(block: &Block, index: usize) -> Result<InclusionProof, Error> {
+    let transactions: &[Transaction] = &block.transactions;
+    prove_item(transactions, index, block.header.transactions_root)
+}
+
+/// Proof of the receipt at `index` against the receipts root of
+/// `block`, given all receipts of the block in their consensus form.
+pub fn receipt_proof(block: &Block, receipts: &[ConsensusReceipt], index: usize) -> Result<InclusionProof, Error> {
+    prove_item(receipts, index, block.header.receipts_root)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bigint::{Address, Gas};
+    use block::{RlpHash, TransactionAction, UnsignedTransaction};
+    use secp256k1::SECP256K1;
+    use secp256k1::key::SecretKey;
+    use sha3::{Digest, Keccak256};
+    use sputnikvm_network_classic::MainnetEIP160Patch;
+    use std::sync::{Arc, RwLock};
+    use trie::FixedTrie;
+    use super::super::{consensus_receipts, make_state_at, mine_one_at, sign_transaction};
+
+    /// Item found at the end of a path: a node to fetch, a value, or
+    /// nothing.
+    enum Step {
+        Node(H256),
+        Value(Vec<u8>),
+        Absent,
+    }
+
+    fn step(rlp: &UntrustedRlp, key: &[u8], at: &mut usize) -> Step {
+        match rlp.item_count() {
+            2 => {
+                let (path, is_leaf) = decode_path(rlp.at(0).unwrap().data().unwrap());
+                if !key[*at..].starts_with(&path) {
+                    return Step::Absent;
+                }
+                *at += path.len();
+                if is_leaf {
+                    return if *at == key.len() {
+                        Step::Value(rlp.at(1).unwrap().data().unwrap().to_vec())
+                    } else {
+                        Step::Absent
+                    };
+                }
+                child(&rlp.at(1).unwrap(), key, at)
+            },
+            17 => {
+                if *at == key.len() {
+                    return Step::Value(rlp.at(16).unwrap().data().unwrap().to_vec());
+                }
+                let branch = rlp.at(key[*at] as usize).unwrap();
+                *at += 1;
+                child(&branch, key, at)
+            },
+            _ => Step::Absent,
+        }
+    }
+
+    fn child(rlp: &UntrustedRlp, key: &[u8], at: &mut usize) -> Step {
+        if rlp.is_list() {
+            step(rlp, key, at)
+        } else if rlp.is_empty() {
+            Step::Absent
+        } else {
+            Step::Node(rlp.as_val().unwrap())
+        }
+    }
+
+    /// Whether `nodes` prove that `key` maps to `value` in the trie of
+    /// `root`: each node hashes to the reference its parent holds, and
+    /// the path ends at `value` in the last one.
+    fn verify(root: H256, key: &[u8], value: &[u8], nodes: &[Vec<u8>]) -> bool {
+        let key = nibbles(key);
+        let mut at = 0;
+        let mut expected = root;
+        for (index, node) in nodes.iter().enumerate() {
+            if H256::from(Keccak256::digest(node).as_slice()) != expected {
+                return false;
+            }
+            match step(&UntrustedRlp::new(node), &key, &mut at) {
+                Step::Node(hash) => expected = hash,
+                Step::Value(found) => return index + 1 == nodes.len() && found == value,
+                Step::Absent => return false,
+            }
+        }
+        false
+    }
+
+    #[test]
+    fn proof_starts_at_root_and_ends_at_value() {
+        let database = MemoryDatabase::default();
+        let mut trie: FixedTrie<_, U256, Vec<u8>> = database.create_fixed_trie(database.create_empty().root());
+        for i in 0..20u64 {
+            trie.insert(U256::from(i), vec![i as u8; 40]);
+        }
+        let root = trie.root();
+
+        let key = rlp::encode(&U256::from(7u64)).to_vec();
+        let nodes = prove(&database, root, &key).unwrap();
+        assert_eq!(H256::from(Keccak256::digest(&nodes[0]).as_slice()), root);
+
+        let value = rlp::encode(&vec![7u8; 40]).to_vec();
+        let last = nodes.last().unwrap();
+        assert!(last.windows(value.len()).any(|window| window == &value[..]));
+
+        assert!(verify(root, &key, &value, &nodes));
+        assert!(!verify(root, &key, &rlp::encode(&vec![8u8; 40]), &nodes));
+        assert!(!verify(root, &rlp::encode(&U256::from(8u64)), &value, &nodes));
+    }
+
+    #[test]
+    fn receipt_proof_verifies_against_receipts_root() {
+        let key = SecretKey::from_slice(&SECP256K1, &[1u8; 32]).unwrap();
+        let mut state = make_state_at::<MainnetEIP160Patch>(vec![(key.clone(), U256::from(1_000_000u64))], 0);
+        for nonce in 0..3u64 {
+            state.append_pending_transaction(sign_transaction(UnsignedTransaction {
+                nonce: U256::from(nonce),
+                gas_price: Gas::zero(),
+                gas_limit: Gas::from(21000u64),
+                action: TransactionAction::Call(Address::from(0x10u64)),
+                value: U256::from(1000u64),
+                input: Vec::new(),
+            }, &key, None)).unwrap();
+        }
+        let state = Arc::new(RwLock::new(state));
+        mine_one_at::<MainnetEIP160Patch>(state.clone(), Address::default(), 1);
+
+        let state = state.read().unwrap();
+        let block = state.current_block();
+        let receipts: Vec<_> = block.transactions.iter()
+            .map(|transaction| state.get_receipt_by_transaction_hash(transaction.rlp_hash()).unwrap())
+            .collect();
+        let receipts = consensus_receipts::<MainnetEIP160Patch>(&state, &block.transactions, &receipts);
+
+        for index in 0..receipts.len() {
+            let proof = receipt_proof(&block, &receipts, index).unwrap();
+            assert_eq!(proof.root, block.header.receipts_root);
+            assert_eq!(proof.value, rlp::encode(&receipts[index]).to_vec());
+            assert!(verify(block.header.receipts_root, &proof.key, &proof.value, &proof.nodes));
+        }
+
+        let proof = transaction_proof(&block, 1).unwrap();
+        assert!(verify(block.header.transactions_root, &proof.key, &proof.value, &proof.nodes));
+        assert!(!verify(block.header.receipts_root, &proof.key, &proof.value, &proof.nodes));
+    }
+}
@@ -0,0 +1,133 @@
+use bigint::{Gas, H256};
+use block::{Log, Receipt, Transaction, RlpHash};
+use bloom::LogsBloom;
+use rlp::{Encodable, Decodable, RlpStream, UntrustedRlp, DecoderError};
+use sputnikvm::Patch;
+use trie::MemoryDatabase;
+
+use super::state::MinerState;
+use super::ordered_trie::build_ordered_trie;
+use super::patch::is_byzantium;
+
+/// What a receipt commits to about the outcome of its transaction: the
+/// state root after it before Byzantium, its EIP-658 status after.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ReceiptOutcome {
+    StateRoot(H256),
+    Status(bool),
+}
+
+/// Receipt as hashed into the receipts root of a block.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConsensusReceipt {
+    pub outcome: ReceiptOutcome,
+    pub used_gas: Gas,
+    pub logs_bloom: LogsBloom,
+    pub logs: Vec<Log>,
+}
+
+impl ConsensusReceipt {
+    /// Consensus form under `P` of a receipt whose transaction
+    /// succeeded or not.
+    pub fn new<P: Patch>(receipt: &Receipt, status: bool) -> Self {
+        ConsensusReceipt {
+            outcome: if is_byzantium::<P>() {
+                ReceiptOutcome::Status(status)
+            } else {
+                ReceiptOutcome::StateRoot(receipt.state_root)
+            },
+            used_gas: receipt.used_gas,
+            logs_bloom: receipt.logs_bloom.clone(),
+            logs: receipt.logs.clone(),
+        }
+    }
+}
+
+impl Encodable for ConsensusReceipt {
+    fn rlp_append(&self, s: &mut RlpStream) {
+        s.begin_list(4);
+        match self.outcome {
+            ReceiptOutcome::StateRoot(ref root) => s.append(root),
+            // A failure is the empty string, a success the byte 0x01.
+            ReceiptOutcome::Status(status) => s.append(&(status as u8)),
+        };
+        s.append(&self.used_gas);
+        s.append(&self.logs_bloom);
+        s.append_list(&self.logs);
+    }
+}
+
+impl Decodable for ConsensusReceipt {
+    fn decode(rlp: &UntrustedRlp) -> Result<Self, DecoderError> {
+        let outcome = rlp.at(0)?;
+        Ok(ConsensusReceipt {
+            outcome: if outcome.size() == 32 {
+                ReceiptOutcome::StateRoot(outcome.as_val()?)
+            } else {
+                match outcome.as_val::<u8>()? {
+                    0 => ReceiptOutcome::Status(false),
+                    1 => ReceiptOutcome::Status(true),
+                    _ => return Err(DecoderError::Custom("invalid receipt status")),
+                }
+            },
+            used_gas: rlp.val_at(1)?,
+            logs_bloom: rlp.val_at(2)?,
+            logs: rlp.list_at(3)?,
+        })
+    }
+}
+
+/// Consensus receipts of `transactions`, executed on `state` with
+/// `receipts` as a result.
+pub fn consensus_receipts<P: Patch>(
+    state: &MinerState, transactions: &[Transaction], receipts: &[Receipt]
+) -> Vec<ConsensusReceipt> {
+    transactions.iter().zip(receipts).map(|(transaction, receipt)| {
+        ConsensusReceipt::new::<P>(receipt, state.receipt_status(transaction.rlp_hash()))
+    }).collect()
+}
+
+pub fn receipts_root(receipts: &[ConsensusReceipt]) -> H256 {
+    build_ordered_trie(&MemoryDatabase::default(), receipts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bigint::Address;
+    use block;
+    use rlp;
+    use sputnikvm_network_classic::MainnetEIP160Patch;
+    use sputnikvm_network_foundation::ByzantiumPatch;
+
+    fn receipt() -> Receipt {
+        Receipt {
+            state_root: H256::from(7u64),
+            used_gas: Gas::from(21000u64),
+            logs_bloom: LogsBloom::new(),
+            logs: vec![Log { address: Address::from(1u64), topics: vec![H256::from(2u64)], data: vec![3] }],
+        }
+    }
+
+    #[test]
+    fn state_root_before_byzantium() {
+        let receipts = vec![receipt(), receipt()];
+        let consensus: Vec<ConsensusReceipt> = receipts.iter()
+            .map(|receipt| ConsensusReceipt::new::<MainnetEIP160Patch>(receipt, false)).collect();
+        assert_eq!(rlp::encode(&consensus[0]).to_vec(), rlp::encode(&receipts[0]).to_vec());
+        assert_eq!(receipts_root(&consensus), block::receipts_root(&receipts));
+    }
+
+    #[test]
+    fn status_after_byzantium() {
+        for &status in &[false, true] {
+            let consensus = ConsensusReceipt::new::<ByzantiumPatch>(&receipt(), status);
+            assert_eq!(consensus.outcome, ReceiptOutcome::Status(status));
+
+            let encoded = rlp::encode(&consensus).to_vec();
+            let decoded: ConsensusReceipt = UntrustedRlp::new(&encoded).as_val().unwrap();
+            assert_eq!(decoded, consensus);
+            assert!(receipts_root(&[consensus]) != block::receipts_root(&[receipt()]));
+        }
+    }
+}
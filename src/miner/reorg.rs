@@ -0,0 +1,91 @@
+use bigint::{Address, H256, U256};
+use block::HeaderHash;
+use sputnikvm::Patch;
+
+use error::Error;
+use super::{MinerState, BlockBuilder, insert_block, reward};
+
+/// Branch built with `extend_side_chain`, off the canonical block
+/// `base`.
+#[derive(Clone, Debug)]
+pub struct SideChain {
+    pub base: usize,
+    /// Hash of its last block, the base block until it is extended.
+    pub head: H256,
+}
+
+/// Last block of a side chain.
+pub struct SideChainHead {
+    pub base: usize,
+    pub number: usize,
+    pub hash: H256,
+    /// Whether the side chain is the canonical chain.
+    pub canonical: bool,
+}
+
+/// Start a side chain branching off after the canonical block
+/// `number`, returning its id.
+pub fn create_side_chain(state: &mut MinerState, number: usize) -> Result<usize, Error> {
+    if number > state.block_height() {
+        return Err(Error::NotFound);
+    }
+    if number + 1 < state.frozen_before() {
+        return Err(Error::Frozen(state.frozen_before()));
+    }
+
+    let head = state.get_block_by_number(number).header.header_hash();
+    Ok(state.insert_side_chain(SideChain { base: number, head }))
+}
+
+/// Append `count` empty blocks to the side chain `id`, rewarding the
+/// zero address `eth_coinbase` reports. The blocks go through fork
+/// choice like any inserted block, so the node switches to the side
+/// chain as soon as it is longer than the canonical chain.
+pub fn extend_side_chain<P: Patch>(
+    state: &mut MinerState, id: usize, count: usize
+) -> Result<SideChainHead, Error> {
+    let mut side = state.side_chain(id).ok_or(Error::NotFound)?;
+
+    for _ in 0..count {
+        // The side chain is gone once fork choice dropped its blocks.
+        let parent = match state.get_block_by_hash(side.head) {
+            Ok(parent) => parent,
+            Err(_) => {
+                state.remove_side_chain(id);
+                return Err(Error::NotFound);
+            },
+        };
+
+        let beneficiary = Address::default();
+        // On top of the head, the block also seals the changes of the
+        // `set_*` methods, as a mined block would.
+        state.begin_sealing();
+        if side.head != state.current_block().header.header_hash() {
+            *state.stateful_mut() = state.stateful_at(parent.header.state_root);
+        }
+        if let Err(err) = reward::apply::<P>(state, parent.header.number + U256::one(), beneficiary, &[]) {
+            state.end_sealing(false);
+            return Err(err);
+        }
+        let state_root = state.stateful().root();
+        state.end_sealing(false);
+
+        // The mix hash carries the side chain id, which keeps the first
+        // block apart from blocks built on the same parent elsewhere.
+        let block = BlockBuilder::new(&parent).header(|header| header
+            .beneficiary(beneficiary)
+            .state_root(state_root)
+            .mix_hash(H256::from(U256::from(id as u64 + 1))))
+            .build();
+        side.head = insert_block::<P>(state, block)?;
+        state.update_side_chain(id, side.clone());
+    }
+
+    let number = state.get_total_header_by_hash(side.head)?.header.number.as_usize();
+    Ok(SideChainHead {
+        base: side.base,
+        number,
+        hash: side.head,
+        canonical: state.is_canonical(side.head),
+    })
+}
@@ -0,0 +1,67 @@
+use rlp::RlpStream;
+use block::RlpHash;
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+
+use error::Error;
+use super::MinerState;
+
+/// Keep receipts and logs of the last `blocks` blocks only. Headers,
+/// transactions and state are always kept.
+#[derive(Clone, Debug)]
+pub struct LogRetention {
+    pub blocks: usize,
+    /// Append pruned receipts to this file instead of dropping them.
+    pub archive: Option<PathBuf>,
+}
+
+/// Prune receipts that fell out of the retention window after a block
+/// was sealed. Each call appends one gzip member to the archive file,
+/// holding the RLP list of `[number, [[hash, receipt, status], ...]]`
+/// for every pruned block.
+pub fn prune_receipts(state: &mut MinerState) -> Result<(), Error> {
+    let retention = match state.log_retention() {
+        Some(retention) => retention.clone(),
+        None => return Ok(()),
+    };
+
+    let from = state.pruned_before();
+    let until = (state.block_height() + 1).saturating_sub(retention.blocks);
+    if from >= until {
+        return Ok(());
+    }
+
+    let mut hashes = Vec::new();
+    let mut stream = RlpStream::new_list(until - from);
+    for number in from..until {
+        let block = state.get_block_by_number(number);
+        stream.begin_list(2);
+        stream.append(&number);
+        stream.begin_list(block.transactions.len());
+        for transaction in &block.transactions {
+            let hash = transaction.rlp_hash();
+            stream.begin_list(3);
+            stream.append(&hash);
+            stream.append(&state.get_receipt_by_transaction_hash(hash)?);
+            stream.append(&(if state.receipt_status(hash) { 1u8 } else { 0u8 }));
+            hashes.push(hash);
+        }
+    }
+
+    if let Some(ref path) = retention.archive {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let mut encoder = GzEncoder::new(file, Compression::Default);
+        encoder.write_all(&stream.out())?;
+        encoder.finish()?;
+    }
+
+    for hash in hashes {
+        state.remove_receipt(hash);
+    }
+    state.set_pruned_before(until);
+
+    Ok(())
+}
@@ -0,0 +1,67 @@
+use bigint::{Address, U256};
+use block::Header;
+use sputnikvm::Patch;
+
+use error::Error;
+use super::state::MinerState;
+use super::{is_byzantium, materialize_account};
+
+/// Most ommers a block may include.
+pub const MAX_OMMERS: usize = 2;
+
+/// How many generations back an ommer may branch off.
+pub const MAX_OMMER_DEPTH: usize = 6;
+
+/// Reward of the miner of a block, before ommer inclusion rewards.
+pub fn block_reward<P: Patch>() -> U256 {
+    let ether = U256::from(1_000_000_000_000_000_000u64);
+    if is_byzantium::<P>() {
+        ether * U256::from(3u64)
+    } else {
+        ether * U256::from(5u64)
+    }
+}
+
+/// Reward of the miner of an ommer at `ommer_number` included in the
+/// block at `number`, at most `MAX_OMMER_DEPTH` blocks below it.
+pub fn ommer_reward<P: Patch>(number: U256, ommer_number: U256) -> U256 {
+    block_reward::<P>() * (U256::from(8u64) + ommer_number - number) / U256::from(8u64)
+}
+
+/// Credit the rewards of the block at `number` to the head state: the
+/// block reward plus 1/32 of it per ommer to `beneficiary`, and the
+/// ommer reward to the beneficiary of each ommer.
+pub fn apply<P: Patch>(
+    state: &mut MinerState, number: U256, beneficiary: Address, ommers: &[Header]
+) -> Result<(), Error> {
+    let reward = block_reward::<P>();
+    let inclusion = reward / U256::from(32u64) * U256::from(ommers.len());
+
+    materialize_account(state, beneficiary)?;
+    state.add_balance(beneficiary, reward + inclusion);
+
+    for ommer in ommers {
+        if ommer.number >= number || number - ommer.number > U256::from(MAX_OMMER_DEPTH) {
+            return Err(Error::InvalidHeader(
+                "ommers", format!("0x{:x} is not within {} blocks of 0x{:x}", ommer.number, MAX_OMMER_DEPTH, number)));
+        }
+        materialize_account(state, ommer.beneficiary)?;
+        state.add_balance(ommer.beneficiary, ommer_reward::<P>(number, ommer.number));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sputnikvm_network_classic::MainnetEIP160Patch;
+
+    #[test]
+    fn ommer_reward_decreases_with_depth() {
+        let reward = block_reward::<MainnetEIP160Patch>();
+        assert_eq!(ommer_reward::<MainnetEIP160Patch>(U256::from(10u64), U256::from(9u64)),
+                   reward * U256::from(7u64) / U256::from(8u64));
+        assert_eq!(ommer_reward::<MainnetEIP160Patch>(U256::from(10u64), U256::from(4u64)),
+                   reward * U256::from(2u64) / U256::from(8u64));
+    }
+}
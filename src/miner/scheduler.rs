@@ -0,0 +1,122 @@
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+/// Something the miner reacts to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MinerEvent {
+    /// A transaction entered the pool.
+    Transaction,
+    /// A block was requested explicitly.
+    Mine,
+}
+
+/// When blocks are mined. Explicit requests are always honoured, so
+/// with neither instamine nor an interval blocks are only mined on
+/// demand.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MiningSchedule {
+    /// Mine as soon as a transaction enters the pool.
+    pub instamine: bool,
+    /// Mine at least every so many seconds, even without transactions.
+    pub interval: Option<u64>,
+}
+
+impl Default for MiningSchedule {
+    fn default() -> Self {
+        MiningSchedule {
+            instamine: true,
+            interval: Some(super::MINING_INTERVAL),
+        }
+    }
+}
+
+/// What the scheduler decided after waiting.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Wake {
+    Mine,
+    Idle,
+    Stop,
+}
+
+/// Turns miner events and timer ticks into mining decisions, keeping
+/// the deadline of the next interval block.
+pub struct Scheduler {
+    deadline: Option<Instant>,
+}
+
+impl Scheduler {
+    pub fn new(schedule: MiningSchedule, now: Instant) -> Self {
+        Scheduler {
+            deadline: schedule.interval.map(|interval| now + Duration::new(interval, 0)),
+        }
+    }
+
+    /// Whether an event, or the timer when `event` is `None`, should
+    /// mine a block at `now`.
+    pub fn decide(&self, schedule: MiningSchedule, event: Option<MinerEvent>, now: Instant) -> Wake {
+        match event {
+            Some(MinerEvent::Mine) => Wake::Mine,
+            Some(MinerEvent::Transaction) if schedule.instamine => Wake::Mine,
+            _ => match self.deadline {
+                Some(deadline) if deadline <= now => Wake::Mine,
+                _ => Wake::Idle,
+            },
+        }
+    }
+
+    /// Restart the interval after a block was mined at `now`.
+    pub fn mined(&mut self, schedule: MiningSchedule, now: Instant) {
+        self.deadline = schedule.interval.map(|interval| now + Duration::new(interval, 0));
+    }
+
+    /// Block until the next event or the deadline.
+    pub fn wait(&self, schedule: MiningSchedule, channel: &Receiver<MinerEvent>) -> Wake {
+        let event = match self.deadline {
+            Some(deadline) => {
+                let now = Instant::now();
+                let timeout = if deadline > now { deadline - now } else { Duration::new(0, 0) };
+                match channel.recv_timeout(timeout) {
+                    Ok(event) => Some(event),
+                    Err(RecvTimeoutError::Timeout) => None,
+                    Err(RecvTimeoutError::Disconnected) => return Wake::Stop,
+                }
+            },
+            None => match channel.recv() {
+                Ok(event) => Some(event),
+                Err(_) => return Wake::Stop,
+            },
+        };
+
+        // Events that queued up meanwhile are served by the same block.
+        let mut wake = self.decide(schedule, event, Instant::now());
+        while let Ok(event) = channel.try_recv() {
+            if self.decide(schedule, Some(event), Instant::now()) == Wake::Mine {
+                wake = Wake::Mine;
+            }
+        }
+        wake
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn events_and_ticks() {
+        let now = Instant::now();
+        let manual = MiningSchedule { instamine: false, interval: None };
+        let scheduler = Scheduler::new(manual, now);
+        assert_eq!(scheduler.decide(manual, Some(MinerEvent::Transaction), now), Wake::Idle);
+        assert_eq!(scheduler.decide(manual, Some(MinerEvent::Mine), now), Wake::Mine);
+        assert_eq!(scheduler.decide(manual, None, now + Duration::new(3600, 0)), Wake::Idle);
+
+        let interval = MiningSchedule { instamine: false, interval: Some(5) };
+        let mut scheduler = Scheduler::new(interval, now);
+        assert_eq!(scheduler.decide(interval, None, now + Duration::new(4, 0)), Wake::Idle);
+        assert_eq!(scheduler.decide(interval, None, now + Duration::new(5, 0)), Wake::Mine);
+        scheduler.mined(interval, now + Duration::new(5, 0));
+        assert_eq!(scheduler.decide(interval, None, now + Duration::new(6, 0)), Wake::Idle);
+        assert_eq!(scheduler.decide(MiningSchedule::default(), Some(MinerEvent::Transaction), now), Wake::Mine);
+    }
+}
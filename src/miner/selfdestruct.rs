@@ -0,0 +1,69 @@
+use bigint::U256;
+use block::Account;
+use sputnikvm::{AccountChange, Storage};
+use sputnikvm_stateful::MemoryStateful;
+use std::rc::Rc;
+use std::str::FromStr;
+
+use error::Error;
+
+/// What SELFDESTRUCT does to a contract that existed before the
+/// transaction.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SelfdestructMode {
+    /// Delete the contract, as the VM does.
+    Legacy,
+    /// Only send its balance away, as in EIP-6780. Contracts created in
+    /// the same transaction are still deleted.
+    Eip6780,
+}
+
+impl FromStr for SelfdestructMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s {
+            "legacy" => Ok(SelfdestructMode::Legacy),
+            "eip6780" => Ok(SelfdestructMode::Eip6780),
+            _ => Err(Error::InvalidParams),
+        }
+    }
+}
+
+/// Rewrite the deletion of contracts that existed before the
+/// transaction into keeping them with an empty balance. `stateful` is
+/// the state the transaction was executed on. Storage written by the
+/// transaction before the SELFDESTRUCT is not recovered, as the VM has
+/// already dropped it.
+pub fn apply(mode: SelfdestructMode, stateful: &MemoryStateful, accounts: &mut Vec<AccountChange>) {
+    if mode == SelfdestructMode::Legacy {
+        return;
+    }
+
+    let trie = stateful.state_of(stateful.root());
+    for change in accounts.iter_mut() {
+        let address = match change {
+            &mut AccountChange::Nonexist(address) => address,
+            _ => continue,
+        };
+
+        let account: Account = match trie.get(&address) {
+            Some(account) => account,
+            None => continue,
+        };
+        // Empty accounts are removed by EIP-161, not by SELFDESTRUCT.
+        let code = match stateful.code(account.code_hash) {
+            Some(ref code) if code.is_empty() => continue,
+            Some(code) => code,
+            None => continue,
+        };
+
+        *change = AccountChange::Full {
+            nonce: account.nonce,
+            address,
+            balance: U256::zero(),
+            changing_storage: Storage::new(address, false),
+            code: Rc::new(code),
+        };
+    }
+}
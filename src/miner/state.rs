@@ -1,32 +1,149 @@
 use rlp;
 
-use error::Error;
-use block::{Receipt, Block, TotalHeader, HeaderHash, Transaction};
-use trie::{MemoryDatabase};
-use bigint::{H256, M256, U256, Address};
+use error::{Error, MinerError};
+use block::{Receipt, Block, Header, TotalHeader, HeaderHash, Transaction, Account, Log, FromKey, RlpHash};
+use trie::{MemoryDatabase, Database, DatabaseGuard, FixedSecureTrie};
+use bigint::{H256, M256, U256, Address, Gas};
 use sha3::{Digest, Keccak256};
 use secp256k1::key::SecretKey;
-use sputnikvm::AccountChange;
+use sputnikvm::{AccountChange, HeaderParams, Patch, SeqTransactionVM, ValidTransaction};
 use sputnikvm_stateful::{MemoryStateful};
-use std::collections::{HashMap};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::mem;
+use std::time::{Duration, Instant};
+
+use super::fee::{FeePolicy, StandardFeePolicy};
+use super::retention::LogRetention;
+use super::fork::{self, Fork};
+use super::selfdestruct::SelfdestructMode;
+use super::watch::WatchEvent;
+use super::reward::{MAX_OMMERS, MAX_OMMER_DEPTH};
+use super::scheduler::MiningSchedule;
+use super::oracle::GasPriceOracle;
+use super::tracer::{Tracer, call_with_tracer};
+use super::pool::{TransactionPool, DEFAULT_PRICE_BUMP, DEFAULT_POOL_SIZE, DEFAULT_POOL_SENDER_SLOTS, accepts_gas_price};
+use super::freeze::{FreezeConfig, FrozenSegment};
+use super::account_cache::{AccountCache, DEFAULT_ACCOUNT_CACHE};
+use super::log_index::LogIndex;
+use super::reorg::SideChain;
+use super::patch::with_memory_limit;
+use super::GenesisAccount;
+use checksum::AddressFormat;
+use futures_cpupool::CpuPool;
+use std::path::{Path, PathBuf};
+
+/// Receipt fields that depend on the rest of the block, recorded when
+/// the transaction is executed.
+#[derive(Clone, Debug)]
+pub struct ReceiptContext {
+    pub transaction_index: usize,
+    /// Index in the block of the first log of the transaction.
+    pub first_log_index: usize,
+    pub cumulative_gas_used: Gas,
+    pub contract_address: Option<Address>,
+}
+
+/// A change to the head state made outside of any transaction, by the
+/// `set_*` methods.
+#[derive(Clone, Debug, PartialEq)]
+pub enum StateWrite {
+    Balance(Address, U256),
+    Nonce(Address, U256),
+    Code(Address, Vec<u8>),
+    Storage(Address, U256, M256),
+}
+
+/// Number of submitted transaction hashes kept for pending transaction
+/// filters.
+const PENDING_TRANSACTION_HISTORY: usize = 4096;
 
 pub struct MinerState {
-    all_pending_transaction_hashes: Vec<H256>,
-    pending_transaction_hashes: Vec<H256>,
+    /// Hashes of the last transactions submitted, and the number of
+    /// older ones dropped before them.
+    pending_transaction_hashes: VecDeque<H256>,
+    dropped_pending_transaction_hashes: usize,
+    transaction_pool: TransactionPool,
     current_block: H256,
-    block_hashes: Vec<H256>,
+    /// Hash of the canonical block at each number. Blocks of other
+    /// branches are kept by hash along with the canonical ones.
+    canonical_hashes: Vec<H256>,
     transaction_block_hashes: HashMap<H256, H256>,
 
     total_header_database: HashMap<H256, TotalHeader>,
     transaction_database: HashMap<H256, Transaction>,
     block_database: HashMap<H256, Block>,
+    /// RLP of blocks and transactions as they were sealed or received,
+    /// by hash.
+    raw_database: HashMap<H256, Vec<u8>>,
     receipt_database: HashMap<H256, Receipt>,
+    frozen: FrozenSegment,
     fat_database: Vec<HashMap<Address, HashMap<U256, M256>>>,
     status_database: HashMap<H256, bool>,
+    revert_reason_database: HashMap<H256, String>,
+    receipt_context_database: HashMap<H256, ReceiptContext>,
+    /// Stored blocks that are not on the canonical chain, which may
+    /// become canonical through fork choice or be included as ommers.
+    side_blocks: HashSet<H256>,
+    session_database: HashMap<String, Vec<H256>>,
+    side_chains: HashMap<usize, SideChain>,
+    next_side_chain: usize,
 
     accounts: Vec<SecretKey>,
+    unlocked_accounts: HashMap<Address, (SecretKey, Option<Instant>)>,
+    genesis_accounts: Vec<(SecretKey, U256)>,
+    genesis_alloc: Vec<GenesisAccount>,
+    impersonated_accounts: HashSet<Address>,
+    impersonated_transactions: HashMap<H256, Address>,
+    /// Writes of the `set_*` methods, by the number of the block they
+    /// were made before. Blocks re-executed from an archive need them
+    /// to reach the same state roots.
+    state_writes: Vec<(usize, StateWrite)>,
+    work_difficulty: U256,
+    max_clock_drift: u64,
+    fee_policy: Box<FeePolicy + Send + Sync>,
+    tracer: Option<Box<Tracer + Send + Sync>>,
+    log_retention: Option<LogRetention>,
+    pruned_before: usize,
+    freeze: Option<FreezeConfig>,
+    price_bump: usize,
+    pool_size: usize,
+    pool_sender_slots: usize,
+    min_gas_price: Gas,
+    allow_zero_gas_price: bool,
+    allow_unprotected_transactions: bool,
+    expose_private_keys: bool,
+    mining_schedule: MiningSchedule,
+    gas_price_oracle: GasPriceOracle,
+    gas_margin: usize,
+    fork: Option<Fork>,
+    selfdestruct_mode: SelfdestructMode,
+    chain: String,
+    network_id: u64,
+    chain_id: Option<u64>,
+    memory_limit: Option<usize>,
+    address_format: AddressFormat,
+    data_dir: Option<PathBuf>,
+    log_workers: usize,
+    execution_workers: usize,
+    /// Threads executing the transactions of mined blocks, when there
+    /// is more than one.
+    execution_pool: Option<CpuPool>,
+    account_cache: AccountCache,
+    rpc_cache: usize,
+    /// Number of times blocks left the chain or the store, see
+    /// `reorgs`.
+    reorgs: u64,
+    log_index: Option<LogIndex>,
+    watch_events: Vec<WatchEvent>,
     database: &'static MemoryDatabase,
+    /// Working trie of the miner. While a block is being sealed it
+    /// holds the partially executed block, and read paths use
+    /// `head_root` instead.
     stateful: MemoryStateful<'static>,
+    /// Head state seen by read paths: the last sealed block, plus the
+    /// changes of the `set_*` methods.
+    head_root: H256,
+    sealing: bool,
 }
 
 impl MinerState {
@@ -34,7 +151,7 @@ impl MinerState {
         let mut block_database = HashMap::new();
         let mut transaction_block_hashes = HashMap::new();
         let mut total_header_database = HashMap::new();
-        let mut block_hashes = Vec::new();
+        let mut canonical_hashes = Vec::new();
 
         let hash = genesis.header.header_hash();
         block_database.insert(hash, genesis.clone());
@@ -42,74 +159,498 @@ impl MinerState {
         assert!(genesis.transactions.len() == 0);
 
         total_header_database.insert(hash, TotalHeader::from_genesis(genesis.header.clone()));
-        block_hashes.push(hash);
+        canonical_hashes.push(hash);
 
         let current_block = hash;
 
         Self {
             database: stateful.database(),
+            head_root: stateful.root(),
+            sealing: false,
 
             block_database, transaction_block_hashes, total_header_database,
-            block_hashes, current_block, stateful,
+            canonical_hashes, current_block, stateful,
 
-            all_pending_transaction_hashes: Vec::new(),
-            pending_transaction_hashes: Vec::new(),
+            pending_transaction_hashes: VecDeque::new(),
+            dropped_pending_transaction_hashes: 0,
+            transaction_pool: TransactionPool::new(),
             transaction_database: HashMap::new(),
+            raw_database: HashMap::new(),
             receipt_database: HashMap::new(),
+            frozen: FrozenSegment::new(),
             fat_database: vec![HashMap::new()],
             status_database: HashMap::new(),
+            revert_reason_database: HashMap::new(),
+            receipt_context_database: HashMap::new(),
+            side_blocks: HashSet::new(),
+            session_database: HashMap::new(),
+            side_chains: HashMap::new(),
+            next_side_chain: 0,
 
             accounts: Vec::new(),
+            unlocked_accounts: HashMap::new(),
+            genesis_accounts: Vec::new(),
+            genesis_alloc: Vec::new(),
+            impersonated_accounts: HashSet::new(),
+            impersonated_transactions: HashMap::new(),
+            state_writes: Vec::new(),
+            work_difficulty: U256::one(),
+            max_clock_drift: 15,
+            fee_policy: Box::new(StandardFeePolicy),
+            tracer: None,
+            log_retention: None,
+            pruned_before: 0,
+            freeze: None,
+            price_bump: DEFAULT_PRICE_BUMP,
+            pool_size: DEFAULT_POOL_SIZE,
+            pool_sender_slots: DEFAULT_POOL_SENDER_SLOTS,
+            min_gas_price: Gas::zero(),
+            allow_zero_gas_price: false,
+            allow_unprotected_transactions: false,
+            expose_private_keys: false,
+            mining_schedule: MiningSchedule::default(),
+            gas_price_oracle: GasPriceOracle::default(),
+            gas_margin: super::DEFAULT_GAS_MARGIN,
+            fork: None,
+            selfdestruct_mode: SelfdestructMode::Legacy,
+            chain: "classic".to_string(),
+            network_id: 1,
+            chain_id: None,
+            memory_limit: None,
+            address_format: AddressFormat::default(),
+            data_dir: None,
+            log_workers: 1,
+            execution_workers: 1,
+            execution_pool: None,
+            account_cache: AccountCache::new(DEFAULT_ACCOUNT_CACHE),
+            rpc_cache: super::DEFAULT_RPC_CACHE,
+            reorgs: 0,
+            log_index: None,
+            watch_events: Vec::new(),
         }
     }
 
-    pub fn append_pending_transaction(&mut self, transaction: Transaction) -> H256 {
+    /// Add a transaction to the pool, replacing the one of the same
+    /// sender and nonce if any. Impersonated transactions must be
+    /// recorded with `insert_impersonated_transaction` first.
+    /// Transactions below the minimum gas price are rejected.
+    pub fn append_pending_transaction(&mut self, transaction: Transaction) -> Result<H256, Error> {
+        if !self.accepts_gas_price(transaction.gas_price) {
+            return Err(Error::CallError(format!(
+                "gas price 0x{:x} is below the minimum of 0x{:x}", transaction.gas_price, self.min_gas_price)));
+        }
+
         let value = rlp::encode(&transaction).to_vec();
         let hash = H256::from(Keccak256::digest(&value).as_slice());
 
+        let sender = self.transaction_sender(&transaction).unwrap_or(Address::default());
+        let replaced = self.transaction_pool.insert(sender, transaction.nonce, transaction.gas_price, hash);
         self.transaction_database.insert(hash, transaction);
-        self.pending_transaction_hashes.push(hash);
-        self.all_pending_transaction_hashes.push(hash);
+        self.pending_transaction_hashes.push_back(hash);
+        if self.pending_transaction_hashes.len() > PENDING_TRANSACTION_HISTORY {
+            self.pending_transaction_hashes.pop_front();
+            self.dropped_pending_transaction_hashes += 1;
+        }
+
+        if let Some(replaced) = replaced {
+            if replaced != hash {
+                self.drop_transaction(replaced);
+            }
+        }
+        for evicted in self.transaction_pool.evict(self.pool_size, self.pool_sender_slots) {
+            self.drop_transaction(evicted);
+        }
+
+        Ok(hash)
+    }
 
-        hash
+    /// Forget a transaction that left the pool without being mined.
+    pub fn drop_transaction(&mut self, hash: H256) {
+        self.transaction_database.remove(&hash);
+        self.raw_database.remove(&hash);
+        self.impersonated_transactions.remove(&hash);
+        for hashes in self.session_database.values_mut() {
+            hashes.retain(|session_hash| *session_hash != hash);
+        }
     }
 
+    /// Take the transactions to be sealed in the next block out of the
+    /// pool, leaving the queued ones. Transactions whose nonce got used
+    /// in the meantime, or below the minimum gas price, are dropped.
     pub fn clear_pending_transactions(&mut self) -> Vec<Transaction> {
-        let transaction_hashes = {
-            let ret_hashes = self.pending_transaction_hashes.clone();
-            self.pending_transaction_hashes.clear();
-            ret_hashes
-        };
+        let (min_gas_price, allow_zero) = (self.min_gas_price, self.allow_zero_gas_price);
+        let underpriced = self.transaction_pool.retain_gas_price(
+            |gas_price| accepts_gas_price(gas_price, min_gas_price, allow_zero));
+        for hash in underpriced {
+            self.drop_transaction(hash);
+        }
 
-        let mut transactions = Vec::new();
-        for hash in transaction_hashes {
-            transactions.push(self.transaction_database.get(&hash).unwrap().clone());
+        let nonces = self.pool_nonces();
+        self.transaction_pool.prune(|sender| nonces.get(&sender).cloned());
+        let transaction_hashes = self.transaction_pool.pending(|sender| nonces.get(&sender).cloned());
+        self.transaction_pool.remove(&transaction_hashes);
+
+        transaction_hashes.iter().filter_map(|hash| {
+            self.transaction_database.get(hash).cloned()
+        }).collect()
+    }
+
+    /// Put transactions taken by `clear_pending_transactions` back in
+    /// the pool, when the block they were taken for cannot be sealed.
+    /// Those dropped in the meantime stay out.
+    pub fn return_pending_transactions(&mut self, transactions: &[Transaction]) {
+        for transaction in transactions {
+            let hash = transaction.rlp_hash();
+            if self.transaction_database.contains_key(&hash) {
+                let sender = self.transaction_sender(transaction).unwrap_or(Address::default());
+                self.transaction_pool.insert(sender, transaction.nonce, transaction.gas_price, hash);
+            }
         }
-        transactions
     }
 
-    pub fn all_pending_transaction_hashes(&self) -> Vec<H256> {
-        self.all_pending_transaction_hashes.clone()
+    /// Transactions to be sealed in the next block, in execution order.
+    pub fn pending_transactions(&self) -> Vec<Transaction> {
+        let nonces = self.pool_nonces();
+        self.transaction_pool.pending(|sender| nonces.get(&sender).cloned())
+            .iter().filter_map(|hash| self.transaction_database.get(hash).cloned()).collect()
+    }
+
+    /// Transactions waiting for a lower nonce of their sender.
+    pub fn queued_transactions(&self) -> Vec<Transaction> {
+        let nonces = self.pool_nonces();
+        self.transaction_pool.queued(|sender| nonces.get(&sender).cloned())
+            .iter().filter_map(|hash| self.transaction_database.get(hash).cloned()).collect()
+    }
+
+    /// Pending and queued transactions, in submission order.
+    pub fn pool_transactions(&self) -> Vec<Transaction> {
+        self.transaction_pool.all().iter().filter_map(|hash| {
+            self.transaction_database.get(hash).cloned()
+        }).collect()
+    }
+
+    pub fn pooled_transaction(&self, sender: Address, nonce: U256) -> Option<H256> {
+        self.transaction_pool.get(sender, nonce)
+    }
+
+    /// Percentage by which a transaction must raise the gas price of
+    /// the pooled one of the same sender and nonce to replace it.
+    pub fn price_bump(&self) -> usize {
+        self.price_bump
+    }
+
+    pub fn set_price_bump(&mut self, price_bump: usize) {
+        self.price_bump = price_bump;
+    }
+
+    /// Lowest gas price of transactions entering the pool, and default
+    /// gas price of transactions signed by the node.
+    pub fn min_gas_price(&self) -> Gas {
+        self.min_gas_price
+    }
+
+    pub fn set_min_gas_price(&mut self, min_gas_price: Gas) {
+        self.min_gas_price = min_gas_price;
+    }
+
+    pub fn set_allow_zero_gas_price(&mut self, allow: bool) {
+        self.allow_zero_gas_price = allow;
+    }
+
+    /// Whether transactions signed without an EIP-155 chain id are
+    /// accepted when the node has a chain id.
+    pub fn allow_unprotected_transactions(&self) -> bool {
+        self.allow_unprotected_transactions
+    }
+
+    pub fn set_allow_unprotected_transactions(&mut self, allow: bool) {
+        self.allow_unprotected_transactions = allow;
+    }
+
+    /// Whether `dev_accounts` returns the private keys of the generated
+    /// accounts.
+    pub fn expose_private_keys(&self) -> bool {
+        self.expose_private_keys
+    }
+
+    pub fn set_expose_private_keys(&mut self, expose: bool) {
+        self.expose_private_keys = expose;
+    }
+
+    pub fn accepts_gas_price(&self, gas_price: Gas) -> bool {
+        accepts_gas_price(gas_price, self.min_gas_price, self.allow_zero_gas_price)
+    }
+
+    pub fn mining_schedule(&self) -> MiningSchedule {
+        self.mining_schedule
+    }
+
+    pub fn set_mining_schedule(&mut self, mining_schedule: MiningSchedule) {
+        self.mining_schedule = mining_schedule;
+    }
+
+    pub fn gas_price_oracle(&self) -> GasPriceOracle {
+        self.gas_price_oracle
+    }
+
+    pub fn set_gas_price_oracle(&mut self, gas_price_oracle: GasPriceOracle) {
+        self.gas_price_oracle = gas_price_oracle;
+    }
+
+    /// Percentage added to the estimated gas of transactions sent
+    /// without a gas limit.
+    pub fn gas_margin(&self) -> usize {
+        self.gas_margin
+    }
+
+    pub fn set_gas_margin(&mut self, gas_margin: usize) {
+        self.gas_margin = gas_margin;
+    }
+
+    pub fn transaction_pool(&self) -> &TransactionPool {
+        &self.transaction_pool
+    }
+
+    /// Most transactions the pool holds, beyond which the lowest gas
+    /// price ones are evicted.
+    pub fn pool_size(&self) -> usize {
+        self.pool_size
+    }
+
+    pub fn set_pool_size(&mut self, pool_size: usize) {
+        self.pool_size = pool_size;
+    }
+
+    /// Most transactions of one sender the pool holds.
+    pub fn pool_sender_slots(&self) -> usize {
+        self.pool_sender_slots
+    }
+
+    pub fn set_pool_sender_slots(&mut self, pool_sender_slots: usize) {
+        self.pool_sender_slots = pool_sender_slots;
+    }
+
+    /// Nonce of an account in the head state, or on the fork if it was
+    /// not copied locally yet.
+    pub fn account_nonce(&self, address: Address) -> Result<U256, Error> {
+        let account: Option<Account> = self.stateful.state_of(self.head_root).get(&address);
+        match (account, self.fork.as_ref()) {
+            (Some(account), _) => Ok(account.nonce),
+            (None, Some(fork)) => Ok(fork.account(address)?.map(|account| account.nonce).unwrap_or(U256::zero())),
+            (None, None) => Ok(U256::zero()),
+        }
+    }
+
+    /// Nonce for a new transaction of `address`, after those it already
+    /// has pending in the pool.
+    pub fn next_nonce(&self, address: Address) -> Result<U256, Error> {
+        Ok(self.transaction_pool.next_nonce(address, self.account_nonce(address)?))
+    }
+
+    /// Head nonces of the senders in the pool. Senders whose nonce
+    /// cannot be read from the fork are left out, and their
+    /// transactions held back until it can.
+    fn pool_nonces(&self) -> HashMap<Address, U256> {
+        self.transaction_pool.senders().into_iter().filter_map(|sender| match self.account_nonce(sender) {
+            Ok(nonce) => Some((sender, nonce)),
+            Err(err) => {
+                warn!("cannot read the nonce of 0x{:x}: {}", sender, err);
+                None
+            },
+        }).collect()
     }
 
-    pub fn append_block(&mut self, block: Block) -> H256 {
+    /// Number of transactions submitted so far, which is the position
+    /// of the next one.
+    pub fn pending_transaction_count(&self) -> usize {
+        self.dropped_pending_transaction_hashes + self.pending_transaction_hashes.len()
+    }
+
+    /// Hashes of the transactions submitted from position `start` on,
+    /// starting at the oldest one kept if those before were dropped.
+    pub fn pending_transaction_hashes_since(&self, start: usize) -> Vec<H256> {
+        let skip = start.saturating_sub(self.dropped_pending_transaction_hashes);
+        self.pending_transaction_hashes.iter().skip(skip).cloned().collect()
+    }
+
+    pub fn append_block(&mut self, block: Block) -> Result<H256, Error> {
+        let parent = match self.canonical_hashes.last() {
+            Some(parent_hash) => self.total_header_database.get(parent_hash).cloned()
+                .ok_or(Error::Internal("head block has no total header"))?,
+            None => return Err(Error::Internal("chain has no genesis block")),
+        };
+
         let hash = block.header.header_hash();
+        if !self.side_blocks.remove(&hash) {
+            self.raw_database.insert(hash, rlp::encode(&block).to_vec());
+        }
         self.block_database.insert(hash, block.clone());
 
         for transaction in &block.transactions {
             let transaction_hash = H256::from(Keccak256::digest(&rlp::encode(transaction).to_vec()).as_slice());
+            self.transaction_database.insert(transaction_hash, transaction.clone());
             self.transaction_block_hashes.insert(transaction_hash, hash);
         }
 
-        assert!(self.block_hashes.len() > 0);
-        let parent_hash = self.block_hashes[self.block_hashes.len() - 1];
-        let parent = self.total_header_database.get(&parent_hash).unwrap().clone();
         self.total_header_database.insert(hash, TotalHeader::from_parent(block.header.clone(), &parent));
 
-        self.block_hashes.push(hash);
+        self.canonical_hashes.push(hash);
         self.current_block = hash;
 
-        hash
+        if self.log_index.is_some() {
+            let logs = self.block_logs(&block);
+            let number = block.header.number.as_usize();
+            if let Err(err) = self.log_index.as_mut().unwrap().append_block(number, hash, &logs) {
+                warn!("cannot write block 0x{:x} to the log index: {}", number, err);
+            }
+        }
+
+        Ok(hash)
+    }
+
+    /// Logs of the transactions of `block`, of which receipts are kept.
+    fn block_logs(&self, block: &Block) -> Vec<Log> {
+        block.transactions.iter()
+            .filter_map(|transaction| self.get_receipt_by_transaction_hash(transaction.rlp_hash()).ok())
+            .flat_map(|receipt| receipt.logs.into_iter())
+            .collect()
+    }
+
+    /// Rewind the chain to block `number`. Later blocks become side
+    /// blocks, while their transactions and receipts are discarded.
+    /// Pending transactions are kept and will be sealed on top of the
+    /// new head.
+    pub fn set_head(&mut self, number: usize) -> Result<(), Error> {
+        if number > self.block_height() {
+            return Err(Error::NotFound);
+        }
+        if number + 1 < self.frozen.frozen_before() {
+            return Err(Error::Frozen(self.frozen.frozen_before()));
+        }
+        self.reorgs += 1;
+
+        for hash in self.canonical_hashes.split_off(number + 1) {
+            let block = self.block_database.get(&hash).cloned()
+                .ok_or(Error::Internal("block of the chain is not stored"))?;
+            self.side_blocks.insert(hash);
+
+            for transaction in &block.transactions {
+                let transaction_hash = H256::from(Keccak256::digest(&rlp::encode(transaction).to_vec()).as_slice());
+                self.transaction_database.remove(&transaction_hash);
+                self.raw_database.remove(&transaction_hash);
+                self.transaction_block_hashes.remove(&transaction_hash);
+                self.receipt_database.remove(&transaction_hash);
+                self.status_database.remove(&transaction_hash);
+                self.revert_reason_database.remove(&transaction_hash);
+                self.receipt_context_database.remove(&transaction_hash);
+                self.impersonated_transactions.remove(&transaction_hash);
+            }
+        }
+
+        let transaction_database = &self.transaction_database;
+        let transaction_block_hashes = &self.transaction_block_hashes;
+        for hashes in self.session_database.values_mut() {
+            hashes.retain(|hash| transaction_database.contains_key(hash) ||
+                          transaction_block_hashes.contains_key(hash));
+        }
+
+        if let Some(ref mut index) = self.log_index {
+            if let Err(err) = index.rewind(number) {
+                warn!("cannot rewind the log index: {}", err);
+            }
+        }
+
+        // Writes made on top of the new head or later are lost with
+        // its state.
+        self.state_writes.retain(|&(before, _)| before <= number);
+
+        self.fat_database.truncate(number + 1);
+        if self.pruned_before > number + 1 {
+            self.pruned_before = number + 1;
+        }
+
+        self.current_block = self.canonical_hashes[number];
+        let state_root = self.get_block_by_number(number).header.state_root;
+        self.stateful = MemoryStateful::new(self.database, state_root);
+        self.head_root = state_root;
+        self.sealing = false;
+
+        Ok(())
+    }
+
+    /// Store a block of another branch than the canonical chain, on top
+    /// of a stored parent. Its transactions are neither executed nor
+    /// indexed until fork choice makes it canonical.
+    pub fn insert_side_block(&mut self, block: Block) -> Result<H256, Error> {
+        let hash = block.header.header_hash();
+        if self.total_header_database.contains_key(&hash) {
+            return Ok(hash);
+        }
+        let parent = self.total_header_database.get(&block.header.parent_hash).cloned()
+            .ok_or(Error::InvalidHeader("parentHash", format!("0x{:x} is not a known block", block.header.parent_hash)))?;
+
+        self.raw_database.insert(hash, rlp::encode(&block).to_vec());
+        self.total_header_database.insert(hash, TotalHeader::from_parent(block.header.clone(), &parent));
+        self.block_database.insert(hash, block);
+        self.side_blocks.insert(hash);
+        Ok(hash)
+    }
+
+    /// Drop the side block `hash` together with the side blocks built on
+    /// top of it.
+    pub fn remove_side_block(&mut self, hash: H256) {
+        self.reorgs += 1;
+        let mut removed = vec![hash];
+        while let Some(hash) = removed.pop() {
+            if !self.side_blocks.remove(&hash) {
+                continue;
+            }
+            self.block_database.remove(&hash);
+            self.raw_database.remove(&hash);
+            self.total_header_database.remove(&hash);
+
+            let block_database = &self.block_database;
+            removed.extend(self.side_blocks.iter().filter(|child| {
+                block_database.get(child).map(|block| block.header.parent_hash == hash).unwrap_or(false)
+            }).cloned());
+        }
+    }
+
+    pub fn is_canonical(&self, hash: H256) -> bool {
+        match self.total_header_database.get(&hash) {
+            Some(total) => self.canonical_hashes.get(total.header.number.as_usize()) == Some(&hash),
+            None => false,
+        }
+    }
+
+    /// Fork choice: the side block with the highest total difficulty,
+    /// the highest number breaking ties, if it is preferred over the
+    /// head. The head wins remaining ties.
+    pub fn preferred_side_block(&self) -> Option<H256> {
+        let rank = |total: &TotalHeader| (total.total_difficulty(), total.header.number);
+        let head = rank(self.total_header_database.get(&self.current_block)?);
+
+        self.side_blocks.iter()
+            .filter_map(|hash| self.total_header_database.get(hash).map(|total| (*hash, rank(total))))
+            .filter(|&(_, ref side)| *side > head)
+            .max_by(|a, b| a.1.cmp(&b.1).then(b.0.cmp(&a.0)))
+            .map(|(hash, _)| hash)
+    }
+
+    /// Side blocks from the one after the last canonical ancestor of
+    /// `hash` up to `hash`, with the number of that ancestor.
+    pub fn side_branch(&self, hash: H256) -> Result<(usize, Vec<Block>), Error> {
+        let mut blocks = Vec::new();
+        let mut current = hash;
+        while !self.is_canonical(current) {
+            let block = self.block_database.get(&current).cloned().ok_or(Error::NotFound)?;
+            current = block.header.parent_hash;
+            blocks.push(block);
+        }
+        blocks.reverse();
+
+        let ancestor = self.get_total_header_by_hash(current)?.header.number.as_usize();
+        Ok((ancestor, blocks))
     }
 
     pub fn fat_transit(&mut self, number: usize, accounts: &[AccountChange]) {
@@ -165,12 +706,36 @@ impl MinerState {
         self.fat_database[number].clone()
     }
 
+    /// Every account and storage index written at any height, standing
+    /// for the preimages of the hashed keys of the state tries.
+    pub fn known_storage(&self) -> HashMap<Address, HashSet<U256>> {
+        let mut known: HashMap<Address, HashSet<U256>> = HashMap::new();
+        for accounts in &self.fat_database {
+            for (address, storage) in accounts {
+                known.entry(*address).or_insert(HashSet::new()).extend(storage.keys().cloned());
+            }
+        }
+        known
+    }
+
+    /// Keep the RLP of a block or transaction exactly as it was sealed
+    /// or received.
+    pub fn insert_hash_raw(&mut self, hash: H256, raw: Vec<u8>) {
+        self.raw_database.insert(hash, raw);
+    }
+
+    /// RLP kept for `hash` by `insert_hash_raw`. Blocks are left out
+    /// once frozen.
+    pub fn get_hash_raw(&self, hash: H256) -> Option<Vec<u8>> {
+        self.raw_database.get(&hash).cloned()
+    }
+
     pub fn insert_receipt(&mut self, transaction_hash: H256, receipt: Receipt) {
         self.receipt_database.insert(transaction_hash, receipt);
     }
 
     pub fn block_height(&self) -> usize {
-        self.block_hashes.len() - 1
+        self.canonical_hashes.len() - 1
     }
 
     pub fn get_transaction_block_hash_by_hash(&self, key: H256) -> Result<H256, Error> {
@@ -178,19 +743,346 @@ impl MinerState {
     }
 
     pub fn get_block_by_hash(&self, key: H256) -> Result<Block, Error> {
-        self.block_database.get(&key).map(|v| v.clone()).ok_or(Error::NotFound)
+        match self.block_database.get(&key) {
+            Some(block) => Ok(block.clone()),
+            None => self.frozen.block(key).ok_or(Error::NotFound),
+        }
     }
 
     pub fn get_transaction_by_hash(&self, key: H256) -> Result<Transaction, Error> {
-        self.transaction_database.get(&key).map(|v| v.clone()).ok_or(Error::NotFound)
+        if let Some(transaction) = self.transaction_database.get(&key) {
+            return Ok(transaction.clone());
+        }
+
+        // Transactions of frozen blocks are only kept in their block.
+        let block = self.get_block_by_hash(self.get_transaction_block_hash_by_hash(key)?)?;
+        block.transactions.into_iter().find(|transaction| transaction.rlp_hash() == key)
+            .ok_or(Error::NotFound)
     }
 
     pub fn get_receipt_by_transaction_hash(&self, key: H256) -> Result<Receipt, Error> {
-        self.receipt_database.get(&key).map(|v| v.clone()).ok_or(Error::NotFound)
+        match self.receipt_database.get(&key) {
+            Some(receipt) => Ok(receipt.clone()),
+            None => {
+                if let Some((number, receipt)) = self.frozen.receipt(key) {
+                    if number >= self.pruned_before {
+                        return Ok(receipt);
+                    }
+                }
+
+                let block_hash = self.get_transaction_block_hash_by_hash(key)?;
+                let number = self.get_total_header_by_hash(block_hash)?.header.number.as_usize();
+                if number < self.pruned_before {
+                    Err(Error::Pruned(self.pruned_before))
+                } else {
+                    Err(Error::NotFound)
+                }
+            },
+        }
+    }
+
+    pub fn remove_receipt(&mut self, transaction_hash: H256) {
+        self.receipt_database.remove(&transaction_hash);
+        self.status_database.remove(&transaction_hash);
+        self.revert_reason_database.remove(&transaction_hash);
+        self.receipt_context_database.remove(&transaction_hash);
+    }
+
+    /// First block whose receipts and logs are still available.
+    pub fn pruned_before(&self) -> usize {
+        self.pruned_before
+    }
+
+    pub fn set_pruned_before(&mut self, number: usize) {
+        self.pruned_before = number;
+    }
+
+    pub fn freeze_config(&self) -> Option<&FreezeConfig> {
+        self.freeze.as_ref()
+    }
+
+    pub fn set_freeze_config(&mut self, freeze: Option<FreezeConfig>) {
+        self.freeze = freeze;
+    }
+
+    /// First block that is not frozen.
+    pub fn frozen_before(&self) -> usize {
+        self.frozen.frozen_before()
     }
 
+    /// Move the blocks before `until` that are not frozen yet, their
+    /// transactions and receipts, into the frozen segment.
+    pub fn freeze_until(&mut self, until: usize, dir: Option<&Path>) -> Result<(), Error> {
+        let from = self.frozen.frozen_before();
+
+        let mut blocks = Vec::new();
+        let mut receipts = Vec::new();
+        for number in from..until {
+            let hash = self.canonical_hashes[number];
+            let block = self.block_database.get(&hash).cloned()
+                .ok_or(Error::Internal("block of the chain is not stored"))?;
+            for transaction in &block.transactions {
+                let transaction_hash = transaction.rlp_hash();
+                if let Some(receipt) = self.receipt_database.get(&transaction_hash) {
+                    receipts.push((transaction_hash, number, receipt.clone()));
+                }
+            }
+            blocks.push((hash, block));
+        }
+
+        let name = format!("{:x}", self.canonical_hashes[0]);
+        self.frozen.append(until, &blocks, &receipts, dir, &name)?;
+
+        for (hash, block) in blocks {
+            self.block_database.remove(&hash);
+            self.raw_database.remove(&hash);
+            for transaction in &block.transactions {
+                self.transaction_database.remove(&transaction.rlp_hash());
+                self.raw_database.remove(&transaction.rlp_hash());
+            }
+        }
+        for (transaction_hash, _, _) in receipts {
+            self.receipt_database.remove(&transaction_hash);
+        }
+
+        // Side blocks below the segment can no longer become canonical.
+        let total_header_database = &self.total_header_database;
+        let stale: Vec<H256> = self.side_blocks.iter().filter(|hash| {
+            total_header_database.get(hash).map(|total| total.header.number.as_usize() < until).unwrap_or(true)
+        }).cloned().collect();
+        for hash in stale {
+            self.remove_side_block(hash);
+        }
+
+        Ok(())
+    }
+
+    pub fn log_retention(&self) -> Option<&LogRetention> {
+        self.log_retention.as_ref()
+    }
+
+    pub fn selfdestruct_mode(&self) -> SelfdestructMode {
+        self.selfdestruct_mode
+    }
+
+    pub fn set_selfdestruct_mode(&mut self, mode: SelfdestructMode) {
+        self.selfdestruct_mode = mode;
+    }
+
+    /// Name of the chain given on the command line.
+    pub fn chain(&self) -> &str {
+        &self.chain
+    }
+
+    pub fn set_chain(&mut self, chain: String) {
+        self.chain = chain;
+    }
+
+    /// Directory of the keystore and caches, reported by
+    /// `admin_nodeInfo`.
+    pub fn data_dir(&self) -> Option<&Path> {
+        self.data_dir.as_ref().map(|dir| dir.as_path())
+    }
+
+    pub fn set_data_dir(&mut self, data_dir: PathBuf) {
+        self.data_dir = Some(data_dir);
+    }
+
+    pub fn log_index(&self) -> Option<&LogIndex> {
+        self.log_index.as_ref()
+    }
+
+    /// Keep an index of the logs of the chain in `dir`, indexing the
+    /// blocks already in the chain that it does not hold yet.
+    pub fn enable_log_index(&mut self, dir: &Path) -> Result<(), Error> {
+        let mut index = LogIndex::open(dir, self.canonical_hashes[0])?;
+        for number in 1..(self.block_height() + 1) {
+            let block = self.get_block_by_number(number);
+            index.append_block(number, self.canonical_hashes[number], &self.block_logs(&block))?;
+        }
+        self.log_index = Some(index);
+        Ok(())
+    }
+
+    /// Network id reported by `net_version`.
+    pub fn network_id(&self) -> u64 {
+        self.network_id
+    }
+
+    pub fn set_network_id(&mut self, network_id: u64) {
+        self.network_id = network_id;
+    }
+
+    /// EIP-155 chain id the node signs with and requires of replay
+    /// protected transactions, `None` to sign without one.
+    pub fn chain_id(&self) -> Option<u64> {
+        self.chain_id
+    }
+
+    pub fn set_chain_id(&mut self, chain_id: Option<u64>) {
+        self.chain_id = chain_id;
+    }
+
+    /// Most bytes of memory a transaction may use, `None` for the limit
+    /// of the patch. Installed around each execution with
+    /// `with_memory_limit`.
+    pub fn memory_limit(&self) -> Option<usize> {
+        self.memory_limit
+    }
+
+    pub fn set_memory_limit(&mut self, memory_limit: Option<usize>) {
+        self.memory_limit = memory_limit;
+    }
+
+    /// How addresses are parsed and written by the RPC of this node.
+    pub fn address_format(&self) -> AddressFormat {
+        self.address_format
+    }
+
+    pub fn set_address_format(&mut self, address_format: AddressFormat) {
+        self.address_format = address_format;
+    }
+
+    /// Number of threads a log query is split across.
+    pub fn log_workers(&self) -> usize {
+        self.log_workers
+    }
+
+    pub fn set_log_workers(&mut self, log_workers: usize) {
+        self.log_workers = log_workers;
+    }
+
+    /// Number of transactions of a mined block executed at once.
+    pub fn execution_workers(&self) -> usize {
+        self.execution_workers
+    }
+
+    pub fn set_execution_workers(&mut self, execution_workers: usize) {
+        self.execution_workers = execution_workers;
+        self.execution_pool = if execution_workers > 1 { Some(CpuPool::new(execution_workers)) } else { None };
+    }
+
+    /// Threads of the `execution_workers`, started once for every block
+    /// mined on this state.
+    pub fn execution_pool(&self) -> Option<&CpuPool> {
+        self.execution_pool.as_ref()
+    }
+
+    /// Accounts read by executions on this state, shared between them.
+    pub fn account_cache(&self) -> &AccountCache {
+        &self.account_cache
+    }
+
+    /// Keep up to `capacity` accounts read by executions, zero to
+    /// always read them from the trie.
+    pub fn set_account_cache(&mut self, capacity: usize) {
+        self.account_cache = AccountCache::new(capacity);
+    }
+
+    /// Number of blocks, and of receipts, the RPC keeps converted.
+    pub fn rpc_cache(&self) -> usize {
+        self.rpc_cache
+    }
+
+    pub fn set_rpc_cache(&mut self, rpc_cache: usize) {
+        self.rpc_cache = rpc_cache;
+    }
+
+    /// Changes each time blocks are rewound or dropped, or the chain is
+    /// reset, so that what was read from the chain before can be told
+    /// apart from what is read after.
+    pub fn reorgs(&self) -> u64 {
+        self.reorgs
+    }
+
+    pub fn fork(&self) -> Option<&Fork> {
+        self.fork.as_ref()
+    }
+
+    pub fn set_fork(&mut self, fork: Option<Fork>) {
+        self.fork = fork;
+    }
+
+    /// Execute a transaction on the head state, resolving missing
+    /// accounts against the fork if any.
+    pub fn call<P: Patch>(
+        &mut self, valid: ValidTransaction, header: &HeaderParams, last_hashes: &[H256]
+    ) -> Result<SeqTransactionVM<P>, Error> {
+        let remote = self.fork.as_ref();
+        let account_cache = &self.account_cache;
+        let stateful = &mut self.stateful;
+        let tracer = &mut self.tracer;
+        with_memory_limit(self.memory_limit, move || match *tracer {
+            Some(ref mut tracer) => call_with_tracer::<P>(
+                remote, account_cache, stateful, valid, header, last_hashes, &mut **tracer),
+            None => fork::call::<P>(remote, account_cache, stateful, valid, header, last_hashes),
+        })
+    }
+
+    pub fn set_log_retention(&mut self, retention: Option<LogRetention>) {
+        self.log_retention = retention;
+    }
+
+    /// Panics if `index` is above the block height, which callers check
+    /// with `block_height` or `from_block_number` first.
     pub fn get_block_by_number(&self, index: usize) -> Block {
-        self.get_block_by_hash(self.block_hashes[index]).unwrap()
+        self.get_block_by_hash(self.canonical_hashes[index]).unwrap()
+    }
+
+    /// Hash of the canonical block at `index`, with the same bounds as
+    /// `get_block_by_number`.
+    pub fn get_canonical_hash(&self, index: usize) -> H256 {
+        self.canonical_hashes[index]
+    }
+
+    /// Transaction at `index` in the block `block_hash`. `NotFound` if
+    /// the block is unknown or has no transaction at that index.
+    pub fn get_transaction_by_block_hash_and_index(&self, block_hash: H256, index: U256) -> Result<Transaction, Error> {
+        let block = self.get_block_by_hash(block_hash)?;
+        if index >= U256::from(block.transactions.len()) {
+            return Err(Error::NotFound);
+        }
+        Ok(block.transactions[index.as_usize()].clone())
+    }
+
+    /// Blocks that left the chain and may be included as ommers of the
+    /// block at `number` on top of the head: they branch off the chain
+    /// at most `MAX_OMMER_DEPTH` generations back and are not included
+    /// by a block yet. The closest ones come first.
+    pub fn ommer_candidates(&self, number: usize) -> Vec<Header> {
+        let oldest = if number > MAX_OMMER_DEPTH { number - MAX_OMMER_DEPTH } else { 1 };
+
+        let mut included = HashSet::new();
+        for ancestor in oldest..number {
+            if let Some(block) = self.canonical_hashes.get(ancestor).and_then(|hash| self.get_block_by_hash(*hash).ok()) {
+                for ommer in &block.ommers {
+                    included.insert(ommer.header_hash());
+                }
+            }
+        }
+
+        let mut candidates: Vec<Header> = self.side_blocks.iter().filter_map(|hash| {
+            self.block_database.get(hash).map(|block| (hash, &block.header))
+        }).filter(|&(hash, header)| {
+            let ommer_number = header.number.as_usize();
+            ommer_number >= oldest && ommer_number < number &&
+                self.canonical_hashes.get(ommer_number - 1) == Some(&header.parent_hash) &&
+                !included.contains(hash)
+        }).map(|(_, header)| header.clone()).collect();
+
+        candidates.sort_by(|a, b| b.number.cmp(&a.number).then(a.header_hash().cmp(&b.header_hash())));
+        candidates.truncate(MAX_OMMERS);
+        candidates
+    }
+
+    /// Ommer at `index` in the block `block_hash`. `NotFound` if the
+    /// block is unknown or has no ommer at that index.
+    pub fn get_ommer_by_block_hash_and_index(&self, block_hash: H256, index: U256) -> Result<Header, Error> {
+        let block = self.get_block_by_hash(block_hash)?;
+        if index >= U256::from(block.ommers.len()) {
+            return Err(Error::NotFound);
+        }
+        Ok(block.ommers[index.as_usize()].clone())
     }
 
     pub fn get_total_header_by_hash(&self, key: H256) -> Result<TotalHeader, Error> {
@@ -198,11 +1090,11 @@ impl MinerState {
     }
 
     pub fn get_total_header_by_number(&self, index: usize) -> TotalHeader {
-        self.total_header_database.get(&self.block_hashes[index]).map(|v| v.clone()).unwrap()
+        self.total_header_database.get(&self.canonical_hashes[index]).map(|v| v.clone()).unwrap()
     }
 
     pub fn get_last_256_block_hashes_by_number(&self, number: usize) -> Vec<H256> {
-        let mut hashes: Vec<H256> = (&self.block_hashes[0..number]).into();
+        let mut hashes: Vec<H256> = (&self.canonical_hashes[0..number]).into();
         let mut ret = Vec::new();
 
         for _ in 0..256 {
@@ -231,10 +1123,173 @@ impl MinerState {
         &self.stateful
     }
 
+    pub fn head_root(&self) -> H256 {
+        self.head_root
+    }
+
+    /// Start executing a block on the working trie. Until
+    /// `end_sealing`, read paths keep seeing the head state as of the
+    /// start.
+    pub fn begin_sealing(&mut self) {
+        self.stateful = MemoryStateful::new(self.database, self.head_root);
+        self.sealing = true;
+    }
+
+    /// Publish the working trie as the head state once the block is
+    /// appended, or roll it back to the head state otherwise.
+    pub fn end_sealing(&mut self, appended: bool) {
+        if appended {
+            self.head_root = self.stateful.root();
+        } else {
+            self.stateful = MemoryStateful::new(self.database, self.head_root);
+        }
+        self.sealing = false;
+    }
+
     pub fn stateful_at(&self, root: H256) -> MemoryStateful<'static> {
         MemoryStateful::new(self.database, root)
     }
 
+    /// State root of a block. For the head, this includes changes made
+    /// through the `set_*` methods that are not sealed yet.
+    pub fn state_root_at(&self, number: usize) -> H256 {
+        if number == self.block_height() {
+            self.head_root
+        } else {
+            self.get_block_by_number(number).header.state_root
+        }
+    }
+
+    /// Rewrite an account record of the head state directly, outside of
+    /// any transaction. The change is sealed with the next block.
+    fn modify_account<F: FnOnce(&mut Account)>(&mut self, address: Address, f: F) {
+        let root = modify_account_at(self.database, self.stateful.root(), address, f);
+        self.stateful = MemoryStateful::new(self.database, root);
+        if !self.sealing {
+            self.head_root = root;
+        }
+
+        let height = self.block_height();
+        self.touch_fat_account(height, address);
+    }
+
+    pub fn set_balance(&mut self, address: Address, balance: U256) {
+        self.modify_account(address, |account| account.balance = balance);
+        self.record_write(StateWrite::Balance(address, balance));
+    }
+
+    pub fn add_balance(&mut self, address: Address, value: U256) {
+        self.modify_account(address, |account| account.balance = account.balance + value);
+    }
+
+    pub fn set_nonce(&mut self, address: Address, nonce: U256) {
+        self.modify_account(address, |account| account.nonce = nonce);
+        self.record_write(StateWrite::Nonce(address, nonce));
+    }
+
+    pub fn set_code(&mut self, address: Address, code: Vec<u8>) {
+        let database = self.database;
+        self.modify_account(address, |account| set_account_code(database, account, code.clone()));
+        self.record_write(StateWrite::Code(address, code));
+    }
+
+    pub fn set_storage(&mut self, address: Address, index: U256, value: M256) {
+        let database = self.database;
+        self.modify_account(address, |account| set_account_storage(database, account, index, value));
+
+        let height = self.block_height();
+        self.set_fat_storage(height, address, index, value);
+        self.record_write(StateWrite::Storage(address, index, value));
+    }
+
+    fn record_write(&mut self, write: StateWrite) {
+        let before = self.block_height() + 1;
+        self.state_writes.push((before, write));
+    }
+
+    /// Writes of the `set_*` methods still part of the chain, in the
+    /// order they were made, with the number of the block they were
+    /// made before.
+    pub fn state_writes(&self) -> &[(usize, StateWrite)] {
+        &self.state_writes
+    }
+
+    /// Make `write` again, as the `set_*` method it was recorded by.
+    pub fn apply_state_write(&mut self, write: StateWrite) {
+        match write {
+            StateWrite::Balance(address, balance) => self.set_balance(address, balance),
+            StateWrite::Nonce(address, nonce) => self.set_nonce(address, nonce),
+            StateWrite::Code(address, code) => self.set_code(address, code),
+            StateWrite::Storage(address, index, value) => self.set_storage(address, index, value),
+        }
+    }
+
+    pub fn touch_fat_account(&mut self, number: usize, address: Address) {
+        self.fat_database[number].entry(address).or_insert(HashMap::new());
+    }
+
+    pub fn set_fat_storage(&mut self, number: usize, address: Address, index: U256, value: M256) {
+        let fat_storage = self.fat_database[number].entry(address).or_insert(HashMap::new());
+        if value == M256::zero() {
+            fat_storage.remove(&index);
+        } else {
+            fat_storage.insert(index, value);
+        }
+    }
+
+    /// Replace the chain by the one of `fresh`, keeping the node
+    /// settings, unlocked and impersonated accounts. All chains share the
+    /// raw hash store, so the trie nodes of the replaced chain are kept.
+    pub fn reset(&mut self, mut fresh: MinerState) {
+        fresh.unlocked_accounts = mem::replace(&mut self.unlocked_accounts, HashMap::new());
+        fresh.impersonated_accounts = mem::replace(&mut self.impersonated_accounts, HashSet::new());
+        fresh.work_difficulty = self.work_difficulty;
+        fresh.max_clock_drift = self.max_clock_drift;
+        fresh.fee_policy = mem::replace(&mut self.fee_policy, Box::new(StandardFeePolicy));
+        fresh.tracer = self.tracer.take();
+        fresh.log_retention = self.log_retention.take();
+        fresh.freeze = self.freeze.take();
+        fresh.price_bump = self.price_bump;
+        fresh.pool_size = self.pool_size;
+        fresh.pool_sender_slots = self.pool_sender_slots;
+        fresh.min_gas_price = self.min_gas_price;
+        fresh.allow_zero_gas_price = self.allow_zero_gas_price;
+        fresh.allow_unprotected_transactions = self.allow_unprotected_transactions;
+        fresh.expose_private_keys = self.expose_private_keys;
+        fresh.mining_schedule = self.mining_schedule;
+        fresh.gas_price_oracle = self.gas_price_oracle;
+        fresh.gas_margin = self.gas_margin;
+        fresh.selfdestruct_mode = self.selfdestruct_mode;
+        fresh.chain = mem::replace(&mut self.chain, String::new());
+        fresh.network_id = self.network_id;
+        fresh.chain_id = self.chain_id;
+        fresh.memory_limit = self.memory_limit;
+        fresh.address_format = self.address_format;
+        fresh.data_dir = self.data_dir.take();
+        fresh.log_workers = self.log_workers;
+        fresh.execution_workers = self.execution_workers;
+        fresh.execution_pool = self.execution_pool.take();
+        fresh.account_cache = AccountCache::new(self.account_cache.capacity());
+        fresh.rpc_cache = self.rpc_cache;
+        fresh.reorgs = self.reorgs + 1;
+        if let Some(index) = self.log_index.take() {
+            // The index of the old chain is closed first, as it may be
+            // the one of the fresh chain.
+            let dir = index.dir().to_path_buf();
+            drop(index);
+            if let Err(err) = fresh.enable_log_index(&dir) {
+                warn!("cannot open the log index of the reset chain: {}", err);
+            }
+        }
+        fresh.watch_events = mem::replace(&mut self.watch_events, Vec::new());
+        fresh.fork = self.fork.take();
+        if let Some(ref fork) = fresh.fork {
+            fork.clear_changes();
+        }
+
+        *self = fresh;
+    }
+
     pub fn accounts(&self) -> Vec<SecretKey> {
         self.accounts.clone()
     }
@@ -243,6 +1298,163 @@ impl MinerState {
         self.accounts.push(key)
     }
 
+    /// Keep a keystore account usable for signing, until the duration
+    /// expires or forever if none is given.
+    /// Durations too long to be represented as a point in time are
+    /// rejected; unlock without a duration instead.
+    pub fn unlock_account(&mut self, key: SecretKey, duration: Option<Duration>) -> Result<(), Error> {
+        let address = Address::from_secret_key(&key)?;
+        let until = match duration {
+            Some(duration) => Some(Instant::now().checked_add(duration).ok_or(Error::InvalidParams)?),
+            None => None,
+        };
+        self.unlocked_accounts.insert(address, (key, until));
+        Ok(())
+    }
+
+    pub fn lock_account(&mut self, address: Address) -> bool {
+        self.unlocked_accounts.remove(&address).is_some()
+    }
+
+    pub fn secret_key(&self, address: Address) -> Option<SecretKey> {
+        for key in &self.accounts {
+            if Address::from_secret_key(key).ok() == Some(address) {
+                return Some(key.clone());
+            }
+        }
+
+        match self.unlocked_accounts.get(&address) {
+            Some(&(ref key, None)) => Some(key.clone()),
+            Some(&(ref key, Some(until))) if Instant::now() < until => Some(key.clone()),
+            _ => None,
+        }
+    }
+
+    /// Accept transactions from this address without its key.
+    pub fn impersonate_account(&mut self, address: Address) {
+        self.impersonated_accounts.insert(address);
+    }
+
+    pub fn stop_impersonating_account(&mut self, address: Address) -> bool {
+        self.impersonated_accounts.remove(&address)
+    }
+
+    pub fn is_impersonated(&self, address: Address) -> bool {
+        self.impersonated_accounts.contains(&address)
+    }
+
+    /// Impersonated accounts, in address order.
+    pub fn impersonated_accounts(&self) -> Vec<Address> {
+        let mut accounts: Vec<Address> = self.impersonated_accounts.iter().cloned().collect();
+        accounts.sort();
+        accounts
+    }
+
+    /// Record that a transaction is to be executed as sent from
+    /// `sender`, whatever its signature recovers to.
+    pub fn insert_impersonated_transaction(&mut self, transaction_hash: H256, sender: Address) {
+        self.impersonated_transactions.insert(transaction_hash, sender);
+    }
+
+    pub fn impersonated_sender(&self, transaction_hash: H256) -> Option<Address> {
+        self.impersonated_transactions.get(&transaction_hash).cloned()
+    }
+
+    /// Hashes of the transactions sent from an impersonated account,
+    /// with their sender, in hash order.
+    pub fn impersonated_transactions(&self) -> Vec<(H256, Address)> {
+        let mut transactions: Vec<(H256, Address)> = self.impersonated_transactions.iter()
+            .map(|(hash, sender)| (*hash, *sender)).collect();
+        transactions.sort();
+        transactions
+    }
+
+    pub fn transaction_sender(&self, transaction: &Transaction) -> Result<Address, Error> {
+        let transaction_hash = H256::from(Keccak256::digest(&rlp::encode(transaction).to_vec()).as_slice());
+        match self.impersonated_sender(transaction_hash) {
+            Some(sender) => Ok(sender),
+            None => transaction.caller().map_err(|_| MinerError::InvalidSignature.into()),
+        }
+    }
+
+    pub fn genesis_accounts(&self) -> &[(SecretKey, U256)] {
+        &self.genesis_accounts
+    }
+
+    pub fn set_genesis_accounts(&mut self, accounts: Vec<(SecretKey, U256)>) {
+        self.genesis_accounts = accounts;
+    }
+
+    /// Accounts pre-allocated in the genesis state, besides the
+    /// generated accounts.
+    pub fn genesis_alloc(&self) -> &[GenesisAccount] {
+        &self.genesis_alloc
+    }
+
+    pub fn set_genesis_alloc(&mut self, alloc: Vec<GenesisAccount>) {
+        self.genesis_alloc = alloc;
+    }
+
+    pub fn tag_transaction(&mut self, transaction_hash: H256, session: String) {
+        self.session_database.entry(session).or_insert(Vec::new()).push(transaction_hash);
+    }
+
+    pub fn session_transaction_hashes(&self, session: &str) -> Vec<H256> {
+        self.session_database.get(session).cloned().unwrap_or(Vec::new())
+    }
+
+    pub fn insert_side_chain(&mut self, side_chain: SideChain) -> usize {
+        let id = self.next_side_chain;
+        self.next_side_chain += 1;
+        self.side_chains.insert(id, side_chain);
+        id
+    }
+
+    pub fn side_chain(&self, id: usize) -> Option<SideChain> {
+        self.side_chains.get(&id).cloned()
+    }
+
+    pub fn update_side_chain(&mut self, id: usize, side_chain: SideChain) {
+        self.side_chains.insert(id, side_chain);
+    }
+
+    pub fn remove_side_chain(&mut self, id: usize) {
+        self.side_chains.remove(&id);
+    }
+
+    pub fn work_difficulty(&self) -> U256 {
+        self.work_difficulty
+    }
+
+    pub fn set_work_difficulty(&mut self, difficulty: U256) {
+        self.work_difficulty = difficulty;
+    }
+
+    pub fn max_clock_drift(&self) -> u64 {
+        self.max_clock_drift
+    }
+
+    pub fn set_max_clock_drift(&mut self, seconds: u64) {
+        self.max_clock_drift = seconds;
+    }
+
+    pub fn fee_policy(&self) -> &FeePolicy {
+        &*self.fee_policy
+    }
+
+    pub fn set_fee_policy(&mut self, fee_policy: Box<FeePolicy + Send + Sync>) {
+        self.fee_policy = fee_policy;
+    }
+
+    /// Report the execution of every mined transaction to `tracer`.
+    pub fn set_tracer(&mut self, tracer: Option<Box<Tracer + Send + Sync>>) {
+        self.tracer = tracer;
+    }
+
+    pub fn has_tracer(&self) -> bool {
+        self.tracer.is_some()
+    }
+
     pub fn set_receipt_status(&mut self, transaction_hash: H256, is_okay: bool) {
         self.status_database.insert(transaction_hash, is_okay);
     }
@@ -250,4 +1462,77 @@ impl MinerState {
     pub fn receipt_status(&self, transaction_hash: H256) -> bool {
         *self.status_database.get(&transaction_hash).unwrap_or(&false)
     }
+
+    pub fn set_revert_reason(&mut self, transaction_hash: H256, reason: String) {
+        self.revert_reason_database.insert(transaction_hash, reason);
+    }
+
+    pub fn revert_reason(&self, transaction_hash: H256) -> Option<String> {
+        self.revert_reason_database.get(&transaction_hash).cloned()
+    }
+
+    pub fn set_receipt_context(&mut self, transaction_hash: H256, context: ReceiptContext) {
+        self.receipt_context_database.insert(transaction_hash, context);
+    }
+
+    pub fn receipt_context(&self, transaction_hash: H256) -> Result<ReceiptContext, Error> {
+        self.receipt_context_database.get(&transaction_hash).cloned().ok_or(Error::NotFound)
+    }
+
+    pub fn append_watch_event(&mut self, event: WatchEvent) {
+        self.watch_events.push(event);
+    }
+
+    /// Events of the artifact watcher, starting from the `from`-th.
+    pub fn watch_events(&self, from: usize) -> &[WatchEvent] {
+        if from >= self.watch_events.len() {
+            &[]
+        } else {
+            &self.watch_events[from..]
+        }
+    }
+}
+
+/// Rewrite an account record in the state trie at `root`, creating an
+/// empty account if it does not exist, and return the new root.
+pub fn modify_account_at<F: FnOnce(&mut Account)>(
+    database: &MemoryDatabase, root: H256, address: Address, f: F
+) -> H256 {
+    let mut trie: FixedSecureTrie<_, Address, Account> = database.create_fixed_secure_trie(root);
+
+    let mut account = match trie.get(&address) {
+        Some(account) => account,
+        None => {
+            let code_hash = H256::from(Keccak256::digest(&[]).as_slice());
+            database.create_guard().set(code_hash, Vec::new());
+            Account {
+                nonce: U256::zero(),
+                balance: U256::zero(),
+                storage_root: database.create_empty().root(),
+                code_hash,
+            }
+        },
+    };
+    f(&mut account);
+    trie.insert(address, account);
+
+    trie.root()
+}
+
+/// Insert the code into the raw hash store and point the account to it.
+pub fn set_account_code(database: &MemoryDatabase, account: &mut Account, code: Vec<u8>) {
+    let code_hash = H256::from(Keccak256::digest(&code).as_slice());
+    database.create_guard().set(code_hash, code);
+    account.code_hash = code_hash;
+}
+
+pub fn set_account_storage(database: &MemoryDatabase, account: &mut Account, index: U256, value: M256) {
+    let mut storage: FixedSecureTrie<_, H256, M256> =
+        database.create_fixed_secure_trie(account.storage_root);
+    if value == M256::zero() {
+        storage.remove(&H256::from(index));
+    } else {
+        storage.insert(H256::from(index), value);
+    }
+    account.storage_root = storage.root();
 }
@@ -0,0 +1,321 @@
+use bigint::{H256, M256, U256, Gas, Address};
+use block::{Log, TransactionAction};
+use sputnikvm::{HeaderParams, Machine, MachineStatus, Patch, SeqMemory, SeqTransactionVM, Stack, ValidTransaction, VM, VMStatus};
+use sputnikvm_stateful::MemoryStateful;
+
+use error::Error;
+use super::fork::{Fork, fulfill};
+use super::account_cache::AccountCache;
+
+/// An opcode about to run.
+pub struct StepContext<'a> {
+    pub depth: usize,
+    pub address: Address,
+    pub pc: usize,
+    pub opcode: u8,
+    /// Gas left in the frame.
+    pub gas: Gas,
+    pub stack: &'a Stack,
+}
+
+/// A call or creation starting. For creations, `input` is the init
+/// code and `address` the created contract.
+pub struct FrameContext<'a> {
+    pub depth: usize,
+    pub caller: Address,
+    pub address: Address,
+    pub value: U256,
+    pub gas: Gas,
+    pub input: &'a [u8],
+}
+
+/// A call or creation that ended.
+pub struct ReturnContext<'a> {
+    pub depth: usize,
+    pub address: Address,
+    pub success: bool,
+    pub gas_used: Gas,
+    pub output: &'a [u8],
+}
+
+/// Hooks into the execution of a transaction, for analyses of crates
+/// embedding the node. Every hook does nothing by default. Depths start
+/// at 0 for the frame of the transaction itself.
+pub trait Tracer {
+    fn on_step(&mut self, _step: &StepContext) { }
+    fn on_call(&mut self, _frame: &FrameContext) { }
+    fn on_create(&mut self, _frame: &FrameContext) { }
+    /// A SSTORE succeeded.
+    fn on_sstore(&mut self, _address: Address, _index: U256, _value: M256) { }
+    /// A LOG succeeded. Logs of frames that fail later are reported as
+    /// well.
+    fn on_log(&mut self, _log: &Log) { }
+    fn on_return(&mut self, _frame: &ReturnContext) { }
+}
+
+/// Execute a transaction on `stateful` as `fork::call` does, reporting
+/// its execution to `tracer`.
+pub fn call_with_tracer<P: Patch>(
+    fork: Option<&Fork>, cache: &AccountCache, stateful: &mut MemoryStateful<'static>, valid: ValidTransaction,
+    header: &HeaderParams, last_hashes: &[H256], tracer: &mut Tracer
+) -> Result<SeqTransactionVM<P>, Error> {
+    let create = match valid.action {
+        TransactionAction::Create => true,
+        _ => false,
+    };
+    let mut vm = SeqTransactionVM::<P>::new(valid, header.clone());
+    // Address of each open frame and the last opcode it ran, telling
+    // calls and creations it starts apart.
+    let mut frames: Vec<(Address, u8)> = Vec::new();
+    // Step reported before the VM required something to run it.
+    let mut retried = None;
+
+    loop {
+        match vm.status() {
+            VMStatus::Running => (),
+            _ => break,
+        }
+
+        let step = match retried.take() {
+            Some(step) => Some(step),
+            None => match vm.current_machine() {
+                Some(machine) => {
+                    follow(machine, &mut frames, create, tracer);
+                    before_step(machine, tracer)
+                },
+                None => None,
+            },
+        };
+
+        if let Err(require) = vm.step() {
+            fulfill(fork, cache, stateful, &mut vm, require, header, last_hashes)?;
+            retried = step;
+            continue;
+        }
+
+        if let Some((depth, opcode, stack)) = step {
+            if let Some(last) = frames.last_mut() {
+                last.1 = opcode;
+            }
+            if let Some(machine) = vm.current_machine() {
+                if machine.state().depth == depth {
+                    after_step(machine, opcode, &stack, tracer);
+                }
+            }
+        }
+    }
+
+    while let Some((address, _)) = frames.pop() {
+        tracer.on_return(&ReturnContext {
+            depth: frames.len(),
+            address,
+            success: match vm.status() {
+                VMStatus::ExitedOk => true,
+                _ => false,
+            },
+            gas_used: vm.used_gas(),
+            output: vm.out(),
+        });
+    }
+    Ok(vm)
+}
+
+/// Report frames that started or ended.
+fn follow<P: Patch>(
+    machine: &Machine<SeqMemory<P>, P>, frames: &mut Vec<(Address, u8)>, create: bool, tracer: &mut Tracer
+) {
+    let state = machine.state();
+    match machine.status() {
+        MachineStatus::Running if state.depth == frames.len() => {
+            let creating = frames.last().map(|&(_, opcode)| opcode == 0xf0).unwrap_or(create);
+            let frame = FrameContext {
+                depth: state.depth,
+                caller: state.context.caller,
+                address: state.context.address,
+                value: state.context.value,
+                gas: state.context.gas_limit,
+                input: if creating { &state.context.code } else { &state.context.data },
+            };
+            if creating {
+                tracer.on_create(&frame);
+            } else {
+                tracer.on_call(&frame);
+            }
+            frames.push((state.context.address, 0));
+        },
+        MachineStatus::ExitedOk | MachineStatus::ExitedErr(_) if state.depth + 1 == frames.len() => {
+            frames.pop();
+            tracer.on_return(&ReturnContext {
+                depth: state.depth,
+                address: state.context.address,
+                success: match machine.status() {
+                    MachineStatus::ExitedOk => true,
+                    _ => false,
+                },
+                gas_used: state.context.gas_limit - state.available_gas(),
+                output: &state.out,
+            });
+        },
+        _ => (),
+    }
+}
+
+/// Report the opcode about to run, keeping the stack items its effects
+/// are reported from afterwards.
+fn before_step<P: Patch>(machine: &Machine<SeqMemory<P>, P>, tracer: &mut Tracer) -> Option<(usize, u8, Vec<M256>)> {
+    match machine.status() {
+        MachineStatus::Running => (),
+        _ => return None,
+    }
+    let pc = machine.pc().position();
+    if pc >= machine.pc().code().len() {
+        return None;
+    }
+
+    let state = machine.state();
+    let opcode = machine.pc().code()[pc];
+    tracer.on_step(&StepContext {
+        depth: state.depth,
+        address: state.context.address,
+        pc, opcode,
+        gas: state.available_gas(),
+        stack: &state.stack,
+    });
+
+    let arguments = match opcode {
+        0x55 => 2,
+        0xa0..=0xa4 => 2 + (opcode - 0xa0) as usize,
+        _ => 0,
+    };
+    let stack = (0..arguments.min(state.stack.len())).map(|i| state.stack.peek(i).unwrap()).collect();
+    Some((state.depth, opcode, stack))
+}
+
+/// Report the storage writes and logs of an opcode that ran.
+fn after_step<P: Patch>(machine: &Machine<SeqMemory<P>, P>, opcode: u8, stack: &[M256], tracer: &mut Tracer) {
+    if let MachineStatus::ExitedErr(_) = machine.status() {
+        return;
+    }
+    let state = machine.state();
+
+    match opcode {
+        0x55 if stack.len() == 2 => tracer.on_sstore(state.context.address, U256::from(stack[0]), stack[1]),
+        0xa0..=0xa4 if stack.len() >= 2 => {
+            let (offset, length) = (U256::from(stack[0]), U256::from(stack[1]));
+            let size = U256::from(state.memory.len());
+            let data = if offset > size || length > size - offset {
+                Vec::new()
+            } else {
+                (offset.as_usize()..(offset + length).as_usize())
+                    .map(|i| state.memory.read_raw(U256::from(i)))
+                    .collect()
+            };
+            tracer.on_log(&Log {
+                address: state.context.address,
+                topics: stack[2..].iter().map(|&topic| H256::from(U256::from(topic))).collect(),
+                data,
+            });
+        },
+        _ => (),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sputnikvm_network_classic::MainnetEIP160Patch;
+    use std::rc::Rc;
+    use miner;
+
+    #[derive(Debug, PartialEq)]
+    enum Event {
+        Call(usize, Address),
+        Create(usize, Address),
+        Log(Address, Vec<H256>),
+        Return(usize, Address, bool),
+    }
+
+    #[derive(Default)]
+    struct Recorder(Vec<Event>);
+
+    impl Tracer for Recorder {
+        fn on_call(&mut self, frame: &FrameContext) {
+            self.0.push(Event::Call(frame.depth, frame.address));
+        }
+
+        fn on_create(&mut self, frame: &FrameContext) {
+            self.0.push(Event::Create(frame.depth, frame.address));
+        }
+
+        fn on_log(&mut self, log: &Log) {
+            self.0.push(Event::Log(log.address, log.topics.clone()));
+        }
+
+        fn on_return(&mut self, frame: &ReturnContext) {
+            self.0.push(Event::Return(frame.depth, frame.address, frame.success));
+        }
+    }
+
+    fn trace(contracts: Vec<(Address, Vec<u8>)>, action: TransactionAction, input: Vec<u8>) -> Vec<Event> {
+        let mut state = miner::make_state_at::<MainnetEIP160Patch>(Vec::new(), 0);
+        for (address, code) in contracts {
+            state.set_code(address, code);
+        }
+        let mut stateful = state.stateful_at(state.head_root());
+        let valid = ValidTransaction {
+            caller: Some(Address::from(0x99u64)),
+            gas_price: Gas::zero(),
+            gas_limit: Gas::from(100000u64),
+            action, input: Rc::new(input),
+            value: U256::zero(),
+            nonce: U256::zero(),
+        };
+
+        let mut recorder = Recorder::default();
+        call_with_tracer::<MainnetEIP160Patch>(
+            state.fork(), state.account_cache(), &mut stateful, valid,
+            &HeaderParams::from(&state.current_block().header), &[], &mut recorder).unwrap();
+        recorder.0
+    }
+
+    #[test]
+    fn calls_and_logs() {
+        let (outer, inner) = (Address::from(0x40u64), Address::from(0x41u64));
+        // CALL(gas, inner, 0, 0, 0, 0, 0), then STOP.
+        let mut code = vec![0x60, 0x00, 0x60, 0x00, 0x60, 0x00, 0x60, 0x00, 0x60, 0x00, 0x73];
+        code.extend_from_slice(&inner[..]);
+        code.extend_from_slice(&[0x5a, 0xf1, 0x50, 0x00]);
+
+        let events = trace(vec![
+            (outer, code),
+            // LOG1(0, 0, 7), then STOP.
+            (inner, vec![0x60, 0x07, 0x60, 0x00, 0x60, 0x00, 0xa1, 0x00]),
+        ], TransactionAction::Call(outer), Vec::new());
+        assert_eq!(events, vec![
+            Event::Call(0, outer),
+            Event::Call(1, inner),
+            Event::Log(inner, vec![H256::from(7u64)]),
+            Event::Return(1, inner, true),
+            Event::Return(0, outer, true),
+        ]);
+    }
+
+    #[test]
+    fn creations() {
+        // MSTORE8(0, STOP), then CREATE(0, 0, 1) of that init code.
+        let events = trace(Vec::new(), TransactionAction::Create, vec![
+            0x60, 0x00, 0x60, 0x00, 0x53,
+            0x60, 0x01, 0x60, 0x00, 0x60, 0x00, 0xf0, 0x50, 0x00,
+        ]);
+        assert_eq!(events.len(), 4);
+        match (&events[0], &events[1], &events[2], &events[3]) {
+            (&Event::Create(0, outer), &Event::Create(1, inner), &Event::Return(1, inner_returned, true),
+             &Event::Return(0, outer_returned, true)) => {
+                assert!(outer != inner);
+                assert_eq!(inner_returned, inner);
+                assert_eq!(outer_returned, outer);
+            },
+            _ => panic!("unexpected events {:?}", events),
+        }
+    }
+}
@@ -0,0 +1,174 @@
+use rlp::UntrustedRlp;
+use block::Account;
+use bigint::H256;
+use trie::{MemoryDatabase, Database, DatabaseGuard};
+use sha3::{Digest, Keccak256};
+use sputnikvm::Patch;
+use std::collections::HashSet;
+
+use error::Error;
+use super::{MinerState, read_archive, write_archive};
+
+/// Problems found while walking the tries of all stored state roots.
+#[derive(Clone, Debug, Default)]
+pub struct VerifyReport {
+    pub roots: usize,
+    pub nodes: usize,
+    pub missing: Vec<H256>,
+    pub corrupt: Vec<H256>,
+    pub missing_code: Vec<H256>,
+    /// Lowest block whose state cannot be fully read, one past the
+    /// current height standing for the unsealed head state.
+    pub first_broken_block: Option<usize>,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.missing.is_empty() && self.corrupt.is_empty() && self.missing_code.is_empty()
+    }
+
+    fn problems(&self) -> usize {
+        self.missing.len() + self.corrupt.len() + self.missing_code.len()
+    }
+}
+
+fn keccak(data: &[u8]) -> H256 {
+    H256::from(Keccak256::digest(data).as_slice())
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum TrieKind {
+    State,
+    Storage,
+}
+
+struct Walker<'a> {
+    database: &'a MemoryDatabase,
+    empty_root: H256,
+    empty_code: H256,
+    visited: HashSet<H256>,
+    report: VerifyReport,
+}
+
+impl<'a> Walker<'a> {
+    fn walk_root(&mut self, root: H256, kind: TrieKind) {
+        if root == self.empty_root || !self.visited.insert(root) {
+            return;
+        }
+        self.report.nodes += 1;
+
+        let node = match self.database.create_guard().get(root) {
+            Some(node) => node,
+            None => {
+                self.report.missing.push(root);
+                return;
+            },
+        };
+        if keccak(&node) != root {
+            self.report.corrupt.push(root);
+            return;
+        }
+        if self.walk_node(&UntrustedRlp::new(&node), kind).is_err() {
+            self.report.corrupt.push(root);
+        }
+    }
+
+    /// Children of less than 32 bytes are embedded in their parent
+    /// instead of being referenced by hash.
+    fn walk_reference(&mut self, rlp: &UntrustedRlp, kind: TrieKind) -> Result<(), Error> {
+        if rlp.is_list() {
+            self.walk_node(rlp, kind)
+        } else if rlp.is_empty() {
+            Ok(())
+        } else {
+            let hash: H256 = rlp.as_val()?;
+            self.walk_root(hash, kind);
+            Ok(())
+        }
+    }
+
+    fn walk_node(&mut self, rlp: &UntrustedRlp, kind: TrieKind) -> Result<(), Error> {
+        match rlp.item_count() {
+            0 => Ok(()),
+            2 => {
+                let path = rlp.at(0)?;
+                let path = path.data()?;
+                let is_leaf = path.len() > 0 && path[0] & 0x20 != 0;
+                if is_leaf {
+                    let value = rlp.at(1)?;
+                    self.walk_value(value.data()?, kind)
+                } else {
+                    self.walk_reference(&rlp.at(1)?, kind)
+                }
+            },
+            17 => {
+                for i in 0..16 {
+                    self.walk_reference(&rlp.at(i)?, kind)?;
+                }
+                Ok(())
+            },
+            _ => Err(Error::RlpError),
+        }
+    }
+
+    fn walk_value(&mut self, value: &[u8], kind: TrieKind) -> Result<(), Error> {
+        if kind == TrieKind::Storage {
+            return Ok(());
+        }
+
+        let account: Account = UntrustedRlp::new(value).as_val()?;
+        if account.code_hash != self.empty_code &&
+            self.visited.insert(account.code_hash)
+        {
+            match self.database.create_guard().get(account.code_hash) {
+                Some(ref code) if keccak(code) == account.code_hash => (),
+                Some(_) => self.report.corrupt.push(account.code_hash),
+                None => self.report.missing_code.push(account.code_hash),
+            }
+        }
+        self.walk_root(account.storage_root, TrieKind::Storage);
+        Ok(())
+    }
+}
+
+/// Walk every trie node reachable from the state root of each block
+/// and of the head state, checking that it is present in the raw hash
+/// store and hashes to its key. Nodes shared between roots are only
+/// checked once.
+pub fn verify_database(state: &MinerState) -> VerifyReport {
+    let database = state.stateful().database();
+    let mut walker = Walker {
+        database,
+        empty_root: database.create_empty().root(),
+        empty_code: keccak(&[]),
+        visited: HashSet::new(),
+        report: VerifyReport::default(),
+    };
+
+    for number in 0..(state.block_height() + 2) {
+        let root = if number > state.block_height() {
+            state.head_root()
+        } else {
+            state.get_block_by_number(number).header.state_root
+        };
+
+        let before = walker.report.problems();
+        walker.report.roots += 1;
+        walker.walk_root(root, TrieKind::State);
+        if walker.report.first_broken_block.is_none() && walker.report.problems() > before {
+            walker.report.first_broken_block = Some(number);
+        }
+    }
+
+    walker.report
+}
+
+/// Write the trie nodes of every block again by re-executing the whole
+/// chain from its genesis with the settings of the node, then verify it
+/// again. Changes not sealed into a block are not re-created.
+pub fn repair_database<P: Patch>(state: &mut MinerState) -> Result<VerifyReport, Error> {
+    let archive = write_archive(state, true);
+    read_archive::<P>(&archive, state)?;
+
+    Ok(verify_database(state))
+}
@@ -0,0 +1,246 @@
+use bigint::{H256, U256, Address};
+use block::{Account, TransactionAction, UnsignedTransaction, FromKey};
+use hexutil::read_hex;
+use serde_json::{self, Value};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::sync::mpsc::Sender;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use error::Error;
+use super::state::MinerState;
+use super::scheduler::MinerEvent;
+use super::patch::sign_transaction;
+use super::{block_gas_limit, contract_address};
+
+/// Seconds between two scans of the watched directory.
+pub const WATCH_INTERVAL: u64 = 1;
+
+/// What the watcher did after an artifact changed.
+#[derive(Clone, Debug)]
+pub struct WatchEvent {
+    pub name: String,
+    pub path: PathBuf,
+    pub address: Address,
+    /// Deployment transaction, or `None` when the code was swapped at
+    /// the existing address.
+    pub transaction_hash: Option<H256>,
+}
+
+/// Bytecode of a compiled contract.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Artifact {
+    pub init: Vec<u8>,
+    pub runtime: Option<Vec<u8>>,
+}
+
+fn bytecode(value: Option<&Value>) -> Result<Option<Vec<u8>>, Error> {
+    // Foundry nests the bytecode in an object, Truffle and Hardhat do
+    // not.
+    let hex = match value {
+        Some(&Value::String(ref hex)) => hex.as_str(),
+        Some(&Value::Object(ref object)) => match object.get("object") {
+            Some(&Value::String(ref hex)) => hex.as_str(),
+            _ => return Ok(None),
+        },
+        _ => return Ok(None),
+    };
+    let hex = if hex.starts_with("0x") { hex.to_string() } else { format!("0x{}", hex) };
+    if hex == "0x" {
+        return Ok(None);
+    }
+    Ok(Some(read_hex(&hex)?))
+}
+
+/// Read a compiled contract, either a JSON artifact with `bytecode` and
+/// `deployedBytecode` fields, or a `.bin` file of solc with an optional
+/// `.bin-runtime` next to it. Files that are not artifacts, or of
+/// abstract contracts, give `None`.
+pub fn read_artifact(path: &Path) -> Result<Option<Artifact>, Error> {
+    let mut content = String::new();
+    File::open(path)?.read_to_string(&mut content)?;
+
+    match path.extension().and_then(|extension| extension.to_str()) {
+        Some("json") => {
+            let value: Value = match serde_json::from_str(&content) {
+                Ok(value) => value,
+                Err(_) => return Ok(None),
+            };
+            Ok(bytecode(value.get("bytecode"))?.map(|init| Artifact {
+                init, runtime: bytecode(value.get("deployedBytecode")).unwrap_or(None),
+            }))
+        },
+        Some("bin") => {
+            let init = match bytecode(Some(&Value::String(content.trim().to_string())))? {
+                Some(init) => init,
+                None => return Ok(None),
+            };
+            let runtime = match File::open(path.with_extension("bin-runtime")) {
+                Ok(mut file) => {
+                    let mut content = String::new();
+                    file.read_to_string(&mut content)?;
+                    bytecode(Some(&Value::String(content.trim().to_string())))?
+                },
+                Err(_) => None,
+            };
+            Ok(Some(Artifact { init, runtime }))
+        },
+        _ => Ok(None),
+    }
+}
+
+struct Watched {
+    modified: SystemTime,
+    artifact: Option<Artifact>,
+    address: Option<Address>,
+}
+
+/// Artifacts of a directory, redeployed to the chain when they change.
+pub struct Watcher {
+    dir: PathBuf,
+    watched: HashMap<PathBuf, Watched>,
+}
+
+impl Watcher {
+    pub fn new(dir: PathBuf) -> Self {
+        Watcher { dir, watched: HashMap::new() }
+    }
+
+    fn scan_dir(&mut self, dir: &Path, changed: &mut Vec<(PathBuf, Artifact)>) -> Result<(), Error> {
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                self.scan_dir(&path, changed)?;
+                continue;
+            }
+
+            let modified = fs::metadata(&path)?.modified()?;
+            if self.watched.get(&path).map(|watched| watched.modified == modified).unwrap_or(false) {
+                continue;
+            }
+
+            let artifact = match read_artifact(&path) {
+                Ok(artifact) => artifact,
+                Err(err) => {
+                    // Usually a file still being written, or bytecode
+                    // with unlinked libraries.
+                    warn!("watch: cannot read {}: {:?}", path.display(), err);
+                    None
+                },
+            };
+            let watched = self.watched.entry(path.clone()).or_insert(Watched {
+                modified, artifact: None, address: None,
+            });
+            watched.modified = modified;
+            if artifact.is_some() && artifact != watched.artifact {
+                watched.artifact = artifact.clone();
+                changed.push((path, artifact.unwrap()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Artifacts that are new or whose bytecode changed since the last
+    /// scan.
+    pub fn scan(&mut self) -> Result<Vec<(PathBuf, Artifact)>, Error> {
+        let mut changed = Vec::new();
+        let dir = self.dir.clone();
+        self.scan_dir(&dir, &mut changed)?;
+        Ok(changed)
+    }
+
+    /// Swap the runtime code of a contract already deployed from `path`,
+    /// or deploy it from the first genesis account otherwise. The code
+    /// is only swapped if the contract still exists on the current
+    /// chain, so contracts are deployed again after a reset.
+    pub fn apply(&mut self, state: &mut MinerState, path: PathBuf, artifact: Artifact) -> Result<WatchEvent, Error> {
+        let name = path.file_stem().and_then(|stem| stem.to_str()).unwrap_or("").to_string();
+        let previous = self.watched.get(&path).and_then(|watched| watched.address);
+
+        if let (Some(address), Some(runtime)) = (previous, artifact.runtime.clone()) {
+            let account: Option<Account> = state.stateful().state_of(state.stateful().root()).get(&address);
+            let deployed = account.and_then(|account| state.stateful().code(account.code_hash))
+                .map(|code| !code.is_empty()).unwrap_or(false);
+            if deployed {
+                state.set_code(address, runtime);
+                return Ok(WatchEvent { name, path, address, transaction_hash: None });
+            }
+        }
+
+        let secret_key = state.genesis_accounts().first().map(|&(ref key, _)| key.clone())
+            .ok_or(Error::NotFound)?;
+        let caller = Address::from_secret_key(&secret_key)?;
+        let nonce = state.next_nonce(caller)?;
+
+        let transaction = sign_transaction(UnsignedTransaction {
+            nonce,
+            gas_price: state.min_gas_price(),
+            gas_limit: block_gas_limit(),
+            action: TransactionAction::Create,
+            value: U256::zero(),
+            input: artifact.init,
+        }, &secret_key, state.chain_id());
+        let transaction_hash = state.append_pending_transaction(transaction)?;
+        let address = contract_address(caller, nonce);
+
+        if let Some(watched) = self.watched.get_mut(&path) {
+            watched.address = Some(address);
+        }
+        Ok(WatchEvent { name, path, address, transaction_hash: Some(transaction_hash) })
+    }
+}
+
+/// Watch `dir` for artifacts, deploying them at start and again
+/// whenever they change, and record the events in the state.
+pub fn watch_loop(state: Arc<RwLock<MinerState>>, channel: Sender<MinerEvent>, dir: PathBuf) {
+    let mut watcher = Watcher::new(dir);
+
+    loop {
+        let changed = match watcher.scan() {
+            Ok(changed) => changed,
+            Err(err) => {
+                warn!("watch: cannot scan {}: {:?}", watcher.dir.display(), err);
+                Vec::new()
+            },
+        };
+
+        if !changed.is_empty() {
+            let mut state = state.write().unwrap();
+            for (path, artifact) in changed {
+                match watcher.apply(&mut state, path.clone(), artifact) {
+                    Ok(event) => {
+                        match event.transaction_hash {
+                            Some(_) => println!("watch: deploying {} at 0x{:x}", event.name, event.address),
+                            None => println!("watch: swapped code of {} at 0x{:x}", event.name, event.address),
+                        }
+                        state.append_watch_event(event);
+                    },
+                    Err(err) => warn!("watch: cannot deploy {}: {:?}", path.display(), err),
+                }
+            }
+            let _ = channel.send(MinerEvent::Transaction);
+        }
+
+        thread::sleep(Duration::new(WATCH_INTERVAL, 0));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bytecode_formats() {
+        assert_eq!(bytecode(Some(&Value::String("0x6001".to_string()))).unwrap(), Some(vec![0x60, 0x01]));
+        assert_eq!(bytecode(Some(&Value::String("6001".to_string()))).unwrap(), Some(vec![0x60, 0x01]));
+        assert_eq!(bytecode(Some(&Value::String("0x".to_string()))).unwrap(), None);
+
+        let foundry: Value = serde_json::from_str(r#"{ "object": "0x6001" }"#).unwrap();
+        assert_eq!(bytecode(Some(&foundry)).unwrap(), Some(vec![0x60, 0x01]));
+        assert_eq!(bytecode(None).unwrap(), None);
+    }
+}
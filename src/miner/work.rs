@@ -0,0 +1,76 @@
+use rlp::RlpStream;
+use bigint::{H256, H64, U256};
+use block::HeaderHash;
+use sha3::{Digest, Keccak256};
+
+use super::MinerState;
+
+const EPOCH_LENGTH: usize = 30000;
+
+/// Work package handed out to external miners through `eth_getWork`.
+pub struct WorkPackage {
+    pub pow_hash: H256,
+    pub seed_hash: H256,
+    pub boundary: H256,
+    pub number: U256,
+}
+
+fn keccak(data: &[u8]) -> H256 {
+    H256::from(Keccak256::digest(data).as_slice())
+}
+
+/// The sealing hash commits to the current head and the pending pool,
+/// so it changes whenever the block that would be sealed changes.
+fn pow_hash(state: &MinerState) -> H256 {
+    let pending = state.pending_transactions();
+
+    let mut stream = RlpStream::new_list(2);
+    stream.append(&state.current_block().header.header_hash());
+    stream.begin_list(pending.len());
+    for transaction in &pending {
+        stream.append(transaction);
+    }
+    keccak(&stream.out())
+}
+
+fn seed_hash(number: usize) -> H256 {
+    let mut seed = H256::default();
+    for _ in 0..(number / EPOCH_LENGTH) {
+        seed = keccak(&seed);
+    }
+    seed
+}
+
+fn boundary(difficulty: U256) -> U256 {
+    if difficulty <= U256::one() {
+        U256::max_value()
+    } else {
+        U256::max_value() / difficulty
+    }
+}
+
+pub fn work_package(state: &MinerState) -> WorkPackage {
+    let number = state.block_height() + 1;
+
+    WorkPackage {
+        pow_hash: pow_hash(state),
+        seed_hash: seed_hash(number),
+        boundary: H256::from(boundary(state.work_difficulty())),
+        number: U256::from(number),
+    }
+}
+
+/// Check a submitted solution. This is a stub without the ethash DAG:
+/// the solution is accepted when `keccak256(pow_hash ++ nonce)` is
+/// below the boundary derived from the configured work difficulty.
+pub fn verify_work(state: &MinerState, pow_hash_value: H256, nonce: H64) -> bool {
+    if pow_hash_value != pow_hash(state) {
+        return false;
+    }
+
+    let mut data = Vec::new();
+    data.extend_from_slice(&pow_hash_value);
+    data.extend_from_slice(&nonce);
+
+    U256::from(&keccak(&data)[..]) <= boundary(state.work_difficulty())
+}
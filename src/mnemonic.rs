@@ -0,0 +1,136 @@
+use crypto::digest::Digest;
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::pbkdf2::pbkdf2;
+use crypto::sha2::{Sha256, Sha512};
+use secp256k1::SECP256K1;
+use secp256k1::key::{SecretKey, PublicKey};
+
+use error::Error;
+
+/// Mnemonic used by `--deterministic`, the same as ganache's, so that
+/// test suites written against it find the accounts they expect.
+pub const DEFAULT_MNEMONIC: &str =
+    "myth like bonus scare over problem client lizard pioneer submit female collect";
+
+const HARDENED: u32 = 0x80000000;
+
+/// The 2048 words of the BIP-39 English wordlist, in order.
+const WORDLIST: &str = include_str!("mnemonic_english.txt");
+
+fn hmac_sha512(key: &[u8], data: &[u8]) -> [u8; 64] {
+    let mut hmac = Hmac::new(Sha512::new(), key);
+    hmac.input(data);
+    let mut ret = [0u8; 64];
+    hmac.raw_result(&mut ret);
+    ret
+}
+
+/// Check that every word of `mnemonic` is in the wordlist and that its
+/// last bits are the checksum of the entropy the sentence encodes.
+pub fn validate(mnemonic: &str) -> Result<(), Error> {
+    let words: Vec<&str> = WORDLIST.lines().collect();
+    let sentence: Vec<&str> = mnemonic.split_whitespace().collect();
+    if sentence.len() < 12 || sentence.len() > 24 || sentence.len() % 3 != 0 {
+        return Err(Error::InvalidMnemonic(format!("{} words, expected 12, 15, 18, 21 or 24", sentence.len())));
+    }
+
+    let mut bits = Vec::with_capacity(sentence.len() * 11);
+    for word in &sentence {
+        let index = words.binary_search(word)
+            .map_err(|_| Error::InvalidMnemonic(format!("unknown word {}", word)))?;
+        for shift in (0..11).rev() {
+            bits.push((index >> shift) & 1 == 1);
+        }
+    }
+
+    let checksum_len = bits.len() / 33;
+    let entropy_len = bits.len() - checksum_len;
+    let entropy: Vec<u8> = bits[..entropy_len].chunks(8).map(|byte| {
+        byte.iter().fold(0u8, |acc, &bit| (acc << 1) | bit as u8)
+    }).collect();
+
+    let mut hash = [0u8; 32];
+    let mut sha256 = Sha256::new();
+    sha256.input(&entropy);
+    sha256.result(&mut hash);
+    for i in 0..checksum_len {
+        if bits[entropy_len + i] != ((hash[i / 8] >> (7 - i % 8)) & 1 == 1) {
+            return Err(Error::InvalidMnemonic("checksum mismatch".to_string()));
+        }
+    }
+
+    Ok(())
+}
+
+/// BIP-39 seed of a mnemonic sentence.
+pub fn seed(mnemonic: &str, passphrase: &str) -> [u8; 64] {
+    let mut hmac = Hmac::new(Sha512::new(), mnemonic.as_bytes());
+    let mut ret = [0u8; 64];
+    pbkdf2(&mut hmac, format!("mnemonic{}", passphrase).as_bytes(), 2048, &mut ret);
+    ret
+}
+
+/// BIP-32 private key derivation along the given path.
+pub fn derive(seed: &[u8], path: &[u32]) -> Result<SecretKey, Error> {
+    let master = hmac_sha512(b"Bitcoin seed", seed);
+    let mut key = SecretKey::from_slice(&SECP256K1, &master[0..32])?;
+    let mut chain_code = master[32..64].to_vec();
+
+    for &index in path {
+        let mut data = Vec::new();
+        if index & HARDENED != 0 {
+            data.push(0u8);
+            data.extend_from_slice(&key[..]);
+        } else {
+            let public = PublicKey::from_secret_key(&SECP256K1, &key)?;
+            data.extend_from_slice(&public.serialize_vec(&SECP256K1, true));
+        }
+        data.extend_from_slice(&[(index >> 24) as u8, (index >> 16) as u8,
+                                 (index >> 8) as u8, index as u8]);
+
+        let child = hmac_sha512(&chain_code, &data);
+        let mut child_key = SecretKey::from_slice(&SECP256K1, &child[0..32])?;
+        child_key.add_assign(&SECP256K1, &key)?;
+
+        key = child_key;
+        chain_code = child[32..64].to_vec();
+    }
+
+    Ok(key)
+}
+
+/// Key of the `index`-th account, at `m/44'/60'/0'/0/index`.
+pub fn account(mnemonic: &str, index: u32) -> Result<SecretKey, Error> {
+    validate(mnemonic)?;
+    derive(&seed(mnemonic, ""), &[44 | HARDENED, 60 | HARDENED, HARDENED, 0, index])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hexutil::to_hex;
+
+    #[test]
+    fn default_mnemonic_first_account() {
+        let key = account(DEFAULT_MNEMONIC, 0).unwrap();
+        assert_eq!(to_hex(&key[..]),
+                   "0xc87509a1c067bbde78beb793e6fa76530b6382a4c0241e5e4a9ec0a0f44dc0d3");
+    }
+
+    #[test]
+    fn checksum_is_validated() {
+        // Test vectors of the BIP-39 reference implementation.
+        assert!(validate("abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about").is_ok());
+        assert!(validate("legal winner thank year wave sausage worth useful legal winner thank yellow").is_ok());
+        assert!(validate("zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo vote").is_ok());
+
+        match validate("abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon") {
+            Err(Error::InvalidMnemonic(message)) => assert_eq!(message, "checksum mismatch"),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(validate("abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abou").is_err());
+        assert!(validate("abandon about").is_err());
+        assert!(account("myth like bonus scare over problem client lizard pioneer submit female female", 0).is_err());
+    }
+}
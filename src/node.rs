@@ -0,0 +1,360 @@
+//! A development chain running in-process. Each node owns its state,
+//! with its own database, block index and transaction pool, a miner
+//! thread and the RPC handlers serving them, so that several
+//! independent chains can run side by side in one process.
+
+use bigint::{Address, U256};
+use rand::os::OsRng;
+use secp256k1::SECP256K1;
+use secp256k1::key::SecretKey;
+use serde_json::Value;
+use sputnikvm::Patch;
+use sputnikvm_network_classic::MainnetEIP160Patch;
+use std::env;
+use std::marker::PhantomData;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::process;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{Sender, channel};
+use std::thread;
+
+use error::Error;
+use journal::{Journal, JournalEntry};
+use keystore::KeyStore;
+use miner::{self, DevPatch, MinerEvent, MinerState, MiningSchedule, DEFAULT_ACCOUNT_CACHE, DEFAULT_RPC_CACHE};
+use mnemonic;
+use rpc::{ApiKeyConfig, LocalNode};
+
+pub struct DevNode {
+    state: Arc<RwLock<MinerState>>,
+    channel: Sender<MinerEvent>,
+    rpc: LocalNode,
+    journal: Option<Arc<Journal>>,
+    mine_one: fn(Arc<RwLock<MinerState>>, Address) -> u64,
+}
+
+impl DevNode {
+    /// Start mining `state` under the rules of `P` on a thread of its
+    /// own, following the mining schedule of the state, with RPC calls
+    /// executed on `rpc_workers` threads, or one per CPU if not given.
+    /// The threads stop once the node and everything it handed a
+    /// channel to are dropped.
+    pub fn start<P: 'static + Patch + Send>(
+        state: Arc<RwLock<MinerState>>, keystore: KeyStore,
+        api_keys: Vec<ApiKeyConfig>, journal: Option<Arc<Journal>>, rpc_workers: Option<usize>
+    ) -> Self {
+        let (sender, receiver) = channel::<MinerEvent>();
+        let rpc = LocalNode::with_channel::<P>(state.clone(), sender.clone(), api_keys,
+                                               keystore, journal.clone(), rpc_workers);
+
+        let miner_state = state.clone();
+        let miner_journal = journal.clone();
+        let address_format = state.read().unwrap().address_format();
+        thread::spawn(move || address_format.scope(|| {
+            miner::mine_loop::<P>(miner_state, receiver, miner_journal);
+        }));
+
+        DevNode {
+            state,
+            channel: sender,
+            rpc,
+            journal,
+            mine_one: miner::mine_one::<P>,
+        }
+    }
+
+    pub fn state(&self) -> Arc<RwLock<MinerState>> {
+        self.state.clone()
+    }
+
+    /// Channel waking up the miner of the node.
+    pub fn channel(&self) -> Sender<MinerEvent> {
+        self.channel.clone()
+    }
+
+    pub fn request(&self, method: &str, params: Value) -> Result<Value, Error> {
+        self.rpc.request(method, params)
+    }
+
+    /// Mine a block right away, on the calling thread, returning its
+    /// timestamp. Unlike `evm_mine`, the block is mined once this
+    /// returns. Fails if the block could not be recorded in the
+    /// journal, in which case it is mined all the same.
+    pub fn mine(&self) -> Result<u64, Error> {
+        let mut journal = self.journal.as_ref().map(|journal| journal.lock());
+        let timestamp = (self.mine_one)(self.state.clone(), Address::default());
+        if let Some(ref mut journal) = journal {
+            journal.append(&JournalEntry::Mine { timestamp })?;
+        }
+        Ok(timestamp)
+    }
+
+    /// Serve the RPC of the node over HTTP on `addr`, until the server
+    /// stops.
+    pub fn serve(self, addr: &SocketAddr) {
+        self.rpc.serve(addr)
+    }
+}
+
+/// Number of nodes built without a data directory so far, telling
+/// their temporary directories apart.
+static TEMPORARY_DIRS: AtomicUsize = AtomicUsize::new(0);
+
+/// Sets up and starts a `DevNode`, with the defaults of the command
+/// line: the `classic` chain, ten generated accounts and instamining
+/// with a block at least every ten seconds.
+pub struct DevNodeBuilder<P: Patch = DevPatch<MainnetEIP160Patch>> {
+    accounts: usize,
+    balance: U256,
+    mnemonic: Option<String>,
+    genesis: Vec<(SecretKey, U256)>,
+    chain_id: Option<u64>,
+    network_id: Option<u64>,
+    mining_schedule: MiningSchedule,
+    data_dir: Option<PathBuf>,
+    api_keys: Vec<ApiKeyConfig>,
+    rpc_workers: Option<usize>,
+    execution_workers: usize,
+    account_cache: usize,
+    rpc_cache: usize,
+    log_index: bool,
+    _patch: PhantomData<P>,
+}
+
+impl DevNodeBuilder {
+    pub fn new() -> Self {
+        DevNodeBuilder {
+            accounts: 10,
+            balance: U256::from_str("0x10000000000000000000000000000").unwrap(),
+            mnemonic: None,
+            genesis: Vec::new(),
+            chain_id: None,
+            network_id: None,
+            mining_schedule: MiningSchedule::default(),
+            data_dir: None,
+            api_keys: Vec::new(),
+            rpc_workers: None,
+            execution_workers: 1,
+            account_cache: DEFAULT_ACCOUNT_CACHE,
+            rpc_cache: DEFAULT_RPC_CACHE,
+            log_index: false,
+            _patch: PhantomData,
+        }
+    }
+}
+
+impl Default for DevNodeBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<P: 'static + Patch + Send> DevNodeBuilder<P> {
+    /// Run the chain under the rules of `Q` instead.
+    pub fn patch<Q: 'static + Patch + Send>(self) -> DevNodeBuilder<DevPatch<Q>> {
+        DevNodeBuilder {
+            accounts: self.accounts,
+            balance: self.balance,
+            mnemonic: self.mnemonic,
+            genesis: self.genesis,
+            chain_id: self.chain_id,
+            network_id: self.network_id,
+            mining_schedule: self.mining_schedule,
+            data_dir: self.data_dir,
+            api_keys: self.api_keys,
+            rpc_workers: self.rpc_workers,
+            execution_workers: self.execution_workers,
+            account_cache: self.account_cache,
+            rpc_cache: self.rpc_cache,
+            log_index: self.log_index,
+            _patch: PhantomData,
+        }
+    }
+
+    /// Number of accounts generated and funded in the genesis block.
+    pub fn accounts(mut self, accounts: usize) -> Self {
+        self.accounts = accounts;
+        self
+    }
+
+    /// Balance of each generated account, in Wei.
+    pub fn balance(mut self, balance: U256) -> Self {
+        self.balance = balance;
+        self
+    }
+
+    /// Derive the generated accounts from a BIP-39 mnemonic, as
+    /// `--mnemonic` does, instead of random keys.
+    pub fn mnemonic(mut self, mnemonic: &str) -> Self {
+        self.mnemonic = Some(mnemonic.to_string());
+        self
+    }
+
+    /// Fund the account of `secret_key` in the genesis block, in
+    /// addition to the generated accounts.
+    pub fn account(mut self, secret_key: SecretKey, balance: U256) -> Self {
+        self.genesis.push((secret_key, balance));
+        self
+    }
+
+    /// EIP-155 chain id, see `--chain-id`.
+    pub fn chain_id(mut self, chain_id: u64) -> Self {
+        self.chain_id = Some(chain_id);
+        self
+    }
+
+    /// Network id reported by `net_version`, the chain id by default.
+    pub fn network_id(mut self, network_id: u64) -> Self {
+        self.network_id = Some(network_id);
+        self
+    }
+
+    /// Mine at least every so many seconds. Zero only mines on demand.
+    pub fn block_time(mut self, seconds: u64) -> Self {
+        self.mining_schedule.interval = match seconds {
+            0 => None,
+            seconds => Some(seconds),
+        };
+        self
+    }
+
+    /// Whether to mine as soon as a transaction enters the pool.
+    pub fn instamine(mut self, instamine: bool) -> Self {
+        self.mining_schedule.instamine = instamine;
+        self
+    }
+
+    /// Data directory holding the keystore, by default a temporary
+    /// directory of the node alone.
+    pub fn data_dir(mut self, data_dir: PathBuf) -> Self {
+        self.data_dir = Some(data_dir);
+        self
+    }
+
+    pub fn api_keys(mut self, api_keys: Vec<ApiKeyConfig>) -> Self {
+        self.api_keys = api_keys;
+        self
+    }
+
+    /// Number of threads executing RPC calls, one per CPU by default.
+    pub fn rpc_workers(mut self, rpc_workers: usize) -> Self {
+        self.rpc_workers = Some(rpc_workers);
+        self
+    }
+
+    /// Number of transactions of a mined block executed in parallel,
+    /// see `--execution-workers`.
+    pub fn execution_workers(mut self, execution_workers: usize) -> Self {
+        self.execution_workers = execution_workers;
+        self
+    }
+
+    /// Number of accounts read by executions kept in memory, see
+    /// `--account-cache`.
+    pub fn account_cache(mut self, account_cache: usize) -> Self {
+        self.account_cache = account_cache;
+        self
+    }
+
+    /// Number of blocks, and of receipts, kept as last returned by the
+    /// RPC, see `--rpc-cache`.
+    pub fn rpc_cache(mut self, rpc_cache: usize) -> Self {
+        self.rpc_cache = rpc_cache;
+        self
+    }
+
+    /// Keep an index of the blocks holding logs of each address and
+    /// topic in the data directory, see `--log-index`.
+    pub fn log_index(mut self, log_index: bool) -> Self {
+        self.log_index = log_index;
+        self
+    }
+
+    pub fn build(self) -> Result<DevNode, Error> {
+        let mut genesis = self.genesis;
+        match self.mnemonic {
+            Some(ref mnemonic) => {
+                for index in 0..self.accounts {
+                    genesis.push((mnemonic::account(mnemonic, index as u32)?, self.balance));
+                }
+            },
+            None => {
+                let mut rng = OsRng::new()?;
+                for _ in 0..self.accounts {
+                    genesis.push((SecretKey::new(&SECP256K1, &mut rng), self.balance));
+                }
+            },
+        }
+
+        let mut state = miner::make_state::<P>(genesis);
+        state.set_chain_id(self.chain_id);
+        state.set_network_id(self.network_id.or(self.chain_id).unwrap_or(1));
+        state.set_mining_schedule(self.mining_schedule);
+        state.set_execution_workers(self.execution_workers);
+        state.set_account_cache(self.account_cache);
+        state.set_rpc_cache(self.rpc_cache);
+        let data_dir = self.data_dir.unwrap_or_else(|| env::temp_dir().join(format!(
+            "svmdev-{}-{}", process::id(), TEMPORARY_DIRS.fetch_add(1, Ordering::SeqCst))));
+        state.set_data_dir(data_dir.clone());
+        if self.log_index {
+            state.enable_log_index(&data_dir.join("logindex"))?;
+        }
+
+        Ok(DevNode::start::<P>(Arc::new(RwLock::new(state)), KeyStore::new(data_dir.join("keystore")),
+                               self.api_keys, None, self.rpc_workers))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json;
+
+    fn start_node() -> DevNode {
+        DevNodeBuilder::new().accounts(1).block_time(0).instamine(false).build().unwrap()
+    }
+
+    #[test]
+    fn state_can_be_read_from_several_threads() {
+        fn shared<T: Send + Sync>() { }
+        shared::<MinerState>();
+    }
+
+    #[test]
+    fn nodes_do_not_share_chains() {
+        let first = start_node();
+        let second = start_node();
+
+        first.mine().unwrap();
+        assert_eq!(first.request("eth_blockNumber", Value::Array(Vec::new())).unwrap(), Value::String("0x1".to_string()));
+        assert_eq!(second.request("eth_blockNumber", Value::Array(Vec::new())).unwrap(), Value::String("0x0".to_string()));
+    }
+
+    #[test]
+    fn nodes_do_not_share_settings() {
+        let first = DevNodeBuilder::new().accounts(1).block_time(0).chain_id(1337).build().unwrap();
+        let second = DevNodeBuilder::new().accounts(1).block_time(0).chain_id(61).build().unwrap();
+
+        assert_eq!(first.request("eth_chainId", Value::Array(Vec::new())).unwrap(), Value::String("0x539".to_string()));
+        assert_eq!(second.request("eth_chainId", Value::Array(Vec::new())).unwrap(), Value::String("0x3d".to_string()));
+        assert_ne!(first.state().read().unwrap().data_dir(), second.state().read().unwrap().data_dir());
+    }
+
+    #[test]
+    fn receipts_are_not_served_once_rewound() {
+        let node = start_node();
+        let accounts = node.request("eth_accounts", Value::Array(Vec::new())).unwrap();
+        let transaction = format!(r#"[{{"from": {}, "to": {}, "value": "0x1"}}]"#, accounts[0], accounts[0]);
+        let hash = node.request("eth_sendTransaction", serde_json::from_str(&transaction).unwrap()).unwrap();
+        node.mine().unwrap();
+
+        let receipt = node.request("eth_getTransactionReceipt", Value::Array(vec![hash.clone()])).unwrap();
+        assert!(!receipt.is_null());
+        node.request("debug_setHead", Value::Array(vec![Value::String("0x0".to_string())])).unwrap();
+        let receipt = node.request("eth_getTransactionReceipt", Value::Array(vec![hash])).unwrap();
+        assert!(receipt.is_null());
+    }
+}
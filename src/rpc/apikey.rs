@@ -0,0 +1,241 @@
+use jsonrpc_core::{Metadata, Middleware, Request, Response, Call, FutureResponse};
+use jsonrpc_core::futures;
+use jsonrpc_http_server::MetaExtractor;
+use jsonrpc_http_server::hyper::server;
+use hexutil::to_hex;
+use sha3::{Digest, Keccak256};
+use std::collections::HashMap;
+use std::str;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use error::Error;
+use super::RPCApiKeyUsage;
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ApiKeyConfig {
+    pub key: String,
+    /// Name the usage of the key is reported under, instead of a hash
+    /// of the key.
+    pub label: Option<String>,
+    pub namespaces: Vec<String>,
+    /// Maximum number of calls per minute, unlimited if not set.
+    pub rate_limit: Option<usize>,
+    /// Whether the key may read the usage of every key.
+    #[serde(default)]
+    pub admin: bool,
+}
+
+impl ApiKeyConfig {
+    /// Label of the key, or the first bytes of its hash, so that usage
+    /// reports never contain the key itself.
+    pub fn name(&self) -> String {
+        match self.label {
+            Some(ref label) => label.clone(),
+            None => to_hex(&Keccak256::digest(self.key.as_bytes())[..8]),
+        }
+    }
+}
+
+#[derive(Default, Clone, Debug)]
+pub struct RPCMeta {
+    pub api_key: Option<String>,
+}
+
+impl Metadata for RPCMeta { }
+
+pub struct ApiKeyExtractor;
+
+impl MetaExtractor<RPCMeta> for ApiKeyExtractor {
+    fn read_metadata(&self, req: &server::Request) -> RPCMeta {
+        let api_key = req.headers().get_raw("x-api-key")
+            .and_then(|raw| raw.one())
+            .and_then(|raw| str::from_utf8(raw).ok())
+            .map(|key| key.to_string());
+
+        RPCMeta { api_key }
+    }
+}
+
+struct ApiKey {
+    config: ApiKeyConfig,
+    window_start: Instant,
+    window_calls: usize,
+    usage: RPCApiKeyUsage,
+}
+
+pub struct ApiKeyManager {
+    keys: Mutex<HashMap<String, ApiKey>>,
+}
+
+impl ApiKeyManager {
+    pub fn new(configs: Vec<ApiKeyConfig>) -> Self {
+        let mut keys = HashMap::new();
+        for config in configs {
+            keys.insert(config.key.clone(), ApiKey {
+                config,
+                window_start: Instant::now(),
+                window_calls: 0,
+                usage: RPCApiKeyUsage::default(),
+            });
+        }
+
+        ApiKeyManager {
+            keys: Mutex::new(keys),
+        }
+    }
+
+    /// When no key is configured, the node is open to everyone.
+    pub fn check(&self, api_key: Option<&str>, methods: &[String]) -> Result<(), Error> {
+        let mut keys = self.keys.lock().unwrap();
+        if keys.is_empty() {
+            return Ok(());
+        }
+
+        let key = match api_key.and_then(|api_key| keys.get_mut(api_key)) {
+            Some(key) => key,
+            None => return Err(Error::Unauthorized),
+        };
+
+        for method in methods {
+            let namespace = method.split('_').next().unwrap_or("");
+            let allowed = key.config.namespaces.iter().any(|allowed| allowed == namespace) &&
+                (method != "dev_apiKeyUsage" || key.config.admin);
+            if !allowed {
+                key.usage.rejected += 1;
+                return Err(Error::Unauthorized);
+            }
+        }
+
+        if key.window_start.elapsed() >= Duration::from_secs(60) {
+            key.window_start = Instant::now();
+            key.window_calls = 0;
+        }
+        if let Some(rate_limit) = key.config.rate_limit {
+            if key.window_calls + methods.len() > rate_limit {
+                key.usage.rate_limited += 1;
+                return Err(Error::RateLimited);
+            }
+        }
+
+        key.window_calls += methods.len();
+        key.usage.requests += methods.len();
+        Ok(())
+    }
+
+    /// Usage of every key, by the name of its configuration.
+    pub fn usage(&self) -> HashMap<String, RPCApiKeyUsage> {
+        self.keys.lock().unwrap().values().map(|key| {
+            (key.config.name(), key.usage.clone())
+        }).collect()
+    }
+}
+
+pub struct ApiKeyMiddleware {
+    keys: Arc<ApiKeyManager>,
+}
+
+impl ApiKeyMiddleware {
+    pub fn new(keys: Arc<ApiKeyManager>) -> Self {
+        ApiKeyMiddleware { keys }
+    }
+}
+
+fn call_method(call: &Call) -> String {
+    match call {
+        &Call::MethodCall(ref call) => call.method.clone(),
+        &Call::Notification(ref notification) => notification.method.clone(),
+        &Call::Invalid(_) => String::new(),
+    }
+}
+
+impl Middleware<RPCMeta> for ApiKeyMiddleware {
+    fn on_request<F>(&self, request: Request, meta: RPCMeta, process: F) -> FutureResponse where
+        F: FnOnce(Request, RPCMeta) -> FutureResponse
+    {
+        let methods: Vec<String> = match request {
+            Request::Single(ref call) => vec![call_method(call)],
+            Request::Batch(ref calls) => calls.iter().map(call_method).collect(),
+        };
+
+        match self.keys.check(meta.api_key.as_ref().map(|key| key.as_str()), &methods) {
+            Ok(()) => process(request, meta),
+            Err(err) => Box::new(futures::finished(Some(Response::from(err.into(), None)))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manager(rate_limit: Option<usize>) -> ApiKeyManager {
+        ApiKeyManager::new(vec![ApiKeyConfig {
+            key: "team-a".to_string(),
+            label: None,
+            namespaces: vec!["eth".to_string(), "net".to_string(), "dev".to_string()],
+            rate_limit,
+            admin: false,
+        }])
+    }
+
+    fn name() -> String {
+        to_hex(&Keccak256::digest(b"team-a")[..8])
+    }
+
+    #[test]
+    fn open_without_keys() {
+        let keys = ApiKeyManager::new(Vec::new());
+        assert!(keys.check(None, &["debug_traceTransaction".to_string()]).is_ok());
+    }
+
+    #[test]
+    fn rejects_unknown_key_and_namespace() {
+        let keys = manager(None);
+        assert!(keys.check(None, &["eth_blockNumber".to_string()]).is_err());
+        assert!(keys.check(Some("team-b"), &["eth_blockNumber".to_string()]).is_err());
+        assert!(keys.check(Some("team-a"), &["debug_traceTransaction".to_string()]).is_err());
+        assert!(keys.check(Some("team-a"), &["eth_blockNumber".to_string()]).is_ok());
+        assert_eq!(keys.usage()[&name()].rejected, 1);
+    }
+
+    #[test]
+    fn enforces_rate_limit() {
+        let keys = manager(Some(2));
+        let call = vec!["eth_blockNumber".to_string()];
+        assert!(keys.check(Some("team-a"), &call).is_ok());
+        assert!(keys.check(Some("team-a"), &call).is_ok());
+        assert!(keys.check(Some("team-a"), &call).is_err());
+        assert_eq!(keys.usage()[&name()].requests, 2);
+        assert_eq!(keys.usage()[&name()].rate_limited, 1);
+    }
+
+    #[test]
+    fn usage_is_admin_only_and_hides_keys() {
+        let keys = ApiKeyManager::new(vec![
+            ApiKeyConfig {
+                key: "team-a".to_string(),
+                label: None,
+                namespaces: vec!["dev".to_string()],
+                rate_limit: None,
+                admin: false,
+            },
+            ApiKeyConfig {
+                key: "secret-ops".to_string(),
+                label: Some("ops".to_string()),
+                namespaces: vec!["dev".to_string()],
+                rate_limit: None,
+                admin: true,
+            },
+        ]);
+        let call = vec!["dev_apiKeyUsage".to_string()];
+        assert!(keys.check(Some("team-a"), &call).is_err());
+        assert!(keys.check(Some("secret-ops"), &call).is_ok());
+
+        let usage = keys.usage();
+        assert_eq!(usage["ops"].requests, 1);
+        assert_eq!(usage[&name()].rejected, 1);
+        assert!(!usage.contains_key("team-a") && !usage.contains_key("secret-ops"));
+    }
+}
@@ -0,0 +1,86 @@
+use bigint::{H256, U256};
+use miner::{LruCache, MinerState};
+use std::sync::{Mutex, MutexGuard};
+
+use error::Error;
+use super::{RPCBlock, RPCReceipt};
+
+struct Entries {
+    reorgs: u64,
+    blocks: LruCache<(H256, bool), RPCBlock>,
+    receipts: LruCache<H256, RPCReceipt>,
+}
+
+/// Blocks and receipts as last returned by the RPC, so that explorers
+/// polling the same ones do not have their transactions hashed, their
+/// senders recovered and frozen ones decoded again on every request.
+/// Blocks are kept by hash and whether their transactions are full,
+/// receipts by transaction hash. Both are dropped once the chain
+/// reorganizes, which can move a transaction to another block or drop
+/// blocks altogether.
+pub struct ReadCache {
+    entries: Mutex<Entries>,
+}
+
+impl ReadCache {
+    /// Cache of `capacity` blocks and as many receipts. Zero disables
+    /// it.
+    pub fn new(capacity: usize) -> Self {
+        ReadCache {
+            entries: Mutex::new(Entries {
+                reorgs: 0,
+                blocks: LruCache::new(capacity),
+                receipts: LruCache::new(capacity),
+            }),
+        }
+    }
+
+    /// Entries read from the chain as `state` holds it.
+    fn entries(&self, state: &MinerState) -> MutexGuard<Entries> {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.reorgs != state.reorgs() {
+            entries.blocks.clear();
+            entries.receipts.clear();
+            entries.reorgs = state.reorgs();
+        }
+        entries
+    }
+
+    /// Block `hash` as converted by `convert`, which is only called if
+    /// it is not cached.
+    pub fn block<F>(&self, state: &MinerState, hash: H256, full: bool, convert: F) -> Result<Option<RPCBlock>, Error> where
+        F: FnOnce() -> Result<Option<RPCBlock>, Error>
+    {
+        if let Some(block) = self.entries(state).blocks.get(&(hash, full)) {
+            return Ok(Some(block));
+        }
+
+        let block = convert()?;
+        if let Some(ref block) = block {
+            self.entries(state).blocks.insert((hash, full), block.clone());
+        }
+        Ok(block)
+    }
+
+    /// Receipt of the transaction `hash` as converted by `convert`,
+    /// which is only called if it is not cached or was pruned since.
+    pub fn receipt<F>(&self, state: &MinerState, hash: H256, convert: F) -> Result<Option<RPCReceipt>, Error> where
+        F: FnOnce() -> Result<Option<RPCReceipt>, Error>
+    {
+        {
+            let mut entries = self.entries(state);
+            if let Some(receipt) = entries.receipts.get(&hash) {
+                if receipt.block_number.0 >= U256::from(state.pruned_before()) {
+                    return Ok(Some(receipt));
+                }
+                entries.receipts.remove(&hash);
+            }
+        }
+
+        let receipt = convert()?;
+        if let Some(ref receipt) = receipt {
+            self.entries(state).receipts.insert(hash, receipt.clone());
+        }
+        Ok(receipt)
+    }
+}
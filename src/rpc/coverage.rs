@@ -0,0 +1,25 @@
+use super::serialize::*;
+use miner::{Tracer, StepContext};
+
+use bigint::Address;
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Tracer recording the program counters executed in each contract.
+/// Code run by DELEGATECALL or CALLCODE counts for the calling
+/// contract, whose storage it runs on.
+#[derive(Default)]
+pub struct CoverageTracer {
+    hits: BTreeMap<Hex<Address>, BTreeSet<usize>>,
+}
+
+impl CoverageTracer {
+    pub fn into_hits(self) -> BTreeMap<Hex<Address>, BTreeSet<usize>> {
+        self.hits
+    }
+}
+
+impl Tracer for CoverageTracer {
+    fn on_step(&mut self, step: &StepContext) {
+        self.hits.entry(Hex(step.address)).or_insert_with(BTreeSet::new).insert(step.pc);
+    }
+}
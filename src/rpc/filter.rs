@@ -1,8 +1,11 @@
-use bigint::{Address, H256};
-use block::{HeaderHash, Log};
+use bigint::{Address, H256, H2048, U256};
+use block::{HeaderHash, Log, Receipt};
+use bloom::LogsBloom;
 use sha3::{Digest, Keccak256};
-use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
+use std::cmp;
+use std::collections::{BTreeSet, HashMap};
+use std::sync::{Arc, RwLock};
+use std::thread;
 use rpc::RPCLogFilter;
 
 use super::{RPCLog, Either};
@@ -10,7 +13,7 @@ use super::util::*;
 
 use error::Error;
 use rlp;
-use miner::MinerState;
+use miner::{LogIndex, MinerState, ReceiptContext};
 
 #[derive(Clone, Debug)]
 pub enum TopicFilter {
@@ -37,7 +40,7 @@ fn check_log(log: &Log, index: usize, filter: &TopicFilter) -> bool {
     match filter {
         &TopicFilter::All => true,
         &TopicFilter::Or(ref hashes) => {
-            if log.topics.len() >= index {
+            if index >= log.topics.len() {
                 false
             } else {
                 let mut matched = false;
@@ -52,49 +55,201 @@ fn check_log(log: &Log, index: usize, filter: &TopicFilter) -> bool {
     }
 }
 
-pub fn get_logs(state: &MinerState, filter: LogFilter) -> Result<Vec<RPCLog>, Error> {
-    let mut current_block_number = filter.from_block;
-    let mut ret = Vec::new();
+fn check_filter(log: &Log, filter: &LogFilter) -> bool {
+    check_log(log, 0, &filter.topics[0]) &&
+        check_log(log, 1, &filter.topics[1]) &&
+        check_log(log, 2, &filter.topics[2]) &&
+        check_log(log, 3, &filter.topics[3]) &&
+        match filter.address {
+            Some(address) => address == log.address,
+            None => true,
+        }
+}
+
+fn bloom_of(value: &[u8]) -> H2048 {
+    let mut bloom = LogsBloom::new();
+    bloom.set(value);
+    bloom.into()
+}
+
+/// Whether every bit of `value` is set in `bloom`.
+fn bloom_contains(bloom: &H2048, value: &H2048) -> bool {
+    bloom.iter().zip(value.iter()).all(|(bloom, value)| bloom & value == *value)
+}
+
+/// Blooms a block or receipt has to contain for one of its logs to
+/// match a filter: that of the address, and one of those of the hashes
+/// of each filtered topic.
+struct BloomFilter {
+    address: Option<H2048>,
+    topics: Vec<Vec<H2048>>,
+}
 
-    while current_block_number >= filter.to_block {
-        if current_block_number > state.block_height() {
-            break;
+impl BloomFilter {
+    fn new(filter: &LogFilter) -> Self {
+        BloomFilter {
+            address: filter.address.map(|address| bloom_of(&address)),
+            topics: filter.topics.iter().filter_map(|topic| match topic {
+                &TopicFilter::All => None,
+                &TopicFilter::Or(ref hashes) => Some(hashes.iter().map(|hash| bloom_of(hash)).collect()),
+            }).collect(),
         }
+    }
+
+    /// False only if no log summarized by `bloom` can match. Blooms have
+    /// false positives, so a match still has to be checked on the logs.
+    fn matches(&self, bloom: &LogsBloom) -> bool {
+        let bloom: H2048 = bloom.clone().into();
+        self.address.as_ref().map(|address| bloom_contains(&bloom, address)).unwrap_or(true) &&
+            self.topics.iter().all(|hashes| hashes.iter().any(|hash| bloom_contains(&bloom, hash)))
+    }
+}
 
-        let block = state.get_block_by_number(current_block_number);
+/// Blocks in each sub-range of a log query handed to a worker.
+pub const LOG_CHUNK_BLOCKS: usize = 1024;
+
+/// Receipts of a range of blocks, copied out of the state so that
+/// they can be matched without holding it.
+struct ReceiptChunk {
+    blocks: Vec<(H256, U256, Vec<(H256, ReceiptContext, Receipt)>)>,
+}
+
+/// Receipts of the blocks `numbers` which may hold logs matching
+/// `filter`. Blocks whose `logsBloom` rules it out are skipped without
+/// loading their body or receipts.
+fn receipt_chunk(state: &MinerState, filter: &BloomFilter, numbers: &[usize]) -> Result<ReceiptChunk, Error> {
+    let mut blocks = Vec::new();
+    for &number in numbers {
+        let header = state.get_total_header_by_hash(state.get_canonical_hash(number))?.header;
+        if !filter.matches(&header.logs_bloom) {
+            continue;
+        }
+
+        let block = state.get_block_by_number(number);
+        let mut receipts = Vec::new();
         for transaction in &block.transactions {
             let transaction_hash = H256::from(Keccak256::digest(&rlp::encode(transaction).to_vec()).as_slice());
             let receipt = state.get_receipt_by_transaction_hash(transaction_hash)?;
+            if receipt.logs.is_empty() || !filter.matches(&receipt.logs_bloom) {
+                continue;
+            }
+            receipts.push((transaction_hash, state.receipt_context(transaction_hash)?, receipt));
+        }
+        blocks.push((block.header.header_hash(), block.header.number, receipts));
+    }
+    Ok(ReceiptChunk { blocks })
+}
+
+fn match_chunk(filter: &LogFilter, chunk: &ReceiptChunk) -> Vec<RPCLog> {
+    let mut ret = Vec::new();
+    for &(block_hash, block_number, ref receipts) in &chunk.blocks {
+        for &(transaction_hash, ref context, ref receipt) in receipts {
             for i in 0..receipt.logs.len() {
-                let log = &receipt.logs[i];
-                if check_log(log, 0, &filter.topics[0]) &&
-                    check_log(log, 1, &filter.topics[1]) &&
-                    check_log(log, 2, &filter.topics[2]) &&
-                    check_log(log, 3, &filter.topics[3]) &&
-                    match filter.address {
-                        Some(address) => address == log.address,
-                        None => true,
-                    }
-                {
-                    ret.push(to_rpc_log(&receipt, i, transaction, &block));
+                if check_filter(&receipt.logs[i], filter) {
+                    ret.push(to_rpc_log_in_context(context, receipt, i, transaction_hash,
+                                                   block_hash, block_number));
                 }
             }
         }
+    }
+    ret
+}
 
-        current_block_number += 1;
+/// Blocks the log index has logs matching the address and topics of
+/// `filter` in, or None if it restricts neither.
+fn indexed_blocks(index: &LogIndex, filter: &LogFilter) -> Option<BTreeSet<usize>> {
+    let mut sets = Vec::new();
+    if let Some(address) = filter.address {
+        sets.push(index.address_blocks(&address));
+    }
+    for topic in &filter.topics {
+        if let TopicFilter::Or(ref hashes) = *topic {
+            let mut blocks = BTreeSet::new();
+            for hash in hashes {
+                blocks.extend(index.topic_blocks(hash));
+            }
+            sets.push(blocks);
+        }
     }
 
-    return Ok(ret);
+    let mut sets = sets.into_iter();
+    let first = sets.next()?;
+    Some(sets.fold(first, |blocks, set| blocks.intersection(&set).cloned().collect()))
+}
+
+/// Receipts a log query has to match, copied out of the chain so that
+/// they are matched once its lock is released.
+pub struct LogQuery {
+    filter: LogFilter,
+    chunks: Vec<ReceiptChunk>,
+    workers: usize,
+}
+
+/// Copy the receipts that may hold logs matching `filter`, in chunks
+/// of `LOG_CHUNK_BLOCKS` blocks. With a log index, only the blocks it
+/// lists are looked at.
+pub fn log_query(state: &MinerState, filter: LogFilter) -> Result<LogQuery, Error> {
+    if filter.from_block < state.pruned_before() {
+        return Err(Error::Pruned(state.pruned_before()));
+    }
+
+    let to_block = cmp::min(filter.to_block, state.block_height());
+    let blooms = BloomFilter::new(&filter);
+
+    let numbers: Vec<usize> = match state.log_index().and_then(|index| indexed_blocks(index, &filter)) {
+        Some(ref blocks) if filter.from_block <= to_block =>
+            blocks.range(filter.from_block..(to_block + 1)).cloned().collect(),
+        Some(_) => Vec::new(),
+        None => (filter.from_block..(to_block + 1)).collect(),
+    };
+
+    let mut chunks = Vec::new();
+    for numbers in numbers.chunks(LOG_CHUNK_BLOCKS) {
+        chunks.push(receipt_chunk(state, &blooms, numbers)?);
+    }
+
+    Ok(LogQuery {
+        filter, chunks,
+        workers: cmp::max(state.log_workers(), 1),
+    })
+}
+
+impl LogQuery {
+    /// Logs matching the filter, in block order. Chunks are matched by
+    /// up to `log_workers` threads at once, started for the query.
+    pub fn run(self) -> Result<Vec<RPCLog>, Error> {
+        let LogQuery { filter, chunks, workers } = self;
+        let mut ret = Vec::new();
+
+        let mut chunks = chunks.into_iter().peekable();
+        while chunks.peek().is_some() {
+            let batch: Vec<ReceiptChunk> = chunks.by_ref().take(workers).collect();
+            if batch.len() == 1 {
+                ret.extend(match_chunk(&filter, &batch[0]));
+                continue;
+            }
+
+            let handles: Vec<_> = batch.into_iter().map(|chunk| {
+                let filter = filter.clone();
+                thread::spawn(move || match_chunk(&filter, &chunk))
+            }).collect();
+            for handle in handles {
+                ret.extend(handle.join().map_err(|_| Error::Internal("log query worker panicked"))?);
+            }
+        }
+
+        Ok(ret)
+    }
 }
 
 pub struct FilterManager {
     filters: HashMap<usize, Filter>,
-    state: Arc<Mutex<MinerState>>,
+    state: Arc<RwLock<MinerState>>,
     unmodified_filters: HashMap<usize, Filter>,
 }
 
 impl FilterManager {
-    pub fn new(state: Arc<Mutex<MinerState>>) -> Self {
+    pub fn new(state: Arc<RwLock<MinerState>>) -> Self {
         FilterManager {
             state,
             filters: HashMap::new(),
@@ -103,7 +258,7 @@ impl FilterManager {
     }
 
     pub fn from_log_filter(&self, log: RPCLogFilter) -> Result<LogFilter, Error> {
-        let state = self.state.lock().unwrap();
+        let state = self.state.read().unwrap();
         from_log_filter(&state, log)
     }
 
@@ -115,7 +270,7 @@ impl FilterManager {
     }
 
     pub fn install_block_filter(&mut self) -> usize {
-        let state = self.state.lock().unwrap();
+        let state = self.state.read().unwrap();
 
         let block_height = state.block_height();
         let id = self.filters.len();
@@ -125,12 +280,12 @@ impl FilterManager {
     }
 
     pub fn install_pending_transaction_filter(&mut self) -> usize {
-        let state = self.state.lock().unwrap();
+        let state = self.state.read().unwrap();
 
-        let pending_transactions = state.all_pending_transaction_hashes();
+        let next_start = state.pending_transaction_count();
         let id = self.filters.len();
-        self.filters.insert(id, Filter::PendingTransaction(pending_transactions.len()));
-        self.unmodified_filters.insert(id, Filter::PendingTransaction(pending_transactions.len()));
+        self.filters.insert(id, Filter::PendingTransaction(next_start));
+        self.unmodified_filters.insert(id, Filter::PendingTransaction(next_start));
         id
     }
 
@@ -140,31 +295,28 @@ impl FilterManager {
     }
 
     pub fn get_logs(&mut self, id: usize) -> Result<Vec<RPCLog>, Error> {
-        let state = self.state.lock().unwrap();
+        let query = {
+            let state = self.state.read().unwrap();
 
-        let filter = self.unmodified_filters.get(&id).ok_or(Error::NotFound)?;
+            let filter = self.unmodified_filters.get(&id).ok_or(Error::NotFound)?;
 
-        match filter {
-            &Filter::Log(ref filter) => {
-                let ret = get_logs(&state, filter.clone())?;
-                Ok(ret)
-            },
-            _ => Err(Error::NotFound),
-        }
+            match filter {
+                &Filter::Log(ref filter) => log_query(&state, filter.clone())?,
+                _ => return Err(Error::NotFound),
+            }
+        };
+        query.run()
     }
 
     pub fn get_changes(&mut self, id: usize) -> Result<Either<Vec<String>, Vec<RPCLog>>, Error> {
-        let state = self.state.lock().unwrap();
+        let state = self.state.read().unwrap();
         let filter = self.filters.get_mut(&id).ok_or(Error::NotFound)?;
 
         match filter {
             &mut Filter::PendingTransaction(ref mut next_start) => {
-                let pending_transactions = state.all_pending_transaction_hashes();
-                let mut ret = Vec::new();
-                while *next_start < pending_transactions.len() {
-                    ret.push(format!("0x{:x}", &pending_transactions[*next_start]));
-                    *next_start += 1;
-                }
+                let ret = state.pending_transaction_hashes_since(*next_start).iter()
+                    .map(|hash| format!("0x{:x}", hash)).collect();
+                *next_start = state.pending_transaction_count();
                 Ok(Either::Left(ret))
             },
             &mut Filter::Block(ref mut next_start) => {
@@ -177,10 +329,59 @@ impl FilterManager {
                 Ok(Either::Left(ret))
             },
             &mut Filter::Log(ref mut filter) => {
-                let ret = get_logs(&state, filter.clone())?;
+                let query = log_query(&state, filter.clone())?;
                 filter.from_block = state.block_height() + 1;
-                Ok(Either::Right(ret))
+                drop(state);
+                Ok(Either::Right(query.run()?))
             },
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn topic_past_the_end_does_not_match() {
+        let log = Log {
+            address: Address::default(),
+            topics: vec![H256::from(1u64)],
+            data: Vec::new(),
+        };
+        let filter = |topics: Vec<TopicFilter>| LogFilter {
+            from_block: 0, to_block: 0, address: None, topics,
+        };
+
+        assert!(check_filter(&log, &filter(vec![
+            TopicFilter::Or(vec![H256::from(1u64)]), TopicFilter::All, TopicFilter::All, TopicFilter::All,
+        ])));
+        assert!(!check_filter(&log, &filter(vec![
+            TopicFilter::All, TopicFilter::Or(vec![H256::from(1u64)]), TopicFilter::All, TopicFilter::All,
+        ])));
+    }
+
+    #[test]
+    fn bloom_rules_out_other_addresses_and_topics() {
+        let address = Address::from(1u64);
+        let topic = H256::from(2u64);
+        let mut bloom = LogsBloom::new();
+        bloom.set(&address);
+        bloom.set(&topic);
+
+        let filter = |address: Option<Address>, topics: Vec<TopicFilter>| BloomFilter::new(&LogFilter {
+            from_block: 0, to_block: 0, address, topics,
+        });
+        let all = || vec![TopicFilter::All, TopicFilter::All, TopicFilter::All, TopicFilter::All];
+
+        assert!(filter(None, all()).matches(&bloom));
+        assert!(filter(Some(address), all()).matches(&bloom));
+        assert!(!filter(Some(Address::from(3u64)), all()).matches(&bloom));
+        assert!(filter(None, vec![
+            TopicFilter::All, TopicFilter::Or(vec![H256::from(4u64), topic]), TopicFilter::All, TopicFilter::All,
+        ]).matches(&bloom));
+        assert!(!filter(Some(address), vec![
+            TopicFilter::Or(vec![H256::from(4u64)]), TopicFilter::All, TopicFilter::All, TopicFilter::All,
+        ]).matches(&bloom));
+    }
+}
@@ -0,0 +1,428 @@
+use bigint::{U256, Address, Gas};
+use rand::{Rng, SeedableRng, StdRng};
+use serde_json::Value;
+use sha3::{Digest, Keccak256};
+use sputnikvm::{Patch, VM, VMStatus};
+use std::sync::{Arc, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use block::{Account, FromKey};
+use error::Error;
+use miner::{self, MinerState};
+use super::{RPCFuzzConfig, RPCFuzzReport, RPCFuzzRevert, RPCFuzzViolation, RPCTransaction};
+use super::serialize::*;
+use super::util::{call_at_block, to_signed_transaction_with_key};
+
+/// Transactions sent per fuzzed block if not configured.
+pub const DEFAULT_TRANSACTIONS_PER_BLOCK: usize = 10;
+
+/// Seed of a run that was not given one, so that two runs do not send
+/// the same calls.
+fn clock_seed() -> u64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    now.as_secs() ^ (now.subsec_nanos() as u64)
+}
+
+/// Gas limit of every fuzzing transaction.
+pub fn fuzz_gas_limit() -> Gas {
+    Gas::from(3_000_000u64)
+}
+
+/// State-changing function of a target contract.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FuzzFunction {
+    pub name: String,
+    pub inputs: Vec<String>,
+    pub payable: bool,
+}
+
+impl FuzzFunction {
+    pub fn signature(&self) -> String {
+        format!("{}({})", self.name, self.inputs.join(","))
+    }
+
+    pub fn selector(&self) -> [u8; 4] {
+        let hash = Keccak256::digest(self.signature().as_bytes());
+        [hash[0], hash[1], hash[2], hash[3]]
+    }
+}
+
+/// `uint` and `int` are aliases of their 256-bit versions, and must be
+/// written out in signatures.
+fn canonical_type(ty: &str) -> String {
+    match element_type(ty) {
+        Some(element) => format!("{}{}", canonical_type(element), &ty[element.len()..]),
+        None => match ty {
+            "uint" => "uint256".to_string(),
+            "int" => "int256".to_string(),
+            _ => ty.to_string(),
+        },
+    }
+}
+
+fn element_type(ty: &str) -> Option<&str> {
+    if ty.ends_with("[]") {
+        Some(&ty[..(ty.len() - 2)])
+    } else {
+        None
+    }
+}
+
+fn integer_bits(ty: &str, prefix: &str) -> Option<usize> {
+    if !ty.starts_with(prefix) {
+        return None;
+    }
+    match ty[prefix.len()..].parse::<usize>() {
+        Ok(bits) if bits > 0 && bits <= 256 && bits % 8 == 0 => Some(bits),
+        _ => None,
+    }
+}
+
+fn fixed_bytes_len(ty: &str) -> Option<usize> {
+    if !ty.starts_with("bytes") {
+        return None;
+    }
+    match ty[5..].parse::<usize>() {
+        Ok(len) if len > 0 && len <= 32 => Some(len),
+        _ => None,
+    }
+}
+
+fn is_static(ty: &str) -> bool {
+    ty == "address" || ty == "bool" || integer_bits(ty, "uint").is_some() ||
+        integer_bits(ty, "int").is_some() || fixed_bytes_len(ty).is_some()
+}
+
+/// Types the fuzzer can generate: static elementary types, `bytes`,
+/// `string` and dynamic arrays of static elementary types.
+fn is_supported(ty: &str) -> bool {
+    is_static(ty) || ty == "bytes" || ty == "string" ||
+        element_type(ty).map(is_static).unwrap_or(false)
+}
+
+/// State-changing functions of a JSON ABI whose parameters can all be
+/// generated. Views, pure functions and other entries are skipped.
+pub fn parse_abi(abi: &Value) -> Result<Vec<FuzzFunction>, Error> {
+    let entries = abi.as_array().ok_or(Error::InvalidParams)?;
+
+    let mut functions = Vec::new();
+    for entry in entries {
+        if entry.get("type").and_then(|ty| ty.as_str()).unwrap_or("function") != "function" {
+            continue;
+        }
+        let mutability = entry.get("stateMutability").and_then(|value| value.as_str());
+        let constant = entry.get("constant").and_then(|value| value.as_bool()).unwrap_or(false);
+        if constant || mutability == Some("view") || mutability == Some("pure") {
+            continue;
+        }
+
+        let name = entry.get("name").and_then(|name| name.as_str()).ok_or(Error::InvalidParams)?;
+        let mut inputs = Vec::new();
+        for input in entry.get("inputs").and_then(|inputs| inputs.as_array()).unwrap_or(&Vec::new()) {
+            let ty = input.get("type").and_then(|ty| ty.as_str()).ok_or(Error::InvalidParams)?;
+            inputs.push(canonical_type(ty));
+        }
+        if !inputs.iter().all(|ty| is_supported(ty)) {
+            continue;
+        }
+
+        functions.push(FuzzFunction {
+            name: name.to_string(),
+            inputs,
+            payable: mutability == Some("payable") ||
+                entry.get("payable").and_then(|value| value.as_bool()).unwrap_or(false),
+        });
+    }
+    Ok(functions)
+}
+
+fn word(value: U256) -> Vec<u8> {
+    let mut word = vec![0u8; 32];
+    value.to_big_endian(&mut word);
+    word
+}
+
+fn mask(bits: usize) -> U256 {
+    if bits == 256 {
+        !U256::zero()
+    } else {
+        (U256::one() << bits) - U256::one()
+    }
+}
+
+/// Random value of a static type, biased towards edge cases.
+fn random_word<R: Rng>(rng: &mut R, ty: &str, addresses: &[Address]) -> Vec<u8> {
+    let mut random = [0u8; 32];
+    rng.fill_bytes(&mut random);
+    let random = U256::from(&random[..]);
+
+    if ty == "address" {
+        let address = if !addresses.is_empty() && rng.gen_weighted_bool(2) {
+            addresses[rng.gen_range(0, addresses.len())]
+        } else {
+            Address::from(&word(random)[12..])
+        };
+        let mut ret = vec![0u8; 12];
+        ret.extend_from_slice(&address[..]);
+        ret
+    } else if ty == "bool" {
+        word(U256::from(rng.gen_range(0u64, 2)))
+    } else if let Some(len) = fixed_bytes_len(ty) {
+        let mut ret = word(random);
+        for byte in ret[len..].iter_mut() {
+            *byte = 0;
+        }
+        ret
+    } else if let Some(bits) = integer_bits(ty, "uint") {
+        word(match rng.gen_range(0, 4) {
+            0 => U256::zero(),
+            1 => U256::one(),
+            2 => mask(bits),
+            _ => random & mask(bits),
+        })
+    } else if let Some(bits) = integer_bits(ty, "int") {
+        // Two's complement, sign-extended to 256 bits.
+        let value = match rng.gen_range(0, 5) {
+            0 => U256::zero(),
+            1 => U256::one(),
+            2 => !U256::zero(),
+            3 => mask(bits - 1),
+            _ => random & mask(bits),
+        };
+        if bits < 256 && value & (U256::one() << (bits - 1)) != U256::zero() {
+            word(value | !mask(bits))
+        } else {
+            word(value)
+        }
+    } else {
+        word(U256::zero())
+    }
+}
+
+fn padded(mut data: Vec<u8>) -> Vec<u8> {
+    let len = (data.len() + 31) / 32 * 32;
+    data.resize(len, 0);
+    data
+}
+
+/// ABI-encoded call of `function` with random arguments.
+pub fn random_call<R: Rng>(rng: &mut R, function: &FuzzFunction, addresses: &[Address]) -> Vec<u8> {
+    let mut head = Vec::new();
+    let mut tail = Vec::new();
+    let head_len = 32 * function.inputs.len();
+
+    for ty in &function.inputs {
+        if is_static(ty) {
+            head.extend(random_word(rng, ty, addresses));
+            continue;
+        }
+
+        head.extend(word(U256::from(head_len + tail.len())));
+        match element_type(ty) {
+            Some(element) => {
+                let len = rng.gen_range(0, 5);
+                tail.extend(word(U256::from(len)));
+                for _ in 0..len {
+                    tail.extend(random_word(rng, element, addresses));
+                }
+            },
+            None => {
+                let len = rng.gen_range(0, 65);
+                let data: Vec<u8> = if ty == "string" {
+                    (0..len).map(|_| rng.gen_range(0x20u8, 0x7f)).collect()
+                } else {
+                    (0..len).map(|_| rng.gen::<u8>()).collect()
+                };
+                tail.extend(word(U256::from(len)));
+                tail.extend(padded(data));
+            },
+        }
+    }
+
+    let mut ret = function.selector().to_vec();
+    ret.extend(head);
+    ret.extend(tail);
+    ret
+}
+
+/// Send random calls to the targets from the generated accounts and
+/// mine them, one block at a time, recording reverted calls and
+/// invariants that stop holding after a block. The report carries the
+/// seed used, so that a run can be repeated.
+pub fn fuzz<P: 'static + Patch>(state: Arc<RwLock<MinerState>>, config: RPCFuzzConfig) -> Result<RPCFuzzReport, Error> {
+    let mut targets = Vec::new();
+    for target in &config.targets {
+        let functions = parse_abi(&target.abi)?;
+        if !functions.is_empty() {
+            targets.push((target.address.0, functions));
+        }
+    }
+    if targets.is_empty() {
+        return Err(Error::InvalidParams);
+    }
+
+    let senders = {
+        let state = state.read().unwrap();
+        let mut senders = Vec::new();
+        for &(ref secret_key, _) in state.genesis_accounts() {
+            let address = Address::from_secret_key(secret_key)?;
+            let wanted = match config.senders {
+                Some(ref senders) => senders.iter().any(|sender| sender.0 == address),
+                None => true,
+            };
+            if wanted {
+                senders.push((address, secret_key.clone()));
+            }
+        }
+        senders
+    };
+    if senders.is_empty() {
+        return Err(Error::NotFound);
+    }
+
+    let mut addresses: Vec<Address> = senders.iter().map(|&(address, _)| address).collect();
+    addresses.extend(targets.iter().map(|&(address, _)| address));
+
+    let seed = config.seed.unwrap_or_else(clock_seed);
+    let mut rng: StdRng = SeedableRng::from_seed(&[seed as usize][..]);
+    let per_block = config.transactions_per_block.unwrap_or(DEFAULT_TRANSACTIONS_PER_BLOCK);
+    let invariants = config.invariants.unwrap_or(Vec::new());
+
+    let mut report = RPCFuzzReport {
+        seed,
+        blocks: 0,
+        transactions: 0,
+        reverts: Vec::new(),
+        violations: Vec::new(),
+    };
+
+    for _ in 0..config.blocks {
+        let mut sent = Vec::new();
+        {
+            let mut state = state.write().unwrap();
+            let mut nonces: Vec<U256> = senders.iter().map(|&(address, _)| {
+                let account: Option<Account> = state.stateful().state_of(state.stateful().root()).get(&address);
+                account.map(|account| account.nonce).unwrap_or(U256::zero())
+            }).collect();
+
+            for _ in 0..per_block {
+                let &(to, ref functions) = &targets[rng.gen_range(0, targets.len())];
+                let function = &functions[rng.gen_range(0, functions.len())];
+                let sender = rng.gen_range(0, senders.len());
+                let input = random_call(&mut rng, function, &addresses);
+                let value = if function.payable && rng.gen_weighted_bool(2) {
+                    U256::from(rng.gen_range(0u64, 1_000_000_000_000_000u64))
+                } else {
+                    U256::zero()
+                };
+
+                let transaction = to_signed_transaction_with_key::<P>(&state, RPCTransaction {
+                    from: Some(Hex(senders[sender].0)),
+                    to: Some(Hex(to)),
+                    gas: Some(Hex(fuzz_gas_limit())),
+                    gas_price: None,
+                    value: Some(Hex(value)),
+                    data: Some(Bytes(input.clone())),
+                    input: None,
+                    nonce: Some(Hex(nonces[sender])),
+                    hash: None,
+                    block_hash: None,
+                    block_number: None,
+                    transaction_index: None,
+                    trace: None,
+                }, &senders[sender].1)?;
+                nonces[sender] = nonces[sender] + U256::one();
+
+                let hash = state.append_pending_transaction(transaction)?;
+                sent.push((hash, to, function.signature(), input));
+            }
+        }
+
+        miner::mine_one::<P>(state.clone(), Address::default());
+
+        let state = state.read().unwrap();
+        report.blocks += 1;
+        report.transactions += sent.len();
+        for (hash, to, function, input) in sent {
+            if !state.receipt_status(hash) {
+                report.reverts.push(RPCFuzzRevert {
+                    transaction_hash: Hex(hash),
+                    to: Hex(to),
+                    function,
+                    input: Bytes(input),
+                    reason: state.revert_reason(hash),
+                });
+            }
+        }
+
+        let number = state.block_height();
+        for (index, invariant) in invariants.iter().enumerate() {
+            let output = match call_at_block::<P>(&state, invariant.clone(), number) {
+                Ok(vm) => match vm.status() {
+                    VMStatus::ExitedOk => Some(vm.out().to_vec()),
+                    _ => None,
+                },
+                Err(_) => None,
+            };
+            let holds = output.as_ref().map(|out| out.iter().any(|byte| *byte != 0)).unwrap_or(false);
+            if !holds {
+                report.violations.push(RPCFuzzViolation {
+                    invariant: index,
+                    block_number: Hex(number),
+                    output: output.map(Bytes),
+                });
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json;
+
+    #[test]
+    fn abi_functions() {
+        let abi: Value = serde_json::from_str(r#"[
+            { "type": "function", "name": "transfer", "stateMutability": "nonpayable",
+              "inputs": [{ "name": "to", "type": "address" }, { "name": "value", "type": "uint" }] },
+            { "type": "function", "name": "balanceOf", "stateMutability": "view",
+              "inputs": [{ "name": "owner", "type": "address" }] },
+            { "type": "function", "name": "batch", "stateMutability": "payable",
+              "inputs": [{ "name": "values", "type": "uint[]" }, { "name": "data", "type": "bytes" }] },
+            { "type": "function", "name": "nested", "stateMutability": "nonpayable",
+              "inputs": [{ "name": "values", "type": "uint[][]" }] },
+            { "type": "event", "name": "Transfer", "inputs": [] }
+        ]"#).unwrap();
+
+        let functions = parse_abi(&abi).unwrap();
+        assert_eq!(functions.len(), 2);
+        assert_eq!(functions[0].signature(), "transfer(address,uint256)");
+        assert_eq!(functions[0].selector(), [0xa9, 0x05, 0x9c, 0xbb]);
+        assert_eq!(functions[1].signature(), "batch(uint256[],bytes)");
+        assert!(functions[1].payable);
+    }
+
+    #[test]
+    fn random_call_layout() {
+        let function = FuzzFunction {
+            name: "f".to_string(),
+            inputs: vec!["uint8".to_string(), "bytes".to_string()],
+            payable: false,
+        };
+        let mut rng: StdRng = SeedableRng::from_seed(&[1usize][..]);
+
+        for _ in 0..20 {
+            let call = random_call(&mut rng, &function, &[]);
+            assert_eq!(&call[..4], &function.selector()[..]);
+            // uint8 fits in its last byte.
+            assert!(call[4..35].iter().all(|byte| *byte == 0));
+            // bytes start right after the head.
+            assert_eq!(U256::from(&call[36..68]), U256::from(64u64));
+            let len = U256::from(&call[68..100]).as_usize();
+            assert_eq!(call.len(), 100 + (len + 31) / 32 * 32);
+        }
+    }
+}
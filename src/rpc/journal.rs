@@ -0,0 +1,262 @@
+use bigint::H256;
+use hexutil::to_hex;
+use jsonrpc_core::{Middleware, Request, Response, Call, MethodCall, Output, Params, FutureResponse};
+use jsonrpc_core::futures::{self, Future};
+use rlp;
+use serde_json::{self, Value};
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+
+use error::Error;
+use journal::{Journal, JournalEntry, JournalGuard};
+use miner::MinerState;
+use super::apikey::{RPCMeta, ApiKeyMiddleware};
+
+/// Records every call that passed the API key check into the journal
+/// before it is processed, or once processed for transactions sent and
+/// fuzzing runs.
+///
+/// Journals are meant to be attached to bug reports, so `personal_*`
+/// calls, which carry passwords and raw keys and only change the
+/// keystore, are not recorded. Transactions the node signs with a
+/// managed key are recorded once sent, as the `eth_sendRawTransaction`
+/// of the signed transaction, so that they are replayed without the
+/// key. A `dev_fuzz` is recorded with the seed it ran with, so that a
+/// run seeded from the clock replays the same calls.
+///
+/// Calls that may change the node hold the journal until they have
+/// completed, so that they are applied in the order they are recorded.
+/// Read-only calls are recorded and then run without it, concurrently
+/// with any other call.
+pub struct JournalMiddleware {
+    journal: Option<Arc<Journal>>,
+    state: Arc<RwLock<MinerState>>,
+    inner: ApiKeyMiddleware,
+}
+
+impl JournalMiddleware {
+    pub fn new(journal: Option<Arc<Journal>>, state: Arc<RwLock<MinerState>>, inner: ApiKeyMiddleware) -> Self {
+        JournalMiddleware { journal, state, inner }
+    }
+}
+
+fn is_keystore_call(method: &str) -> bool {
+    method.starts_with("personal_")
+}
+
+fn is_send(method: &str) -> bool {
+    method == "eth_sendTransaction" || method == "personal_sendTransaction"
+}
+
+fn is_fuzz(method: &str) -> bool {
+    method == "dev_fuzz"
+}
+
+/// Methods that leave the node as it is. Filter polls move their filter
+/// on and `eth_getWork` keeps the work it hands out, so they are not
+/// among them.
+fn is_read_only(method: &str) -> bool {
+    match method {
+        "eth_call" | "eth_estimateGas" | "eth_blockNumber" | "eth_chainId" | "eth_accounts" | "eth_coinbase"
+            | "eth_gasPrice" | "eth_syncing" | "eth_mining" | "eth_hashrate" | "eth_protocolVersion" => true,
+        "eth_getFilterChanges" | "eth_getFilterLogs" | "eth_getWork" => false,
+        _ => ["eth_get", "web3_", "net_", "debug_trace", "debug_get", "trace_"].iter()
+            .any(|prefix| method.starts_with(prefix)),
+    }
+}
+
+/// `call` as it is recorded: a send replaced by the signed transaction
+/// it resulted in, found among `outputs`.
+fn signed_send(state: &RwLock<MinerState>, call: Call, outputs: &[&Output]) -> Call {
+    let send = match call {
+        Call::MethodCall(ref send) if is_send(&send.method) => Some(send.clone()),
+        _ => None,
+    };
+    let send = match send {
+        Some(send) => send,
+        None => return call,
+    };
+
+    let hash = outputs.iter().filter_map(|output| match **output {
+        Output::Success(ref success) if success.id == send.id =>
+            success.result.as_str().and_then(|hash| H256::from_str(hash.trim_start_matches("0x")).ok()),
+        _ => None,
+    }).next();
+    let raw = {
+        let state = state.read().unwrap();
+        // Impersonated transactions are not signed by their sender, and
+        // are replayed from the original call.
+        match hash {
+            Some(hash) if state.impersonated_sender(hash).is_none() =>
+                state.get_transaction_by_hash(hash).ok()
+                    .map(|transaction| state.get_hash_raw(hash).unwrap_or_else(|| rlp::encode(&transaction).to_vec())),
+            _ => None,
+        }
+    };
+
+    match raw {
+        Some(raw) => Call::MethodCall(MethodCall {
+            jsonrpc: send.jsonrpc,
+            method: "eth_sendRawTransaction".to_string(),
+            params: Some(Params::Array(vec![Value::String(to_hex(&raw))])),
+            id: send.id,
+        }),
+        None => call,
+    }
+}
+
+/// `call` as it is recorded: a fuzzing run given the seed it reported
+/// among `outputs`.
+fn seeded_fuzz(call: Call, outputs: &[&Output]) -> Call {
+    let fuzz = match call {
+        Call::MethodCall(ref fuzz) if is_fuzz(&fuzz.method) => Some(fuzz.clone()),
+        _ => None,
+    };
+    let mut fuzz = match fuzz {
+        Some(fuzz) => fuzz,
+        None => return call,
+    };
+
+    let seed = outputs.iter().filter_map(|output| match **output {
+        Output::Success(ref success) if success.id == fuzz.id => success.result.get("seed").cloned(),
+        _ => None,
+    }).next();
+    if let (Some(seed), Some(&mut Params::Array(ref mut params))) = (seed, fuzz.params.as_mut()) {
+        if let Some(&mut Value::Object(ref mut config)) = params.get_mut(0) {
+            config.insert("seed".to_string(), seed);
+        }
+    }
+    Call::MethodCall(fuzz)
+}
+
+fn record(journal: &mut JournalGuard, calls: Vec<Call>) -> Result<(), Error> {
+    let mut calls: Vec<Call> = calls.into_iter().filter(|call| match *call {
+        Call::MethodCall(ref call) => !is_keystore_call(&call.method),
+        Call::Notification(ref notification) => !is_keystore_call(&notification.method),
+        Call::Invalid(_) => true,
+    }).collect();
+
+    let request = match calls.len() {
+        0 => return Ok(()),
+        1 => Request::Single(calls.pop().unwrap()),
+        _ => Request::Batch(calls),
+    };
+    journal.append(&JournalEntry::Rpc {
+        request: serde_json::to_value(&request).unwrap(),
+    })
+}
+
+fn failure(err: Error) -> FutureResponse {
+    Box::new(futures::finished(Some(Response::from(err.into(), None))))
+}
+
+impl Middleware<RPCMeta> for JournalMiddleware {
+    fn on_request<F>(&self, request: Request, meta: RPCMeta, process: F) -> FutureResponse where
+        F: FnOnce(Request, RPCMeta) -> FutureResponse
+    {
+        let journal = match self.journal {
+            Some(ref journal) => journal.clone(),
+            None => return self.inner.on_request(request, meta, process),
+        };
+        let state = self.state.clone();
+
+        self.inner.on_request(request, meta, move |request, meta| {
+            let calls = match request {
+                Request::Single(ref call) => vec![call.clone()],
+                Request::Batch(ref calls) => calls.clone(),
+            };
+            let read_only = calls.iter().all(|call| match *call {
+                Call::MethodCall(ref call) => is_read_only(&call.method),
+                Call::Notification(ref notification) => is_read_only(&notification.method),
+                Call::Invalid(_) => true,
+            });
+            if read_only {
+                if let Err(err) = record(&mut journal.lock(), calls) {
+                    error!("cannot write to the journal: {}", err);
+                    return failure(err);
+                }
+                return process(request, meta);
+            }
+
+            // The guard is held until the call has completed, so that the
+            // miner cannot seal a block in between.
+            let mut journal = journal.lock();
+            let after = calls.iter().any(|call| match *call {
+                Call::MethodCall(ref call) => is_send(&call.method) || is_fuzz(&call.method),
+                _ => false,
+            });
+
+            if !after {
+                if let Err(err) = record(&mut journal, calls) {
+                    error!("cannot write to the journal: {}", err);
+                    return failure(err);
+                }
+                let response = process(request, meta).wait();
+                return Box::new(futures::done(response));
+            }
+
+            // A sent transaction is only known once it has been signed,
+            // and the seed of a fuzzing run once it has started.
+            let response = process(request, meta).wait();
+            let calls = {
+                let outputs: Vec<&Output> = match response {
+                    Ok(Some(Response::Single(ref output))) => vec![output],
+                    Ok(Some(Response::Batch(ref outputs))) => outputs.iter().collect(),
+                    _ => Vec::new(),
+                };
+                calls.into_iter()
+                    .map(|call| seeded_fuzz(signed_send(&state, call, &outputs), &outputs))
+                    .collect()
+            };
+            if let Err(err) = record(&mut journal, calls) {
+                error!("cannot write to the journal: {}", err);
+                return failure(err);
+            }
+            Box::new(futures::done(response))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonrpc_core::{Id, Success, Version};
+
+    fn value(json: &str) -> Value {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn fuzz_is_recorded_with_its_seed() {
+        let call = Call::MethodCall(MethodCall {
+            jsonrpc: Some(Version::V2),
+            method: "dev_fuzz".to_string(),
+            params: Some(Params::Array(vec![value(r#"{ "targets": [], "blocks": 1 }"#)])),
+            id: Id::Num(1),
+        });
+        let output = Output::Success(Success {
+            jsonrpc: Some(Version::V2),
+            result: value(r#"{ "seed": 42, "blocks": 1 }"#),
+            id: Id::Num(1),
+        });
+
+        match seeded_fuzz(call, &[&output]) {
+            Call::MethodCall(call) => assert_eq!(call.params, Some(Params::Array(vec![
+                value(r#"{ "targets": [], "blocks": 1, "seed": 42 }"#),
+            ]))),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn only_reads_run_outside_of_the_journal() {
+        assert!(is_read_only("eth_getBalance"));
+        assert!(is_read_only("eth_call"));
+        assert!(is_read_only("debug_traceTransaction"));
+        assert!(!is_read_only("eth_sendRawTransaction"));
+        assert!(!is_read_only("eth_getFilterChanges"));
+        assert!(!is_read_only("eth_getWork"));
+        assert!(!is_read_only("dev_mine"));
+        assert!(!is_read_only("debug_setHead"));
+    }
+}
@@ -1,12 +1,12 @@
-use jsonrpc_core::{IoHandler};
+use jsonrpc_core::{MetaIoHandler, Params};
 use jsonrpc_http_server::*;
 use jsonrpc_macros::Trailing;
 
 use bigint::{U256, H256, M256, H2048, H64, Address, Gas};
 use std::net::SocketAddr;
-use std::sync::{Arc, Mutex};
-use std::sync::mpsc::{Sender};
-use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::sync::mpsc::{Sender, channel};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use sputnikvm::Patch;
 
 mod serves;
@@ -14,10 +14,45 @@ mod filter;
 mod util;
 mod serialize;
 mod solidity;
+mod apikey;
+mod journal;
+mod typed_data;
+mod fuzz;
+mod opcode;
+mod trace;
+mod profile;
+mod coverage;
+mod workers;
+mod cache;
+
+pub use self::apikey::ApiKeyConfig;
 
 use error::Error;
-use super::miner::MinerState;
+use client;
+use keystore::KeyStore;
+use serde_json::Value;
+use journal::{Journal, JournalEntry};
+use super::miner::{self, MinerEvent, MinerState};
 use self::serialize::*;
+use self::apikey::{ApiKeyManager, ApiKeyMiddleware, ApiKeyExtractor, RPCMeta};
+use self::journal::JournalMiddleware;
+use self::workers::Workers;
+
+/// Returned by `web3_clientVersion`.
+pub const CLIENT_VERSION: &str = concat!("sputnikvm-dev/", env!("CARGO_PKG_VERSION"));
+
+/// Version of the `eth` wire protocol whose messages the node mimics.
+pub const PROTOCOL_VERSION: usize = 63;
+
+/// Namespaces served by the RPC, before API key restrictions.
+pub const API_NAMESPACES: &[&str] = &["web3", "net", "eth", "personal", "debug", "dev", "admin", "trace"];
+
+/// Most accounts in a page of `debug_accountRange`, as in geth.
+pub const MAX_ACCOUNT_RANGE: usize = 256;
+
+/// Most blocks `trace_filter` re-executes in one call, as it holds the
+/// chain state meanwhile.
+pub const MAX_TRACE_FILTER_RANGE: usize = 1000;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(untagged)]
@@ -42,6 +77,17 @@ pub struct RPCLogFilter {
     pub topics: Option<Vec<Option<RPCTopicFilter>>>,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct RPCTraceFilter {
+    pub from_block: Option<String>,
+    pub to_block: Option<String>,
+    pub from_address: Option<Vec<Hex<Address>>>,
+    pub to_address: Option<Vec<Hex<Address>>>,
+    pub after: Option<usize>,
+    pub count: Option<usize>,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct RPCLog {
@@ -51,6 +97,7 @@ pub struct RPCLog {
     pub transaction_hash: Hex<H256>,
     pub block_hash: Hex<H256>,
     pub block_number: Hex<U256>,
+    pub address: Hex<Address>,
     pub data: Bytes,
     pub topics: Vec<Hex<H256>>,
 }
@@ -66,8 +113,12 @@ pub struct RPCReceipt {
     pub gas_used: Hex<Gas>,
     pub contract_address: Option<Hex<Address>>,
     pub logs: Vec<RPCLog>,
-    pub root: Hex<H256>,
-    pub status: usize,
+    /// Intermediate state root, only before Byzantium.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub root: Option<Hex<H256>>,
+    pub status: Hex<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub revert_reason: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -110,16 +161,30 @@ pub struct RPCTransaction {
     pub block_hash: Option<Hex<H256>>,
     pub block_number: Option<Hex<U256>>,
     pub transaction_index: Option<Hex<usize>>,
+
+    /// Extension of `eth_call` and `eth_estimateGas` returning the
+    /// execution trace together with the result.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trace: Option<bool>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct RPCTrace {
     pub gas: Hex<Gas>,
+    pub failed: bool,
     pub return_value: Bytes,
     pub struct_logs: Vec<RPCStep>,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct RPCTracedResult<T> {
+    pub result: T,
+    pub failed: bool,
+    pub trace: RPCTrace,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, Default)]
 #[serde(rename_all = "camelCase")]
 pub struct RPCTraceConfig {
@@ -149,8 +214,165 @@ pub struct RPCSourceMapConfig {
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
-pub struct RPCBlockTrace {
-    pub struct_logs: Vec<RPCStep>,
+pub struct RPCTransactionTrace {
+    pub tx_hash: Hex<H256>,
+    pub result: RPCTrace,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct RPCCallTrace {
+    pub action: RPCTraceAction,
+    pub result: Option<RPCTraceResult>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub subtraces: usize,
+    pub trace_address: Vec<usize>,
+    #[serde(rename = "type")]
+    pub type_: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub block_hash: Option<Hex<H256>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub block_number: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transaction_hash: Option<Hex<H256>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transaction_position: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum RPCTraceAction {
+    Call(RPCCallAction),
+    Create(RPCCreateAction),
+    Suicide(RPCSuicideAction),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct RPCCallAction {
+    pub call_type: String,
+    pub from: Hex<Address>,
+    pub to: Hex<Address>,
+    pub gas: Hex<Gas>,
+    pub input: Bytes,
+    pub value: Hex<U256>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct RPCCreateAction {
+    pub from: Hex<Address>,
+    pub gas: Hex<Gas>,
+    pub init: Bytes,
+    pub value: Hex<U256>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct RPCSuicideAction {
+    pub address: Hex<Address>,
+    pub refund_address: Hex<Address>,
+    pub balance: Hex<U256>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum RPCTraceResult {
+    Create(RPCCreateResult),
+    Call(RPCCallResult),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct RPCCallResult {
+    pub gas_used: Hex<Gas>,
+    pub output: Bytes,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct RPCCreateResult {
+    pub gas_used: Hex<Gas>,
+    pub code: Bytes,
+    pub address: Hex<Address>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct RPCVMTrace {
+    pub code: Bytes,
+    pub ops: Vec<RPCVMOperation>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct RPCVMOperation {
+    pub pc: usize,
+    pub cost: u64,
+    pub ex: Option<RPCVMExecuted>,
+    pub sub: Option<RPCVMTrace>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct RPCVMExecuted {
+    pub used: u64,
+    pub push: Vec<Hex<M256>>,
+    pub mem: Option<RPCMemoryDiff>,
+    pub store: Option<RPCStorageDiff>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct RPCMemoryDiff {
+    pub off: usize,
+    pub data: Bytes,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct RPCStorageDiff {
+    pub key: Hex<U256>,
+    pub val: Hex<M256>,
+}
+
+/// Change of a value in a Parity state diff: `"="` when unchanged, or
+/// the value born, died or changed.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum RPCDiff<T> {
+    #[serde(rename = "=")]
+    Same,
+    #[serde(rename = "+")]
+    Born(T),
+    #[serde(rename = "-")]
+    Died(T),
+    #[serde(rename = "*")]
+    Changed(RPCDiffChange<T>),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RPCDiffChange<T> {
+    pub from: T,
+    pub to: T,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct RPCAccountDiff {
+    pub balance: RPCDiff<Hex<U256>>,
+    pub nonce: RPCDiff<Hex<U256>>,
+    pub code: RPCDiff<Bytes>,
+    pub storage: BTreeMap<Hex<H256>, RPCDiff<Hex<H256>>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct RPCTraceResults {
+    pub output: Bytes,
+    pub trace: Vec<RPCCallTrace>,
+    pub vm_trace: Option<RPCVMTrace>,
+    pub state_diff: Option<BTreeMap<Hex<Address>, RPCAccountDiff>>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -160,7 +382,7 @@ pub struct RPCStep {
     pub error: String,
     pub gas: Hex<Gas>,
     pub gas_cost: Hex<Gas>,
-    pub op: u8,
+    pub op: String,
     pub pc: usize,
     pub opcode_pc: usize,
     pub code_hash: Hex<H256>,
@@ -172,10 +394,62 @@ pub struct RPCStep {
     pub storage: Option<HashMap<Hex<U256>, Hex<M256>>>,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct RPCGasReportEntry {
+    pub chain: String,
+    pub gas_used: Option<Hex<Gas>>,
+    pub success: bool,
+    pub output: Bytes,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct RPCGasProfile {
+    pub gas_used: Hex<Gas>,
+    pub opcodes: Vec<RPCOpcodeGas>,
+    pub frames: Vec<RPCFrameGas>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct RPCOpcodeGas {
+    pub op: String,
+    pub count: usize,
+    pub gas: Hex<Gas>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct RPCFrameGas {
+    pub depth: usize,
+    #[serde(rename = "type")]
+    pub type_: String,
+    pub address: Hex<Address>,
+    pub gas_used: Hex<Gas>,
+    pub own_gas: Hex<Gas>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct RPCInclusionProof {
+    pub block_hash: Hex<H256>,
+    pub block_number: Hex<U256>,
+    pub transaction_index: Hex<usize>,
+    pub root: Hex<H256>,
+    pub key: Bytes,
+    pub value: Bytes,
+    pub proof: Vec<Bytes>,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct RPCDump {
-    pub accounts: HashMap<Hex<Address>, RPCDumpAccount>,
+    pub accounts: BTreeMap<Hex<Address>, RPCDumpAccount>,
+    /// Accounts whose address is not known to the node, by hashed key.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub unknown_accounts: BTreeMap<Hex<H256>, RPCDumpAccount>,
     pub root: Hex<H256>,
 }
 
@@ -187,7 +461,258 @@ pub struct RPCDumpAccount {
     pub code_hash: Hex<H256>,
     pub nonce: Hex<U256>,
     pub root: Hex<H256>,
-    pub storage: HashMap<Hex<U256>, Hex<M256>>,
+    pub storage: BTreeMap<Hex<U256>, Hex<M256>>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub unknown_storage: BTreeMap<Hex<H256>, Hex<M256>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct RPCAccountRange {
+    pub accounts: BTreeMap<Hex<Address>, RPCDumpAccount>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub unknown_accounts: BTreeMap<Hex<H256>, RPCDumpAccount>,
+    pub root: Hex<H256>,
+    /// Hashed key to start the next page from, none after the last
+    /// account.
+    pub next: Option<Hex<H256>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RPCTypedField {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub type_: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct RPCTypedData {
+    pub types: HashMap<String, Vec<RPCTypedField>>,
+    pub primary_type: String,
+    pub domain: Value,
+    pub message: Value,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct RPCBisect {
+    pub call: RPCTransaction,
+    /// Return value making the condition true. If not given, any
+    /// successful call returning a non-zero value does.
+    pub expected: Option<Bytes>,
+    pub from_block: Option<String>,
+    pub to_block: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct RPCDevAccount {
+    pub address: Hex<Address>,
+    /// Only returned by nodes started with `--expose-private-keys`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub private_key: Option<Bytes>,
+    pub balance: Hex<U256>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct RPCWatchEvent {
+    pub name: String,
+    pub path: String,
+    pub address: Hex<Address>,
+    pub transaction_hash: Option<Hex<H256>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct RPCFuzzTarget {
+    pub address: Hex<Address>,
+    pub abi: Value,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct RPCFuzzConfig {
+    pub targets: Vec<RPCFuzzTarget>,
+    pub blocks: usize,
+    pub transactions_per_block: Option<usize>,
+    pub seed: Option<u64>,
+    pub senders: Option<Vec<Hex<Address>>>,
+    pub invariants: Option<Vec<RPCTransaction>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct RPCFuzzRevert {
+    pub transaction_hash: Hex<H256>,
+    pub to: Hex<Address>,
+    pub function: String,
+    pub input: Bytes,
+    pub reason: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct RPCFuzzViolation {
+    pub invariant: usize,
+    pub block_number: Hex<usize>,
+    pub output: Option<Bytes>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct RPCFuzzReport {
+    pub seed: u64,
+    pub blocks: usize,
+    pub transactions: usize,
+    pub reverts: Vec<RPCFuzzRevert>,
+    pub violations: Vec<RPCFuzzViolation>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct RPCGenesisAccount {
+    pub balance: Hex<U256>,
+    #[serde(default)]
+    pub nonce: Option<Hex<U256>>,
+    #[serde(default)]
+    pub code: Option<Bytes>,
+    #[serde(default)]
+    pub storage: HashMap<Hex<U256>, Hex<U256>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct RPCGenesis {
+    #[serde(default)]
+    pub timestamp: Option<Hex<U256>>,
+    #[serde(default)]
+    pub alloc: HashMap<Hex<Address>, RPCGenesisAccount>,
+}
+
+/// Geth-style replacement of an account for the duration of an
+/// `eth_call`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct RPCAccountOverride {
+    #[serde(default)]
+    pub balance: Option<Hex<U256>>,
+    #[serde(default)]
+    pub nonce: Option<Hex<U256>>,
+    #[serde(default)]
+    pub code: Option<Bytes>,
+    #[serde(default)]
+    pub state: Option<HashMap<Hex<U256>, Hex<U256>>>,
+    #[serde(default)]
+    pub state_diff: Option<HashMap<Hex<U256>, Hex<U256>>>,
+}
+
+pub type RPCStateOverrides = HashMap<Hex<Address>, RPCAccountOverride>;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct RPCVerifyReport {
+    pub roots: usize,
+    pub nodes: usize,
+    pub missing: Vec<Hex<H256>>,
+    pub corrupt: Vec<Hex<H256>>,
+    pub missing_code: Vec<Hex<H256>>,
+    pub first_broken_block: Option<Hex<usize>>,
+    pub repaired: bool,
+}
+
+/// Side chain of `dev_createFork` and `dev_extendFork`, by the number
+/// and hash of its last block.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct RPCSideChain {
+    pub id: Hex<usize>,
+    pub base: Hex<usize>,
+    pub number: Hex<usize>,
+    pub hash: Hex<H256>,
+    pub canonical: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct RPCSessionReport {
+    pub transactions: Vec<Hex<H256>>,
+    pub pending_transactions: Vec<Hex<H256>>,
+    pub gas_used: Hex<Gas>,
+    pub logs: Vec<RPCLog>,
+    pub contracts_created: Vec<Hex<Address>>,
+}
+
+/// VM rules of the selected chain.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct RPCPatchConfig {
+    pub code_deposit_limit: Option<usize>,
+    pub callstack_limit: usize,
+    pub memory_limit: usize,
+    pub has_delegate_call: bool,
+    pub has_static_call: bool,
+    pub has_revert: bool,
+    pub has_return_data: bool,
+    pub call_create_l64_after_gas: bool,
+    pub err_on_call_with_more_gas: bool,
+    pub force_code_deposit: bool,
+    pub precompiles: Vec<Hex<Address>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct RPCForkConfig {
+    pub url: String,
+    pub block: Hex<U256>,
+}
+
+/// Configuration of the node, for test frameworks to adapt to.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct RPCNodeConfig {
+    pub chain: String,
+    pub network_id: String,
+    pub chain_id: Option<Hex<u64>>,
+    pub patch: RPCPatchConfig,
+    pub block_gas_limit: Hex<Gas>,
+    pub block_reward: Hex<U256>,
+    /// Blocks are mined as soon as a transaction is received if
+    /// `instamine` is set, and at least every `mining_interval` seconds
+    /// if given. `dev_mine` mines a block in any case.
+    pub instamine: bool,
+    pub mining_interval: Option<u64>,
+    pub fork: Option<RPCForkConfig>,
+    pub selfdestruct: String,
+    pub fee_payer: Option<Hex<Address>>,
+    pub log_retention: Option<usize>,
+    pub freeze_depth: Option<usize>,
+    pub cheats: Vec<String>,
+}
+
+/// Metadata of the node, for tooling managing several of them.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct RPCNodeInfo {
+    pub client_version: String,
+    pub protocol_version: usize,
+    pub chain: String,
+    pub chain_id: Option<Hex<u64>>,
+    pub network_id: String,
+    pub genesis_hash: Hex<H256>,
+    pub head_number: Hex<usize>,
+    pub head_hash: Hex<H256>,
+    pub data_dir: Option<String>,
+    pub apis: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct RPCApiKeyUsage {
+    pub requests: usize,
+    pub rejected: usize,
+    pub rate_limited: usize,
 }
 
 build_rpc_trait! {
@@ -206,6 +731,8 @@ build_rpc_trait! {
 
         #[rpc(name = "eth_protocolVersion")]
         fn protocol_version(&self) -> Result<String, Error>;
+        #[rpc(name = "eth_chainId")]
+        fn chain_id(&self) -> Result<Hex<u64>, Error>;
         #[rpc(name = "eth_syncing")]
         fn is_syncing(&self) -> Result<bool, Error>;
         #[rpc(name = "eth_coinbase")]
@@ -214,6 +741,10 @@ build_rpc_trait! {
         fn is_mining(&self) -> Result<bool, Error>;
         #[rpc(name = "eth_hashrate")]
         fn hashrate(&self) -> Result<String, Error>;
+        #[rpc(name = "eth_getWork")]
+        fn work(&self) -> Result<Vec<String>, Error>;
+        #[rpc(name = "eth_submitWork")]
+        fn submit_work(&self, Hex<H64>, Hex<H256>, Hex<H256>) -> Result<bool, Error>;
         #[rpc(name = "eth_gasPrice")]
         fn gas_price(&self) -> Result<Hex<Gas>, Error>;
         #[rpc(name = "eth_accounts")]
@@ -238,15 +769,19 @@ build_rpc_trait! {
         fn code(&self, Hex<Address>, Trailing<String>) -> Result<Bytes, Error>;
         #[rpc(name = "eth_sign")]
         fn sign(&self, Hex<Address>, Bytes) -> Result<Bytes, Error>;
+        #[rpc(name = "eth_signTypedData")]
+        fn sign_typed_data(&self, Hex<Address>, RPCTypedData) -> Result<Bytes, Error>;
+        #[rpc(name = "eth_signTransaction")]
+        fn sign_transaction(&self, RPCTransaction) -> Result<Bytes, Error>;
         #[rpc(name = "eth_sendTransaction")]
-        fn send_transaction(&self, RPCTransaction) -> Result<Hex<H256>, Error>;
+        fn send_transaction(&self, RPCTransaction, Trailing<String>) -> Result<Hex<H256>, Error>;
         #[rpc(name = "eth_sendRawTransaction")]
-        fn send_raw_transaction(&self, Bytes) -> Result<Hex<H256>, Error>;
+        fn send_raw_transaction(&self, Bytes, Trailing<String>) -> Result<Hex<H256>, Error>;
 
         #[rpc(name = "eth_call")]
-        fn call(&self, RPCTransaction, Trailing<String>) -> Result<Bytes, Error>;
+        fn call(&self, RPCTransaction, Trailing<String>) -> Result<Either<Bytes, RPCTracedResult<Bytes>>, Error>;
         #[rpc(name = "eth_estimateGas")]
-        fn estimate_gas(&self, RPCTransaction, Trailing<String>) -> Result<Hex<Gas>, Error>;
+        fn estimate_gas(&self, RPCTransaction, Trailing<String>) -> Result<Either<Hex<Gas>, RPCTracedResult<Hex<Gas>>>, Error>;
 
         #[rpc(name = "eth_getBlockByHash")]
         fn block_by_hash(&self, Hex<H256>, bool) -> Result<Option<RPCBlock>, Error>;
@@ -254,6 +789,8 @@ build_rpc_trait! {
         fn block_by_number(&self, String, bool) -> Result<Option<RPCBlock>, Error>;
         #[rpc(name = "eth_getTransactionByHash")]
         fn transaction_by_hash(&self, Hex<H256>) -> Result<Option<RPCTransaction>, Error>;
+        #[rpc(name = "eth_getRawTransactionByHash")]
+        fn raw_transaction_by_hash(&self, Hex<H256>) -> Result<Option<Bytes>, Error>;
         #[rpc(name = "eth_getTransactionByBlockHashAndIndex")]
         fn transaction_by_block_hash_and_index(&self, Hex<H256>, Hex<U256>) -> Result<Option<RPCTransaction>, Error>;
         #[rpc(name = "eth_getTransactionByBlockNumberAndIndex")]
@@ -295,46 +832,250 @@ build_rpc_trait! {
     pub trait DebugRPC {
         #[rpc(name = "debug_getBlockRlp")]
         fn block_rlp(&self, usize) -> Result<Bytes, Error>;
+        #[rpc(name = "debug_getRawReceipts")]
+        fn raw_receipts(&self, usize) -> Result<Vec<Bytes>, Error>;
         #[rpc(name = "debug_traceTransaction")]
         fn trace_transaction(&self, Hex<H256>, Trailing<RPCTraceConfig>)
                              -> Result<RPCTrace, Error>;
         #[rpc(name = "debug_traceBlock")]
         fn trace_block(&self, Bytes, Trailing<RPCTraceConfig>)
-                       -> Result<RPCBlockTrace, Error>;
+                       -> Result<Vec<RPCTransactionTrace>, Error>;
         #[rpc(name = "debug_traceBlockByNumber")]
         fn trace_block_by_number(&self, usize, Trailing<RPCTraceConfig>)
-                                 -> Result<RPCBlockTrace, Error>;
+                                 -> Result<Vec<RPCTransactionTrace>, Error>;
         #[rpc(name = "debug_traceBlockByHash")]
         fn trace_block_by_hash(&self, Hex<H256>, Trailing<RPCTraceConfig>)
-                               -> Result<RPCBlockTrace, Error>;
+                               -> Result<Vec<RPCTransactionTrace>, Error>;
         #[rpc(name = "debug_traceBlockFromFile")]
         fn trace_block_from_file(&self, String, Trailing<RPCTraceConfig>)
-                                 -> Result<RPCBlockTrace, Error>;
+                                 -> Result<Vec<RPCTransactionTrace>, Error>;
         #[rpc(name = "debug_dumpBlock")]
         fn dump_block(&self, usize) -> Result<RPCDump, Error>;
+        #[rpc(name = "debug_setHead")]
+        fn set_head(&self, Hex<usize>) -> Result<bool, Error>;
+        #[rpc(name = "debug_insertBlock")]
+        fn insert_block(&self, Bytes) -> Result<Hex<H256>, Error>;
+        #[rpc(name = "debug_gasReport")]
+        fn gas_report(&self, RPCTransaction, Trailing<String>) -> Result<Vec<RPCGasReportEntry>, Error>;
+        #[rpc(name = "debug_coverage")]
+        fn coverage(&self, Hex<H256>) -> Result<BTreeMap<Hex<Address>, BTreeSet<usize>>, Error>;
+        #[rpc(name = "debug_gasProfile")]
+        fn gas_profile(&self, Hex<H256>) -> Result<RPCGasProfile, Error>;
+        #[rpc(name = "debug_getTransactionProof")]
+        fn transaction_proof(&self, Hex<H256>) -> Result<RPCInclusionProof, Error>;
+        #[rpc(name = "debug_getReceiptProof")]
+        fn receipt_proof(&self, Hex<H256>) -> Result<RPCInclusionProof, Error>;
     }
 }
 
-pub fn rpc_loop<P: 'static + Patch + Send>(
-    state: Arc<Mutex<MinerState>>, addr: &SocketAddr, channel: Sender<bool>
-) {
-    let rpc = serves::MinerEthereumRPC::<P>::new(state.clone(), channel);
+build_rpc_trait! {
+    pub trait PersonalRPC {
+        #[rpc(name = "personal_newAccount")]
+        fn new_account(&self, String) -> Result<Hex<Address>, Error>;
+        #[rpc(name = "personal_listAccounts")]
+        fn list_accounts(&self) -> Result<Vec<Hex<Address>>, Error>;
+        #[rpc(name = "personal_importRawKey")]
+        fn import_raw_key(&self, String, String) -> Result<Hex<Address>, Error>;
+        #[rpc(name = "personal_unlockAccount")]
+        fn unlock_account(&self, Hex<Address>, String, Trailing<u64>) -> Result<bool, Error>;
+        #[rpc(name = "personal_lockAccount")]
+        fn lock_account(&self, Hex<Address>) -> Result<bool, Error>;
+        #[rpc(name = "personal_sendTransaction")]
+        fn send_transaction(&self, RPCTransaction, String) -> Result<Hex<H256>, Error>;
+    }
+}
+
+build_rpc_trait! {
+    pub trait TraceRPC {
+        #[rpc(name = "trace_transaction")]
+        fn transaction(&self, Hex<H256>) -> Result<Vec<RPCCallTrace>, Error>;
+        #[rpc(name = "trace_replayTransaction")]
+        fn replay_transaction(&self, Hex<H256>, Vec<String>) -> Result<RPCTraceResults, Error>;
+        #[rpc(name = "trace_filter")]
+        fn filter(&self, RPCTraceFilter) -> Result<Vec<RPCCallTrace>, Error>;
+    }
+}
+
+build_rpc_trait! {
+    pub trait AdminRPC {
+        #[rpc(name = "admin_nodeInfo")]
+        fn node_info(&self) -> Result<RPCNodeInfo, Error>;
+    }
+}
+
+build_rpc_trait! {
+    pub trait DevRPC {
+        #[rpc(name = "dev_accounts")]
+        fn accounts(&self) -> Result<Vec<RPCDevAccount>, Error>;
+        #[rpc(name = "dev_impersonateAccount")]
+        fn impersonate_account(&self, Hex<Address>) -> Result<bool, Error>;
+        #[rpc(name = "dev_stopImpersonatingAccount")]
+        fn stop_impersonating_account(&self, Hex<Address>) -> Result<bool, Error>;
+        #[rpc(name = "dev_setBalance")]
+        fn set_balance(&self, Hex<Address>, Hex<U256>) -> Result<bool, Error>;
+        #[rpc(name = "dev_setNonce")]
+        fn set_nonce(&self, Hex<Address>, Hex<U256>) -> Result<bool, Error>;
+        #[rpc(name = "dev_setCode")]
+        fn set_code(&self, Hex<Address>, Bytes) -> Result<bool, Error>;
+        #[rpc(name = "dev_setStorageAt")]
+        fn set_storage_at(&self, Hex<Address>, Hex<U256>, Hex<U256>) -> Result<bool, Error>;
+        #[rpc(name = "dev_bisect")]
+        fn bisect(&self, RPCBisect) -> Result<Option<Hex<usize>>, Error>;
+        #[rpc(name = "dev_reset")]
+        fn reset(&self, Trailing<RPCGenesis>) -> Result<bool, Error>;
+        #[rpc(name = "dev_mine")]
+        fn mine(&self) -> Result<bool, Error>;
+        #[rpc(name = "dev_createFork")]
+        fn create_fork(&self, Hex<usize>) -> Result<RPCSideChain, Error>;
+        #[rpc(name = "dev_extendFork")]
+        fn extend_fork(&self, Hex<usize>, Trailing<Hex<usize>>) -> Result<RPCSideChain, Error>;
+        #[rpc(name = "dev_verifyDatabase")]
+        fn verify_database(&self, Trailing<bool>) -> Result<RPCVerifyReport, Error>;
+        #[rpc(name = "dev_clearForkCache")]
+        fn clear_fork_cache(&self) -> Result<bool, Error>;
+        #[rpc(name = "dev_backup")]
+        fn backup(&self, Trailing<bool>) -> Result<Bytes, Error>;
+        #[rpc(name = "dev_sessionReport")]
+        fn session_report(&self, String) -> Result<RPCSessionReport, Error>;
+        #[rpc(name = "dev_config")]
+        fn config(&self) -> Result<RPCNodeConfig, Error>;
+        #[rpc(name = "dev_watchEvents")]
+        fn watch_events(&self, Trailing<usize>) -> Result<Vec<RPCWatchEvent>, Error>;
+        #[rpc(name = "dev_fuzz")]
+        fn fuzz(&self, RPCFuzzConfig) -> Result<RPCFuzzReport, Error>;
+        #[rpc(name = "dev_apiKeyUsage")]
+        fn api_key_usage(&self) -> Result<HashMap<String, RPCApiKeyUsage>, Error>;
+    }
+}
+
+/// Methods `dev_config` reports as cheats: those of the `dev_`
+/// namespace, and the `debug_set*` methods rewriting the chain.
+fn is_cheat(method: &str) -> bool {
+    method.starts_with("dev_") || method.starts_with("debug_set")
+}
+
+fn rpc_handler<P: 'static + Patch + Send>(
+    state: Arc<RwLock<MinerState>>, channel: Sender<MinerEvent>,
+    api_keys: Arc<ApiKeyManager>, keystore: Arc<KeyStore>, journal: Option<Arc<Journal>>,
+    workers: Workers
+) -> MetaIoHandler<RPCMeta, JournalMiddleware> {
+    let rpc = serves::MinerEthereumRPC::<P>::new(state.clone(), channel.clone(), keystore.clone());
+    let call = serves::MinerEthereumRPC::<P>::new(state.clone(), channel.clone(), keystore.clone());
+    let personal = serves::MinerPersonalRPC::<P>::new(state.clone(), channel.clone(), keystore);
     let filter = serves::MinerFilterRPC::<P>::new(state.clone());
-    let debug = serves::MinerDebugRPC::<P>::new(state);
+    let debug = serves::MinerDebugRPC::<P>::new(state.clone());
+    let trace_call = serves::MinerDebugRPC::<P>::new(state.clone());
+    let account_range = serves::MinerDebugRPC::<P>::new(state.clone());
+    let admin = serves::MinerAdminRPC::<P>::new(state.clone());
+    let trace = serves::MinerTraceRPC::<P>::new(state.clone());
+    let cheats = Arc::new(RwLock::new(Vec::new()));
+    let dev = serves::MinerDevRPC::<P>::new(state.clone(), channel, api_keys.clone(), cheats.clone());
+
+    let mut io = MetaIoHandler::with_middleware(
+        JournalMiddleware::new(journal, state, ApiKeyMiddleware::new(api_keys)));
 
-    let mut io = IoHandler::default();
+    let delegates = vec![
+        workers.offload(rpc.to_delegate()),
+        workers.offload(filter.to_delegate()),
+        workers.offload(debug.to_delegate()),
+        workers.offload(personal.to_delegate()),
+        workers.offload(admin.to_delegate()),
+        workers.offload(trace.to_delegate()),
+        workers.offload(dev.to_delegate()),
+    ];
+    let mut registered = Vec::new();
+    for delegate in delegates {
+        registered.extend(delegate.keys().filter(|method| is_cheat(method)).cloned());
+        io.extend_with(delegate);
+    }
+    registered.sort();
+    *cheats.write().unwrap() = registered;
+
+    // The generated delegate only allows the last parameter to be
+    // optional, while `eth_call` takes state overrides after the block
+    // and `debug_traceCall` tracer options, and `debug_accountRange`
+    // takes two flags after its page size.
+    workers.add_method(&mut io, "eth_call", move |params: Params| call.call_params(params));
+    workers.add_method(&mut io, "debug_traceCall", move |params: Params| trace_call.trace_call_params(params));
+    workers.add_method(&mut io, "debug_accountRange", move |params: Params| account_range.account_range_params(params));
 
-    io.extend_with(rpc.to_delegate());
-    io.extend_with(filter.to_delegate());
-    io.extend_with(debug.to_delegate());
+    io
+}
+
+/// Re-apply the entries following the genesis of a journal, in order.
+/// Replayed inputs are recorded again if a journal is given.
+pub fn replay<P: 'static + Patch + Send>(
+    state: Arc<RwLock<MinerState>>, channel: Sender<MinerEvent>, keystore: KeyStore,
+    entries: &[JournalEntry], journal: Option<Arc<Journal>>
+) {
+    let io = rpc_handler::<P>(state.clone(), channel, Arc::new(ApiKeyManager::new(Vec::new())),
+                              Arc::new(keystore), journal.clone(), Workers::new(state.clone(), Some(1)));
 
-    let server = ServerBuilder::new(io)
-        .cors(DomainsValidation::AllowOnly(vec![
-            AccessControlAllowOrigin::Any,
-            AccessControlAllowOrigin::Null,
-        ]))
-        .start_http(addr)
-        .expect("Expect to build HTTP RPC server");
+    for entry in entries {
+        match entry {
+            &JournalEntry::Genesis { .. } => (),
+            &JournalEntry::Rpc { ref request } => {
+                io.handle_request_sync(&request.to_string(), RPCMeta::default());
+            },
+            &JournalEntry::Mine { timestamp } => {
+                let mut journal = journal.as_ref().map(|journal| journal.lock());
+                miner::mine_one_at::<P>(state.clone(), Address::default(), timestamp);
+                if let Some(ref mut journal) = journal {
+                    if let Err(err) = journal.append(entry) {
+                        error!("cannot record block mined at {} in the journal: {}", timestamp, err);
+                    }
+                }
+            },
+        }
+    }
+}
+
+/// Serves RPC calls in-process against a state, and over HTTP once
+/// `serve` is called. A node built with `new` is not mined, e.g. a
+/// chain loaded from an archive.
+pub struct LocalNode {
+    io: MetaIoHandler<RPCMeta, JournalMiddleware>,
+}
 
-    server.wait();
+impl LocalNode {
+    pub fn new<P: 'static + Patch + Send>(state: MinerState, keystore: KeyStore) -> Self {
+        let (sender, _) = channel::<MinerEvent>();
+        Self::with_channel::<P>(Arc::new(RwLock::new(state)), sender, Vec::new(), keystore, None, Some(1))
+    }
+
+    /// Serve calls against a state shared with a miner listening on
+    /// `channel`, on `workers` threads, or one per CPU if not given.
+    pub fn with_channel<P: 'static + Patch + Send>(
+        state: Arc<RwLock<MinerState>>, channel: Sender<MinerEvent>,
+        api_keys: Vec<ApiKeyConfig>, keystore: KeyStore, journal: Option<Arc<Journal>>,
+        workers: Option<usize>
+    ) -> Self {
+        LocalNode {
+            io: rpc_handler::<P>(state.clone(), channel, Arc::new(ApiKeyManager::new(api_keys)),
+                                 Arc::new(keystore), journal, Workers::new(state, workers)),
+        }
+    }
+
+    pub fn request(&self, method: &str, params: Value) -> Result<Value, Error> {
+        let request = client::encode_request(method, params)?;
+        match self.io.handle_request_sync(&request, RPCMeta::default()) {
+            Some(response) => client::parse_response(&response),
+            None => Err(Error::Internal("no response to a call")),
+        }
+    }
+
+    /// Serve calls over HTTP on `addr`, until the server stops. The
+    /// server runs on an event loop, and calls on the workers.
+    pub fn serve(self, addr: &SocketAddr) {
+        let server = ServerBuilder::new(self.io)
+            .meta_extractor(ApiKeyExtractor)
+            .cors(DomainsValidation::AllowOnly(vec![
+                AccessControlAllowOrigin::Any,
+                AccessControlAllowOrigin::Null,
+            ]))
+            .start_http(addr)
+            .expect("Expect to build HTTP RPC server");
+
+        server.wait();
+    }
 }
@@ -0,0 +1,118 @@
+/// Mnemonic of an opcode as reported in geth struct logs, such as
+/// `PUSH1` or `SSTORE`. Undefined opcodes are named by their value.
+pub fn opcode_name(op: u8) -> String {
+    let name = match op {
+        0x00 => "STOP",
+        0x01 => "ADD",
+        0x02 => "MUL",
+        0x03 => "SUB",
+        0x04 => "DIV",
+        0x05 => "SDIV",
+        0x06 => "MOD",
+        0x07 => "SMOD",
+        0x08 => "ADDMOD",
+        0x09 => "MULMOD",
+        0x0a => "EXP",
+        0x0b => "SIGNEXTEND",
+        0x10 => "LT",
+        0x11 => "GT",
+        0x12 => "SLT",
+        0x13 => "SGT",
+        0x14 => "EQ",
+        0x15 => "ISZERO",
+        0x16 => "AND",
+        0x17 => "OR",
+        0x18 => "XOR",
+        0x19 => "NOT",
+        0x1a => "BYTE",
+        0x20 => "SHA3",
+        0x30 => "ADDRESS",
+        0x31 => "BALANCE",
+        0x32 => "ORIGIN",
+        0x33 => "CALLER",
+        0x34 => "CALLVALUE",
+        0x35 => "CALLDATALOAD",
+        0x36 => "CALLDATASIZE",
+        0x37 => "CALLDATACOPY",
+        0x38 => "CODESIZE",
+        0x39 => "CODECOPY",
+        0x3a => "GASPRICE",
+        0x3b => "EXTCODESIZE",
+        0x3c => "EXTCODECOPY",
+        0x3d => "RETURNDATASIZE",
+        0x3e => "RETURNDATACOPY",
+        0x40 => "BLOCKHASH",
+        0x41 => "COINBASE",
+        0x42 => "TIMESTAMP",
+        0x43 => "NUMBER",
+        0x44 => "DIFFICULTY",
+        0x45 => "GASLIMIT",
+        0x50 => "POP",
+        0x51 => "MLOAD",
+        0x52 => "MSTORE",
+        0x53 => "MSTORE8",
+        0x54 => "SLOAD",
+        0x55 => "SSTORE",
+        0x56 => "JUMP",
+        0x57 => "JUMPI",
+        0x58 => "PC",
+        0x59 => "MSIZE",
+        0x5a => "GAS",
+        0x5b => "JUMPDEST",
+        0x60..=0x7f => return format!("PUSH{}", op - 0x5f),
+        0x80..=0x8f => return format!("DUP{}", op - 0x7f),
+        0x90..=0x9f => return format!("SWAP{}", op - 0x8f),
+        0xa0..=0xa4 => return format!("LOG{}", op - 0xa0),
+        0xf0 => "CREATE",
+        0xf1 => "CALL",
+        0xf2 => "CALLCODE",
+        0xf3 => "RETURN",
+        0xf4 => "DELEGATECALL",
+        0xfa => "STATICCALL",
+        0xfd => "REVERT",
+        0xfe => "INVALID",
+        0xff => "SELFDESTRUCT",
+        _ => return format!("opcode 0x{:x} not defined", op),
+    };
+    name.to_string()
+}
+
+/// Number of stack items an opcode leaves changed on top of the stack,
+/// as listed in Parity VM traces. Duplications and swaps list every
+/// item they touch.
+pub fn stack_pushed(op: u8) -> usize {
+    match op {
+        0x01..=0x0b | 0x10..=0x1a | 0x20 | 0x30..=0x36 | 0x38 | 0x3a | 0x3b | 0x3d |
+        0x40..=0x45 | 0x51 | 0x54 | 0x58..=0x5a | 0x60..=0x7f | 0xf0..=0xf2 | 0xf4 | 0xfa => 1,
+        0x80..=0x8f => (op - 0x7f) as usize + 1,
+        0x90..=0x9f => (op - 0x8f) as usize + 1,
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_of_opcodes() {
+        assert_eq!(opcode_name(0x00), "STOP");
+        assert_eq!(opcode_name(0x60), "PUSH1");
+        assert_eq!(opcode_name(0x7f), "PUSH32");
+        assert_eq!(opcode_name(0x80), "DUP1");
+        assert_eq!(opcode_name(0x9f), "SWAP16");
+        assert_eq!(opcode_name(0xa2), "LOG2");
+        assert_eq!(opcode_name(0x55), "SSTORE");
+        assert_eq!(opcode_name(0x0c), "opcode 0xc not defined");
+    }
+
+    #[test]
+    fn pushed_stack_items() {
+        assert_eq!(stack_pushed(0x01), 1);
+        assert_eq!(stack_pushed(0x50), 0);
+        assert_eq!(stack_pushed(0x55), 0);
+        assert_eq!(stack_pushed(0x80), 2);
+        assert_eq!(stack_pushed(0x91), 3);
+        assert_eq!(stack_pushed(0xf1), 1);
+    }
+}
@@ -0,0 +1,118 @@
+use super::{RPCGasProfile, RPCOpcodeGas, RPCFrameGas};
+use super::serialize::*;
+use super::opcode::opcode_name;
+use miner::{Tracer, StepContext, FrameContext, ReturnContext};
+
+use bigint::Gas;
+use std::collections::HashMap;
+
+/// Frame being profiled.
+struct Frame {
+    /// Index of the frame in the report.
+    index: usize,
+    gas: Gas,
+    /// Opcode that last ran with the gas left before it.
+    pending: Option<(u8, Gas)>,
+    /// Gas used by callees since the pending opcode.
+    callees: Gas,
+}
+
+/// Tracer adding up the gas of each opcode and each call frame. The
+/// gas of an opcode invoking a callee only counts what the opcode costs
+/// itself, while frames also report their gas including callees.
+#[derive(Default)]
+pub struct GasProfiler {
+    frames: Vec<Frame>,
+    opcodes: HashMap<u8, (usize, Gas)>,
+    report: Vec<RPCFrameGas>,
+}
+
+impl GasProfiler {
+    /// Charge the pending opcode of the current frame, now that `gas`
+    /// is left.
+    fn settle(&mut self, gas: Gas) {
+        let (opcode, cost) = match self.frames.last_mut() {
+            Some(frame) => match frame.pending.take() {
+                Some((opcode, before)) => {
+                    let spent = if before > gas { before - gas } else { Gas::zero() };
+                    let cost = if spent > frame.callees { spent - frame.callees } else { Gas::zero() };
+                    frame.callees = Gas::zero();
+                    (opcode, cost)
+                },
+                None => return,
+            },
+            None => return,
+        };
+
+        let index = self.frames.last().unwrap().index;
+        self.report[index].own_gas.0 = self.report[index].own_gas.0 + cost;
+        let entry = self.opcodes.entry(opcode).or_insert((0, Gas::zero()));
+        entry.0 += 1;
+        entry.1 = entry.1 + cost;
+    }
+
+    fn enter(&mut self, frame: &FrameContext, type_: &str) {
+        self.report.push(RPCFrameGas {
+            depth: frame.depth,
+            type_: type_.to_string(),
+            address: Hex(frame.address),
+            gas_used: Hex(Gas::zero()),
+            own_gas: Hex(Gas::zero()),
+        });
+        self.frames.push(Frame {
+            index: self.report.len() - 1,
+            gas: frame.gas,
+            pending: None,
+            callees: Gas::zero(),
+        });
+    }
+
+    /// Report of the profiled transaction, opcodes costing the most
+    /// gas first.
+    pub fn into_report(self, gas_used: Gas) -> RPCGasProfile {
+        let mut opcodes: Vec<RPCOpcodeGas> = self.opcodes.into_iter().map(|(opcode, (count, gas))| RPCOpcodeGas {
+            op: opcode_name(opcode),
+            count,
+            gas: Hex(gas),
+        }).collect();
+        opcodes.sort_by(|a, b| b.gas.0.cmp(&a.gas.0).then(a.op.cmp(&b.op)));
+
+        RPCGasProfile {
+            gas_used: Hex(gas_used),
+            opcodes,
+            frames: self.report,
+        }
+    }
+}
+
+impl Tracer for GasProfiler {
+    fn on_step(&mut self, step: &StepContext) {
+        self.settle(step.gas);
+        if let Some(frame) = self.frames.last_mut() {
+            frame.pending = Some((step.opcode, step.gas));
+        }
+    }
+
+    fn on_call(&mut self, frame: &FrameContext) {
+        self.enter(frame, "call");
+    }
+
+    fn on_create(&mut self, frame: &FrameContext) {
+        self.enter(frame, "create");
+    }
+
+    fn on_return(&mut self, frame: &ReturnContext) {
+        let left = match self.frames.last() {
+            Some(current) if current.gas > frame.gas_used => current.gas - frame.gas_used,
+            _ => Gas::zero(),
+        };
+        self.settle(left);
+
+        if let Some(current) = self.frames.pop() {
+            self.report[current.index].gas_used = Hex(frame.gas_used);
+        }
+        if let Some(parent) = self.frames.last_mut() {
+            parent.callees = parent.callees + frame.gas_used;
+        }
+    }
+}
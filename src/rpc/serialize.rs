@@ -1,18 +1,25 @@
 use serde::{Serialize, Serializer, Deserializer, Deserialize, de};
+use std::any::Any;
 use std::fmt::{self, LowerHex};
 use std::marker::PhantomData;
 use std::str::FromStr;
+use bigint::Address;
 use hexutil::*;
+use checksum;
 
-#[derive(Debug, Hash, Clone, Eq, PartialEq)]
+#[derive(Debug, Hash, Clone, Eq, PartialEq, Ord, PartialOrd)]
 pub struct Hex<T>(pub T);
 #[derive(Debug, Clone)]
 pub struct Bytes(pub Vec<u8>);
 
-impl<T: LowerHex> Serialize for Hex<T> {
+impl<T: LowerHex + Any> Serialize for Hex<T> {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
         where S: Serializer
     {
+        if let Some(address) = (&self.0 as &Any).downcast_ref::<Address>() {
+            return serializer.serialize_str(&checksum::format_address(address));
+        }
+
         let value = format!("0x{:x}", self.0);
         if &value == "0x" {
             serializer.serialize_str("0x0")
@@ -34,7 +41,7 @@ struct HexVisitor<T> {
     _marker: PhantomData<T>,
 }
 
-impl<'de, T: FromStr> de::Visitor<'de> for HexVisitor<T> {
+impl<'de, T: FromStr + Any> de::Visitor<'de> for HexVisitor<T> {
     type Value = Hex<T>;
 
     fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
@@ -44,8 +51,17 @@ impl<'de, T: FromStr> de::Visitor<'de> for HexVisitor<T> {
     fn visit_str<E>(self, s: &str) -> Result<Self::Value, E>
         where E: de::Error
     {
-        match T::from_str(s) {
-            Ok(s) => Ok(Hex(s)),
+        match T::from_str(&s.to_lowercase()) {
+            Ok(value) => {
+                if let Some(address) = (&value as &Any).downcast_ref::<Address>() {
+                    if checksum::is_strict_checksum() && !checksum::is_valid_checksum(s, address) {
+                        return Err(de::Error::custom(format!(
+                            "invalid EIP-55 checksum for address {}, expected {}",
+                            s, checksum::to_checksum(address))));
+                    }
+                }
+                Ok(Hex(value))
+            },
             Err(_) => Err(de::Error::invalid_value(de::Unexpected::Str(s), &self)),
         }
     }
@@ -70,7 +86,7 @@ impl<'de> de::Visitor<'de> for BytesVisitor {
     }
 }
 
-impl<'de, T: FromStr> Deserialize<'de> for Hex<T> {
+impl<'de, T: FromStr + Any> Deserialize<'de> for Hex<T> {
     fn deserialize<D>(deserializer: D) -> Result<Hex<T>, D::Error>
         where D: Deserializer<'de>
     {
@@ -1,28 +1,39 @@
-use super::{EthereumRPC, FilterRPC, DebugRPC, Either, RPCTransaction, RPCTrace, RPCBlock, RPCLog, RPCReceipt, RPCLogFilter, RPCBlockTrace, RPCDump, RPCDumpAccount, RPCTraceConfig};
+use super::{CLIENT_VERSION, PROTOCOL_VERSION, API_NAMESPACES, MAX_ACCOUNT_RANGE, MAX_TRACE_FILTER_RANGE, EthereumRPC, FilterRPC, DebugRPC, PersonalRPC, AdminRPC, TraceRPC, DevRPC, RPCNodeInfo, RPCCallTrace, RPCTraceResults, RPCTraceFilter, Either, RPCApiKeyUsage, RPCSessionReport, RPCDevAccount, RPCGenesis, RPCNodeConfig, RPCStateOverrides, RPCVerifyReport, RPCSideChain, RPCWatchEvent, RPCFuzzConfig, RPCFuzzReport, RPCBisect, RPCTypedData, RPCTracedResult, RPCTransaction, RPCTrace, RPCBlock, RPCLog, RPCReceipt, RPCLogFilter, RPCTransactionTrace, RPCGasReportEntry, RPCGasProfile, RPCDump, RPCAccountRange, RPCInclusionProof, RPCTraceConfig};
 use super::util::*;
+use super::typed_data::typed_data_hash;
+use super::fuzz;
+use super::profile::GasProfiler;
+use super::coverage::CoverageTracer;
+use super::trace::{ParityTrace, trace_parity_transaction, matches_addresses};
 use super::filter::*;
 use super::serialize::*;
+use super::apikey::ApiKeyManager;
+use super::cache::ReadCache;
 
 use error::Error;
-use miner::MinerState;
+use keystore::{self, KeyStore};
+use miner::{self, MinerEvent, MinerState};
 
 use rlp::{self, UntrustedRlp};
-use bigint::{M256, U256, H256, Address, Gas};
-use block::{HeaderHash, Block, Account, FromKey, Transaction};
-use trie::{Database, DatabaseGuard, FixedSecureTrie};
-use sputnikvm::{SeqTransactionVM, VM, HeaderParams, Patch};
-use sputnikvm_stateful::MemoryStateful;
+use bigint::{M256, U256, H256, H64, Address, Gas};
+use block::{HeaderHash, Block, Account, FromKey, Transaction, RlpHash};
+use sputnikvm::{SeqTransactionVM, VM, VMStatus, HeaderParams, Patch};
 use std::str::FromStr;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, RwLock};
 use std::sync::mpsc::{Sender};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::marker::PhantomData;
+use std::time::Duration;
 
+use jsonrpc_core::{self, Params};
 use jsonrpc_macros::Trailing;
+use serde_json::{self, Value};
 
 pub struct MinerEthereumRPC<P: Patch + Send> {
-    state: Arc<Mutex<MinerState>>,
-    channel: Sender<bool>,
+    state: Arc<RwLock<MinerState>>,
+    channel: Sender<MinerEvent>,
+    keystore: Arc<KeyStore>,
+    cache: ReadCache,
     _patch: PhantomData<P>,
 }
 
@@ -32,26 +43,123 @@ pub struct MinerFilterRPC<P: Patch + Send> {
 }
 
 pub struct MinerDebugRPC<P: Patch + Send> {
-    state: Arc<Mutex<MinerState>>,
+    state: Arc<RwLock<MinerState>>,
+    _patch: PhantomData<P>,
+}
+
+pub struct MinerPersonalRPC<P: Patch + Send> {
+    state: Arc<RwLock<MinerState>>,
+    channel: Sender<MinerEvent>,
+    keystore: Arc<KeyStore>,
+    _patch: PhantomData<P>,
+}
+
+pub struct MinerAdminRPC<P: Patch + Send> {
+    state: Arc<RwLock<MinerState>>,
+    _patch: PhantomData<P>,
+}
+
+pub struct MinerTraceRPC<P: Patch + Send> {
+    state: Arc<RwLock<MinerState>>,
+    _patch: PhantomData<P>,
+}
+
+pub struct MinerDevRPC<P: Patch + Send> {
+    state: Arc<RwLock<MinerState>>,
+    channel: Sender<MinerEvent>,
+    api_keys: Arc<ApiKeyManager>,
+    /// Cheat methods registered with this one, filled in once the
+    /// handler is built.
+    cheats: Arc<RwLock<Vec<String>>>,
     _patch: PhantomData<P>,
 }
 
 unsafe impl<P: Patch + Send> Sync for MinerEthereumRPC<P> { }
 unsafe impl<P: Patch + Send> Sync for MinerFilterRPC<P> { }
 unsafe impl<P: Patch + Send> Sync for MinerDebugRPC<P> { }
+unsafe impl<P: Patch + Send> Sync for MinerPersonalRPC<P> { }
+unsafe impl<P: Patch + Send> Sync for MinerAdminRPC<P> { }
+unsafe impl<P: Patch + Send> Sync for MinerTraceRPC<P> { }
+unsafe impl<P: Patch + Send> Sync for MinerDevRPC<P> { }
 
 impl<P: Patch + Send> MinerEthereumRPC<P> {
-    pub fn new(state: Arc<Mutex<MinerState>>, channel: Sender<bool>) -> Self {
+    pub fn new(state: Arc<RwLock<MinerState>>, channel: Sender<MinerEvent>, keystore: Arc<KeyStore>) -> Self {
+        let cache = ReadCache::new(state.read().unwrap().rpc_cache());
         MinerEthereumRPC {
             channel,
             state,
+            keystore,
+            cache,
             _patch: PhantomData,
         }
     }
 }
 
+impl<P: 'static + Patch + Send> MinerEthereumRPC<P> {
+    /// Raw handler of `eth_call`, taking optional state overrides as
+    /// third parameter.
+    pub fn call_params(&self, params: Params) -> Result<Value, jsonrpc_core::Error> {
+        let invalid = || -> jsonrpc_core::Error { Error::InvalidParams.into() };
+        let mut values: Vec<Value> = params.parse()?;
+        if values.is_empty() || values.len() > 3 {
+            return Err(invalid());
+        }
+
+        let overrides: Option<RPCStateOverrides> = if values.len() == 3 {
+            Some(serde_json::from_value(values.pop().unwrap()).map_err(|_| invalid())?)
+        } else {
+            None
+        };
+        let block: Option<String> = if values.len() == 2 {
+            serde_json::from_value(values.pop().unwrap()).map_err(|_| invalid())?
+        } else {
+            None
+        };
+        let transaction: RPCTransaction = serde_json::from_value(values.pop().unwrap())
+            .map_err(|_| invalid())?;
+
+        match self.call_with_overrides(transaction, block, overrides) {
+            Ok(result) => Ok(serde_json::to_value(result).unwrap()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn call_with_overrides(
+        &self, transaction: RPCTransaction, block: Option<String>, overrides: Option<RPCStateOverrides>
+    ) -> Result<Either<Bytes, RPCTracedResult<Bytes>>, Error> {
+        let state = self.state.read().unwrap();
+
+        let (mut stateful, header, last_hashes) = call_state::<P>(&state, block)?;
+
+        let trace = transaction.trace.unwrap_or(false);
+        let valid = to_valid_transaction::<P>(&state, transaction, &stateful)?;
+
+        if trace {
+            if overrides.is_some() {
+                return Err(Error::InvalidParams);
+            }
+            let (steps, vm) = trace_valid_transaction::<P>(
+                &state, &mut stateful, valid, &header, &last_hashes, &RPCTraceConfig::default())?;
+            return Ok(Either::Right(to_rpc_traced_result(Bytes(vm.out().into()), steps, &vm)));
+        }
+
+        let vm: SeqTransactionVM<P> = match overrides {
+            Some(overrides) => miner::call_with_overrides::<P>(
+                state.fork(), state.account_cache(), &mut stateful, &from_state_overrides(overrides), valid,
+                &header, &last_hashes)?,
+            None => miner::call::<P>(state.fork(), state.account_cache(), &mut stateful, valid, &header, &last_hashes)?,
+        };
+        check_memory_limit(&vm)?;
+
+        match vm.status() {
+            VMStatus::ExitedOk => Ok(Either::Left(Bytes(vm.out().into()))),
+            _ => Err(Error::ExecutionError(vm.out().into())),
+        }
+    }
+}
+
 impl<P: Patch + Send> MinerFilterRPC<P> {
-    pub fn new(state: Arc<Mutex<MinerState>>) -> Self {
+    pub fn new(state: Arc<RwLock<MinerState>>) -> Self {
         MinerFilterRPC {
             filter: Mutex::new(FilterManager::new(state)),
             _patch: PhantomData,
@@ -60,7 +168,7 @@ impl<P: Patch + Send> MinerFilterRPC<P> {
 }
 
 impl<P: Patch + Send> MinerDebugRPC<P> {
-    pub fn new(state: Arc<Mutex<MinerState>>) -> Self {
+    pub fn new(state: Arc<RwLock<MinerState>>) -> Self {
         MinerDebugRPC {
             state,
             _patch: PhantomData,
@@ -68,9 +176,153 @@ impl<P: Patch + Send> MinerDebugRPC<P> {
     }
 }
 
+impl<P: 'static + Patch + Send> MinerDebugRPC<P> {
+    /// Raw handler of `debug_traceCall`, whose block and tracer options
+    /// are both optional.
+    pub fn trace_call_params(&self, params: Params) -> Result<Value, jsonrpc_core::Error> {
+        let invalid = || -> jsonrpc_core::Error { Error::InvalidParams.into() };
+        let mut values: Vec<Value> = params.parse()?;
+        if values.is_empty() || values.len() > 3 {
+            return Err(invalid());
+        }
+
+        let config: Option<RPCTraceConfig> = if values.len() == 3 {
+            serde_json::from_value(values.pop().unwrap()).map_err(|_| invalid())?
+        } else {
+            None
+        };
+        let block: Option<String> = if values.len() == 2 {
+            serde_json::from_value(values.pop().unwrap()).map_err(|_| invalid())?
+        } else {
+            None
+        };
+        let transaction: RPCTransaction = serde_json::from_value(values.pop().unwrap())
+            .map_err(|_| invalid())?;
+
+        match self.trace_call(transaction, block, config.unwrap_or(RPCTraceConfig::default())) {
+            Ok(result) => Ok(serde_json::to_value(result).unwrap()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn trace_call(
+        &self, transaction: RPCTransaction, block: Option<String>, config: RPCTraceConfig
+    ) -> Result<RPCTrace, Error> {
+        let state = self.state.read().unwrap();
+
+        let (mut stateful, header, last_hashes) = call_state::<P>(&state, block)?;
+        let valid = to_valid_transaction::<P>(&state, transaction, &stateful)?;
+        let (steps, vm) = trace_valid_transaction::<P>(&state, &mut stateful, valid, &header, &last_hashes, &config)?;
+
+        Ok(to_rpc_trace(steps, &vm))
+    }
+
+    /// Raw handler of `debug_accountRange`, whose `nocode` and
+    /// `nostorage` flags are both optional.
+    pub fn account_range_params(&self, params: Params) -> Result<Value, jsonrpc_core::Error> {
+        let invalid = || -> jsonrpc_core::Error { Error::InvalidParams.into() };
+        let mut values: Vec<Value> = params.parse()?;
+        if values.len() < 3 || values.len() > 5 {
+            return Err(invalid());
+        }
+
+        let mut flags = [false; 2];
+        for i in (0..values.len() - 3).rev() {
+            flags[i] = serde_json::from_value(values.pop().unwrap()).map_err(|_| invalid())?;
+        }
+        let max_results: usize = serde_json::from_value(values.pop().unwrap()).map_err(|_| invalid())?;
+        let start: Bytes = serde_json::from_value(values.pop().unwrap()).map_err(|_| invalid())?;
+        let block: String = serde_json::from_value(values.pop().unwrap()).map_err(|_| invalid())?;
+
+        match self.account_range(block, start, max_results, flags[0], flags[1]) {
+            Ok(result) => Ok(serde_json::to_value(result).unwrap()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn account_range(
+        &self, block: String, start: Bytes, max_results: usize, nocode: bool, nostorage: bool
+    ) -> Result<RPCAccountRange, Error> {
+        let state = self.state.read().unwrap();
+
+        let number = from_block_number(&state, Some(block))?;
+        let root = state.state_root_at(number);
+        let limit = if max_results == 0 || max_results > MAX_ACCOUNT_RANGE {
+            MAX_ACCOUNT_RANGE
+        } else {
+            max_results
+        };
+        let (dump, next) = miner::dump_range(&state, root, &start.0, limit, !nocode, !nostorage)?;
+
+        Ok(RPCAccountRange {
+            accounts: dump.accounts.into_iter().map(|(address, account)| (Hex(address), to_rpc_dump_account(account))).collect(),
+            unknown_accounts: dump.unknown_accounts.into_iter().map(|(key, account)| (Hex(key), to_rpc_dump_account(account))).collect(),
+            root: Hex(root),
+            next: next.map(Hex),
+        })
+    }
+}
+
+impl<P: Patch + Send> MinerPersonalRPC<P> {
+    pub fn new(state: Arc<RwLock<MinerState>>, channel: Sender<MinerEvent>, keystore: Arc<KeyStore>) -> Self {
+        MinerPersonalRPC {
+            state,
+            channel,
+            keystore,
+            _patch: PhantomData,
+        }
+    }
+}
+
+impl<P: Patch + Send> MinerAdminRPC<P> {
+    pub fn new(state: Arc<RwLock<MinerState>>) -> Self {
+        MinerAdminRPC {
+            state,
+            _patch: PhantomData,
+        }
+    }
+}
+
+impl<P: Patch + Send> MinerTraceRPC<P> {
+    pub fn new(state: Arc<RwLock<MinerState>>) -> Self {
+        MinerTraceRPC {
+            state,
+            _patch: PhantomData,
+        }
+    }
+
+    /// Parity-style traces of a mined transaction, replayed on the
+    /// state left by the transactions before it in its block.
+    fn replay(&self, state: &MinerState, hash: H256) -> Result<ParityTrace, Error> {
+        let block = state.get_block_by_hash(state.get_transaction_block_hash_by_hash(hash)?)?;
+        let last_hashes = state.get_last_256_block_hashes_by_number(block.header.number.as_usize());
+
+        let index = block.transactions.iter()
+            .position(|transaction| transaction.rlp_hash() == hash)
+            .ok_or(Error::NotFound)?;
+        let mut stateful = state_before_transaction::<P>(state, &block, index, &last_hashes)?;
+        trace_parity_transaction::<P>(state, &mut stateful, &block, index, &last_hashes)
+    }
+}
+
+impl<P: Patch + Send> MinerDevRPC<P> {
+    pub fn new(
+        state: Arc<RwLock<MinerState>>, channel: Sender<MinerEvent>, api_keys: Arc<ApiKeyManager>,
+        cheats: Arc<RwLock<Vec<String>>>
+    ) -> Self {
+        MinerDevRPC {
+            state,
+            channel,
+            api_keys,
+            cheats,
+            _patch: PhantomData,
+        }
+    }
+}
+
 impl<P: 'static + Patch + Send> EthereumRPC for MinerEthereumRPC<P> {
     fn client_version(&self) -> Result<String, Error> {
-        Ok("sputnikvm-dev/v0.1".to_string())
+        Ok(CLIENT_VERSION.to_string())
     }
 
     fn sha3(&self, data: Bytes) -> Result<Hex<H256>, Error> {
@@ -79,11 +331,22 @@ impl<P: 'static + Patch + Send> EthereumRPC for MinerEthereumRPC<P> {
     }
 
     fn network_id(&self) -> Result<String, Error> {
-        Ok(format!("{}", 1))
+        let state = self.state.read().unwrap();
+
+        Ok(format!("{}", state.network_id()))
+    }
+
+    fn chain_id(&self) -> Result<Hex<u64>, Error> {
+        let state = self.state.read().unwrap();
+
+        Ok(Hex(state.chain_id().unwrap_or(state.network_id())))
     }
 
+    // There is no peer-to-peer networking: the node listens for RPC
+    // connections only, never has peers, and its chain is complete as
+    // soon as the RPC is served.
     fn is_listening(&self) -> Result<bool, Error> {
-        Ok(false)
+        Ok(true)
     }
 
     fn peer_count(&self) -> Result<Hex<usize>, Error> {
@@ -91,7 +354,7 @@ impl<P: 'static + Patch + Send> EthereumRPC for MinerEthereumRPC<P> {
     }
 
     fn protocol_version(&self) -> Result<String, Error> {
-        Ok(format!("{}", 63))
+        Ok(format!("{}", PROTOCOL_VERSION))
     }
 
     fn is_syncing(&self) -> Result<bool, Error> {
@@ -110,34 +373,63 @@ impl<P: 'static + Patch + Send> EthereumRPC for MinerEthereumRPC<P> {
         Ok(format!("{}", 0))
     }
 
+    fn work(&self) -> Result<Vec<String>, Error> {
+        let state = self.state.read().unwrap();
+
+        let work = miner::work_package(&state);
+        Ok(vec![
+            format!("0x{:x}", work.pow_hash),
+            format!("0x{:x}", work.seed_hash),
+            format!("0x{:x}", work.boundary),
+            format!("0x{:x}", work.number),
+        ])
+    }
+
+    fn submit_work(&self, nonce: Hex<H64>, pow_hash: Hex<H256>, _mix_digest: Hex<H256>) -> Result<bool, Error> {
+        let state = self.state.read().unwrap();
+
+        if miner::verify_work(&state, pow_hash.0, nonce.0) {
+            self.channel.send(MinerEvent::Mine);
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
     fn gas_price(&self) -> Result<Hex<Gas>, Error> {
-        Ok(Hex(Gas::zero()))
+        let state = self.state.read().unwrap();
+
+        Ok(Hex(state.gas_price_oracle().suggest(&state)))
     }
 
     fn accounts(&self) -> Result<Vec<Hex<Address>>, Error> {
-        let state = self.state.lock().unwrap();
+        let state = self.state.read().unwrap();
 
-        Ok(state.accounts().iter().map(|key| {
+        let mut addresses: Vec<Address> = state.accounts().iter().map(|key| {
             Address::from_secret_key(key).unwrap()
-        }).map(|address| {
-            Hex(address)
-        }).collect())
+        }).collect();
+        for address in self.keystore.addresses()? {
+            if !addresses.contains(&address) {
+                addresses.push(address);
+            }
+        }
+
+        Ok(addresses.into_iter().map(|address| Hex(address)).collect())
     }
 
     fn block_number(&self) -> Result<Hex<usize>, Error> {
-        let state = self.state.lock().unwrap();
+        let state = self.state.read().unwrap();
 
         Ok(Hex(state.block_height()))
     }
 
     fn balance(&self, address: Hex<Address>, block: Trailing<String>) -> Result<Hex<U256>, Error> {
-        let state = self.state.lock().unwrap();
+        let state = self.state.read().unwrap();
 
         let block = from_block_number(&state, block)?;
 
-        let block = state.get_block_by_number(block);
         let stateful = state.stateful();
-        let trie = stateful.state_of(block.header.state_root);
+        let trie = stateful.state_of(state.state_root_at(block));
 
         let account: Option<Account> = trie.get(&address.0);
         match account {
@@ -145,41 +437,51 @@ impl<P: 'static + Patch + Send> EthereumRPC for MinerEthereumRPC<P> {
                 Ok(Hex(account.balance))
             },
             None => {
-                Ok(Hex(U256::zero()))
+                let remote = match state.fork() {
+                    Some(fork) => fork.account(address.0)?,
+                    None => None,
+                };
+                Ok(Hex(remote.map(|account| account.balance).unwrap_or(U256::zero())))
             },
         }
     }
 
     fn storage_at(&self, address: Hex<Address>, index: Hex<U256>, block: Trailing<String>) -> Result<Hex<M256>, Error> {
-        let state = self.state.lock().unwrap();
+        let state = self.state.read().unwrap();
 
         let block = from_block_number(&state, block)?;
 
-        let block = state.get_block_by_number(block);
         let stateful = state.stateful();
-        let trie = stateful.state_of(block.header.state_root);
+        let trie = stateful.state_of(state.state_root_at(block));
 
         let account: Option<Account> = trie.get(&address.0);
         match account {
             Some(account) => {
                 let storage = stateful.storage_state_of(account.storage_root);
-                let value = storage.get(&H256::from(index.0)).unwrap_or(M256::zero());
-                Ok(Hex(value))
+                let value = match (storage.get(&H256::from(index.0)), state.fork()) {
+                    (Some(value), _) => Some(value),
+                    (None, Some(fork)) => fork.storage(address.0, index.0)?,
+                    (None, None) => None,
+                };
+                Ok(Hex(value.unwrap_or(M256::zero())))
             },
             None => {
-                Ok(Hex(M256::zero()))
+                let remote = match state.fork() {
+                    Some(fork) => fork.storage(address.0, index.0)?,
+                    None => None,
+                };
+                Ok(Hex(remote.unwrap_or(M256::zero())))
             },
         }
     }
 
     fn transaction_count(&self, address: Hex<Address>, block: Trailing<String>) -> Result<Hex<U256>, Error> {
-        let state = self.state.lock().unwrap();
+        let state = self.state.read().unwrap();
 
         let block = from_block_number(&state, block)?;
 
-        let block = state.get_block_by_number(block);
         let stateful = state.stateful();
-        let trie = stateful.state_of(block.header.state_root);
+        let trie = stateful.state_of(state.state_root_at(block));
 
         let account: Option<Account> = trie.get(&address.0);
         match account {
@@ -187,13 +489,17 @@ impl<P: 'static + Patch + Send> EthereumRPC for MinerEthereumRPC<P> {
                 Ok(Hex(account.nonce))
             },
             None => {
-                Ok(Hex(U256::zero()))
+                let remote = match state.fork() {
+                    Some(fork) => fork.account(address.0)?,
+                    None => None,
+                };
+                Ok(Hex(remote.map(|account| account.nonce).unwrap_or(U256::zero())))
             },
         }
     }
 
     fn block_transaction_count_by_hash(&self, block: Hex<H256>) -> Result<Option<Hex<usize>>, Error> {
-        let state = self.state.lock().unwrap();
+        let state = self.state.read().unwrap();
 
         let block = match state.get_block_by_hash(block.0) {
             Ok(val) => val,
@@ -205,8 +511,11 @@ impl<P: 'static + Patch + Send> EthereumRPC for MinerEthereumRPC<P> {
     }
 
     fn block_transaction_count_by_number(&self, number: String) -> Result<Option<Hex<usize>>, Error> {
-        let state = self.state.lock().unwrap();
+        let state = self.state.read().unwrap();
 
+        if number == "pending" {
+            return Ok(Some(Hex(state.pending_transactions().len())));
+        }
         let number = match from_block_number(&state, number) {
             Ok(val) => val,
             Err(Error::NotFound) => return Ok(None),
@@ -218,7 +527,7 @@ impl<P: 'static + Patch + Send> EthereumRPC for MinerEthereumRPC<P> {
     }
 
     fn block_uncles_count_by_hash(&self, block: Hex<H256>) -> Result<Option<Hex<usize>>, Error> {
-        let state = self.state.lock().unwrap();
+        let state = self.state.read().unwrap();
 
         let block = match state.get_block_by_hash(block.0) {
             Ok(val) => val,
@@ -230,8 +539,11 @@ impl<P: 'static + Patch + Send> EthereumRPC for MinerEthereumRPC<P> {
     }
 
     fn block_uncles_count_by_number(&self, number: String) -> Result<Option<Hex<usize>>, Error> {
-        let state = self.state.lock().unwrap();
+        let state = self.state.read().unwrap();
 
+        if number == "pending" {
+            return Ok(Some(Hex(0)));
+        }
         let number = match from_block_number(&state, number) {
             Ok(val) => val,
             Err(Error::NotFound) => return Ok(None),
@@ -243,13 +555,12 @@ impl<P: 'static + Patch + Send> EthereumRPC for MinerEthereumRPC<P> {
     }
 
     fn code(&self, address: Hex<Address>, block: Trailing<String>) -> Result<Bytes, Error> {
-        let state = self.state.lock().unwrap();
+        let state = self.state.read().unwrap();
 
         let block = from_block_number(&state, block)?;
 
-        let block = state.get_block_by_number(block);
         let stateful = state.stateful();
-        let trie = stateful.state_of(block.header.state_root);
+        let trie = stateful.state_of(state.state_root_at(block));
 
         let account: Option<Account> = trie.get(&address.0);
         match account {
@@ -257,149 +568,166 @@ impl<P: 'static + Patch + Send> EthereumRPC for MinerEthereumRPC<P> {
                 Ok(Bytes(stateful.code(account.code_hash).unwrap()))
             },
             None => {
-                Ok(Bytes(Vec::new()))
+                let remote = match state.fork() {
+                    Some(fork) => fork.account(address.0)?,
+                    None => None,
+                };
+                Ok(Bytes(remote.map(|account| account.code).unwrap_or(Vec::new())))
             },
         }
     }
 
     fn sign(&self, address: Hex<Address>, message: Bytes) -> Result<Bytes, Error> {
-        use sha3::{Digest, Keccak256};
-        use secp256k1::{SECP256K1, Message};
+        let state = self.state.read().unwrap();
 
-        let state = self.state.lock().unwrap();
+        let hash = signed_message_hash(&message.0);
+        let secret_key = state.secret_key(address.0).ok_or(Error::NotFound)?;
 
-        let mut signing_message = Vec::new();
+        Ok(Bytes(sign_hash(&secret_key, hash)?))
+    }
 
-        signing_message.extend("Ethereum Signed Message:\n".as_bytes().iter().cloned());
-        signing_message.extend(format!("0x{:x}\n", message.0.len()).as_bytes().iter().cloned());
-        signing_message.extend(message.0.iter().cloned());
+    fn sign_typed_data(&self, address: Hex<Address>, typed_data: RPCTypedData) -> Result<Bytes, Error> {
+        let state = self.state.read().unwrap();
 
-        let hash = H256::from(Keccak256::digest(&signing_message).as_slice());
-        let secret_key = {
-            let mut secret_key = None;
-            for key in state.accounts() {
-                if Address::from_secret_key(&key)? == address.0 {
-                    secret_key = Some(key);
-                }
-            }
-            match secret_key {
-                Some(val) => val,
-                None => return Err(Error::NotFound),
-            }
-        };
-        let sign = SECP256K1.sign_recoverable(&Message::from_slice(&hash).unwrap(), &secret_key)?;
-        let (rec, sign) = sign.serialize_compact(&SECP256K1);
-        let mut ret = Vec::new();
-        ret.push(rec.to_i32() as u8);
-        ret.extend(sign.as_ref());
+        let hash = typed_data_hash(&typed_data)?;
+        let secret_key = state.secret_key(address.0).ok_or(Error::NotFound)?;
 
-        Ok(Bytes(ret))
+        Ok(Bytes(sign_hash(&secret_key, hash)?))
     }
 
-    fn send_transaction(&self, transaction: RPCTransaction) -> Result<Hex<H256>, Error> {
-        let mut state = self.state.lock().unwrap();
+    fn sign_transaction(&self, transaction: RPCTransaction) -> Result<Bytes, Error> {
+        let state = self.state.read().unwrap();
+
+        let transaction = to_signed_transaction::<P>(&state, transaction)?;
 
+        Ok(Bytes(rlp::encode(&transaction).to_vec()))
+    }
 
-        let (_valid, transaction) = {
-            let stateful = state.stateful();
-            let transaction = to_signed_transaction(&state, transaction, &stateful)?;
-            let valid = stateful.to_valid::<P>(&transaction.clone())?;
+    fn send_transaction(&self, transaction: RPCTransaction, session: Trailing<String>) -> Result<Hex<H256>, Error> {
+        let mut state = self.state.write().unwrap();
 
-            (valid, transaction)
+        let impersonated = match transaction.from {
+            Some(ref from) if state.is_impersonated(from.0) => Some(from.0),
+            _ => None,
         };
 
-        let hash = state.append_pending_transaction(transaction);
-        self.channel.send(true);
+        let hash = match impersonated {
+            Some(from) => {
+                let transaction = to_signed_transaction_with_key::<P>(
+                    &state, transaction, &miner::impersonation_key(from))?;
+
+                state.insert_impersonated_transaction(transaction.rlp_hash(), from);
+                state.append_pending_transaction(transaction)?
+            },
+            None => {
+                let transaction = to_signed_transaction::<P>(&state, transaction)?;
+                miner::validate_pooled::<P>(&state, &transaction)?;
+
+                state.append_pending_transaction(transaction)?
+            },
+        };
+        if let Some(session) = session.into() {
+            state.tag_transaction(hash, session);
+        }
+        self.channel.send(MinerEvent::Transaction);
         Ok(Hex(hash))
     }
 
-    fn send_raw_transaction(&self, data: Bytes) -> Result<Hex<H256>, Error> {
-        let mut state = self.state.lock().unwrap();
+    fn send_raw_transaction(&self, data: Bytes, session: Trailing<String>) -> Result<Hex<H256>, Error> {
+        let mut state = self.state.write().unwrap();
 
         let rlp = UntrustedRlp::new(&data.0);
         let transaction: Transaction = rlp.as_val()?;
 
-        {
-            let stateful = state.stateful();
-            stateful.to_valid::<P>(&transaction.clone())?;
-        }
+        miner::validate_pooled::<P>(&state, &transaction)?;
 
-        let hash = state.append_pending_transaction(transaction);
-        self.channel.send(true);
+        let hash = state.append_pending_transaction(transaction)?;
+        state.insert_hash_raw(hash, data.0);
+        if let Some(session) = session.into() {
+            state.tag_transaction(hash, session);
+        }
+        self.channel.send(MinerEvent::Transaction);
         Ok(Hex(hash))
     }
 
-    fn call(&self, transaction: RPCTransaction, block: Trailing<String>) -> Result<Bytes, Error> {
-        let state = self.state.lock().unwrap();
-
-        let stateful = state.stateful();
-
-        let valid = to_valid_transaction::<P>(&state, transaction, &stateful)?;
-        let block = from_block_number(&state, block)?;
-
-        let block = state.get_block_by_number(block);
-
-        let vm: SeqTransactionVM<P> = stateful.call(
-            valid, &HeaderParams::from(&block.header),
-            &state.get_last_256_block_hashes());
-
-        Ok(Bytes(vm.out().into()))
+    fn call(&self, transaction: RPCTransaction, block: Trailing<String>) -> Result<Either<Bytes, RPCTracedResult<Bytes>>, Error> {
+        self.call_with_overrides(transaction, block.into(), None)
     }
 
-    fn estimate_gas(&self, transaction: RPCTransaction, block: Trailing<String>) -> Result<Hex<Gas>, Error> {
-        let state = self.state.lock().unwrap();
+    fn estimate_gas(&self, transaction: RPCTransaction, block: Trailing<String>) -> Result<Either<Hex<Gas>, RPCTracedResult<Hex<Gas>>>, Error> {
+        let state = self.state.read().unwrap();
 
-        let stateful = state.stateful();
+        let block: Option<String> = block.into();
+        let (mut stateful, header, last_hashes) = if block == Some("pending".to_string()) {
+            let (stateful, header) = pending_state::<P>(&state)?;
+            (stateful, header, state.get_last_256_block_hashes_by_number(state.block_height() + 1))
+        } else {
+            let number = from_block_number(&state, block)?;
+            let block = state.get_block_by_number(number);
+            (state.stateful_at(state.state_root_at(number)), HeaderParams::from(&block.header),
+             state.get_last_256_block_hashes_by_number(number))
+        };
 
+        let trace = transaction.trace.unwrap_or(false);
+        let cap = match transaction.gas {
+            Some(gas) => gas.0,
+            None => miner::block_gas_limit(),
+        };
         let valid = to_valid_transaction::<P>(&state, transaction, &stateful)?;
-        let block = from_block_number(&state, block)?;
 
-        let block = state.get_block_by_number(block);
+        if trace {
+            let (steps, vm) = trace_valid_transaction::<P>(
+                &state, &mut stateful, valid, &header, &last_hashes, &RPCTraceConfig::default())?;
+            return Ok(Either::Right(to_rpc_traced_result(Hex(vm.used_gas()), steps, &vm)));
+        }
 
-        let vm: SeqTransactionVM<P> = stateful.call(
-            valid, &HeaderParams::from(&block.header),
-            &state.get_last_256_block_hashes());
+        let gas = estimate_gas::<P>(&state, &mut stateful, valid, &header, &last_hashes, cap)?;
 
-        Ok(Hex(vm.used_gas()))
+        Ok(Either::Left(Hex(gas)))
     }
 
     fn block_by_hash(&self, hash: Hex<H256>, full: bool) -> Result<Option<RPCBlock>, Error> {
-        let state = self.state.lock().unwrap();
-
-        let block = match state.get_block_by_hash(hash.0) {
-            Ok(val) => val,
-            Err(Error::NotFound) => return Ok(None),
-            Err(e) => return Err(e.into()),
-        };
-        let total = match state.get_total_header_by_hash(hash.0) {
-            Ok(val) => val,
-            Err(Error::NotFound) => return Ok(None),
-            Err(e) => return Err(e.into()),
-        };
-
-        Ok(Some(to_rpc_block(block, total, full)))
+        let state = self.state.read().unwrap();
+
+        self.cache.block(&state, hash.0, full, || {
+            let block = match state.get_block_by_hash(hash.0) {
+                Ok(val) => val,
+                Err(Error::NotFound) => return Ok(None),
+                Err(e) => return Err(e.into()),
+            };
+            let total = match state.get_total_header_by_hash(hash.0) {
+                Ok(val) => val,
+                Err(Error::NotFound) => return Ok(None),
+                Err(e) => return Err(e.into()),
+            };
+
+            Ok(Some(to_rpc_block(&state, block, total, full)))
+        })
     }
 
     fn block_by_number(&self, number: String, full: bool) -> Result<Option<RPCBlock>, Error> {
-        let state = self.state.lock().unwrap();
+        let state = self.state.read().unwrap();
 
         let number = match from_block_number(&state, Some(number)) {
             Ok(val) => val,
             Err(Error::NotFound) => return Ok(None),
             Err(e) => return Err(e.into()),
         };
-        let block = state.get_block_by_number(number);
-        let total = match state.get_total_header_by_hash(block.header.header_hash()) {
-            Ok(val) => val,
-            Err(Error::NotFound) => return Ok(None),
-            Err(e) => return Err(e.into()),
-        };
-
-        Ok(Some(to_rpc_block(block, total, full)))
+        let hash = state.get_canonical_hash(number);
+        self.cache.block(&state, hash, full, || {
+            let block = state.get_block_by_number(number);
+            let total = match state.get_total_header_by_hash(hash) {
+                Ok(val) => val,
+                Err(Error::NotFound) => return Ok(None),
+                Err(e) => return Err(e.into()),
+            };
+
+            Ok(Some(to_rpc_block(&state, block, total, full)))
+        })
     }
 
     fn transaction_by_hash(&self, hash: Hex<H256>) -> Result<Option<RPCTransaction>, Error> {
-        let state = self.state.lock().unwrap();
+        let state = self.state.read().unwrap();
 
         let transaction = match state.get_transaction_by_hash(hash.0) {
             Ok(val) => val,
@@ -411,117 +739,108 @@ impl<P: 'static + Patch + Send> EthereumRPC for MinerEthereumRPC<P> {
             Err(_) => None,
         };
 
-        Ok(Some(to_rpc_transaction(transaction, block.as_ref())))
+        Ok(Some(to_rpc_transaction(&state, transaction, block.as_ref())))
     }
 
-    fn transaction_by_block_hash_and_index(&self, block_hash: Hex<H256>, index: Hex<U256>) -> Result<Option<RPCTransaction>, Error> {
-        let state = self.state.lock().unwrap();
+    fn raw_transaction_by_hash(&self, hash: Hex<H256>) -> Result<Option<Bytes>, Error> {
+        let state = self.state.read().unwrap();
 
-        let block = match state.get_block_by_hash(block_hash.0) {
+        let transaction = match state.get_transaction_by_hash(hash.0) {
             Ok(val) => val,
             Err(Error::NotFound) => return Ok(None),
             Err(e) => return Err(e.into()),
         };
-        if index.0.as_usize() >= block.transactions.len() {
-            return Ok(None);
-        }
-        let transaction = block.transactions[index.0.as_usize()].clone();
-
-        Ok(Some(to_rpc_transaction(transaction, Some(&block))))
+        Ok(Some(Bytes(state.get_hash_raw(hash.0).unwrap_or_else(|| rlp::encode(&transaction).to_vec()))))
     }
 
-    fn transaction_by_block_number_and_index(&self, number: String, index: Hex<U256>) -> Result<Option<RPCTransaction>, Error> {
-        let state = self.state.lock().unwrap();
+    fn transaction_by_block_hash_and_index(&self, block_hash: Hex<H256>, index: Hex<U256>) -> Result<Option<RPCTransaction>, Error> {
+        let state = self.state.read().unwrap();
 
-        let number = match from_block_number(&state, Some(number)) {
+        let transaction = match state.get_transaction_by_block_hash_and_index(block_hash.0, index.0) {
             Ok(val) => val,
             Err(Error::NotFound) => return Ok(None),
             Err(e) => return Err(e.into()),
         };
-        let block = state.get_block_by_number(number);
-        if index.0.as_usize() >= block.transactions.len() {
-            return Ok(None);
-        }
-        let transaction = block.transactions[index.0.as_usize()].clone();
+        let block = state.get_block_by_hash(block_hash.0)?;
 
-        Ok(Some(to_rpc_transaction(transaction, Some(&block))))
+        Ok(Some(to_rpc_transaction(&state, transaction, Some(&block))))
     }
 
-    fn transaction_receipt(&self, hash: Hex<H256>) -> Result<Option<RPCReceipt>, Error> {
-        let state = self.state.lock().unwrap();
+    fn transaction_by_block_number_and_index(&self, number: String, index: Hex<U256>) -> Result<Option<RPCTransaction>, Error> {
+        let state = self.state.read().unwrap();
 
-        let receipt = match state.get_receipt_by_transaction_hash(hash.0) {
+        let number = match from_block_number(&state, Some(number)) {
             Ok(val) => val,
             Err(Error::NotFound) => return Ok(None),
             Err(e) => return Err(e.into()),
         };
-
-        let transaction = match state.get_transaction_by_hash(hash.0) {
+        let block = state.get_block_by_number(number);
+        let transaction = match state.get_transaction_by_block_hash_and_index(block.header.header_hash(), index.0) {
             Ok(val) => val,
             Err(Error::NotFound) => return Ok(None),
             Err(e) => return Err(e.into()),
         };
-        let block = match state.get_transaction_block_hash_by_hash(hash.0) {
-            Ok(val) => state.get_block_by_hash(val).ok(),
-            Err(Error::NotFound) => return Ok(None),
-            Err(e) => return Err(e.into()),
-        };
 
-        if block.is_none() {
-            Ok(None)
-        } else {
-            Ok(Some(to_rpc_receipt(&state, receipt, &transaction, &block.unwrap())?))
-        }
+        Ok(Some(to_rpc_transaction(&state, transaction, Some(&block))))
+    }
+
+    fn transaction_receipt(&self, hash: Hex<H256>) -> Result<Option<RPCReceipt>, Error> {
+        let state = self.state.read().unwrap();
+
+        self.cache.receipt(&state, hash.0, || {
+            let receipt = match state.get_receipt_by_transaction_hash(hash.0) {
+                Ok(val) => val,
+                Err(Error::NotFound) => return Ok(None),
+                Err(e) => return Err(e.into()),
+            };
+
+            let transaction = match state.get_transaction_by_hash(hash.0) {
+                Ok(val) => val,
+                Err(Error::NotFound) => return Ok(None),
+                Err(e) => return Err(e.into()),
+            };
+            let block = match state.get_transaction_block_hash_by_hash(hash.0) {
+                Ok(val) => state.get_block_by_hash(val).ok(),
+                Err(Error::NotFound) => return Ok(None),
+                Err(e) => return Err(e.into()),
+            };
+
+            if block.is_none() {
+                Ok(None)
+            } else {
+                Ok(Some(to_rpc_receipt::<P>(&state, receipt, &transaction, &block.unwrap())?))
+            }
+        })
     }
 
     fn uncle_by_block_hash_and_index(&self, block_hash: Hex<H256>, index: Hex<U256>) -> Result<Option<RPCBlock>, Error> {
-        let state = self.state.lock().unwrap();
+        let state = self.state.read().unwrap();
 
-        let index = index.0.as_usize();
-        let block_hash = block_hash.0;
-        let block = match state.get_block_by_hash(block_hash) {
-            Ok(val) => val,
-            Err(Error::NotFound) => return Ok(None),
-            Err(e) => return Err(e.into()),
-        };
-        let uncle_hash = block.ommers[index].header_hash();
-        let uncle = match state.get_block_by_hash(uncle_hash) {
-            Ok(val) => val,
-            Err(Error::NotFound) => return Ok(None),
-            Err(e) => return Err(e.into()),
-        };
-        let total = match state.get_total_header_by_hash(uncle_hash) {
+        let uncle = match state.get_ommer_by_block_hash_and_index(block_hash.0, index.0) {
             Ok(val) => val,
             Err(Error::NotFound) => return Ok(None),
             Err(e) => return Err(e.into()),
         };
 
-        Ok(Some(to_rpc_block(uncle, total, false)))
+        Ok(Some(to_rpc_uncle(&state, &uncle)))
     }
 
     fn uncle_by_block_number_and_index(&self, block_number: String, index: Hex<U256>) -> Result<Option<RPCBlock>, Error> {
-        let state = self.state.lock().unwrap();
+        let state = self.state.read().unwrap();
 
         let block_number = match from_block_number(&state, Some(block_number)) {
             Ok(val) => val,
             Err(Error::NotFound) => return Ok(None),
             Err(e) => return Err(e.into()),
         };
-        let index = index.0.as_usize();
-        let block = state.get_block_by_number(block_number);
-        let uncle_hash = block.ommers[index].header_hash();
-        let uncle = match state.get_block_by_hash(uncle_hash) {
-            Ok(val) => val,
-            Err(Error::NotFound) => return Ok(None),
-            Err(e) => return Err(e.into()),
-        };
-        let total = match state.get_total_header_by_hash(uncle_hash) {
+        let block_hash = state.get_block_by_number(block_number).header.header_hash();
+        let uncle = match state.get_ommer_by_block_hash_and_index(block_hash, index.0) {
             Ok(val) => val,
             Err(Error::NotFound) => return Ok(None),
             Err(e) => return Err(e.into()),
         };
 
-        Ok(Some(to_rpc_block(uncle, total, false)))
+        Ok(Some(to_rpc_uncle(&state, &uncle)))
     }
 
     fn compilers(&self) -> Result<Vec<String>, Error> {
@@ -529,12 +848,15 @@ impl<P: 'static + Patch + Send> EthereumRPC for MinerEthereumRPC<P> {
     }
 
     fn logs(&self, log: RPCLogFilter) -> Result<Vec<RPCLog>, Error> {
-        let state = self.state.lock().unwrap();
+        let query = {
+            let state = self.state.read().unwrap();
 
-        match from_log_filter(&state, log) {
-            Ok(filter) => Ok(get_logs(&state, filter)?),
-            Err(_) => Ok(Vec::new()),
-        }
+            match from_log_filter(&state, log) {
+                Ok(filter) => log_query(&state, filter)?,
+                Err(_) => return Ok(Vec::new()),
+            }
+        };
+        query.run()
     }
 }
 
@@ -575,129 +897,77 @@ impl<P: 'static + Patch + Send> FilterRPC for MinerFilterRPC<P> {
 
 impl<P: 'static + Patch + Send> DebugRPC for MinerDebugRPC<P> {
     fn block_rlp(&self, number: usize) -> Result<Bytes, Error> {
-        let state = self.state.lock().unwrap();
+        let state = self.state.read().unwrap();
+
+        if number > state.block_height() {
+            return Err(Error::NotFound);
+        }
+
+        let block = state.get_block_by_number(number);
+        Ok(Bytes(state.get_hash_raw(block.header.header_hash()).unwrap_or_else(|| rlp::encode(&block).to_vec())))
+    }
+
+    fn raw_receipts(&self, number: usize) -> Result<Vec<Bytes>, Error> {
+        let state = self.state.read().unwrap();
 
         if number > state.block_height() {
             return Err(Error::NotFound);
         }
 
         let block = state.get_block_by_number(number);
-        Ok(Bytes(rlp::encode(&block).to_vec()))
+        let mut receipts = Vec::new();
+        for transaction in &block.transactions {
+            receipts.push(state.get_receipt_by_transaction_hash(transaction.rlp_hash())?);
+        }
+        Ok(miner::consensus_receipts::<P>(&state, &block.transactions, &receipts).iter()
+           .map(|receipt| Bytes(rlp::encode(receipt).to_vec())).collect())
     }
 
     fn trace_transaction(&self, hash: Hex<H256>, config: Trailing<RPCTraceConfig>) -> Result<RPCTrace, Error> {
         let config = config.unwrap_or(RPCTraceConfig::default());
-        let state = self.state.lock().unwrap();
+        let state = self.state.read().unwrap();
 
         let transaction = state.get_transaction_by_hash(hash.0)?;
         let block = state.get_block_by_hash(state.get_transaction_block_hash_by_hash(hash.0)?)?;
-        let last_block = state.get_block_by_number(if block.header.number == U256::zero() { 0 } else { block.header.number.as_usize() - 1 });
         let last_hashes = state.get_last_256_block_hashes_by_number(block.header.number.as_usize());
 
-        let mut stateful: MemoryStateful<'static> = state.stateful_at(last_block.header.state_root);
-        for other_transaction in &block.transactions {
-            if other_transaction != &transaction {
-                let valid = stateful.to_valid::<P>(&transaction.clone())?;
-                let _: SeqTransactionVM<P> =
-                    stateful.execute::<_, P>(valid, &HeaderParams::from(&block.header), &last_hashes);
-            } else {
-                break;
-            }
-        }
-
-        let (steps, vm) = replay_transaction::<P>(&stateful, transaction, &block, &last_hashes, &config)?;
+        let index = block.transactions.iter()
+            .position(|other_transaction| other_transaction.rlp_hash() == hash.0)
+            .ok_or(Error::NotFound)?;
+        let mut stateful = state_before_transaction::<P>(&state, &block, index, &last_hashes)?;
+        let (steps, vm) = replay_transaction::<P>(&state, &mut stateful, transaction, &block, &last_hashes, &config)?;
 
-        let gas = Hex(vm.used_gas());
-        let return_value = Bytes(vm.out().into());
-
-        Ok(RPCTrace {
-            gas, return_value,
-            struct_logs: steps,
-        })
+        Ok(to_rpc_trace(steps, &vm))
     }
 
-    fn trace_block(&self, block_rlp: Bytes, config: Trailing<RPCTraceConfig>) -> Result<RPCBlockTrace, Error> {
+    fn trace_block(&self, block_rlp: Bytes, config: Trailing<RPCTraceConfig>) -> Result<Vec<RPCTransactionTrace>, Error> {
         let config = config.unwrap_or(RPCTraceConfig::default());
-        let state = self.state.lock().unwrap();
+        let state = self.state.read().unwrap();
         let block: Block = UntrustedRlp::new(&block_rlp.0).as_val()?;
-        let last_block = state.get_block_by_number(if block.header.number == U256::zero() { 0 } else { block.header.number.as_usize() - 1 });
-        let last_hashes = state.get_last_256_block_hashes_by_number(block.header.number.as_usize());
-
-        let mut stateful: MemoryStateful<'static> = state.stateful_at(last_block.header.state_root);
-        let mut steps = Vec::new();
-        for transaction in block.transactions.clone() {
-            let (mut local_steps, vm) = replay_transaction::<P>(&stateful, transaction,
-                                                                &block, &last_hashes,
-                                                                &config)?;
-            steps.append(&mut local_steps);
-            let mut accounts = Vec::new();
-            for account in vm.accounts() {
-                accounts.push(account.clone());
-            }
-            stateful.transit(&accounts);
-        }
 
-        Ok(RPCBlockTrace {
-            struct_logs: steps
-        })
+        trace_block::<P>(&state, &block, &config)
     }
 
-    fn trace_block_by_number(&self, number: usize, config: Trailing<RPCTraceConfig>) -> Result<RPCBlockTrace, Error> {
+    fn trace_block_by_number(&self, number: usize, config: Trailing<RPCTraceConfig>) -> Result<Vec<RPCTransactionTrace>, Error> {
         let config = config.unwrap_or(RPCTraceConfig::default());
-        let state = self.state.lock().unwrap();
+        let state = self.state.read().unwrap();
         if number > state.block_height() {
             return Err(Error::NotFound);
         }
         let block: Block = state.get_block_by_number(number);
-        let last_block = state.get_block_by_number(if block.header.number == U256::zero() { 0 } else { block.header.number.as_usize() - 1 });
-        let last_hashes = state.get_last_256_block_hashes_by_number(block.header.number.as_usize());
 
-        let mut stateful: MemoryStateful<'static> = state.stateful_at(last_block.header.state_root);
-        let mut steps = Vec::new();
-        for transaction in block.transactions.clone() {
-            let (mut local_steps, vm) = replay_transaction::<P>(&stateful, transaction,
-                                                                &block, &last_hashes,
-                                                                &config)?;
-            steps.append(&mut local_steps);
-            let mut accounts = Vec::new();
-            for account in vm.accounts() {
-                accounts.push(account.clone());
-            }
-            stateful.transit(&accounts);
-        }
-
-        Ok(RPCBlockTrace {
-            struct_logs: steps
-        })
+        trace_block::<P>(&state, &block, &config)
     }
 
-    fn trace_block_by_hash(&self, hash: Hex<H256>, config: Trailing<RPCTraceConfig>) -> Result<RPCBlockTrace, Error> {
+    fn trace_block_by_hash(&self, hash: Hex<H256>, config: Trailing<RPCTraceConfig>) -> Result<Vec<RPCTransactionTrace>, Error> {
         let config = config.unwrap_or(RPCTraceConfig::default());
-        let state = self.state.lock().unwrap();
+        let state = self.state.read().unwrap();
         let block: Block = state.get_block_by_hash(hash.0)?;
-        let last_block = state.get_block_by_number(if block.header.number == U256::zero() { 0 } else { block.header.number.as_usize() - 1 });
-        let last_hashes = state.get_last_256_block_hashes_by_number(block.header.number.as_usize());
 
-        let mut stateful: MemoryStateful<'static> = state.stateful_at(last_block.header.state_root);
-        let mut steps = Vec::new();
-        for transaction in block.transactions.clone() {
-            let (mut local_steps, vm) = replay_transaction::<P>(&stateful, transaction,
-                                                                &block, &last_hashes,
-                                                                &config)?;
-            steps.append(&mut local_steps);
-            let mut accounts = Vec::new();
-            for account in vm.accounts() {
-                accounts.push(account.clone());
-            }
-            stateful.transit(&accounts);
-        }
-
-        Ok(RPCBlockTrace {
-            struct_logs: steps
-        })
+        trace_block::<P>(&state, &block, &config)
     }
 
-    fn trace_block_from_file(&self, path: String, config: Trailing<RPCTraceConfig>) -> Result<RPCBlockTrace, Error> {
+    fn trace_block_from_file(&self, path: String, config: Trailing<RPCTraceConfig>) -> Result<Vec<RPCTransactionTrace>, Error> {
         use std::fs::File;
         use std::io::Read;
 
@@ -706,61 +976,439 @@ impl<P: 'static + Patch + Send> DebugRPC for MinerDebugRPC<P> {
         let mut buffer = Vec::new();
         file.read_to_end(&mut buffer).unwrap();
 
-        let state = self.state.lock().unwrap();
+        let state = self.state.read().unwrap();
         let block: Block = UntrustedRlp::new(&buffer).as_val()?;
-        let last_block = state.get_block_by_number(if block.header.number == U256::zero() { 0 } else { block.header.number.as_usize() - 1 });
-        let last_hashes = state.get_last_256_block_hashes_by_number(block.header.number.as_usize());
 
-        let mut stateful: MemoryStateful<'static> = state.stateful_at(last_block.header.state_root);
-        let mut steps = Vec::new();
-        for transaction in block.transactions.clone() {
-            let (mut local_steps, vm) = replay_transaction::<P>(&stateful, transaction,
-                                                                &block, &last_hashes,
-                                                                &config)?;
-            steps.append(&mut local_steps);
-            let mut accounts = Vec::new();
-            for account in vm.accounts() {
-                accounts.push(account.clone());
-            }
-            stateful.transit(&accounts);
+        trace_block::<P>(&state, &block, &config)
+    }
+
+    fn dump_block(&self, number: usize) -> Result<RPCDump, Error> {
+        let state = self.state.read().unwrap();
+        if number > state.block_height() {
+            return Err(Error::NotFound);
         }
+        let block: Block = state.get_block_by_number(number);
+        let dump = miner::dump_state(&state, block.header.state_root)?;
 
-        Ok(RPCBlockTrace {
-            struct_logs: steps
+        Ok(RPCDump {
+            accounts: dump.accounts.into_iter().map(|(address, account)| (Hex(address), to_rpc_dump_account(account))).collect(),
+            unknown_accounts: dump.unknown_accounts.into_iter().map(|(key, account)| (Hex(key), to_rpc_dump_account(account))).collect(),
+            root: Hex(block.header.state_root)
         })
     }
 
-    fn dump_block(&self, number: usize) -> Result<RPCDump, Error> {
-        let state = self.state.lock().unwrap();
-        let block: Block = state.get_block_by_number(number);
+    fn set_head(&self, number: Hex<usize>) -> Result<bool, Error> {
+        let mut state = self.state.write().unwrap();
+
+        state.set_head(number.0)?;
+        Ok(true)
+    }
+
+    fn insert_block(&self, block_rlp: Bytes) -> Result<Hex<H256>, Error> {
+        let block: Block = UntrustedRlp::new(&block_rlp.0).as_val()?;
+        let mut state = self.state.write().unwrap();
+
+        let hash = miner::insert_block::<P>(&mut state, block)?;
+        state.insert_hash_raw(hash, block_rlp.0);
+        Ok(Hex(hash))
+    }
+
+    fn gas_report(&self, transaction: RPCTransaction, block: Trailing<String>) -> Result<Vec<RPCGasReportEntry>, Error> {
+        let state = self.state.read().unwrap();
+
+        let number = from_block_number(&state, block)?;
+        Ok(gas_report::<P>(&state, transaction, number))
+    }
+
+    fn coverage(&self, hash: Hex<H256>) -> Result<BTreeMap<Hex<Address>, BTreeSet<usize>>, Error> {
+        let state = self.state.read().unwrap();
+
+        let mut coverage = CoverageTracer::default();
+        let _: SeqTransactionVM<P> = replay_with_tracer::<P>(&state, hash.0, &mut coverage)?;
+        Ok(coverage.into_hits())
+    }
+
+    fn gas_profile(&self, hash: Hex<H256>) -> Result<RPCGasProfile, Error> {
+        let state = self.state.read().unwrap();
+
+        let mut profiler = GasProfiler::default();
+        let vm: SeqTransactionVM<P> = replay_with_tracer::<P>(&state, hash.0, &mut profiler)?;
+        Ok(profiler.into_report(vm.used_gas()))
+    }
+
+    fn transaction_proof(&self, hash: Hex<H256>) -> Result<RPCInclusionProof, Error> {
+        let state = self.state.read().unwrap();
+
+        let block = state.get_block_by_hash(state.get_transaction_block_hash_by_hash(hash.0)?)?;
+        let index = state.receipt_context(hash.0)?.transaction_index;
+        let proof = miner::transaction_proof(&block, index)?;
+        Ok(to_rpc_inclusion_proof(proof, &block, index))
+    }
+
+    fn receipt_proof(&self, hash: Hex<H256>) -> Result<RPCInclusionProof, Error> {
+        let state = self.state.read().unwrap();
+
+        let block = state.get_block_by_hash(state.get_transaction_block_hash_by_hash(hash.0)?)?;
+        let index = state.receipt_context(hash.0)?.transaction_index;
+        let mut receipts = Vec::new();
+        for transaction in &block.transactions {
+            receipts.push(state.get_receipt_by_transaction_hash(transaction.rlp_hash())?);
+        }
+        let receipts = miner::consensus_receipts::<P>(&state, &block.transactions, &receipts);
+        let proof = miner::receipt_proof(&block, &receipts, index)?;
+        Ok(to_rpc_inclusion_proof(proof, &block, index))
+    }
+}
+
+impl<P: 'static + Patch + Send> PersonalRPC for MinerPersonalRPC<P> {
+    fn new_account(&self, password: String) -> Result<Hex<Address>, Error> {
+        Ok(Hex(self.keystore.new_account(&password)?))
+    }
+
+    fn list_accounts(&self) -> Result<Vec<Hex<Address>>, Error> {
+        Ok(self.keystore.addresses()?.into_iter().map(|address| Hex(address)).collect())
+    }
+
+    fn import_raw_key(&self, key: String, password: String) -> Result<Hex<Address>, Error> {
+        let secret_key = keystore::parse_raw_key(&key)?;
+        Ok(Hex(self.keystore.store(&secret_key, &password)?))
+    }
+
+    fn unlock_account(&self, address: Hex<Address>, password: String, duration: Trailing<u64>) -> Result<bool, Error> {
+        let secret_key = self.keystore.unlock(address.0, &password)?;
+        let duration = match duration.unwrap_or(300) {
+            0 => None,
+            seconds => Some(Duration::from_secs(seconds)),
+        };
+
+        let mut state = self.state.write().unwrap();
+        state.unlock_account(secret_key, duration)?;
+        Ok(true)
+    }
+
+    fn lock_account(&self, address: Hex<Address>) -> Result<bool, Error> {
+        let mut state = self.state.write().unwrap();
+
+        Ok(state.lock_account(address.0))
+    }
+
+    fn send_transaction(&self, transaction: RPCTransaction, password: String) -> Result<Hex<H256>, Error> {
+        let address = transaction.from.clone().ok_or(Error::InvalidParams)?;
+        let secret_key = self.keystore.unlock(address.0, &password)?;
+
+        let mut state = self.state.write().unwrap();
+
+        let transaction = to_signed_transaction_with_key::<P>(&state, transaction, &secret_key)?;
+        miner::validate_pooled::<P>(&state, &transaction)?;
+
+        let hash = state.append_pending_transaction(transaction)?;
+        self.channel.send(MinerEvent::Transaction);
+        Ok(Hex(hash))
+    }
+}
+
+impl<P: 'static + Patch + Send> AdminRPC for MinerAdminRPC<P> {
+    fn node_info(&self) -> Result<RPCNodeInfo, Error> {
+        let state = self.state.read().unwrap();
+
+        let head = state.current_block();
+        Ok(RPCNodeInfo {
+            client_version: CLIENT_VERSION.to_string(),
+            protocol_version: PROTOCOL_VERSION,
+            chain: state.chain().to_string(),
+            chain_id: state.chain_id().map(Hex),
+            network_id: format!("{}", state.network_id()),
+            genesis_hash: Hex(state.get_block_by_number(0).header.header_hash()),
+            head_number: Hex(state.block_height()),
+            head_hash: Hex(head.header.header_hash()),
+            data_dir: state.data_dir().map(|dir| dir.display().to_string()),
+            apis: API_NAMESPACES.iter().map(|namespace| namespace.to_string()).collect(),
+        })
+    }
+}
 
-        let mut accounts = HashMap::new();
-        let database = state.stateful().database();
-        let trie: FixedSecureTrie<_, Address, Account> = database.create_fixed_secure_trie(block.header.state_root);
-        let code_hashes = database.create_guard();
+impl<P: 'static + Patch + Send> TraceRPC for MinerTraceRPC<P> {
+    fn transaction(&self, hash: Hex<H256>) -> Result<Vec<RPCCallTrace>, Error> {
+        let state = self.state.read().unwrap();
 
-        for (address, storage) in state.dump_accounts(number) {
-            let mut rpc_storage = HashMap::new();
-            for (key, value) in storage {
-                rpc_storage.insert(Hex(key), Hex(value));
+        Ok(self.replay(&state, hash.0)?.calls)
+    }
+
+    fn replay_transaction(&self, hash: Hex<H256>, types: Vec<String>) -> Result<RPCTraceResults, Error> {
+        let state = self.state.read().unwrap();
+
+        for type_ in &types {
+            match type_.as_str() {
+                "trace" | "vmTrace" | "stateDiff" => (),
+                _ => return Err(Error::InvalidParams),
             }
+        }
+        let wants = |type_: &str| types.iter().any(|wanted| wanted == type_);
+
+        let trace = self.replay(&state, hash.0)?;
+        Ok(RPCTraceResults {
+            output: Bytes(trace.output),
+            trace: if wants("trace") {
+                trace.calls.into_iter().map(|mut call| {
+                    call.block_hash = None;
+                    call.block_number = None;
+                    call.transaction_hash = None;
+                    call.transaction_position = None;
+                    call
+                }).collect()
+            } else {
+                Vec::new()
+            },
+            vm_trace: if wants("vmTrace") { trace.vm_trace } else { None },
+            state_diff: if wants("stateDiff") { Some(trace.state_diff) } else { None },
+        })
+    }
+
+    fn filter(&self, filter: RPCTraceFilter) -> Result<Vec<RPCCallTrace>, Error> {
+        let state = self.state.read().unwrap();
 
-            let account = trie.get(&address).unwrap();
-            let code = code_hashes.get(account.code_hash).unwrap();
+        let from_block = from_block_number(&state, filter.from_block)?;
+        let to_block = from_block_number(&state, filter.to_block)?;
+        if to_block >= from_block + MAX_TRACE_FILTER_RANGE {
+            return Err(Error::InvalidParams);
+        }
+        let after = filter.after.unwrap_or(0);
+        // Blocks past the last trace returned are not executed.
+        let wanted = filter.count.map(|count| after.saturating_add(count));
+        let from_address: Vec<Address> = filter.from_address.unwrap_or(Vec::new())
+            .into_iter().map(|address| address.0).collect();
+        let to_address: Vec<Address> = filter.to_address.unwrap_or(Vec::new())
+            .into_iter().map(|address| address.0).collect();
+
+        let mut calls = Vec::new();
+        'blocks: for number in from_block..(to_block + 1) {
+            let block = state.get_block_by_number(number);
+            if block.transactions.is_empty() {
+                continue;
+            }
 
-            accounts.insert(Hex(address), RPCDumpAccount {
-                balance: Hex(account.balance),
-                code: Bytes(code),
-                code_hash: Hex(account.code_hash),
-                nonce: Hex(account.nonce),
-                root: Hex(account.storage_root),
-                storage: rpc_storage,
+            let last_hashes = state.get_last_256_block_hashes_by_number(number);
+            let mut stateful = state_before_transaction::<P>(&state, &block, 0, &last_hashes)?;
+            for index in 0..block.transactions.len() {
+                let trace = trace_parity_transaction::<P>(&state, &mut stateful, &block, index, &last_hashes)?;
+                calls.extend(trace.calls.into_iter()
+                             .filter(|call| matches_addresses(call, &from_address, &to_address)));
+                if wanted.map(|wanted| calls.len() >= wanted).unwrap_or(false) {
+                    break 'blocks;
+                }
+            }
+        }
+
+        let calls = calls.into_iter().skip(after);
+        Ok(match filter.count {
+            Some(count) => calls.take(count).collect(),
+            None => calls.collect(),
+        })
+    }
+}
+
+impl<P: 'static + Patch + Send> DevRPC for MinerDevRPC<P> {
+    fn accounts(&self) -> Result<Vec<RPCDevAccount>, Error> {
+        let state = self.state.read().unwrap();
+
+        let mut ret = Vec::new();
+        for &(ref secret_key, balance) in state.genesis_accounts() {
+            ret.push(RPCDevAccount {
+                address: Hex(Address::from_secret_key(secret_key)?),
+                private_key: if state.expose_private_keys() {
+                    Some(Bytes(secret_key[..].to_vec()))
+                } else {
+                    None
+                },
+                balance: Hex(balance),
             });
         }
+        Ok(ret)
+    }
 
-        Ok(RPCDump {
-            accounts,
-            root: Hex(block.header.state_root)
+    fn impersonate_account(&self, address: Hex<Address>) -> Result<bool, Error> {
+        let mut state = self.state.write().unwrap();
+
+        state.impersonate_account(address.0);
+        Ok(true)
+    }
+
+    fn stop_impersonating_account(&self, address: Hex<Address>) -> Result<bool, Error> {
+        let mut state = self.state.write().unwrap();
+
+        Ok(state.stop_impersonating_account(address.0))
+    }
+
+    fn set_balance(&self, address: Hex<Address>, balance: Hex<U256>) -> Result<bool, Error> {
+        let mut state = self.state.write().unwrap();
+
+        state.set_balance(address.0, balance.0);
+        Ok(true)
+    }
+
+    fn set_nonce(&self, address: Hex<Address>, nonce: Hex<U256>) -> Result<bool, Error> {
+        let mut state = self.state.write().unwrap();
+
+        state.set_nonce(address.0, nonce.0);
+        Ok(true)
+    }
+
+    fn set_code(&self, address: Hex<Address>, code: Bytes) -> Result<bool, Error> {
+        let mut state = self.state.write().unwrap();
+
+        state.set_code(address.0, code.0);
+        Ok(true)
+    }
+
+    fn set_storage_at(&self, address: Hex<Address>, index: Hex<U256>, value: Hex<U256>) -> Result<bool, Error> {
+        let mut state = self.state.write().unwrap();
+
+        state.set_storage(address.0, index.0, M256::from(value.0));
+        Ok(true)
+    }
+
+    fn bisect(&self, bisect: RPCBisect) -> Result<Option<Hex<usize>>, Error> {
+        let state = self.state.read().unwrap();
+
+        let from = match bisect.from_block {
+            Some(from) => from_block_number(&state, Some(from))?,
+            None => 0,
+        };
+        let to = from_block_number(&state, bisect.to_block)?;
+
+        let condition = |number: usize| -> Result<bool, Error> {
+            let vm = call_at_block::<P>(&state, bisect.call.clone(), number)?;
+            let out: Vec<u8> = vm.out().into();
+            Ok(match vm.status() {
+                VMStatus::ExitedOk => match bisect.expected {
+                    Some(ref expected) => out == expected.0,
+                    None => out.iter().any(|byte| *byte != 0),
+                },
+                _ => false,
+            })
+        };
+
+        if from > to || !condition(to)? {
+            return Ok(None);
+        }
+
+        // The condition holds at `high`; find the first block where it
+        // does, assuming it stays true once it became true.
+        let (mut low, mut high) = (from, to);
+        while low < high {
+            let mid = low + (high - low) / 2;
+            if condition(mid)? {
+                high = mid;
+            } else {
+                low = mid + 1;
+            }
+        }
+
+        Ok(Some(Hex(low)))
+    }
+
+    fn reset(&self, genesis: Trailing<RPCGenesis>) -> Result<bool, Error> {
+        let mut state = self.state.write().unwrap();
+
+        let genesis: Option<RPCGenesis> = genesis.into();
+        let (alloc, timestamp) = match genesis {
+            Some(genesis) => from_genesis(genesis),
+            None => (Vec::new(), None),
+        };
+        miner::reset::<P>(&mut state, &alloc, timestamp);
+        Ok(true)
+    }
+
+    fn mine(&self) -> Result<bool, Error> {
+        self.channel.send(MinerEvent::Mine);
+        Ok(true)
+    }
+
+    fn create_fork(&self, number: Hex<usize>) -> Result<RPCSideChain, Error> {
+        let mut state = self.state.write().unwrap();
+
+        let id = miner::create_side_chain(&mut state, number.0)?;
+        Ok(RPCSideChain {
+            id: Hex(id),
+            base: number.clone(),
+            number: number.clone(),
+            hash: Hex(state.get_block_by_number(number.0).header.header_hash()),
+            canonical: false,
         })
     }
+
+    fn extend_fork(&self, id: Hex<usize>, count: Trailing<Hex<usize>>) -> Result<RPCSideChain, Error> {
+        let mut state = self.state.write().unwrap();
+
+        let count = count.unwrap_or(Hex(1)).0;
+        let head = miner::extend_side_chain::<P>(&mut state, id.0, count)?;
+        // Transactions of the replaced blocks are back in the pool.
+        if head.canonical && !state.pending_transactions().is_empty() {
+            self.channel.send(MinerEvent::Transaction);
+        }
+        Ok(RPCSideChain {
+            id,
+            base: Hex(head.base),
+            number: Hex(head.number),
+            hash: Hex(head.hash),
+            canonical: head.canonical,
+        })
+    }
+
+    fn verify_database(&self, repair: Trailing<bool>) -> Result<RPCVerifyReport, Error> {
+        let mut state = self.state.write().unwrap();
+
+        let report = miner::verify_database(&state);
+        if report.is_ok() || !repair.unwrap_or(false) {
+            return Ok(to_rpc_verify_report(report, false));
+        }
+
+        let report = miner::repair_database::<P>(&mut state)?;
+        Ok(to_rpc_verify_report(report, true))
+    }
+
+    fn clear_fork_cache(&self) -> Result<bool, Error> {
+        let state = self.state.read().unwrap();
+
+        match state.fork() {
+            Some(fork) => {
+                fork.clear_cache()?;
+                Ok(true)
+            },
+            None => Ok(false),
+        }
+    }
+
+    fn backup(&self, include_keys: Trailing<bool>) -> Result<Bytes, Error> {
+        let state = self.state.read().unwrap();
+
+        Ok(Bytes(miner::write_archive(&state, include_keys.unwrap_or(false))))
+    }
+
+    fn session_report(&self, session: String) -> Result<RPCSessionReport, Error> {
+        let state = self.state.read().unwrap();
+
+        to_rpc_session_report(&state, &session)
+    }
+
+    fn config(&self) -> Result<RPCNodeConfig, Error> {
+        let state = self.state.read().unwrap();
+
+        Ok(to_rpc_node_config::<P>(&state, self.cheats.read().unwrap().clone()))
+    }
+
+    fn watch_events(&self, from: Trailing<usize>) -> Result<Vec<RPCWatchEvent>, Error> {
+        let state = self.state.read().unwrap();
+
+        let from: Option<usize> = from.into();
+        Ok(state.watch_events(from.unwrap_or(0)).iter().map(to_rpc_watch_event).collect())
+    }
+
+    fn fuzz(&self, config: RPCFuzzConfig) -> Result<RPCFuzzReport, Error> {
+        fuzz::fuzz::<P>(self.state.clone(), config)
+    }
+
+    fn api_key_usage(&self) -> Result<HashMap<String, RPCApiKeyUsage>, Error> {
+        Ok(self.api_keys.usage())
+    }
 }
@@ -0,0 +1,694 @@
+use super::{RPCCallTrace, RPCTraceAction, RPCCallAction, RPCCreateAction, RPCSuicideAction, RPCTraceResult, RPCCallResult, RPCCreateResult, RPCVMTrace, RPCVMOperation, RPCVMExecuted, RPCMemoryDiff, RPCStorageDiff, RPCAccountDiff, RPCDiff, RPCDiffChange};
+use super::serialize::*;
+use super::opcode::stack_pushed;
+use error::Error;
+use miner::{self, MinerState};
+
+use bigint::{M256, U256, H256, Address, Gas};
+use block::{Block, Account, TransactionAction, HeaderHash, RlpHash};
+use sputnikvm::{ValidTransaction, VM, VMStatus, MachineStatus, HeaderParams, SeqTransactionVM, Machine, SeqMemory, Patch, AccountChange};
+use sputnikvm_stateful::MemoryStateful;
+use std::collections::{BTreeMap, HashMap};
+
+/// Parity-style traces of a replayed transaction.
+pub struct ParityTrace {
+    pub output: Vec<u8>,
+    pub calls: Vec<RPCCallTrace>,
+    pub vm_trace: Option<RPCVMTrace>,
+    pub state_diff: BTreeMap<Hex<Address>, RPCAccountDiff>,
+}
+
+/// Trace the transaction at `index` of `block` on `stateful`, the state
+/// left by the transactions before it, then commit it to `stateful` as
+/// the miner did.
+pub fn trace_parity_transaction<P: Patch>(
+    state: &MinerState, stateful: &mut MemoryStateful<'static>, block: &Block, index: usize,
+    last_hashes: &[H256]
+) -> Result<ParityTrace, Error> {
+    let transaction = &block.transactions[index];
+    let valid = miner::valid_transaction::<P>(state, stateful, transaction)?;
+    let caller = valid.caller.unwrap_or(Address::default());
+    let gas_price = valid.gas_price;
+
+    let (mut calls, vm_trace, vm) = trace_calls::<P>(
+        state, stateful, valid, &HeaderParams::from(&block.header), last_hashes)?;
+    for call in calls.iter_mut() {
+        call.block_hash = Some(Hex(block.header.header_hash()));
+        call.block_number = Some(block.header.number.as_usize());
+        call.transaction_hash = Some(Hex(transaction.rlp_hash()));
+        call.transaction_position = Some(index);
+    }
+
+    let accounts = miner::mined_account_changes(state, stateful, caller, gas_price, &vm);
+    let state_diff = state_diff(state, stateful, &accounts);
+    stateful.transit(&accounts);
+
+    Ok(ParityTrace {
+        output: vm.out().into(),
+        calls, vm_trace, state_diff,
+    })
+}
+
+/// Whether a trace goes from one of `from` to one of `to`, an empty
+/// list matching any address. Creations go to the created contract
+/// and SELFDESTRUCTs to the refunded address.
+pub fn matches_addresses(call: &RPCCallTrace, from: &[Address], to: &[Address]) -> bool {
+    let (sender, receiver) = match (&call.action, &call.result) {
+        (&RPCTraceAction::Call(ref action), _) => (action.from.0, Some(action.to.0)),
+        (&RPCTraceAction::Create(ref action), &Some(RPCTraceResult::Create(ref result))) =>
+            (action.from.0, Some(result.address.0)),
+        (&RPCTraceAction::Create(ref action), _) => (action.from.0, None),
+        (&RPCTraceAction::Suicide(ref action), _) => (action.address.0, Some(action.refund_address.0)),
+    };
+
+    (from.is_empty() || from.contains(&sender)) &&
+        (to.is_empty() || receiver.map(|receiver| to.contains(&receiver)).unwrap_or(false))
+}
+
+/// Execute a transaction step by step, following calls and creations
+/// into a flattened call tree and a VM trace nested the same way. What
+/// the VM requires is fulfilled as when mining, through the fork and
+/// the account cache of `state`.
+pub fn trace_calls<P: Patch>(
+    state: &MinerState, stateful: &mut MemoryStateful<'static>, valid: ValidTransaction, header: &HeaderParams,
+    last_hashes: &[H256]
+) -> Result<(Vec<RPCCallTrace>, Option<RPCVMTrace>, SeqTransactionVM<P>), Error> {
+    let create = match valid.action {
+        TransactionAction::Create => true,
+        _ => false,
+    };
+    let mut vm = SeqTransactionVM::<P>::new(valid, header.clone());
+    let mut tracer = CallTracer::new(create);
+
+    loop {
+        match vm.status() {
+            VMStatus::ExitedOk | VMStatus::ExitedErr(_) | VMStatus::ExitedNotSupported(_) => break,
+            VMStatus::Running => {
+                let step = match vm.current_machine() {
+                    Some(machine) => {
+                        tracer.follow(machine);
+                        tracer.before_step(stateful, machine)
+                    },
+                    None => None,
+                };
+
+                match vm.step() {
+                    Ok(()) => if let Some(step) = step {
+                        tracer.after_step(step, vm.current_machine());
+                    },
+                    // The opcode did not run, and is traced once it does.
+                    Err(require) => miner::fulfill(state.fork(), state.account_cache(), stateful, &mut vm, require,
+                                                   header, last_hashes)?,
+                }
+            },
+        }
+    }
+
+    tracer.finish(&vm);
+    Ok((tracer.calls, tracer.vm_trace, vm))
+}
+
+/// A call or creation being executed.
+struct Frame {
+    call: usize,
+    trace_address: Vec<usize>,
+    vm_trace: RPCVMTrace,
+    /// Operation of the frame waiting for its callee to return, with
+    /// the stack items it was invoked with.
+    calling: Option<(usize, u8, Vec<M256>)>,
+}
+
+/// An opcode about to run.
+struct Step {
+    depth: usize,
+    pc: usize,
+    op: u8,
+    gas: Gas,
+    stack: Vec<M256>,
+    /// Address, refund address and balance of a SELFDESTRUCT, traced
+    /// once it has run.
+    suicide: Option<(Address, Address, U256)>,
+}
+
+struct CallTracer {
+    create: bool,
+    frames: Vec<Frame>,
+    calls: Vec<RPCCallTrace>,
+    vm_trace: Option<RPCVMTrace>,
+}
+
+impl CallTracer {
+    fn new(create: bool) -> Self {
+        CallTracer {
+            create,
+            frames: Vec::new(),
+            calls: Vec::new(),
+            vm_trace: None,
+        }
+    }
+
+    /// Open a frame for a machine that started, close the frame of a
+    /// machine that exited, or resume the caller of a returned callee.
+    fn follow<P: Patch>(&mut self, machine: &Machine<SeqMemory<P>, P>) {
+        let depth = machine.state().depth;
+        match machine.status() {
+            MachineStatus::ExitedOk | MachineStatus::ExitedErr(_) => {
+                if depth + 1 == self.frames.len() {
+                    self.close(machine);
+                }
+            },
+            MachineStatus::Running => {
+                if depth == self.frames.len() {
+                    self.open(machine);
+                } else if depth + 1 == self.frames.len() {
+                    self.resume(machine);
+                }
+            },
+            _ => (),
+        }
+    }
+
+    fn open<P: Patch>(&mut self, machine: &Machine<SeqMemory<P>, P>) {
+        let context = &machine.state().context;
+        let call_type = match self.frames.last() {
+            Some(&Frame { calling: Some((_, op, _)), .. }) => match op {
+                0xf0 => None,
+                0xf2 => Some("callcode"),
+                0xf4 => Some("delegatecall"),
+                0xfa => Some("staticcall"),
+                _ => Some("call"),
+            },
+            Some(_) => Some("call"),
+            None => if self.create { None } else { Some("call") },
+        };
+
+        let (action, type_) = match call_type {
+            Some(call_type) => (RPCTraceAction::Call(RPCCallAction {
+                call_type: call_type.to_string(),
+                from: Hex(context.caller),
+                to: Hex(context.address),
+                gas: Hex(context.gas_limit),
+                input: Bytes(context.data.to_vec()),
+                value: Hex(context.value),
+            }), "call"),
+            None => (RPCTraceAction::Create(RPCCreateAction {
+                from: Hex(context.caller),
+                gas: Hex(context.gas_limit),
+                init: Bytes(context.code.to_vec()),
+                value: Hex(context.value),
+            }), "create"),
+        };
+
+        let trace_address = self.child_address();
+        self.calls.push(RPCCallTrace {
+            action,
+            result: None,
+            error: None,
+            subtraces: 0,
+            trace_address: trace_address.clone(),
+            type_: type_.to_string(),
+            block_hash: None,
+            block_number: None,
+            transaction_hash: None,
+            transaction_position: None,
+        });
+        self.frames.push(Frame {
+            call: self.calls.len() - 1,
+            trace_address,
+            vm_trace: RPCVMTrace {
+                code: Bytes(context.code.to_vec()),
+                ops: Vec::new(),
+            },
+            calling: None,
+        });
+    }
+
+    /// Trace address of the next subtrace of the current frame, which
+    /// is counted right away.
+    fn child_address(&mut self) -> Vec<usize> {
+        match self.frames.last() {
+            Some(frame) => {
+                let call = &mut self.calls[frame.call];
+                let mut trace_address = frame.trace_address.clone();
+                trace_address.push(call.subtraces);
+                call.subtraces += 1;
+                trace_address
+            },
+            None => Vec::new(),
+        }
+    }
+
+    fn close<P: Patch>(&mut self, machine: &Machine<SeqMemory<P>, P>) {
+        let frame = self.frames.pop().unwrap();
+        let state = machine.state();
+        let gas_used = state.context.gas_limit - state.available_gas();
+        let output = state.out.to_vec();
+
+        {
+            let call = &mut self.calls[frame.call];
+            match machine.status() {
+                MachineStatus::ExitedErr(err) => {
+                    call.error = Some(format!("{:?}", err));
+                },
+                _ => {
+                    call.result = Some(match call.action {
+                        RPCTraceAction::Create(_) => RPCTraceResult::Create(RPCCreateResult {
+                            gas_used: Hex(gas_used),
+                            code: Bytes(output),
+                            address: Hex(state.context.address),
+                        }),
+                        _ => RPCTraceResult::Call(RPCCallResult {
+                            gas_used: Hex(gas_used),
+                            output: Bytes(output),
+                        }),
+                    });
+                },
+            }
+        }
+
+        match self.frames.last_mut() {
+            Some(parent) => if let Some((index, _, _)) = parent.calling {
+                parent.vm_trace.ops[index].sub = Some(frame.vm_trace);
+            },
+            None => self.vm_trace = Some(frame.vm_trace),
+        }
+    }
+
+    /// Complete the operation that invoked a callee, now that its
+    /// result is on the stack of the caller.
+    fn resume<P: Patch>(&mut self, machine: &Machine<SeqMemory<P>, P>) {
+        let frame = self.frames.last_mut().unwrap();
+        let (index, op, stack) = match frame.calling.take() {
+            Some(calling) => calling,
+            None => return,
+        };
+
+        let (offset, length) = match op {
+            0xf1 | 0xf2 => (stack.get(5), stack.get(6)),
+            0xf4 | 0xfa => (stack.get(4), stack.get(5)),
+            _ => (None, None),
+        };
+        if let Some(ref mut ex) = frame.vm_trace.ops[index].ex {
+            ex.used = U256::from(machine.state().available_gas()).as_u64();
+            ex.push = pushed(machine, 1);
+            ex.mem = match (offset, length) {
+                (Some(&offset), Some(&length)) => memory_diff(machine, offset, length),
+                _ => None,
+            };
+        }
+    }
+
+    fn before_step<P: Patch>(
+        &mut self, stateful: &MemoryStateful<'static>, machine: &Machine<SeqMemory<P>, P>
+    ) -> Option<Step> {
+        match machine.status() {
+            MachineStatus::Running => (),
+            _ => return None,
+        }
+        let pc = machine.pc().position();
+        if pc >= machine.pc().code().len() || self.frames.is_empty() {
+            return None;
+        }
+
+        let state = machine.state();
+        let op = machine.pc().code()[pc];
+        let mut stack = Vec::new();
+        for i in 0..state.stack.len().min(7) {
+            stack.push(state.stack.peek(i).unwrap());
+        }
+
+        let suicide = if op == 0xff {
+            let address = state.context.address;
+            let refund_address = stack.get(0).map(|&value| Address::from(value)).unwrap_or(Address::default());
+            Some((address, refund_address, balance_of(stateful, machine, address)))
+        } else {
+            None
+        };
+
+        Some(Step {
+            depth: state.depth,
+            pc, op,
+            gas: state.available_gas(),
+            stack,
+            suicide,
+        })
+    }
+
+    fn after_step<P: Patch>(&mut self, mut step: Step, machine: Option<&Machine<SeqMemory<P>, P>>) {
+        if let Some((address, refund_address, balance)) = step.suicide.take() {
+            let trace_address = self.child_address();
+            self.calls.push(RPCCallTrace {
+                action: RPCTraceAction::Suicide(RPCSuicideAction {
+                    address: Hex(address),
+                    refund_address: Hex(refund_address),
+                    balance: Hex(balance),
+                }),
+                result: None,
+                error: None,
+                subtraces: 0,
+                trace_address,
+                type_: "suicide".to_string(),
+                block_hash: None,
+                block_number: None,
+                transaction_hash: None,
+                transaction_position: None,
+            });
+        }
+
+
+        let machine = match machine {
+            Some(machine) if machine.state().depth == step.depth => machine,
+            _ => return self.push_operation(step, Gas::zero(), None),
+        };
+
+        let used = machine.state().available_gas();
+        let cost = step.gas - used;
+        let invoking = match machine.status() {
+            MachineStatus::InvokeCall(_, _) | MachineStatus::InvokeCreate(_) => true,
+            _ => false,
+        };
+        if invoking {
+            let op = step.op;
+            let stack = step.stack.clone();
+            self.push_operation(step, cost, Some(RPCVMExecuted {
+                used: U256::from(used).as_u64(),
+                push: Vec::new(),
+                mem: None,
+                store: None,
+            }));
+            let frame = self.frames.last_mut().unwrap();
+            frame.calling = Some((frame.vm_trace.ops.len() - 1, op, stack));
+            return;
+        }
+
+        let (offset, length) = match step.op {
+            0x52 => (step.stack.get(0).cloned(), Some(M256::from(32u64))),
+            0x53 => (step.stack.get(0).cloned(), Some(M256::from(1u64))),
+            0x37 | 0x39 | 0x3e => (step.stack.get(0).cloned(), step.stack.get(2).cloned()),
+            0x3c => (step.stack.get(1).cloned(), step.stack.get(3).cloned()),
+            _ => (None, None),
+        };
+        let store = match (step.op, step.stack.get(0), step.stack.get(1)) {
+            (0x55, Some(&key), Some(&value)) => Some(RPCStorageDiff {
+                key: Hex(U256::from(key)),
+                val: Hex(value),
+            }),
+            _ => None,
+        };
+        let ex = RPCVMExecuted {
+            used: U256::from(used).as_u64(),
+            push: pushed(machine, stack_pushed(step.op)),
+            mem: match (offset, length) {
+                (Some(offset), Some(length)) => memory_diff(machine, offset, length),
+                _ => None,
+            },
+            store,
+        };
+        self.push_operation(step, cost, Some(ex));
+    }
+
+    fn push_operation(&mut self, step: Step, cost: Gas, ex: Option<RPCVMExecuted>) {
+        if let Some(frame) = self.frames.last_mut() {
+            frame.vm_trace.ops.push(RPCVMOperation {
+                pc: step.pc,
+                cost: U256::from(cost).as_u64(),
+                ex,
+                sub: None,
+            });
+        }
+    }
+
+    /// Close the frames the VM left without the machine being seen
+    /// exiting, with the outcome of the whole transaction.
+    fn finish<P: Patch>(&mut self, vm: &SeqTransactionVM<P>) {
+        while let Some(frame) = self.frames.pop() {
+            let call = &mut self.calls[frame.call];
+            match vm.status() {
+                VMStatus::ExitedOk if self.frames.is_empty() => {
+                    call.result = Some(RPCTraceResult::Call(RPCCallResult {
+                        gas_used: Hex(vm.used_gas()),
+                        output: Bytes(vm.out().into()),
+                    }));
+                },
+                status => call.error = Some(format!("{:?}", status)),
+            }
+            if self.frames.is_empty() {
+                self.vm_trace = Some(frame.vm_trace);
+            }
+        }
+    }
+}
+
+/// Top `count` stack items, the deepest first.
+fn pushed<P: Patch>(machine: &Machine<SeqMemory<P>, P>, count: usize) -> Vec<Hex<M256>> {
+    let stack = &machine.state().stack;
+    (0..count.min(stack.len())).rev().map(|i| Hex(stack.peek(i).unwrap())).collect()
+}
+
+/// Memory written by an operation, if it lies within the memory of
+/// `machine`.
+fn memory_diff<P: Patch>(machine: &Machine<SeqMemory<P>, P>, offset: M256, length: M256) -> Option<RPCMemoryDiff> {
+    let memory = &machine.state().memory;
+    let (offset, length, size) = (U256::from(offset), U256::from(length), U256::from(memory.len()));
+    if length.is_zero() || offset > size || length > size - offset {
+        return None;
+    }
+
+    let offset = offset.as_usize();
+    let data = (offset..(offset + length.as_usize()))
+        .map(|i| memory.read_raw(U256::from(i)))
+        .collect();
+    Some(RPCMemoryDiff {
+        off: offset,
+        data: Bytes(data),
+    })
+}
+
+/// Balance of `address` as seen by `machine`, falling back to the
+/// state the transaction runs on.
+fn balance_of<P: Patch>(stateful: &MemoryStateful<'static>, machine: &Machine<SeqMemory<P>, P>, address: Address) -> U256 {
+    for account in machine.state().account_state.accounts() {
+        match account {
+            &AccountChange::Full { address: a, balance, .. } |
+            &AccountChange::Create { address: a, balance, .. } if a == address => return balance,
+            _ => (),
+        }
+    }
+    let account: Option<Account> = stateful.state_of(stateful.root()).get(&address);
+    account.map(|account| account.balance).unwrap_or(U256::zero())
+}
+
+/// Parity-style state diff of the accounts changed by `accounts` when
+/// committed to `stateful`.
+pub fn state_diff(
+    state: &MinerState, stateful: &MemoryStateful<'static>, accounts: &[AccountChange]
+) -> BTreeMap<Hex<Address>, RPCAccountDiff> {
+    let mut after = state.stateful_at(stateful.root());
+    after.transit(accounts);
+
+    let mut diffs = BTreeMap::new();
+    for change in accounts {
+        let (address, indexes): (Address, Vec<U256>) = match change {
+            &AccountChange::Full { address, ref changing_storage, .. } => {
+                let storage: HashMap<U256, M256> = changing_storage.clone().into();
+                (address, storage.keys().cloned().collect())
+            },
+            &AccountChange::Create { address, ref storage, .. } => {
+                let storage: HashMap<U256, M256> = storage.clone().into();
+                (address, storage.keys().cloned().collect())
+            },
+            &AccountChange::Nonexist(address) |
+            &AccountChange::IncreaseBalance(address, _) => (address, Vec::new()),
+        };
+
+        let before: Option<Account> = stateful.state_of(stateful.root()).get(&address);
+        let now: Option<Account> = after.state_of(after.root()).get(&address);
+
+        let code = |stateful: &MemoryStateful<'static>, account: &Account| {
+            stateful.code(account.code_hash).unwrap_or(Vec::new())
+        };
+        let slot = |stateful: &MemoryStateful<'static>, account: &Account, index: U256| {
+            let value: Option<M256> = stateful.storage_state_of(account.storage_root).get(&H256::from(index));
+            H256::from(U256::from(value.unwrap_or(M256::zero())))
+        };
+
+        let mut storage = BTreeMap::new();
+        for index in indexes {
+            let from = before.as_ref().map(|account| slot(stateful, account, index));
+            let to = now.as_ref().map(|account| slot(&after, account, index));
+            let entry = diff(from, to, |value| Hex(value));
+            if entry != RPCDiff::Same {
+                storage.insert(Hex(H256::from(index)), entry);
+            }
+        }
+
+        let account_diff = RPCAccountDiff {
+            balance: diff(before.as_ref().map(|account| account.balance),
+                          now.as_ref().map(|account| account.balance), |value| Hex(value)),
+            nonce: diff(before.as_ref().map(|account| account.nonce),
+                        now.as_ref().map(|account| account.nonce), |value| Hex(value)),
+            code: diff(before.as_ref().map(|account| code(stateful, account)),
+                       now.as_ref().map(|account| code(&after, account)), |value| Bytes(value)),
+            storage,
+        };
+        let unchanged = match (&account_diff.balance, &account_diff.nonce, &account_diff.code) {
+            (&RPCDiff::Same, &RPCDiff::Same, &RPCDiff::Same) => account_diff.storage.is_empty(),
+            _ => false,
+        };
+        if !unchanged {
+            diffs.insert(Hex(address), account_diff);
+        }
+    }
+    diffs
+}
+
+/// Difference between two values of an account, `None` meaning that
+/// the account does not exist.
+pub fn diff<T: PartialEq, U, F: Fn(T) -> U>(before: Option<T>, after: Option<T>, f: F) -> RPCDiff<U> {
+    match (before, after) {
+        (None, None) => RPCDiff::Same,
+        (None, Some(after)) => RPCDiff::Born(f(after)),
+        (Some(before), None) => RPCDiff::Died(f(before)),
+        (Some(before), Some(after)) => if before == after {
+            RPCDiff::Same
+        } else {
+            RPCDiff::Changed(RPCDiffChange {
+                from: f(before),
+                to: f(after),
+            })
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use block::UnsignedTransaction;
+    use secp256k1::SECP256K1;
+    use secp256k1::key::SecretKey;
+    use sputnikvm_network_foundation::ByzantiumPatch;
+    use std::sync::{Arc, RwLock};
+
+    fn call(from: Address, to: Address) -> RPCCallTrace {
+        RPCCallTrace {
+            action: RPCTraceAction::Call(RPCCallAction {
+                call_type: "call".to_string(),
+                from: Hex(from),
+                to: Hex(to),
+                gas: Hex(Gas::zero()),
+                input: Bytes(Vec::new()),
+                value: Hex(U256::zero()),
+            }),
+            result: None,
+            error: None,
+            subtraces: 0,
+            trace_address: Vec::new(),
+            type_: "call".to_string(),
+            block_hash: None,
+            block_number: None,
+            transaction_hash: None,
+            transaction_position: None,
+        }
+    }
+
+    #[test]
+    fn filter_by_addresses() {
+        let (alice, bob, carol) = (Address::from(1u64), Address::from(2u64), Address::from(3u64));
+        let trace = call(alice, bob);
+        assert!(matches_addresses(&trace, &[], &[]));
+        assert!(matches_addresses(&trace, &[alice], &[]));
+        assert!(matches_addresses(&trace, &[carol, alice], &[bob]));
+        assert!(!matches_addresses(&trace, &[bob], &[]));
+        assert!(!matches_addresses(&trace, &[], &[carol]));
+    }
+
+    #[test]
+    fn diff_of_values() {
+        let id = |value: u64| value;
+        assert_eq!(diff(None, None, id), RPCDiff::Same);
+        assert_eq!(diff(None, Some(1), id), RPCDiff::Born(1));
+        assert_eq!(diff(Some(1), None, id), RPCDiff::Died(1));
+        assert_eq!(diff(Some(1), Some(1), id), RPCDiff::Same);
+        assert_eq!(diff(Some(1), Some(2), id), RPCDiff::Changed(RPCDiffChange { from: 1, to: 2 }));
+    }
+
+    /// CALL(GAS, to, 0, 0, 0, 0, 0), dropping its result.
+    fn call_code(to: Address) -> Vec<u8> {
+        let mut code = vec![0x60, 0x00, 0x60, 0x00, 0x60, 0x00, 0x60, 0x00, 0x60, 0x00, 0x73];
+        code.extend_from_slice(&to[..]);
+        code.extend_from_slice(&[0x5a, 0xf1, 0x50]);
+        code
+    }
+
+    /// Mine a call of `to`, with `contracts` deployed, and trace it.
+    fn trace_mined_call(contracts: Vec<(Address, Vec<u8>)>, to: Address) -> ParityTrace {
+        let secret_key = SecretKey::from_slice(&SECP256K1, &[1u8; 32]).unwrap();
+        let mut state = miner::make_state_at::<ByzantiumPatch>(vec![(secret_key.clone(), U256::from(1_000_000u64))], 0);
+        for (address, code) in contracts {
+            state.set_code(address, code);
+        }
+        state.append_pending_transaction(miner::sign_transaction(UnsignedTransaction {
+            nonce: U256::zero(),
+            gas_price: Gas::zero(),
+            gas_limit: Gas::from(100000u64),
+            action: TransactionAction::Call(to),
+            value: U256::zero(),
+            input: Vec::new(),
+        }, &secret_key, None)).unwrap();
+
+        // Written accounts are only sealed with the block.
+        let root = state.head_root();
+
+        let state = Arc::new(RwLock::new(state));
+        miner::mine_one_at::<ByzantiumPatch>(state.clone(), Address::default(), 1);
+        let state = state.read().unwrap();
+        let block = state.current_block();
+        assert_eq!(block.transactions.len(), 1);
+
+        let last_hashes = state.get_last_256_block_hashes_by_number(1);
+        let mut stateful = state.stateful_at(root);
+        trace_parity_transaction::<ByzantiumPatch>(&state, &mut stateful, &block, 0, &last_hashes).unwrap()
+    }
+
+    #[test]
+    fn call_tree_of_nested_calls() {
+        let (root, stopping, reverting) = (Address::from(0x30u64), Address::from(0x31u64), Address::from(0x32u64));
+        let mut code = call_code(stopping);
+        code.extend(call_code(reverting));
+        code.push(0x00);
+        let trace = trace_mined_call(vec![
+            (root, code),
+            (stopping, vec![0x00]),
+            // REVERT(0, 0)
+            (reverting, vec![0x60, 0x00, 0x60, 0x00, 0xfd]),
+        ], root);
+
+        let shape: Vec<(Vec<usize>, usize, Address)> = trace.calls.iter().map(|call| match call.action {
+            RPCTraceAction::Call(ref action) => (call.trace_address.clone(), call.subtraces, action.to.0),
+            _ => panic!("only calls were made"),
+        }).collect();
+        assert_eq!(shape, vec![
+            (vec![], 2, root),
+            (vec![0], 0, stopping),
+            (vec![1], 0, reverting),
+        ]);
+
+        assert!(trace.calls[0].result.is_some() && trace.calls[0].error.is_none());
+        assert!(trace.calls[1].result.is_some() && trace.calls[1].error.is_none());
+        assert!(trace.calls[2].result.is_none() && trace.calls[2].error.is_some());
+
+        let vm_trace = trace.vm_trace.unwrap();
+        let subs = vm_trace.ops.iter().filter(|op| op.sub.is_some()).count();
+        assert_eq!(subs, 2);
+    }
+
+    #[test]
+    fn reverted_transaction_is_an_error() {
+        let reverting = Address::from(0x32u64);
+        let trace = trace_mined_call(vec![(reverting, vec![0x60, 0x00, 0x60, 0x00, 0xfd])], reverting);
+
+        assert_eq!(trace.calls.len(), 1);
+        assert!(trace.calls[0].result.is_none());
+        assert!(trace.calls[0].error.is_some());
+    }
+}
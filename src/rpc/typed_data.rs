@@ -0,0 +1,241 @@
+use bigint::{H256, U256, Address};
+use hexutil::read_hex;
+use serde_json::Value;
+use sha3::{Digest, Keccak256};
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use error::Error;
+use super::{RPCTypedData, RPCTypedField};
+
+type Types = HashMap<String, Vec<RPCTypedField>>;
+
+fn keccak(data: &[u8]) -> H256 {
+    H256::from(Keccak256::digest(data).as_slice())
+}
+
+/// `Foo[]` and `Foo[3]` both have `Foo` as element type.
+fn element_type(ty: &str) -> Option<&str> {
+    if ty.ends_with(']') {
+        ty.rfind('[').map(|index| &ty[..index])
+    } else {
+        None
+    }
+}
+
+fn base_type(ty: &str) -> &str {
+    match element_type(ty) {
+        Some(element) => base_type(element),
+        None => ty,
+    }
+}
+
+fn dependencies(ty: &str, types: &Types, found: &mut Vec<String>) {
+    let ty = base_type(ty);
+    if found.iter().any(|name| name == ty) {
+        return;
+    }
+    if let Some(fields) = types.get(ty) {
+        found.push(ty.to_string());
+        for field in fields {
+            dependencies(&field.type_, types, found);
+        }
+    }
+}
+
+/// `encodeType` of EIP-712: the primary type followed by all the types
+/// it references, sorted by name.
+pub fn encode_type(primary_type: &str, types: &Types) -> Result<String, Error> {
+    let mut found = Vec::new();
+    dependencies(primary_type, types, &mut found);
+    if found.is_empty() {
+        return Err(Error::InvalidParams);
+    }
+    let primary = found.remove(0);
+    found.sort();
+    found.insert(0, primary);
+
+    let mut ret = String::new();
+    for name in found {
+        let fields: Vec<String> = types[&name].iter()
+            .map(|field| format!("{} {}", field.type_, field.name))
+            .collect();
+        ret.push_str(&format!("{}({})", name, fields.join(",")));
+    }
+    Ok(ret)
+}
+
+pub fn type_hash(primary_type: &str, types: &Types) -> Result<H256, Error> {
+    Ok(keccak(encode_type(primary_type, types)?.as_bytes()))
+}
+
+fn parse_integer(value: &Value) -> Result<U256, Error> {
+    match value {
+        &Value::Number(ref number) => match number.as_u64() {
+            Some(number) => Ok(U256::from(number)),
+            None => match number.as_i64() {
+                // `wrapping_neg` keeps `i64::MIN`, whose magnitude is
+                // 2^63 as an unsigned value.
+                Some(number) => Ok(negate(U256::from(number.wrapping_neg() as u64))),
+                None => Err(Error::InvalidParams),
+            },
+        },
+        &Value::String(ref s) => {
+            let (negative, digits) = if s.starts_with('-') { (true, &s[1..]) } else { (false, &s[..]) };
+            let abs = if digits.starts_with("0x") {
+                U256::from_str(digits)?
+            } else {
+                U256::from_dec_str(digits).map_err(|_| Error::IntError)?
+            };
+            Ok(if negative { negate(abs) } else { abs })
+        },
+        _ => Err(Error::InvalidParams),
+    }
+}
+
+/// Two's complement of a non-zero value.
+fn negate(value: U256) -> U256 {
+    if value.is_zero() {
+        value
+    } else {
+        U256::max_value() - (value - U256::one())
+    }
+}
+
+fn value_bytes(value: &Value) -> Result<Vec<u8>, Error> {
+    match value.as_str() {
+        Some(s) => Ok(read_hex(s)?),
+        None => Err(Error::InvalidParams),
+    }
+}
+
+fn encode_value(ty: &str, value: &Value, types: &Types) -> Result<H256, Error> {
+    if types.contains_key(ty) {
+        return hash_struct(ty, value, types);
+    }
+
+    if let Some(element) = element_type(ty) {
+        let items = value.as_array().ok_or(Error::InvalidParams)?;
+        let mut encoded = Vec::new();
+        for item in items {
+            encoded.extend_from_slice(&encode_value(element, item, types)?);
+        }
+        return Ok(keccak(&encoded));
+    }
+
+    match ty {
+        "string" => Ok(keccak(value.as_str().ok_or(Error::InvalidParams)?.as_bytes())),
+        "bytes" => Ok(keccak(&value_bytes(value)?)),
+        "bool" => match value.as_bool() {
+            Some(true) => Ok(H256::from(U256::one())),
+            Some(false) => Ok(H256::default()),
+            None => Err(Error::InvalidParams),
+        },
+        "address" => {
+            let address = Address::from_str(&value.as_str().ok_or(Error::InvalidParams)?.to_lowercase())
+                .map_err(|_| Error::HexError)?;
+            let mut ret = [0u8; 32];
+            ret[12..].copy_from_slice(&address);
+            Ok(H256::from(&ret[..]))
+        },
+        _ if ty.starts_with("uint") || ty.starts_with("int") => {
+            Ok(H256::from(parse_integer(value)?))
+        },
+        _ if ty.starts_with("bytes") => {
+            let bytes = value_bytes(value)?;
+            if bytes.len() > 32 {
+                return Err(Error::InvalidParams);
+            }
+            let mut ret = [0u8; 32];
+            ret[..bytes.len()].copy_from_slice(&bytes);
+            Ok(H256::from(&ret[..]))
+        },
+        _ => Err(Error::InvalidParams),
+    }
+}
+
+/// `hashStruct` of EIP-712.
+pub fn hash_struct(primary_type: &str, data: &Value, types: &Types) -> Result<H256, Error> {
+    let fields = types.get(primary_type).ok_or(Error::InvalidParams)?;
+
+    let mut encoded = Vec::new();
+    encoded.extend_from_slice(&type_hash(primary_type, types)?);
+    for field in fields {
+        let value = data.get(&field.name).ok_or(Error::InvalidParams)?;
+        encoded.extend_from_slice(&encode_value(&field.type_, value, types)?);
+    }
+    Ok(keccak(&encoded))
+}
+
+/// Hash to be signed for `eth_signTypedData`,
+/// `keccak256("\x19\x01" ++ domainSeparator ++ hashStruct(message))`.
+pub fn typed_data_hash(typed_data: &RPCTypedData) -> Result<H256, Error> {
+    let domain_separator = hash_struct("EIP712Domain", &typed_data.domain, &typed_data.types)?;
+    let message = hash_struct(&typed_data.primary_type, &typed_data.message, &typed_data.types)?;
+
+    let mut encoded = vec![0x19u8, 0x01u8];
+    encoded.extend_from_slice(&domain_separator);
+    encoded.extend_from_slice(&message);
+    Ok(keccak(&encoded))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json;
+
+    const MAIL: &str = r#"{
+        "types": {
+            "EIP712Domain": [
+                { "name": "name", "type": "string" },
+                { "name": "version", "type": "string" },
+                { "name": "chainId", "type": "uint256" },
+                { "name": "verifyingContract", "type": "address" }
+            ],
+            "Person": [
+                { "name": "name", "type": "string" },
+                { "name": "wallet", "type": "address" }
+            ],
+            "Mail": [
+                { "name": "from", "type": "Person" },
+                { "name": "to", "type": "Person" },
+                { "name": "contents", "type": "string" }
+            ]
+        },
+        "primaryType": "Mail",
+        "domain": {
+            "name": "Ether Mail",
+            "version": "1",
+            "chainId": 1,
+            "verifyingContract": "0xCcCCccccCCCCcCCCCCCcCcCccCcCCCcCcccccccC"
+        },
+        "message": {
+            "from": { "name": "Cow", "wallet": "0xCD2a3d9F938E13CD947Ec05AbC7FE734Df8DD826" },
+            "to": { "name": "Bob", "wallet": "0xbBbBBBBbbBBBbbbBbbBbbbbBBbBbbbbBbBbbBBbB" },
+            "contents": "Hello, Bob!"
+        }
+    }"#;
+
+    #[test]
+    fn eip712_mail_example() {
+        let typed_data: RPCTypedData = serde_json::from_str(MAIL).unwrap();
+
+        assert_eq!(encode_type("Mail", &typed_data.types).unwrap(),
+                   "Mail(Person from,Person to,string contents)Person(string name,address wallet)");
+        assert_eq!(hash_struct("EIP712Domain", &typed_data.domain, &typed_data.types).unwrap(),
+                   H256::from_str("0xf2cee375fa42b42143804025fc449deafd50cc031ca257e0b194a650a912090f").unwrap());
+        assert_eq!(hash_struct("Mail", &typed_data.message, &typed_data.types).unwrap(),
+                   H256::from_str("0xc52c0ee5d84264471806290a3f2c4cecfc5490626bf912d01f240d7a274b371e").unwrap());
+        assert_eq!(typed_data_hash(&typed_data).unwrap(),
+                   H256::from_str("0xbe609aee343fb3c4b28e1df9e632fca64fcfaede20f02e86244efddf30957bd2").unwrap());
+    }
+
+    #[test]
+    fn negative_integers() {
+        assert_eq!(parse_integer(&Value::String("-1".to_string())).unwrap(), U256::max_value());
+        assert_eq!(parse_integer(&Value::String("0x10".to_string())).unwrap(), U256::from(16u64));
+        assert_eq!(parse_integer(&Value::from(-1i64)).unwrap(), U256::max_value());
+        assert_eq!(parse_integer(&Value::from(i64::min_value())).unwrap(),
+                   parse_integer(&Value::String("-9223372036854775808".to_string())).unwrap());
+    }
+}
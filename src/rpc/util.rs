@@ -1,19 +1,47 @@
-use super::{Either, RPCStep, RPCTransaction, RPCBlock, RPCLog, RPCReceipt, RPCTopicFilter, RPCLogFilter, RPCTraceConfig, RPCBreakpointConfig, RPCSourceMapConfig};
+use super::{Either, RPCForkConfig, RPCGasReportEntry, RPCGenesis, RPCInclusionProof, RPCDumpAccount, RPCNodeConfig, RPCPatchConfig, RPCStateOverrides, RPCVerifyReport, RPCWatchEvent, RPCStep, RPCTrace, RPCTracedResult, RPCTransactionTrace, RPCTransaction, RPCBlock, RPCLog, RPCReceipt, RPCSessionReport, RPCTopicFilter, RPCLogFilter, RPCTraceConfig, RPCBreakpointConfig, RPCSourceMapConfig};
 use super::filter::*;
 use super::serialize::*;
 use super::solidity::*;
+use super::opcode::opcode_name;
 use error::Error;
-use miner::MinerState;
+use miner::{self, MinerState};
 
 use rlp::{self};
 use bigint::{M256, U256, H256, H2048, Address, Gas};
 use hexutil::{read_hex};
-use block::{Block, TotalHeader, HeaderHash, Account, Receipt, FromKey, Transaction, UnsignedTransaction, TransactionAction, GlobalSignaturePatch, RlpHash};
-use sputnikvm::{ValidTransaction, UntrustedTransaction, VM, VMStatus, MachineStatus, HeaderParams, SeqTransactionVM, Patch, Memory, AccountChange, AccountCommitment};
+use block::{Block, Header, TotalHeader, HeaderHash, Account, Receipt, Transaction, UnsignedTransaction, TransactionAction, RlpHash, FromKey};
+use sputnikvm::{ValidTransaction, UntrustedTransaction, VM, VMStatus, MachineStatus, HeaderParams, SeqTransactionVM, Machine, SeqMemory, Patch, Memory, AccountChange, AccountCommitment};
+use sputnikvm::errors::NotSupportedError;
 use sputnikvm_stateful::MemoryStateful;
 use std::collections::HashMap;
 use std::rc::Rc;
 use sha3::{Keccak256, Digest};
+use secp256k1::key::SecretKey;
+
+/// Hash signed by `eth_sign`, with the prefix that keeps the signature
+/// from being valid for a transaction.
+pub fn signed_message_hash(message: &[u8]) -> H256 {
+    let mut signing_message = Vec::new();
+    signing_message.extend_from_slice(b"\x19Ethereum Signed Message:\n");
+    signing_message.extend_from_slice(message.len().to_string().as_bytes());
+    signing_message.extend_from_slice(message);
+
+    H256::from(Keccak256::digest(&signing_message).as_slice())
+}
+
+/// Recoverable signature of a hash as `r ++ s ++ v`, with `v` being 27
+/// or 28 as returned by geth.
+pub fn sign_hash(secret_key: &SecretKey, hash: H256) -> Result<Vec<u8>, Error> {
+    use secp256k1::{SECP256K1, Message};
+
+    let sign = SECP256K1.sign_recoverable(&Message::from_slice(&hash).unwrap(), secret_key)?;
+    let (rec, sign) = sign.serialize_compact(&SECP256K1);
+
+    let mut ret = Vec::new();
+    ret.extend(sign.as_ref());
+    ret.push(rec.to_i32() as u8 + 27);
+    Ok(ret)
+}
 
 pub fn from_block_number<T: Into<Option<String>>>(state: &MinerState, value: T) -> Result<usize, Error> {
     let value: Option<String> = value.into();
@@ -22,6 +50,9 @@ pub fn from_block_number<T: Into<Option<String>>>(state: &MinerState, value: T)
         Ok(state.block_height())
     } else if value == Some("earliest".to_string()) {
         Ok(0)
+    } else if value.as_ref().map(|value| value.len() == 66).unwrap_or(false) {
+        let hash = H256::from(read_hex(&value.unwrap())?.as_slice());
+        Ok(state.get_block_by_hash(hash)?.header.number.as_usize())
     } else {
         let v: u64 = U256::from(read_hex(&value.unwrap())?.as_slice()).into();
         let v = v as usize;
@@ -33,100 +64,116 @@ pub fn from_block_number<T: Into<Option<String>>>(state: &MinerState, value: T)
     }
 }
 
-pub fn to_rpc_log(receipt: &Receipt, index: usize, transaction: &Transaction, block: &Block) -> RPCLog {
-    use sha3::{Keccak256, Digest};
+pub fn to_rpc_log(
+    state: &MinerState, receipt: &Receipt, index: usize, transaction: &Transaction, block: &Block
+) -> Result<RPCLog, Error> {
+    let transaction_hash = transaction.rlp_hash();
+    let context = state.receipt_context(transaction_hash)?;
 
-    let transaction_hash = H256::from(Keccak256::digest(&rlp::encode(transaction).to_vec()).as_slice());
-    let transaction_index = {
-        let mut i = 0;
-        let mut found = false;
-        for transaction in &block.transactions {
-            let other_hash = H256::from(Keccak256::digest(&rlp::encode(transaction).to_vec()).as_slice());
-            if transaction_hash == other_hash {
-                found = true;
-                break;
-            }
-            i += 1;
-        }
-        assert!(found);
-        i
-    };
+    Ok(to_rpc_log_in_context(&context, receipt, index, transaction_hash,
+                             block.header.header_hash(), block.header.number))
+}
+
+/// Same as `to_rpc_log`, with everything taken from the state given,
+/// so that it can run without holding the state.
+pub fn to_rpc_log_in_context(
+    context: &miner::ReceiptContext, receipt: &Receipt, index: usize, transaction_hash: H256,
+    block_hash: H256, block_number: U256
+) -> RPCLog {
+    let log = &receipt.logs[index];
 
     RPCLog {
         removed: false,
-        log_index: Hex(index),
-        transaction_index: Hex(transaction_index),
+        log_index: Hex(context.first_log_index + index),
+        transaction_index: Hex(context.transaction_index),
         transaction_hash: Hex(transaction_hash),
-        block_hash: Hex(block.header.header_hash()),
-        block_number: Hex(block.header.number),
-        data: Bytes(receipt.logs[index].data.clone()),
-        topics: receipt.logs[index].topics.iter().map(|t| Hex(*t)).collect(),
+        block_hash: Hex(block_hash),
+        block_number: Hex(block_number),
+        address: Hex(log.address),
+        data: Bytes(log.data.clone()),
+        topics: log.topics.iter().map(|t| Hex(*t)).collect(),
     }
 }
 
-pub fn to_rpc_receipt(state: &MinerState, receipt: Receipt, transaction: &Transaction, block: &Block) -> Result<RPCReceipt, Error> {
-    use sha3::{Keccak256, Digest};
-
-    let transaction_hash = H256::from(Keccak256::digest(&rlp::encode(transaction).to_vec()).as_slice());
-    let transaction_index = {
-        let mut i = 0;
-        let mut found = false;
-        for transaction in &block.transactions {
-            let other_hash = H256::from(Keccak256::digest(&rlp::encode(transaction).to_vec()).as_slice());
-            if transaction_hash == other_hash {
-                found = true;
-                break;
-            }
-            i += 1;
-        }
-        assert!(found);
-        i
+/// Gas used by the transaction at `index` of `block` alone.
+fn transaction_gas_used(state: &MinerState, receipt: &Receipt, index: usize, block: &Block) -> Result<Gas, Error> {
+    let previous = match index {
+        0 => None,
+        index => Some(state.get_receipt_by_transaction_hash(block.transactions[index - 1].rlp_hash())?),
     };
+    Ok(miner::transaction_gas_used(receipt, previous.as_ref()))
+}
 
-    let cumulative_gas_used = {
-        let mut sum = Gas::zero();
-
-        for i in 0..(transaction_index + 1) {
-            let other_hash = H256::from(Keccak256::digest(&rlp::encode(&block.transactions[i]).to_vec()).as_slice());
-            sum = sum + state.get_receipt_by_transaction_hash(other_hash)?.used_gas;
-        }
-        sum
-    };
-
-    let contract_address = {
-        if transaction.action == TransactionAction::Create {
-            Some(transaction.address().unwrap())
-        } else {
-            None
-        }
-    };
+pub fn to_rpc_receipt<P: Patch>(state: &MinerState, receipt: Receipt, transaction: &Transaction, block: &Block) -> Result<RPCReceipt, Error> {
+    let transaction_hash = transaction.rlp_hash();
+    let context = state.receipt_context(transaction_hash)?;
 
     Ok(RPCReceipt {
         transaction_hash: Hex(transaction_hash),
-        transaction_index: Hex(transaction_index),
+        transaction_index: Hex(context.transaction_index),
         block_hash: Hex(block.header.header_hash()),
         block_number: Hex(block.header.number),
-        cumulative_gas_used: Hex(cumulative_gas_used),
-        gas_used: Hex(receipt.used_gas),
-        contract_address: contract_address.map(|v| Hex(v)),
+        cumulative_gas_used: Hex(context.cumulative_gas_used),
+        gas_used: Hex(transaction_gas_used(state, &receipt, context.transaction_index, block)?),
+        contract_address: context.contract_address.map(|v| Hex(v)),
         logs: {
             let mut ret = Vec::new();
             for i in 0..receipt.logs.len() {
-                ret.push(to_rpc_log(&receipt, i, transaction, block));
+                ret.push(to_rpc_log(state, &receipt, i, transaction, block)?);
             }
             ret
         },
-        root: Hex(receipt.state_root),
-        status: if state.receipt_status(transaction.rlp_hash()) { 1 } else { 0 },
+        root: if miner::is_byzantium::<P>() { None } else { Some(Hex(receipt.state_root)) },
+        status: Hex(if state.receipt_status(transaction.rlp_hash()) { 1 } else { 0 }),
+        revert_reason: state.revert_reason(transaction.rlp_hash()),
+    })
+}
+
+pub fn to_rpc_session_report(state: &MinerState, session: &str) -> Result<RPCSessionReport, Error> {
+    let mut transactions = Vec::new();
+    let mut pending_transactions = Vec::new();
+    let mut gas_used = Gas::zero();
+    let mut logs = Vec::new();
+    let mut contracts_created = Vec::new();
+
+    for hash in state.session_transaction_hashes(session) {
+        let block = match state.get_transaction_block_hash_by_hash(hash) {
+            Ok(block_hash) => state.get_block_by_hash(block_hash)?,
+            Err(Error::NotFound) => {
+                pending_transactions.push(Hex(hash));
+                continue;
+            },
+            Err(e) => return Err(e),
+        };
+        let transaction = state.get_transaction_by_hash(hash)?;
+        let receipt = state.get_receipt_by_transaction_hash(hash)?;
+
+        let context = state.receipt_context(hash)?;
+        gas_used = gas_used + transaction_gas_used(state, &receipt, context.transaction_index, &block)?;
+        for i in 0..receipt.logs.len() {
+            logs.push(to_rpc_log(state, &receipt, i, &transaction, &block)?);
+        }
+        if let Some(address) = context.contract_address {
+            if state.receipt_status(hash) {
+                contracts_created.push(Hex(address));
+            }
+        }
+        transactions.push(Hex(hash));
+    }
+
+    Ok(RPCSessionReport {
+        transactions, pending_transactions,
+        gas_used: Hex(gas_used),
+        logs, contracts_created,
     })
 }
 
-pub fn to_rpc_transaction(transaction: Transaction, block: Option<&Block>) -> RPCTransaction {
+pub fn to_rpc_transaction(state: &MinerState, transaction: Transaction, block: Option<&Block>) -> RPCTransaction {
     use sha3::{Keccak256, Digest};
     let hash = H256::from(Keccak256::digest(&rlp::encode(&transaction).to_vec()).as_slice());
 
     RPCTransaction {
-        from: Some(Hex(transaction.caller().unwrap())),
+        from: Some(Hex(state.transaction_sender(&transaction).unwrap())),
         to: match transaction.action {
             TransactionAction::Call(address) => Some(Hex(address)),
             _ => None, /* Create & Create2(???) */
@@ -163,82 +210,134 @@ pub fn to_rpc_transaction(transaction: Transaction, block: Option<&Block>) -> RP
                 None
             }
         },
+        trace: None,
     }
 }
 
-pub fn to_rpc_block(block: Block, total_header: TotalHeader, full_transactions: bool) -> RPCBlock {
+/// Report a call that ran past the VM memory limit distinctly from
+/// other failures.
+pub fn check_memory_limit<P: Patch>(vm: &SeqTransactionVM<P>) -> Result<(), Error> {
+    match vm.status() {
+        VMStatus::ExitedNotSupported(NotSupportedError::MemoryIndexNotSupported) =>
+            Err(Error::MemoryLimitExceeded(P::memory_limit())),
+        _ => Ok(()),
+    }
+}
+
+pub fn to_rpc_traced_result<T, P: Patch>(
+    result: T, steps: Vec<RPCStep>, vm: &SeqTransactionVM<P>
+) -> RPCTracedResult<T> {
+    let trace = to_rpc_trace(steps, vm);
+    RPCTracedResult {
+        result,
+        failed: trace.failed,
+        trace,
+    }
+}
+
+pub fn to_rpc_trace<P: Patch>(steps: Vec<RPCStep>, vm: &SeqTransactionVM<P>) -> RPCTrace {
+    RPCTrace {
+        gas: Hex(vm.used_gas()),
+        failed: match vm.status() {
+            VMStatus::ExitedOk => false,
+            _ => true,
+        },
+        return_value: Bytes(vm.out().into()),
+        struct_logs: steps,
+    }
+}
+
+pub fn to_rpc_block(state: &MinerState, block: Block, total_header: TotalHeader, full_transactions: bool) -> RPCBlock {
     use sha3::{Keccak256, Digest};
-    let logs_bloom: H2048 = block.header.logs_bloom.clone().into();
+
+    let transactions = if full_transactions {
+        Either::Right(block.transactions.iter().map(|t| to_rpc_transaction(state, t.clone(), Some(&block))).collect())
+    } else {
+        Either::Left(block.transactions.iter().map(|t| {
+            let encoded = rlp::encode(t).to_vec();
+            Hex(H256::from(Keccak256::digest(&encoded).as_slice()))
+        }).collect())
+    };
+    let uncles = block.ommers.iter().map(|u| Hex(u.header_hash())).collect();
+
+    to_rpc_block_with_header(&block.header, total_header.total_difficulty(), transactions, uncles)
+}
+
+/// Ommer header as a block without transactions or uncles, as returned
+/// by the uncle RPCs.
+pub fn to_rpc_uncle(state: &MinerState, uncle: &Header) -> RPCBlock {
+    let total_difficulty = match state.get_total_header_by_hash(uncle.parent_hash) {
+        Ok(parent) => parent.total_difficulty() + uncle.difficulty,
+        Err(_) => uncle.difficulty,
+    };
+
+    to_rpc_block_with_header(uncle, total_difficulty, Either::Left(Vec::new()), Vec::new())
+}
+
+fn to_rpc_block_with_header(
+    header: &Header, total_difficulty: U256,
+    transactions: Either<Vec<Hex<H256>>, Vec<RPCTransaction>>, uncles: Vec<Hex<H256>>
+) -> RPCBlock {
+    let logs_bloom: H2048 = header.logs_bloom.clone().into();
 
     RPCBlock {
-        number: Hex(block.header.number),
-        hash: Hex(block.header.header_hash()),
-        parent_hash: Hex(block.header.header_hash()),
-        nonce: Hex(block.header.nonce),
-        sha3_uncles: Hex(block.header.ommers_hash),
+        number: Hex(header.number),
+        hash: Hex(header.header_hash()),
+        parent_hash: Hex(header.parent_hash),
+        nonce: Hex(header.nonce),
+        sha3_uncles: Hex(header.ommers_hash),
         logs_bloom: Hex(logs_bloom),
-        transactions_root: Hex(block.header.transactions_root),
-        state_root: Hex(block.header.state_root),
-        receipts_root: Hex(block.header.receipts_root),
-        miner: Hex(block.header.beneficiary),
-        difficulty: Hex(block.header.difficulty),
-        total_difficulty: Hex(total_header.total_difficulty()),
+        transactions_root: Hex(header.transactions_root),
+        state_root: Hex(header.state_root),
+        receipts_root: Hex(header.receipts_root),
+        miner: Hex(header.beneficiary),
+        difficulty: Hex(header.difficulty),
+        total_difficulty: Hex(total_difficulty),
 
         // TODO: change this to the correct one after the Typhoon is over...
-        extra_data: Bytes(rlp::encode(&block.header.extra_data).to_vec()),
-
-        size: Hex(rlp::encode(&block.header).to_vec().len()),
-        gas_limit: Hex(block.header.gas_limit),
-        gas_used: Hex(block.header.gas_used),
-        timestamp: Hex(block.header.timestamp),
-        transactions: if full_transactions {
-            Either::Right(block.transactions.iter().map(|t| to_rpc_transaction(t.clone(), Some(&block))).collect())
-        } else {
-            Either::Left(block.transactions.iter().map(|t| {
-                let encoded = rlp::encode(t).to_vec();
-                Hex(H256::from(Keccak256::digest(&encoded).as_slice()))
-            }).collect())
-        },
-        uncles: block.ommers.iter().map(|u| Hex(u.header_hash())).collect(),
+        extra_data: Bytes(rlp::encode(&header.extra_data).to_vec()),
+
+        size: Hex(rlp::encode(header).to_vec().len()),
+        gas_limit: Hex(header.gas_limit),
+        gas_used: Hex(header.gas_used),
+        timestamp: Hex(header.timestamp),
+        transactions,
+        uncles,
     }
 }
 
-pub fn to_signed_transaction(state: &MinerState, transaction: RPCTransaction, stateful: &MemoryStateful) -> Result<Transaction, Error> {
+pub fn to_signed_transaction<P: Patch>(state: &MinerState, transaction: RPCTransaction) -> Result<Transaction, Error> {
     let address = match transaction.from {
         Some(val) => val.0,
         None => Address::default(),
     };
-    let secret_key = {
-        let mut secret_key = None;
-        for key in state.accounts() {
-            if Address::from_secret_key(&key)? == address {
-                secret_key = Some(key);
-            }
-        }
-        match secret_key {
-            Some(val) => val,
-            None => return Err(Error::NotFound),
-        }
-    };
-    let block = state.get_block_by_number(state.block_height());
-    let trie = stateful.state_of(block.header.state_root);
+    let secret_key = state.secret_key(address).ok_or(Error::NotFound)?;
 
-    let account: Option<Account> = trie.get(&address);
+    to_signed_transaction_with_key::<P>(state, transaction, &secret_key)
+}
 
+/// Sign a transaction, filling the nonce, gas price and gas limit it
+/// does not give. The gas limit is estimated against the pending state
+/// and raised by the gas margin of the node.
+pub fn to_signed_transaction_with_key<P: Patch>(
+    state: &MinerState, transaction: RPCTransaction, secret_key: &SecretKey
+) -> Result<Transaction, Error> {
+    let address = match transaction.from {
+        Some(ref val) => val.0,
+        None => Address::from_secret_key(secret_key)?,
+    };
     let unsigned = UnsignedTransaction {
         nonce: match transaction.nonce {
             Some(val) => val.0,
-            None => {
-                account.as_ref().map(|account| account.nonce).unwrap_or(U256::zero())
-            }
+            None => state.next_nonce(address)?,
         },
         gas_price: match transaction.gas_price {
             Some(val) => val.0,
-            None => Gas::zero(),
+            None => state.min_gas_price(),
         },
         gas_limit: match transaction.gas {
             Some(val) => val.0,
-            None => Gas::from(90000u64),
+            None => auto_gas_limit::<P>(state, &transaction, address)?,
         },
         action: match transaction.to {
             Some(val) => TransactionAction::Call(val.0),
@@ -253,7 +352,7 @@ pub fn to_signed_transaction(state: &MinerState, transaction: RPCTransaction, st
             None => Vec::new(),
         },
     };
-    let transaction = unsigned.sign::<GlobalSignaturePatch>(&secret_key);
+    let transaction = miner::sign_transaction(unsigned, secret_key, state.chain_id());
 
     Ok(transaction)
 }
@@ -264,8 +363,7 @@ pub fn to_valid_transaction<P: Patch>(state: &MinerState, transaction: RPCTransa
         None => Address::default(),
     };
 
-    let block = state.get_block_by_number(state.block_height());
-    let trie = stateful.state_of(block.header.state_root);
+    let trie = stateful.state_of(stateful.root());
 
     let account: Option<Account> = trie.get(&address);
     let commitment = match account {
@@ -315,6 +413,195 @@ pub fn to_valid_transaction<P: Patch>(state: &MinerState, transaction: RPCTransa
     Ok(valid)
 }
 
+/// Execute a call on top of the state of the given block.
+pub fn call_at_block<P: Patch>(
+    state: &MinerState, transaction: RPCTransaction, number: usize
+) -> Result<SeqTransactionVM<P>, Error> {
+    let block = state.get_block_by_number(number);
+    let mut stateful = state.stateful_at(block.header.state_root);
+
+    let valid = to_valid_transaction::<P>(state, transaction, &stateful)?;
+    miner::call::<P>(state.fork(), state.account_cache(), &mut stateful, valid, &HeaderParams::from(&block.header),
+                     &state.get_last_256_block_hashes_by_number(number))
+}
+
+fn gas_report_entry<P: Patch>(
+    chain: &str, state: &MinerState, transaction: RPCTransaction, number: usize
+) -> RPCGasReportEntry {
+    let block = state.get_block_by_number(number);
+    let mut stateful = state.stateful_at(state.state_root_at(number));
+
+    let vm = to_valid_transaction::<P>(state, transaction, &stateful).and_then(|valid| {
+        let vm: SeqTransactionVM<P> = miner::call::<P>(
+            state.fork(), state.account_cache(), &mut stateful, valid, &HeaderParams::from(&block.header),
+            &state.get_last_256_block_hashes_by_number(number))?;
+        check_memory_limit(&vm)?;
+        Ok(vm)
+    });
+
+    match vm {
+        Ok(vm) => RPCGasReportEntry {
+            chain: chain.to_string(),
+            gas_used: Some(Hex(vm.used_gas())),
+            success: match vm.status() {
+                VMStatus::ExitedOk => true,
+                _ => false,
+            },
+            output: Bytes(vm.out().into()),
+            error: None,
+        },
+        Err(err) => RPCGasReportEntry {
+            chain: chain.to_string(),
+            gas_used: None,
+            success: false,
+            output: Bytes(Vec::new()),
+            error: Some(err.to_string()),
+        },
+    }
+}
+
+/// Execute a call on the state of the given block under the rules of
+/// the selected chain, then of each Ethereum hardfork.
+pub fn gas_report<P: Patch>(state: &MinerState, transaction: RPCTransaction, number: usize) -> Vec<RPCGasReportEntry> {
+    use sputnikvm_network_foundation::{FrontierPatch, HomesteadPatch, EIP150Patch, SpuriousDragonPatch, ByzantiumPatch};
+
+    vec![
+        gas_report_entry::<P>(state.chain(), state, transaction.clone(), number),
+        gas_report_entry::<miner::DevPatch<FrontierPatch>>(
+            "foundation-frontier", state, transaction.clone(), number),
+        gas_report_entry::<miner::DevPatch<HomesteadPatch>>(
+            "foundation-homestead", state, transaction.clone(), number),
+        gas_report_entry::<miner::DevPatch<EIP150Patch>>(
+            "foundation-eip150", state, transaction.clone(), number),
+        gas_report_entry::<miner::DevPatch<SpuriousDragonPatch>>(
+            "foundation-spurious-dragon", state, transaction.clone(), number),
+        gas_report_entry::<miner::DevPatch<ByzantiumPatch>>(
+            "foundation-byzantium", state, transaction, number),
+    ]
+}
+
+fn with_gas_limit(valid: &ValidTransaction, gas_limit: Gas) -> ValidTransaction {
+    ValidTransaction {
+        caller: valid.caller,
+        gas_price: valid.gas_price,
+        gas_limit,
+        action: valid.action.clone(),
+        value: valid.value,
+        input: valid.input.clone(),
+        nonce: valid.nonce,
+    }
+}
+
+/// Lowest gas limit, up to `cap`, with which the transaction succeeds.
+/// The gas used by a run at `cap` is tried first, as it is enough
+/// unless refunds or the 63/64 call gas rule are involved, then the
+/// limit is binary-searched.
+pub fn estimate_gas<P: Patch>(
+    state: &MinerState, stateful: &mut MemoryStateful<'static>, valid: ValidTransaction,
+    header: &HeaderParams, last_hashes: &[H256], cap: Gas
+) -> Result<Gas, Error> {
+    let mut cap = cap;
+    if valid.gas_price != Gas::zero() {
+        let caller = valid.caller.unwrap_or(Address::default());
+        let account: Option<Account> = stateful.state_of(stateful.root()).get(&caller);
+        let balance = account.map(|account| account.balance).unwrap_or(U256::zero());
+        let available = if balance > valid.value { balance - valid.value } else { U256::zero() };
+        let affordable = Gas::from(available / U256::from(valid.gas_price));
+        if affordable < cap {
+            cap = affordable;
+        }
+    }
+
+    let vm: SeqTransactionVM<P> = miner::call::<P>(
+        state.fork(), state.account_cache(), stateful, with_gas_limit(&valid, cap), header, last_hashes)?;
+    check_memory_limit(&vm)?;
+    match vm.status() {
+        VMStatus::ExitedOk => (),
+        _ => return Err(Error::ExecutionError(vm.out().into())),
+    }
+
+    let mut succeeds = |gas: Gas| -> Result<bool, Error> {
+        let vm: SeqTransactionVM<P> = miner::call::<P>(
+            state.fork(), state.account_cache(), stateful, with_gas_limit(&valid, gas), header, last_hashes)?;
+        Ok(match vm.status() {
+            VMStatus::ExitedOk => true,
+            _ => false,
+        })
+    };
+
+    let used_gas = vm.used_gas();
+    if succeeds(used_gas)? {
+        return Ok(used_gas);
+    }
+
+    let mut lo = used_gas;
+    let mut hi = cap;
+    while hi - lo > Gas::from(1u64) {
+        let mid = lo + (hi - lo) / Gas::from(2u64);
+        if succeeds(mid)? {
+            hi = mid;
+        } else {
+            lo = mid;
+        }
+    }
+    Ok(hi)
+}
+
+/// Gas limit of a transaction sent without one: its estimate against
+/// the pending state, raised by the gas margin of the node and capped
+/// by the block gas limit.
+fn auto_gas_limit<P: Patch>(state: &MinerState, transaction: &RPCTransaction, from: Address) -> Result<Gas, Error> {
+    let (mut stateful, header) = pending_state::<P>(state)?;
+    let last_hashes = state.get_last_256_block_hashes_by_number(state.block_height() + 1);
+
+    let mut transaction = transaction.clone();
+    transaction.from = Some(Hex(from));
+    let valid = to_valid_transaction::<P>(state, transaction, &stateful)?;
+    let gas = estimate_gas::<P>(state, &mut stateful, valid, &header, &last_hashes, miner::block_gas_limit())?;
+
+    let padded = gas + gas * Gas::from(state.gas_margin()) / Gas::from(100u64);
+    Ok(if padded > miner::block_gas_limit() { miner::block_gas_limit() } else { padded })
+}
+
+/// State of the block being mined, i.e. the head with all pending
+/// transactions applied, together with the header they are applied in.
+pub fn pending_state<P: Patch>(state: &MinerState) -> Result<(MemoryStateful<'static>, HeaderParams), Error> {
+    let block = state.current_block();
+    let last_hashes = state.get_last_256_block_hashes();
+
+    let mut header = HeaderParams::from(&block.header);
+    header.number = header.number + U256::one();
+
+    let mut stateful = state.stateful_at(block.header.state_root);
+    for transaction in state.pending_transactions() {
+        let valid = match miner::valid_transaction::<P>(state, &stateful, &transaction) {
+            Ok(valid) => valid,
+            Err(_) => continue,
+        };
+        let vm: SeqTransactionVM<P> = miner::call::<P>(state.fork(), state.account_cache(), &mut stateful, valid, &header, &last_hashes)?;
+        let accounts = miner::account_changes(state, &stateful, &vm);
+        stateful.transit(&accounts);
+    }
+
+    Ok((stateful, header))
+}
+
+/// State, header and block hashes a call object runs against at
+/// `block`, the pending block included.
+pub fn call_state<P: Patch>(
+    state: &MinerState, block: Option<String>
+) -> Result<(MemoryStateful<'static>, HeaderParams, Vec<H256>), Error> {
+    if block == Some("pending".to_string()) {
+        let (stateful, header) = pending_state::<P>(state)?;
+        Ok((stateful, header, state.get_last_256_block_hashes_by_number(state.block_height() + 1)))
+    } else {
+        let number = from_block_number(state, block)?;
+        let block = state.get_block_by_number(number);
+        Ok((state.stateful_at(state.state_root_at(number)), HeaderParams::from(&block.header),
+            state.get_last_256_block_hashes_by_number(number)))
+    }
+}
+
 pub fn from_topic_filter(filter: Option<RPCTopicFilter>) -> Result<TopicFilter, Error> {
     Ok(match filter {
         None => TopicFilter::All,
@@ -352,137 +639,258 @@ pub fn from_log_filter(state: &MinerState, filter: RPCLogFilter) -> Result<LogFi
     })
 }
 
+pub fn to_rpc_verify_report(report: miner::VerifyReport, repaired: bool) -> RPCVerifyReport {
+    RPCVerifyReport {
+        roots: report.roots,
+        nodes: report.nodes,
+        missing: report.missing.into_iter().map(Hex).collect(),
+        corrupt: report.corrupt.into_iter().map(Hex).collect(),
+        missing_code: report.missing_code.into_iter().map(Hex).collect(),
+        first_broken_block: report.first_broken_block.map(Hex),
+        repaired,
+    }
+}
+
+pub fn to_rpc_inclusion_proof(proof: miner::InclusionProof, block: &Block, index: usize) -> RPCInclusionProof {
+    RPCInclusionProof {
+        block_hash: Hex(block.header.header_hash()),
+        block_number: Hex(block.header.number),
+        transaction_index: Hex(index),
+        root: Hex(proof.root),
+        key: Bytes(proof.key),
+        value: Bytes(proof.value),
+        proof: proof.nodes.into_iter().map(Bytes).collect(),
+    }
+}
+
+pub fn to_rpc_dump_account(dumped: miner::DumpedAccount) -> RPCDumpAccount {
+    RPCDumpAccount {
+        balance: Hex(dumped.account.balance),
+        code: Bytes(dumped.code),
+        code_hash: Hex(dumped.account.code_hash),
+        nonce: Hex(dumped.account.nonce),
+        root: Hex(dumped.account.storage_root),
+        storage: dumped.storage.into_iter().map(|(index, value)| (Hex(index), Hex(value))).collect(),
+        unknown_storage: dumped.unknown_storage.into_iter().map(|(key, value)| (Hex(key), Hex(value))).collect(),
+    }
+}
+
+pub fn to_rpc_watch_event(event: &miner::WatchEvent) -> RPCWatchEvent {
+    RPCWatchEvent {
+        name: event.name.clone(),
+        path: event.path.display().to_string(),
+        address: Hex(event.address),
+        transaction_hash: event.transaction_hash.map(Hex),
+    }
+}
+
+pub fn from_genesis(genesis: RPCGenesis) -> (Vec<miner::GenesisAccount>, Option<u64>) {
+    // Sorted, so that the genesis is built the same way whatever the
+    // order of the JSON object.
+    let mut alloc: Vec<miner::GenesisAccount> = genesis.alloc.into_iter().map(|(address, account)| {
+        let mut storage: Vec<(U256, M256)> = account.storage.into_iter()
+            .map(|(index, value)| (index.0, M256::from(value.0)))
+            .collect();
+        storage.sort_by(|a, b| a.0.cmp(&b.0));
+
+        miner::GenesisAccount {
+            address: address.0,
+            balance: account.balance.0,
+            nonce: account.nonce.map(|nonce| nonce.0).unwrap_or(U256::zero()),
+            code: account.code.map(|code| code.0).unwrap_or(Vec::new()),
+            storage,
+        }
+    }).collect();
+    alloc.sort_by(|a, b| a.address.cmp(&b.address));
+
+    (alloc, genesis.timestamp.map(|timestamp| timestamp.0.as_u64()))
+}
+
+pub fn to_rpc_node_config<P: Patch>(state: &MinerState, cheats: Vec<String>) -> RPCNodeConfig {
+    RPCNodeConfig {
+        chain: state.chain().to_string(),
+        network_id: format!("{}", state.network_id()),
+        chain_id: state.chain_id().map(Hex),
+        patch: RPCPatchConfig {
+            code_deposit_limit: P::code_deposit_limit(),
+            callstack_limit: P::callstack_limit(),
+            memory_limit: state.memory_limit().unwrap_or(P::memory_limit()),
+            has_delegate_call: P::has_delegate_call(),
+            has_static_call: P::has_static_call(),
+            has_revert: P::has_revert(),
+            has_return_data: P::has_return_data(),
+            call_create_l64_after_gas: P::call_create_l64_after_gas(),
+            err_on_call_with_more_gas: P::err_on_call_with_more_gas(),
+            force_code_deposit: P::force_code_deposit(),
+            precompiles: P::precompileds().iter().map(|&(address, _, _)| Hex(address)).collect(),
+        },
+        block_gas_limit: Hex(miner::block_gas_limit()),
+        block_reward: Hex(miner::block_reward::<P>()),
+        instamine: state.mining_schedule().instamine,
+        mining_interval: state.mining_schedule().interval,
+        fork: state.fork().map(|fork| RPCForkConfig {
+            url: fork.url().to_string(),
+            block: Hex(fork.block()),
+        }),
+        selfdestruct: match state.selfdestruct_mode() {
+            miner::SelfdestructMode::Legacy => "legacy".to_string(),
+            miner::SelfdestructMode::Eip6780 => "eip6780".to_string(),
+        },
+        fee_payer: state.fee_policy().sponsor().map(Hex),
+        log_retention: state.log_retention().map(|retention| retention.blocks),
+        freeze_depth: state.freeze_config().map(|freeze| freeze.depth),
+        cheats,
+    }
+}
+
+pub fn from_state_overrides(overrides: RPCStateOverrides) -> miner::StateOverrides {
+    let from_storage = |storage: HashMap<Hex<U256>, Hex<U256>>| -> HashMap<U256, M256> {
+        storage.into_iter().map(|(index, value)| (index.0, M256::from(value.0))).collect()
+    };
+
+    overrides.into_iter().map(|(address, account)| {
+        (address.0, miner::AccountOverride {
+            balance: account.balance.map(|balance| balance.0),
+            nonce: account.nonce.map(|nonce| nonce.0),
+            code: account.code.map(|code| code.0),
+            state: account.state.map(&from_storage),
+            state_diff: account.state_diff.map(&from_storage),
+        })
+    }).collect()
+}
+
+/// State of `block` right before its transaction at `index`, replaying
+/// the transactions preceding it on the state of its parent the way
+/// the miner executed them.
+pub fn state_before_transaction<P: Patch>(
+    state: &MinerState, block: &Block, index: usize, last_hashes: &[H256]
+) -> Result<MemoryStateful<'static>, Error> {
+    let parent = state.get_block_by_hash(block.header.parent_hash)?;
+    let header = HeaderParams::from(&block.header);
+
+    let mut stateful = state.stateful_at(parent.header.state_root);
+    for transaction in &block.transactions[..index] {
+        let valid = miner::valid_transaction::<P>(state, &stateful, transaction)?;
+        let caller = valid.caller.unwrap_or(Address::default());
+        let gas_price = valid.gas_price;
+        let vm: SeqTransactionVM<P> = miner::call::<P>(state.fork(), state.account_cache(), &mut stateful, valid, &header, last_hashes)?;
+        let accounts = miner::mined_account_changes(state, &stateful, caller, gas_price, &vm);
+        stateful.transit(&accounts);
+    }
+
+    Ok(stateful)
+}
+
+/// Trace every transaction of `block` in order, each on the state left
+/// by the ones before it.
+pub fn trace_block<P: Patch>(
+    state: &MinerState, block: &Block, config: &RPCTraceConfig
+) -> Result<Vec<RPCTransactionTrace>, Error> {
+    let last_hashes = state.get_last_256_block_hashes_by_number(block.header.number.as_usize());
+    let header = HeaderParams::from(&block.header);
+
+    let mut stateful = state_before_transaction::<P>(state, block, 0, &last_hashes)?;
+    let mut traces = Vec::new();
+    for transaction in &block.transactions {
+        let valid = miner::valid_transaction::<P>(state, &stateful, transaction)?;
+        let caller = valid.caller.unwrap_or(Address::default());
+        let gas_price = valid.gas_price;
+        let (steps, vm) = trace_valid_transaction::<P>(state, &mut stateful, valid, &header, &last_hashes, config)?;
+
+        let accounts = miner::mined_account_changes(state, &stateful, caller, gas_price, &vm);
+        stateful.transit(&accounts);
+
+        traces.push(RPCTransactionTrace {
+            tx_hash: Hex(transaction.rlp_hash()),
+            result: to_rpc_trace(steps, &vm),
+        });
+    }
+
+    Ok(traces)
+}
+
+/// Re-execute the mined transaction `hash` on the state it ran on,
+/// reporting its execution to `tracer`.
+pub fn replay_with_tracer<P: Patch>(
+    state: &MinerState, hash: H256, tracer: &mut miner::Tracer
+) -> Result<SeqTransactionVM<P>, Error> {
+    let block = state.get_block_by_hash(state.get_transaction_block_hash_by_hash(hash)?)?;
+    let last_hashes = state.get_last_256_block_hashes_by_number(block.header.number.as_usize());
+    let index = block.transactions.iter()
+        .position(|transaction| transaction.rlp_hash() == hash)
+        .ok_or(Error::NotFound)?;
+
+    let mut stateful = state_before_transaction::<P>(state, &block, index, &last_hashes)?;
+    let valid = miner::valid_transaction::<P>(state, &stateful, &block.transactions[index])?;
+    miner::call_with_tracer::<P>(state.fork(), state.account_cache(), &mut stateful, valid, &HeaderParams::from(&block.header),
+                                 &last_hashes, tracer)
+}
+
 pub fn replay_transaction<P: Patch>(
-    stateful: &MemoryStateful<'static>, transaction: Transaction, block: &Block,
+    state: &MinerState, stateful: &mut MemoryStateful<'static>, transaction: Transaction, block: &Block,
     last_hashes: &[H256], config: &RPCTraceConfig
 ) -> Result<(Vec<RPCStep>, SeqTransactionVM<P>), Error> {
-    let valid = stateful.to_valid::<P>(&transaction)?;
-    let mut vm = SeqTransactionVM::<P>::new(valid, HeaderParams::from(&block.header));
+    let valid = miner::valid_transaction::<P>(state, stateful, &transaction)?;
+    trace_valid_transaction::<P>(state, stateful, valid, &HeaderParams::from(&block.header), last_hashes, config)
+}
+
+/// Execute a transaction step by step, logging each opcode as geth
+/// does: the gas left and the stack, memory and storage of the frame
+/// before the opcode runs, and the gas the opcode cost. The cost of
+/// calls and creations includes the gas passed to the callee. What the
+/// VM requires is fulfilled as when mining, through the fork and the
+/// account cache of `state`.
+pub fn trace_valid_transaction<P: Patch>(
+    state: &MinerState, stateful: &mut MemoryStateful<'static>, valid: ValidTransaction, header: &HeaderParams,
+    last_hashes: &[H256], config: &RPCTraceConfig
+) -> Result<(Vec<RPCStep>, SeqTransactionVM<P>), Error> {
+    let breakpoints = match config.breakpoints {
+        Some(ref breakpoints) => Some(parse_source(&breakpoints.breakpoints)?),
+        None => None,
+    };
+
+    let mut vm = SeqTransactionVM::<P>::new(valid, header.clone());
     let mut steps = Vec::new();
-    let mut last_gas = Gas::zero();
 
     loop {
         match vm.status() {
-            VMStatus::ExitedOk | VMStatus::ExitedErr(_) => break,
-            VMStatus::ExitedNotSupported(_) => panic!(),
+            VMStatus::ExitedOk | VMStatus::ExitedErr(_) | VMStatus::ExitedNotSupported(_) => break,
             VMStatus::Running => {
-                stateful.step(&mut vm, block.header.number, &last_hashes);
-                let gas = vm.used_gas();
-                let gas_cost = gas - last_gas;
-
-                last_gas = gas;
-
-                if let Some(machine) = vm.current_machine() {
-                    let depth = machine.state().depth;
-                    let error = match machine.status() {
-                        MachineStatus::ExitedErr(err) => format!("{:?}", err),
-                        _ => "".to_string(),
-                    };
-                    let pc = machine.pc().position();
-                    let opcode_pc = machine.pc().opcode_position();
-                    let op = machine.pc().code()[pc];
-                    let code_hash = H256::from(Keccak256::digest(machine.pc().code()).as_slice());
-                    let address = machine.state().context.address;
-
-                    let memory = if config.disable_memory {
-                        None
-                    } else {
-                        let mut ret = Vec::new();
-                        for i in 0..machine.state().memory.len() {
-                            ret.push(machine.state().memory.read_raw(U256::from(i)));
-                        }
-                        Some(vec![Bytes(ret)])
-                    };
-                    let stack = if config.disable_stack {
-                        None
-                    } else {
-                        let mut ret = Vec::new();
-
-                        for i in 0..machine.state().stack.len() {
-                            ret.push(Hex(machine.state().stack.peek(i).unwrap()));
-                        }
-                        Some(ret)
-                    };
-                    let storage = if config.disable_storage {
-                        None
-                    } else {
-                        let mut for_storage = None;
-                        let context_address = machine.state().context.address;
-
-                        for account in machine.state().account_state.accounts() {
-                            match account {
-                                &AccountChange::Full { address, ref changing_storage, .. } => {
-                                    if address == context_address {
-                                        for_storage = Some(changing_storage.clone());
-                                    }
-                                },
-                                &AccountChange::Create { address, ref storage, .. } => {
-                                    if address == context_address {
-                                        for_storage = Some(storage.clone());
-                                    }
-                                },
-                                _ => (),
-                            }
+                let step = match vm.current_machine() {
+                    Some(machine) => {
+                        let running = match machine.status() {
+                            MachineStatus::Running => true,
+                            _ => false,
+                        };
+                        let pc = machine.pc().position();
+                        if running && pc < machine.pc().code().len() {
+                            trace_step(machine, config, breakpoints.as_ref().map(|b| b.as_slice()))?
+                        } else {
+                            None
                         }
+                    },
+                    None => None,
+                };
+
+                if let Err(require) = vm.step() {
+                    // The opcode did not run, and is logged once it does.
+                    miner::fulfill(state.fork(), state.account_cache(), stateful, &mut vm, require,
+                                   header, last_hashes)?;
+                    continue;
+                }
 
-                        let storage = for_storage;
-                        let mut ret = HashMap::new();
-                        if let Some(storage) = storage {
-                            let storage: HashMap<U256, M256> = storage.clone().into();
-                            for (key, value) in storage {
-                                ret.insert(Hex(key), Hex(value));
+                if let Some(mut step) = step {
+                    // Right after the step, the current machine is still
+                    // the one that ran the opcode, unless it was popped.
+                    if let Some(machine) = vm.current_machine() {
+                        if machine.state().depth + 1 == step.depth {
+                            step.gas_cost = Hex(step.gas.0 - machine.state().available_gas());
+                            if let MachineStatus::ExitedErr(err) = machine.status() {
+                                step.error = format!("{:?}", err);
                             }
                         }
-                        Some(ret)
-                    };
-
-                    if let &Some(RPCBreakpointConfig {
-                        ref source_map, ref breakpoints
-                    }) = &config.breakpoints {
-                        if let Some(&RPCSourceMapConfig { ref source_map, ref source_list }) =
-                            source_map.get(&Hex(code_hash))
-                        {
-                            let source_map = parse_source_map(source_map, source_list)?;
-                            let source_map = &source_map[opcode_pc];
-
-                            let breakpoints = parse_source(breakpoints)?;
-                            if let Some((breakpoint_index, breakpoint)) =
-                                source_map.source.find_intersection(&breakpoints)
-                            {
-                                steps.push(RPCStep {
-                                    depth,
-                                    error,
-                                    gas: Hex(gas),
-                                    gas_cost: Hex(gas_cost),
-                                    breakpoint_index: Some(breakpoint_index),
-                                    breakpoint: Some(format!(
-                                        "{}:{}:{}", breakpoint.offset, breakpoint.length,
-                                        breakpoint.file_name)),
-                                    code_hash: Hex(code_hash),
-                                    address: Hex(address),
-                                    memory,
-                                    op, pc, opcode_pc,
-                                    stack,
-                                    storage
-                                });
-                            }
-                        }
-                    } else {
-                        steps.push(RPCStep {
-                            depth,
-                            error,
-                            gas: Hex(gas),
-                            gas_cost: Hex(gas_cost),
-                            breakpoint_index: None,
-                            breakpoint: None,
-                            code_hash: Hex(code_hash),
-                            address: Hex(address),
-                            memory,
-                            op, pc, opcode_pc,
-                            stack,
-                            storage
-                        });
                     }
+                    steps.push(step);
                 }
             },
         }
@@ -490,3 +898,95 @@ pub fn replay_transaction<P: Patch>(
 
     Ok((steps, vm))
 }
+
+/// Log entry of the opcode `machine` is about to run, or `None` when
+/// breakpoints are set and the opcode hits none of them.
+fn trace_step<P: Patch>(
+    machine: &Machine<SeqMemory<P>, P>, config: &RPCTraceConfig, breakpoints: Option<&[SourceItem]>
+) -> Result<Option<RPCStep>, Error> {
+    let pc = machine.pc().position();
+    let opcode_pc = machine.pc().opcode_position();
+    let op = machine.pc().code()[pc];
+    let code_hash = H256::from(Keccak256::digest(machine.pc().code()).as_slice());
+    let address = machine.state().context.address;
+
+    let (breakpoint_index, breakpoint) = match (&config.breakpoints, breakpoints) {
+        (&Some(RPCBreakpointConfig { ref source_map, .. }), Some(breakpoints)) => {
+            let hit = match source_map.get(&Hex(code_hash)) {
+                Some(&RPCSourceMapConfig { ref source_map, ref source_list }) => {
+                    let source_map = parse_source_map(source_map, source_list)?;
+                    source_map[opcode_pc].source.find_intersection(breakpoints)
+                        .map(|(index, breakpoint)| (index, format!(
+                            "{}:{}:{}", breakpoint.offset, breakpoint.length, breakpoint.file_name)))
+                },
+                None => None,
+            };
+            match hit {
+                Some((index, breakpoint)) => (Some(index), Some(breakpoint)),
+                None => return Ok(None),
+            }
+        },
+        _ => (None, None),
+    };
+
+    let memory = if config.disable_memory {
+        None
+    } else {
+        let mut ret = Vec::new();
+        for i in 0..machine.state().memory.len() {
+            ret.push(machine.state().memory.read_raw(U256::from(i)));
+        }
+        Some(ret.chunks(32).map(|word| Bytes(word.to_vec())).collect())
+    };
+    let stack = if config.disable_stack {
+        None
+    } else {
+        let mut ret = Vec::new();
+        for i in 0..machine.state().stack.len() {
+            ret.push(Hex(machine.state().stack.peek(i).unwrap()));
+        }
+        Some(ret)
+    };
+    let storage = if config.disable_storage {
+        None
+    } else {
+        let mut ret = HashMap::new();
+        for account in machine.state().account_state.accounts() {
+            let storage = match account {
+                &AccountChange::Full { address: a, ref changing_storage, .. } if a == address => changing_storage,
+                &AccountChange::Create { address: a, ref storage, .. } if a == address => storage,
+                _ => continue,
+            };
+            let storage: HashMap<U256, M256> = storage.clone().into();
+            for (key, value) in storage {
+                ret.insert(Hex(key), Hex(value));
+            }
+        }
+        Some(ret)
+    };
+
+    Ok(Some(RPCStep {
+        depth: machine.state().depth + 1,
+        error: "".to_string(),
+        gas: Hex(machine.state().available_gas()),
+        gas_cost: Hex(Gas::zero()),
+        op: opcode_name(op),
+        pc, opcode_pc,
+        code_hash: Hex(code_hash),
+        address: Hex(address),
+        breakpoint_index, breakpoint,
+        memory, stack, storage,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn message_hash_has_prefix() {
+        assert_eq!(signed_message_hash(b"Hello World"),
+                   H256::from_str("0xa1de988600a42c4b4ab089b619297c17d53cffae5d5120d82d8a92d0bb3b78f2").unwrap());
+    }
+}
@@ -0,0 +1,92 @@
+use futures_cpupool::CpuPool;
+use jsonrpc_core::{BoxFuture, Error, MetaIoHandler, Params, RemoteProcedure, RpcMethod, Value};
+use jsonrpc_core::futures::Future;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use miner::{self, MinerState};
+use super::apikey::RPCMeta;
+use super::journal::JournalMiddleware;
+
+/// Threads RPC methods are executed on, off the event loop of the
+/// server, so that a slow call such as a trace or `eth_getLogs` over a
+/// wide range does not hold up the other requests. Calls still go
+/// through the lock of the state, which orders them with each other
+/// and with the miner.
+#[derive(Clone)]
+pub struct Workers {
+    pool: CpuPool,
+    state: Arc<RwLock<MinerState>>,
+}
+
+/// Run `f` with the memory limit and address format of the node of
+/// `state`, which the patch and the serialization of addresses only
+/// see through the thread executing the call.
+fn with_settings<T, F: FnOnce() -> T>(state: &RwLock<MinerState>, f: F) -> T {
+    let (memory_limit, address_format) = {
+        let state = state.read().unwrap();
+        (state.memory_limit(), state.address_format())
+    };
+    address_format.scope(|| miner::with_memory_limit(memory_limit, f))
+}
+
+impl Workers {
+    /// Pool of `threads` workers executing the calls of the node of
+    /// `state`, or one per CPU if not given.
+    pub fn new(state: Arc<RwLock<MinerState>>, threads: Option<usize>) -> Self {
+        Workers {
+            pool: match threads {
+                Some(threads) => CpuPool::new(threads),
+                None => CpuPool::new_num_cpus(),
+            },
+            state,
+        }
+    }
+
+    /// Have the methods of `delegate` executed by the workers.
+    pub fn offload<D>(&self, delegate: D) -> HashMap<String, RemoteProcedure<RPCMeta>> where
+        D: Into<HashMap<String, RemoteProcedure<RPCMeta>>>
+    {
+        delegate.into().into_iter().map(|(name, procedure)| {
+            let procedure = match procedure {
+                RemoteProcedure::Method(method) => RemoteProcedure::Method(Arc::new(Offloaded {
+                    pool: self.pool.clone(),
+                    state: self.state.clone(),
+                    method,
+                })),
+                procedure => procedure,
+            };
+            (name, procedure)
+        }).collect()
+    }
+
+    /// Add a method taking raw parameters, executed by the workers.
+    pub fn add_method<F>(&self, io: &mut MetaIoHandler<RPCMeta, JournalMiddleware>, name: &str, method: F) where
+        F: Fn(Params) -> Result<Value, Error> + Send + Sync + 'static
+    {
+        let pool = self.pool.clone();
+        let state = self.state.clone();
+        let method = Arc::new(method);
+        io.add_method(name, move |params: Params| {
+            let method = method.clone();
+            let state = state.clone();
+            pool.spawn_fn(move || with_settings(&state, || method(params)))
+        });
+    }
+}
+
+struct Offloaded {
+    pool: CpuPool,
+    state: Arc<RwLock<MinerState>>,
+    method: Arc<RpcMethod<RPCMeta>>,
+}
+
+impl RpcMethod<RPCMeta> for Offloaded {
+    fn call(&self, params: Params, meta: RPCMeta) -> BoxFuture<Value, Error> {
+        // Generated methods compute their result before returning the
+        // future, so the whole call happens on the worker.
+        let method = self.method.clone();
+        let state = self.state.clone();
+        Box::new(self.pool.spawn_fn(move || with_settings(&state, || method.call(params, meta).wait())))
+    }
+}
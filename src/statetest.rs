@@ -0,0 +1,358 @@
+//! Runner of the `GeneralStateTests` fixtures of ethereum/tests. Each
+//! post state of a fixture sets up the pre state in a fresh trie,
+//! executes the transaction picked by its indexes and compares the
+//! resulting state root and logs hash with the expected ones.
+
+use bigint::{Address, Gas, H256, U256};
+use block::{Log, SignaturePatch, TransactionAction, UnsignedTransaction};
+use hexutil::read_hex;
+use rlp::RlpStream;
+use secp256k1::SECP256K1;
+use secp256k1::key::SecretKey;
+use serde::de::DeserializeOwned;
+use serde_json;
+use sha3::{Digest, Keccak256};
+use sputnikvm::{HeaderParams, Patch, SeqTransactionVM, VM};
+use sputnikvm_stateful::MemoryStateful;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::path::Path;
+use std::str::FromStr;
+use trie::{Database, MemoryDatabase};
+
+use error::Error;
+use miner::{self, GenesisAccount};
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct FixtureEnv {
+    pub current_coinbase: String,
+    pub current_difficulty: String,
+    pub current_gas_limit: String,
+    pub current_number: String,
+    pub current_timestamp: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct FixtureAccount {
+    pub balance: String,
+    pub code: String,
+    pub nonce: String,
+    pub storage: HashMap<String, String>,
+}
+
+/// Transaction of a fixture, whose data, gas limit and value are picked
+/// by the indexes of each post state.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct FixtureTransaction {
+    pub data: Vec<String>,
+    pub gas_limit: Vec<String>,
+    pub gas_price: String,
+    pub nonce: String,
+    pub secret_key: String,
+    pub to: String,
+    pub value: Vec<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct PostIndexes {
+    pub data: usize,
+    pub gas: usize,
+    pub value: usize,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct PostState {
+    /// Expected state root.
+    pub hash: String,
+    /// Expected Keccak-256 hash of the RLP list of logs. Older fixtures
+    /// do not have it.
+    #[serde(default)]
+    pub logs: Option<String>,
+    pub indexes: PostIndexes,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct StateTest {
+    pub env: FixtureEnv,
+    pub pre: HashMap<String, FixtureAccount>,
+    pub transaction: FixtureTransaction,
+    /// Post states by hard fork name, such as `Byzantium`.
+    pub post: HashMap<String, Vec<PostState>>,
+}
+
+/// Fixtures sign their transactions without a chain id.
+struct FixtureSignaturePatch;
+
+impl SignaturePatch for FixtureSignaturePatch {
+    fn chain_id() -> Option<u64> { None }
+}
+
+fn keccak(data: &[u8]) -> H256 {
+    H256::from(Keccak256::digest(data).as_slice())
+}
+
+fn invalid(field: &str, value: &str) -> Error {
+    Error::InvalidFixture(format!("{} {:?}", field, value))
+}
+
+pub fn parse_u256(field: &str, value: &str) -> Result<U256, Error> {
+    if value.is_empty() || value == "0x" {
+        return Ok(U256::zero());
+    }
+    if value.starts_with("0x") {
+        U256::from_str(value).map_err(|_| invalid(field, value))
+    } else {
+        U256::from_dec_str(value).map_err(|_| invalid(field, value))
+    }
+}
+
+/// A value that must fit in 64 bits, as block numbers and timestamps
+/// do.
+fn parse_u64(field: &str, value: &str) -> Result<u64, Error> {
+    let parsed = parse_u256(field, value)?;
+    if parsed > U256::from(u64::max_value()) {
+        return Err(invalid(field, value));
+    }
+    Ok(parsed.as_u64())
+}
+
+pub fn parse_bytes(field: &str, value: &str) -> Result<Vec<u8>, Error> {
+    if value.is_empty() || value == "0x" {
+        return Ok(Vec::new());
+    }
+    read_hex(value).map_err(|_| invalid(field, value))
+}
+
+fn parse_address(field: &str, value: &str) -> Result<Address, Error> {
+    let bytes = parse_bytes(field, value)?;
+    if bytes.len() != 20 {
+        return Err(invalid(field, value));
+    }
+    Ok(Address::from(bytes.as_slice()))
+}
+
+pub fn parse_hash(field: &str, value: &str) -> Result<H256, Error> {
+    let bytes = parse_bytes(field, value)?;
+    if bytes.len() != 32 {
+        return Err(invalid(field, value));
+    }
+    Ok(H256::from(bytes.as_slice()))
+}
+
+fn pick<'a>(field: &str, values: &'a [String], index: usize) -> Result<&'a str, Error> {
+    values.get(index).map(|value| value.as_str())
+        .ok_or(Error::InvalidFixture(format!("{} index {} out of range", field, index)))
+}
+
+/// Hashes BLOCKHASH returns in fixtures, the Keccak-256 hash of the
+/// decimal block number as in the reference implementation, most
+/// recent first.
+fn last_hashes(number: u64) -> Vec<H256> {
+    (1..(number.min(256) + 1)).map(|back| keccak((number - back).to_string().as_bytes())).collect()
+}
+
+fn logs_hash(logs: &[Log]) -> H256 {
+    let mut stream = RlpStream::new_list(logs.len());
+    for log in logs {
+        stream.append(log);
+    }
+    keccak(&stream.out())
+}
+
+/// Accounts of the pre state of a fixture.
+pub fn parse_alloc(pre: &HashMap<String, FixtureAccount>) -> Result<Vec<GenesisAccount>, Error> {
+    let mut alloc = Vec::new();
+    for (address, account) in pre {
+        let mut storage = Vec::new();
+        for (index, value) in &account.storage {
+            storage.push((parse_u256("storage index", index)?, parse_u256("storage value", value)?.into()));
+        }
+        alloc.push(GenesisAccount {
+            address: parse_address("pre address", address)?,
+            balance: parse_u256("balance", &account.balance)?,
+            nonce: parse_u256("nonce", &account.nonce)?,
+            code: parse_bytes("code", &account.code)?,
+            storage,
+        });
+    }
+    Ok(alloc)
+}
+
+/// Read the fixtures of a file, or of every JSON file under a
+/// directory, named by file and test.
+pub fn load_fixtures<T: DeserializeOwned>(path: &Path) -> Result<Vec<(String, T)>, Error> {
+    let mut tests = Vec::new();
+    if path.is_dir() {
+        let mut entries = fs::read_dir(path)?.map(|entry| entry.map(|entry| entry.path()))
+            .collect::<Result<Vec<_>, _>>()?;
+        entries.sort();
+        for entry in entries {
+            if entry.is_dir() || entry.extension().map(|ext| ext == "json").unwrap_or(false) {
+                tests.extend(load_fixtures(&entry)?);
+            }
+        }
+        return Ok(tests);
+    }
+
+    let file = File::open(path)?;
+    let fixtures: BTreeMap<String, T> = serde_json::from_reader(file)
+        .map_err(|err| Error::InvalidFixture(format!("{}: {}", path.display(), err)))?;
+    for (name, test) in fixtures {
+        tests.push((format!("{}:{}", path.display(), name), test));
+    }
+    Ok(tests)
+}
+
+/// Execute the transaction of `post` on the pre state of `test` under
+/// the rules of `P`, returning what differs from the expected post
+/// state, if anything.
+pub fn check_post<P: Patch>(test: &StateTest, post: &PostState) -> Result<Option<String>, Error> {
+    let alloc = parse_alloc(&test.pre)?;
+    let database = MemoryDatabase::default();
+    let root = miner::apply_alloc(&database, database.create_empty().root(), &alloc);
+    let mut stateful = MemoryStateful::new(&database, root);
+
+    let env = &test.env;
+    let number = parse_u64("currentNumber", &env.current_number)?;
+    let header = HeaderParams {
+        beneficiary: parse_address("currentCoinbase", &env.current_coinbase)?,
+        timestamp: parse_u64("currentTimestamp", &env.current_timestamp)?,
+        number: U256::from(number),
+        difficulty: parse_u256("currentDifficulty", &env.current_difficulty)?,
+        gas_limit: Gas::from(parse_u256("currentGasLimit", &env.current_gas_limit)?),
+    };
+
+    let fixture = &test.transaction;
+    let secret_key = SecretKey::from_slice(&SECP256K1, &parse_bytes("secretKey", &fixture.secret_key)?)?;
+    let transaction = UnsignedTransaction {
+        nonce: parse_u256("nonce", &fixture.nonce)?,
+        gas_price: Gas::from(parse_u256("gasPrice", &fixture.gas_price)?),
+        gas_limit: Gas::from(parse_u256("gasLimit", pick("gasLimit", &fixture.gas_limit, post.indexes.gas)?)?),
+        action: if fixture.to.is_empty() {
+            TransactionAction::Create
+        } else {
+            TransactionAction::Call(parse_address("to", &fixture.to)?)
+        },
+        value: parse_u256("value", pick("value", &fixture.value, post.indexes.value)?)?,
+        input: parse_bytes("data", pick("data", &fixture.data, post.indexes.data)?)?,
+    }.sign::<FixtureSignaturePatch>(&secret_key);
+
+    // Invalid transactions leave the pre state as it is.
+    let logs: Vec<Log> = match stateful.to_valid::<P>(&transaction) {
+        Ok(valid) => {
+            let vm: SeqTransactionVM<P> = stateful.call(valid, &header, &last_hashes(number));
+            let accounts: Vec<_> = vm.accounts().cloned().collect();
+            stateful.transit(&accounts);
+            vm.logs().into()
+        },
+        Err(_) => Vec::new(),
+    };
+
+    let expected_root = parse_hash("hash", &post.hash)?;
+    if stateful.root() != expected_root {
+        return Ok(Some(format!("state root 0x{:x}, expected 0x{:x}", stateful.root(), expected_root)));
+    }
+    if let Some(ref expected_logs) = post.logs {
+        let expected_logs = parse_hash("logs", expected_logs)?;
+        let actual_logs = logs_hash(&logs);
+        if actual_logs != expected_logs {
+            return Ok(Some(format!("logs hash 0x{:x}, expected 0x{:x}", actual_logs, expected_logs)));
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use block::FromKey;
+    use sputnikvm_network_foundation::ByzantiumPatch;
+
+    /// Fixture of a transfer of 1 wei out of an account holding 1000,
+    /// in a block with `timestamp`.
+    fn transfer(timestamp: &str) -> StateTest {
+        let secret_key = SecretKey::from_slice(&SECP256K1, &[1u8; 32]).unwrap();
+        let sender = Address::from_secret_key(&secret_key).unwrap();
+        let recipient = Address::from(0x42u64);
+
+        let mut pre = HashMap::new();
+        pre.insert(format!("0x{:x}", sender), FixtureAccount {
+            balance: "1000".to_string(),
+            code: "0x".to_string(),
+            nonce: "0".to_string(),
+            storage: HashMap::new(),
+        });
+
+        let database = MemoryDatabase::default();
+        let root = miner::apply_alloc(&database, database.create_empty().root(), &[
+            GenesisAccount { address: sender, balance: U256::from(999u64), nonce: U256::one(), code: Vec::new(), storage: Vec::new() },
+            GenesisAccount { address: recipient, balance: U256::one(), nonce: U256::zero(), code: Vec::new(), storage: Vec::new() },
+        ]);
+        let mut post = HashMap::new();
+        post.insert("Byzantium".to_string(), vec![PostState {
+            hash: format!("0x{:x}", root),
+            logs: Some(format!("0x{:x}", logs_hash(&[]))),
+            indexes: PostIndexes { data: 0, gas: 0, value: 0 },
+        }]);
+
+        StateTest {
+            env: FixtureEnv {
+                // The fee of the transfer is 0, so the recipient is the
+                // coinbase to keep it from being an empty account.
+                current_coinbase: format!("0x{:x}", recipient),
+                current_difficulty: "0x020000".to_string(),
+                current_gas_limit: "0x0f4240".to_string(),
+                current_number: "0x01".to_string(),
+                current_timestamp: timestamp.to_string(),
+            },
+            pre,
+            transaction: FixtureTransaction {
+                data: vec!["0x".to_string()],
+                gas_limit: vec!["0x5208".to_string()],
+                gas_price: "0x00".to_string(),
+                nonce: "0x00".to_string(),
+                secret_key: format!("0x{}", "01".repeat(32)),
+                to: format!("0x{:x}", recipient),
+                value: vec!["0x01".to_string()],
+            },
+            post,
+        }
+    }
+
+    #[test]
+    fn parses_fixture_values() {
+        assert_eq!(parse_u256("nonce", "0x").unwrap(), U256::zero());
+        assert_eq!(parse_u256("nonce", "0x0a").unwrap(), U256::from(10u64));
+        assert_eq!(parse_u256("nonce", "10").unwrap(), U256::from(10u64));
+        assert!(parse_address("to", "0x1234").is_err());
+        assert_eq!(last_hashes(3).len(), 3);
+        assert_eq!(last_hashes(3)[0], keccak(b"2"));
+        assert_eq!(logs_hash(&[]), keccak(&[0xc0]));
+    }
+
+    #[test]
+    fn runs_fixtures() {
+        let test = transfer("0x03e8");
+        let post = &test.post["Byzantium"][0];
+        assert_eq!(check_post::<ByzantiumPatch>(&test, post).unwrap(), None);
+
+        let mut wrong = post.clone();
+        wrong.hash = format!("0x{:x}", H256::default());
+        let failure = check_post::<ByzantiumPatch>(&test, &wrong).unwrap().unwrap();
+        assert!(failure.starts_with("state root"), "{}", failure);
+    }
+
+    #[test]
+    fn rejects_values_over_64_bits() {
+        assert_eq!(parse_u64("currentNumber", "0xffffffffffffffff").unwrap(), u64::max_value());
+        assert!(parse_u64("currentNumber", "0x010000000000000000").is_err());
+
+        let test = transfer("0x010000000000000000");
+        match check_post::<ByzantiumPatch>(&test, &test.post["Byzantium"][0]) {
+            Err(Error::InvalidFixture(_)) => (),
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+}
@@ -0,0 +1,264 @@
+//! Golden RLP encodings of the core types, locking in wire
+//! compatibility with other clients. The genesis header and the EIP-155
+//! transaction are taken from Ethereum mainnet and the EIP, and their
+//! hashes are the ones published there.
+
+/// Header of the Ethereum mainnet genesis block.
+pub const MAINNET_GENESIS_HEADER: &str = concat!(
+    "0xf90214",
+    "a00000000000000000000000000000000000000000000000000000000000000000",
+    "a01dcc4de8dec75d7aab85b567b6ccd41ad312451b948a7413f0a142fd40d49347",
+    "940000000000000000000000000000000000000000",
+    "a0d7f8974fb5ac78d9ac099b9ad5018bedc2ce0a72dad1827a1709da30580f0544",
+    "a056e81f171bcc55a6ff8345e692c0f86e5b48e01b996cadc001622fb5e363b421",
+    "a056e81f171bcc55a6ff8345e692c0f86e5b48e01b996cadc001622fb5e363b421",
+    "b90100",
+    "00000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000",
+    "00000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000",
+    "00000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000",
+    "00000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000",
+    "850400000000",
+    "80",
+    "821388",
+    "80",
+    "80",
+    "a011bbe8db4e347b4e8c937c1c8370e4b5ed33adb3db69cbdb7a38e1e50b1b82fa",
+    "a00000000000000000000000000000000000000000000000000000000000000000",
+    "880000000000000042"
+);
+pub const MAINNET_GENESIS_HASH: &str = "0xd4e56740f876aef8c010b86a40d5f56745a118d0906a34e69aec8c0db1cb8fa3";
+
+/// Example transaction of EIP-155, signed for chain id 1.
+pub const EIP155_TRANSACTION: &str = concat!(
+    "0xf86c",
+    "09",
+    "8504a817c800",
+    "825208",
+    "943535353535353535353535353535353535353535",
+    "880de0b6b3a7640000",
+    "80",
+    "25",
+    "a028ef61340bd939bc2195fe537567866003e1a15d3c71ff63e1590620aa636276",
+    "a067cbe9d8997f761aecb703304b3800ccf555c9f3dc64214b297fb1966a3b6d83"
+);
+pub const EIP155_TRANSACTION_HASH: &str = "0x33469b22e9f636356c4160a87eb19df52b7412e8eac32a4a55ffe88ea8350788";
+
+/// Contract creation with zero nonce, gas price and value, and no init
+/// code, signed without a chain id.
+pub const EMPTY_CREATE_TRANSACTION: &str = concat!(
+    "0xf84b",
+    "80",
+    "80",
+    "82cf08",
+    "80",
+    "80",
+    "80",
+    "1b",
+    "a028ef61340bd939bc2195fe537567866003e1a15d3c71ff63e1590620aa636276",
+    "a067cbe9d8997f761aecb703304b3800ccf555c9f3dc64214b297fb1966a3b6d83"
+);
+pub const EMPTY_CREATE_TRANSACTION_HASH: &str = "0xe8b3de5dfd1c037c19c3cc4316836970f05b680a213a68bf5622eaa073101f25";
+
+/// ERC-20 `Transfer` topic.
+pub const TRANSFER_TOPIC: &str = "0xddf252ad1be2c89b69c2b068fc378daa952ba7f163c4a11628f55a4df523b3ef";
+
+/// Log of 0x3535...35 with the `Transfer` topic and a word of 1 as data.
+pub const TRANSFER_LOG: &str = concat!(
+    "0xf858",
+    "943535353535353535353535353535353535353535",
+    "e1a0ddf252ad1be2c89b69c2b068fc378daa952ba7f163c4a11628f55a4df523b3ef",
+    "a00000000000000000000000000000000000000000000000000000000000000001"
+);
+
+/// Log of the zero address without topics nor data.
+pub const EMPTY_LOG: &str = "0xd7940000000000000000000000000000000000000000c080";
+
+/// Pre-Byzantium receipt with the mainnet genesis state root, 22000 gas
+/// used and `TRANSFER_LOG`.
+pub const TRANSFER_RECEIPT: &str = concat!(
+    "0xf90183",
+    "a0d7f8974fb5ac78d9ac099b9ad5018bedc2ce0a72dad1827a1709da30580f0544",
+    "8255f0",
+    "b90100",
+    "00000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000",
+    "00000000000000000200000800000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000001000000000",
+    "10000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000",
+    "00000002000000000000000000000000000000000000000000000000001000000000000000000000000000000000000000000000000000000000000000000000",
+    "f85a",
+    "f858943535353535353535353535353535353535353535",
+    "e1a0ddf252ad1be2c89b69c2b068fc378daa952ba7f163c4a11628f55a4df523b3ef",
+    "a00000000000000000000000000000000000000000000000000000000000000001"
+);
+
+/// Receipt with a zero state root, no gas used, an empty bloom and no
+/// logs.
+pub const EMPTY_RECEIPT: &str = concat!(
+    "0xf90126",
+    "a00000000000000000000000000000000000000000000000000000000000000000",
+    "80",
+    "b90100",
+    "00000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000",
+    "00000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000",
+    "00000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000",
+    "00000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000",
+    "c0"
+);
+
+/// Account without nonce, balance, storage nor code.
+pub const EMPTY_ACCOUNT: &str = concat!(
+    "0xf844",
+    "80",
+    "80",
+    "a056e81f171bcc55a6ff8345e692c0f86e5b48e01b996cadc001622fb5e363b421",
+    "a0c5d2460186f7233c927e7db2dcc703c0e500b653ca82273b7bfad8045d85a470"
+);
+
+/// Account with nonce 1, a balance of one ether, empty storage and code
+/// `0x6000`.
+pub const CONTRACT_ACCOUNT: &str = concat!(
+    "0xf84c",
+    "01",
+    "880de0b6b3a7640000",
+    "a056e81f171bcc55a6ff8345e692c0f86e5b48e01b996cadc001622fb5e363b421",
+    "a007ad118d6cc8642c86c03827f276d8b791a65e5c99a3845faf186be720a1455d"
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bigint::{Address, Gas, H256, U256};
+    use bloom::LogsBloom;
+    use block::{Account, Header, HeaderHash, Log, Receipt, RlpHash, Transaction, TransactionAction};
+    use hexutil::read_hex;
+    use rlp::{self, UntrustedRlp};
+    use sha3::{Digest, Keccak256};
+
+    fn bytes(vector: &str) -> Vec<u8> {
+        read_hex(vector).unwrap()
+    }
+
+    fn hash(vector: &str) -> H256 {
+        H256::from(bytes(vector).as_slice())
+    }
+
+    fn ether() -> U256 {
+        U256::from(1_000_000_000_000_000_000u64)
+    }
+
+    fn empty_root() -> H256 {
+        hash("0x56e81f171bcc55a6ff8345e692c0f86e5b48e01b996cadc001622fb5e363b421")
+    }
+
+    fn transfer_log() -> Log {
+        let mut data = [0u8; 32];
+        data[31] = 1;
+        Log {
+            address: Address::from(&[0x35u8; 20][..]),
+            topics: vec![hash(TRANSFER_TOPIC)],
+            data: data.to_vec(),
+        }
+    }
+
+    #[test]
+    fn mainnet_genesis_header() {
+        let header: Header = UntrustedRlp::new(&bytes(MAINNET_GENESIS_HEADER)).as_val().unwrap();
+        assert_eq!(header.header_hash(), hash(MAINNET_GENESIS_HASH));
+        assert_eq!(header.number, U256::zero());
+        assert_eq!(header.gas_limit, Gas::from(5000u64));
+        assert_eq!(header.difficulty, U256::from(0x400000000u64));
+        assert_eq!(header.transactions_root, empty_root());
+        assert_eq!(rlp::encode(&header).to_vec(), bytes(MAINNET_GENESIS_HEADER));
+    }
+
+    #[test]
+    fn eip155_transaction() {
+        let transaction: Transaction = UntrustedRlp::new(&bytes(EIP155_TRANSACTION)).as_val().unwrap();
+        assert_eq!(transaction.nonce, U256::from(9u64));
+        assert_eq!(transaction.gas_price, Gas::from(20_000_000_000u64));
+        assert_eq!(transaction.gas_limit, Gas::from(21000u64));
+        assert_eq!(transaction.action, TransactionAction::Call(Address::from(&[0x35u8; 20][..])));
+        assert_eq!(transaction.value, ether());
+        assert!(transaction.input.is_empty());
+        assert_eq!(transaction.signature.v, 37);
+        assert_eq!(transaction.rlp_hash(), hash(EIP155_TRANSACTION_HASH));
+        assert_eq!(rlp::encode(&transaction).to_vec(), bytes(EIP155_TRANSACTION));
+    }
+
+    #[test]
+    fn empty_create_transaction() {
+        let transaction: Transaction = UntrustedRlp::new(&bytes(EMPTY_CREATE_TRANSACTION)).as_val().unwrap();
+        assert_eq!(transaction.nonce, U256::zero());
+        assert_eq!(transaction.gas_price, Gas::zero());
+        assert_eq!(transaction.action, TransactionAction::Create);
+        assert_eq!(transaction.value, U256::zero());
+        assert!(transaction.input.is_empty());
+        assert_eq!(transaction.signature.v, 27);
+        assert_eq!(transaction.rlp_hash(), hash(EMPTY_CREATE_TRANSACTION_HASH));
+        assert_eq!(rlp::encode(&transaction).to_vec(), bytes(EMPTY_CREATE_TRANSACTION));
+    }
+
+    #[test]
+    fn logs() {
+        assert_eq!(hash(TRANSFER_TOPIC),
+                   H256::from(Keccak256::digest(b"Transfer(address,address,uint256)").as_slice()));
+        assert_eq!(rlp::encode(&transfer_log()).to_vec(), bytes(TRANSFER_LOG));
+
+        let empty = Log { address: Address::default(), topics: Vec::new(), data: Vec::new() };
+        assert_eq!(rlp::encode(&empty).to_vec(), bytes(EMPTY_LOG));
+
+        let decoded: Log = UntrustedRlp::new(&bytes(TRANSFER_LOG)).as_val().unwrap();
+        assert_eq!(decoded.topics, vec![hash(TRANSFER_TOPIC)]);
+        assert_eq!(rlp::encode(&decoded).to_vec(), bytes(TRANSFER_LOG));
+    }
+
+    #[test]
+    fn receipts() {
+        let log = transfer_log();
+        let mut logs_bloom = LogsBloom::new();
+        logs_bloom.set(&log.address);
+        for topic in &log.topics {
+            logs_bloom.set(topic);
+        }
+        let receipt = Receipt {
+            state_root: hash("0xd7f8974fb5ac78d9ac099b9ad5018bedc2ce0a72dad1827a1709da30580f0544"),
+            used_gas: Gas::from(22000u64),
+            logs_bloom,
+            logs: vec![log],
+        };
+        assert_eq!(rlp::encode(&receipt).to_vec(), bytes(TRANSFER_RECEIPT));
+
+        let empty = Receipt {
+            state_root: H256::default(),
+            used_gas: Gas::zero(),
+            logs_bloom: LogsBloom::new(),
+            logs: Vec::new(),
+        };
+        assert_eq!(rlp::encode(&empty).to_vec(), bytes(EMPTY_RECEIPT));
+
+        let decoded: Receipt = UntrustedRlp::new(&bytes(TRANSFER_RECEIPT)).as_val().unwrap();
+        assert_eq!(rlp::encode(&decoded).to_vec(), bytes(TRANSFER_RECEIPT));
+    }
+
+    #[test]
+    fn accounts() {
+        let empty = Account {
+            nonce: U256::zero(),
+            balance: U256::zero(),
+            storage_root: empty_root(),
+            code_hash: H256::from(Keccak256::digest(&[]).as_slice()),
+        };
+        assert_eq!(rlp::encode(&empty).to_vec(), bytes(EMPTY_ACCOUNT));
+
+        let contract = Account {
+            nonce: U256::one(),
+            balance: ether(),
+            storage_root: empty_root(),
+            code_hash: H256::from(Keccak256::digest(&[0x60, 0x00]).as_slice()),
+        };
+        assert_eq!(rlp::encode(&contract).to_vec(), bytes(CONTRACT_ACCOUNT));
+
+        let decoded: Account = UntrustedRlp::new(&bytes(CONTRACT_ACCOUNT)).as_val().unwrap();
+        assert_eq!(decoded.nonce, U256::one());
+        assert_eq!(decoded.balance, ether());
+    }
+}